@@ -1,21 +1,26 @@
 use voidray_launcher::EngineApi;
+use voidray_renderer::aov::Aov;
 use voidray_renderer::camera::Camera;
 use voidray_renderer::render::renderer::RenderAction;
 use voidray_renderer::scene::Scene;
 use voidray_renderer::settings::ColorManagementSettings;
+use voidray_renderer::settings::FilterType;
 use voidray_renderer::settings::RenderMode;
 use voidray_renderer::settings::RenderSettings;
+use voidray_renderer::settings::SamplerType;
 use voidray_renderer::settings::Settings;
+use voidray_renderer::settings::TileOrder;
 use voidray_renderer::settings::Tonemap;
 use voidray_renderer::vec3;
 use voidray_renderer::vector::Vec3;
 use voidray_renderer::vector::PI;
 
 use crate::egui::*;
-use crate::examples::cornell;
-use crate::examples::material;
-use crate::examples::mushroom;
-use crate::examples::spheres;
+use voidray_common::examples::cornell;
+use voidray_common::examples::material;
+use voidray_common::examples::mushroom;
+use voidray_common::examples::spheres;
+use voidray_common::scene_file;
 use crate::utils::human_duration;
 use crate::widgets::FatButton;
 use crate::VoidrayEngine;
@@ -23,11 +28,14 @@ use crate::VoidrayEngine;
 pub struct GuiState {
     pub startup: bool,
     pub demo: DemoScene,
+    pub scene_path: String,
+    /// Last error from loading or saving a scene, shown until dismissed
+    pub error: Option<String>,
 }
 
 impl Default for GuiState {
     fn default() -> Self {
-        Self { startup: true, demo: DemoScene::None, }
+        Self { startup: true, demo: DemoScene::None, scene_path: "scene.ron".to_owned(), error: None }
     }
 }
 
@@ -52,6 +60,34 @@ pub fn engine_ui(engine: &mut VoidrayEngine, context: &mut Context, api: &mut En
                     engine.target.force_clear();
                     engine.state.startup = true;
                 }
+
+                ui.separator();
+                ui.text_edit_singleline(&mut engine.state.scene_path);
+                let rendering = engine.renderer.currently_rendering();
+                if ui.add_enabled(!rendering, Button::new("Open scene")).clicked() {
+                    match scene_file::load_scene(&engine.state.scene_path) {
+                        Ok(loaded) => {
+                            load_scene(engine, loaded);
+                            engine.state.startup = false;
+                        }
+                        Err(err) => {
+                            engine.state.error =
+                                Some(format!("Failed to load '{}': {}", engine.state.scene_path, err));
+                        }
+                    }
+                }
+                if ui.button("Save scene").clicked() {
+                    let result = scene_file::save_scene(
+                        &engine.state.scene_path,
+                        &engine.scene.read().unwrap(),
+                        &engine.settings.read().unwrap(),
+                        engine.target.dimensions(),
+                    );
+                    if let Err(err) = result {
+                        engine.state.error =
+                            Some(format!("Failed to save '{}': {}", engine.state.scene_path, err));
+                    }
+                }
             });
 
             ui.menu_button("About", |ui| {});
@@ -82,6 +118,16 @@ pub fn engine_ui(engine: &mut VoidrayEngine, context: &mut Context, api: &mut En
                         .color_management
                         .display_ui(ui, &mut modified, true);
                 }
+                // Keep the viewport in sync with the denoise toggle, finished renders are
+                // denoised right away
+                let denoise = engine.settings.read().unwrap().color_management.denoise;
+                if denoise != engine.target.denoise() {
+                    engine.target.set_denoise(denoise);
+                    if !currently_rendering {
+                        engine.target.push();
+                    }
+                }
+                display_selector(engine, ui, currently_rendering);
                 render_actions(engine, ui, currently_rendering);
 
                 let samples = engine.renderer.samples();
@@ -94,6 +140,10 @@ pub fn engine_ui(engine: &mut VoidrayEngine, context: &mut Context, api: &mut En
                     ui.add_space(5.0);
                 }
                 ui.label(format!("Samples: {}/{}", samples.0, samples.1));
+                ui.label(format!(
+                    "Converged: {:.1}%",
+                    engine.renderer.convergence() * 100.0
+                ));
                 ui.label(format!("Elapsed time: {}", human_duration(&time)));
                 if let Some(remaining) = remaining {
                     ui.label(format!("Remaining time: {}", human_duration(&remaining)));
@@ -114,8 +164,7 @@ pub fn engine_ui(engine: &mut VoidrayEngine, context: &mut Context, api: &mut En
             });
     }
 
-    let startup = &mut engine.state.startup;
-    if *startup {
+    if engine.state.startup {
         // CentralPanel::default().show(context, |ui| {});
         Window::new("")
             .title_bar(false)
@@ -177,24 +226,28 @@ pub fn engine_ui(engine: &mut VoidrayEngine, context: &mut Context, api: &mut En
                         .show(ui, |ui| {
                             ui.horizontal_centered(|ui| {
                                 if ui.add(FatButton::new("Load").width(width)).clicked() {
-                                    let (scene, settings, dimensions) = match engine.state.demo {
-                                        DemoScene::None => (Scene::empty(), Settings::default(), [1000, 1000]),
+                                    let loaded = match engine.state.demo {
+                                        DemoScene::None => Ok((Scene::empty(), Settings::default(), [1000, 1000])),
                                         DemoScene::Spheres => spheres::scene(),
                                         DemoScene::Cornell => cornell::scene(),
                                         DemoScene::Mushroom => mushroom::scene(),
                                         DemoScene::Material => material::scene(),
                                     };
 
-                                    *engine.scene.write().unwrap() = scene;
-                                    *engine.settings.write().unwrap() = settings;
-                                    engine.target.resize(dimensions);
-                
-                                    *startup = false;
+                                    match loaded {
+                                        Ok(loaded) => {
+                                            load_scene(engine, loaded);
+                                            engine.state.startup = false;
+                                        }
+                                        Err(err) => {
+                                            engine.state.error = Some(format!("Failed to load scene: {}", err));
+                                        }
+                                    }
                                 }
                             });
                             ui.horizontal_centered(|ui| {
                                 if ui.add(FatButton::new("Close").width(width)).clicked() {
-                                    *startup = false;
+                                    engine.state.startup = false;
                                 }
                             });
                             ui.end_row();
@@ -202,6 +255,44 @@ pub fn engine_ui(engine: &mut VoidrayEngine, context: &mut Context, api: &mut En
                 });
             });
     }
+
+    // Checkpoint errors of the render thread replace the current error
+    let errors = engine.renderer.take_errors();
+    if !errors.is_empty() {
+        let messages: Vec<String> = errors.iter().map(|err| format!("Checkpoint: {}", err)).collect();
+        engine.state.error = Some(messages.join("\n"));
+    }
+
+    error_window(engine, context);
+}
+
+/// Show the last load or save error in a window until it is dismissed
+fn error_window(engine: &mut VoidrayEngine, context: &mut Context) {
+    let mut dismissed = false;
+    if let Some(error) = &engine.state.error {
+        Window::new("Error")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(context, |ui| {
+                ui.colored_label(Color32::RED, error);
+                ui.add_space(5.0);
+                if ui.button("Dismiss").clicked() {
+                    dismissed = true;
+                }
+            });
+    }
+
+    if dismissed {
+        engine.state.error = None;
+    }
+}
+
+/// Replace the current scene, settings and target dimensions
+fn load_scene(engine: &mut VoidrayEngine, (scene, settings, dimensions): (Scene, Settings, [u32; 2])) {
+    *engine.scene.write().unwrap() = scene;
+    *engine.settings.write().unwrap() = settings;
+    engine.target.resize(dimensions);
 }
 
 impl Editable for Camera {
@@ -309,6 +400,124 @@ impl Editable for RenderSettings {
                                     .clamp_range(0..=255),
                             );
                             ui.end_row();
+                            ui.label("Roulette depth:");
+                            ui.add(
+                                DragValue::new(&mut self.russian_roulette_depth)
+                                    .speed(1)
+                                    .clamp_range(0..=255),
+                            );
+                            ui.end_row();
+                            let mut adaptive = self.noise_threshold > 0.0;
+                            ui.label("Adaptive sampling:");
+                            if ui.checkbox(&mut adaptive, "").changed() {
+                                self.noise_threshold = if adaptive { 0.01 } else { 0.0 };
+                            }
+                            ui.end_row();
+                            if adaptive {
+                                ui.label("Noise threshold:");
+                                ui.add(
+                                    DragValue::new(&mut self.noise_threshold)
+                                        .speed(0.001)
+                                        .max_decimals(4)
+                                        .clamp_range(0.0001..=1.0),
+                                );
+                                ui.end_row();
+                                ui.label("Min samples:");
+                                ui.add(
+                                    DragValue::new(&mut self.adaptive_min_samples)
+                                        .speed(1)
+                                        .clamp_range(2..=u32::max_value()),
+                                );
+                                ui.end_row();
+                            }
+                            ui.label("Seed:");
+                            ui.add(DragValue::new(&mut self.seed).speed(1));
+                            ui.end_row();
+                            ui.label("Sampler:");
+                            ComboBox::from_id_source("sampler")
+                                .selected_text(format!("{:?}", self.sampler))
+                                .width(110.0)
+                                .show_ui(ui, |ui| {
+                                    for sampler in [
+                                        SamplerType::Independent,
+                                        SamplerType::Stratified,
+                                        SamplerType::Halton,
+                                        SamplerType::Sobol,
+                                    ] {
+                                        ui.selectable_value(
+                                            &mut self.sampler,
+                                            sampler,
+                                            format!("{:?}", sampler),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            ui.label("Filter:");
+                            ComboBox::from_id_source("filter")
+                                .selected_text(format!("{:?}", self.filter))
+                                .width(110.0)
+                                .show_ui(ui, |ui| {
+                                    for filter in [
+                                        FilterType::Box,
+                                        FilterType::Tent,
+                                        FilterType::Gaussian,
+                                        FilterType::Mitchell,
+                                        FilterType::BlackmanHarris,
+                                    ] {
+                                        ui.selectable_value(
+                                            &mut self.filter,
+                                            filter,
+                                            format!("{:?}", filter),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            ui.label("Filter radius:");
+                            ui.add(
+                                DragValue::new(&mut self.filter_radius)
+                                    .speed(0.05)
+                                    .clamp_range(0.5..=4.0),
+                            );
+                            ui.end_row();
+                            ui.label("Tile size:");
+                            ui.add(
+                                DragValue::new(&mut self.tile_size)
+                                    .speed(1)
+                                    .clamp_range(8..=512),
+                            );
+                            ui.end_row();
+                            ui.label("Tile order:");
+                            ComboBox::from_id_source("tile_order")
+                                .selected_text(format!("{:?}", self.tile_order))
+                                .width(110.0)
+                                .show_ui(ui, |ui| {
+                                    for order in
+                                        [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert]
+                                    {
+                                        ui.selectable_value(
+                                            &mut self.tile_order,
+                                            order,
+                                            format!("{:?}", order),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            ui.label("Progressive:");
+                            ui.checkbox(&mut self.progressive, "");
+                            ui.end_row();
+                            ui.label("Checkpoint:");
+                            let mut path = self.checkpoint_path.clone().unwrap_or_default();
+                            if ui.text_edit_singleline(&mut path).changed() {
+                                self.checkpoint_path = (!path.is_empty()).then_some(path);
+                            }
+                            ui.end_row();
+                            ui.label("Checkpoint interval:");
+                            ui.add(
+                                DragValue::new(&mut self.checkpoint_interval)
+                                    .speed(1.0)
+                                    .clamp_range(1.0..=86400.0),
+                            );
+                            ui.end_row();
                             ui.label("Render mode:");
                             ComboBox::from_id_source("render_mode")
                                 .selected_text(format!("{:?}", self.render_mode))
@@ -324,9 +533,26 @@ impl Editable for RenderSettings {
                                         RenderMode::Normal,
                                         format!("{:?}", RenderMode::Normal),
                                     );
+                                    ui.selectable_value(
+                                        &mut self.render_mode,
+                                        RenderMode::Spectral,
+                                        format!("{:?}", RenderMode::Spectral),
+                                    );
                                 });
                             ui.end_row();
                         });
+                    CollapsingHeader::new("Passes").show(ui, |ui| {
+                        for aov in Aov::ALL {
+                            let mut enabled = self.aovs.contains(&aov);
+                            if ui.checkbox(&mut enabled, aov.name()).changed() {
+                                if enabled {
+                                    self.aovs.push(aov);
+                                } else {
+                                    self.aovs.retain(|&other| other != aov);
+                                }
+                            }
+                        }
+                    });
                 });
             });
         ui.add_space(15.0);
@@ -378,6 +604,9 @@ impl Editable for ColorManagementSettings {
                                     );
                                 });
                             ui.end_row();
+                            ui.label("Denoise:");
+                            ui.checkbox(&mut self.denoise, "");
+                            ui.end_row();
                             ui.label("Gamma");
                             ui.add(
                                 DragValue::new(&mut self.gamma)
@@ -402,6 +631,33 @@ impl Editable for ColorManagementSettings {
     }
 }
 
+/// Choose between the beauty image and the enabled AOVs for the viewport
+fn display_selector(engine: &mut VoidrayEngine, ui: &mut Ui, rendering: bool) {
+    let aovs = engine.settings.read().unwrap().render.aovs.clone();
+    let display = engine.target.display();
+    let mut selected = display;
+
+    ui.horizontal(|ui| {
+        ui.label("Display:");
+        ComboBox::from_id_source("display")
+            .selected_text(selected.map_or("beauty", |aov| aov.name()))
+            .width(110.0)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut selected, None, "beauty");
+                for aov in aovs {
+                    ui.selectable_value(&mut selected, Some(aov), aov.name());
+                }
+            });
+    });
+
+    if selected != display {
+        engine.target.set_display(selected);
+        if !rendering {
+            engine.target.push();
+        }
+    }
+}
+
 pub fn render_actions(engine: &mut VoidrayEngine, ui: &mut Ui, rendering: bool) {
     let width = 125.0;
     Grid::new("render_actions")
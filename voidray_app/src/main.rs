@@ -17,7 +17,6 @@ use voidray_renderer::vulkano::device::Features;
 use voidray_renderer::vulkano::pipeline::graphics;
 use voidray_renderer::vulkano::render_pass::Subpass;
 
-mod examples;
 mod gui;
 mod utils;
 mod widgets;
@@ -72,15 +71,8 @@ impl Engine for VoidrayEngine {
         viewport: graphics::viewport::Viewport,
         api: &mut EngineApi,
     ) {
-        let samples = self.renderer.samples();
-        let mut scale = samples.1 as f32 / samples.0 as f32;
-        if !scale.is_normal() {
-            scale = 0.0;
-        }
-
         let settings = self.settings.read().unwrap();
         let data = Some(PostProcessingData {
-            scale,
             exposure: settings.color_management.exposure,
             gamma: settings.color_management.gamma,
             tonemap: settings.color_management.tonemap.as_i32(),
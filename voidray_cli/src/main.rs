@@ -0,0 +1,346 @@
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use voidray_common::examples::{cornell, material, mushroom, spheres};
+use voidray_common::{gltf_import, scene_file};
+use voidray_renderer::aov::Aov;
+use voidray_renderer::render::color_management::color_manage;
+use voidray_renderer::render::denoise::{add_denoise_features, denoise_layers};
+use voidray_renderer::render::image::{HdrImage, OutputFormat};
+use voidray_renderer::render::offline::render_to_image_timed;
+use voidray_renderer::scene::Scene;
+use voidray_renderer::settings::{RenderMode, Settings};
+use voidray_renderer::vector::Float;
+
+static USAGE: &str = "Usage: voidray_cli <scene> [options]
+
+Arguments:
+  <scene>                    Scene file (.ron, .gltf, .glb) or example scene to render
+                             (spheres, cornell, mushroom, material)
+
+Options:
+  -o, --output <path>        Output file (default: render.png)
+  -f, --format <format>      Output format, one of png, png16 or exr (default: from extension)
+  -s, --samples <n>          Samples per pixel (default: from the scene settings)
+  -t, --time <seconds>       Stop rendering once the time budget is used up
+  --seed <n>                 Random seed, renders with the same seed are identical
+                             (default: from the scene settings)
+  --noise-threshold <t>      Enable adaptive sampling, pixels stop sampling once their
+                             relative error is below this threshold (default: off)
+  -d, --dimensions <w>x<h>   Output dimensions (default: from the scene)
+  --region <x>,<y>,<w>,<h>   Only render this region of the image, in pixels
+  --aovs <name>,...          Also write these passes as layers of the EXR output, any of
+                             albedo, normal, depth, position, uv, object_id, material_id,
+                             diffuse_direct, diffuse_indirect, specular_direct,
+                             specular_indirect, emission, sample_count
+  --denoise                  Denoise the image, guided by the albedo and normal passes
+  --spectral                 Trace wavelengths instead of rgb, for dispersion and accurate
+                             metals
+  --checkpoint <path>        Periodically save the render to this file, and resume from it
+                             if it holds an interrupted render of the same scene
+  --checkpoint-interval <s>  Seconds between checkpoints (default: from the scene settings)
+  -h, --help                 Print this message";
+
+/// Parsed command line options
+struct Options {
+    scene: String,
+    output: PathBuf,
+    format: Option<OutputFormat>,
+    samples: Option<u32>,
+    time: Option<Duration>,
+    dimensions: Option<[u32; 2]>,
+    seed: Option<u64>,
+    noise_threshold: Option<Float>,
+    region: Option<[u32; 4]>,
+    aovs: Vec<Aov>,
+    denoise: bool,
+    spectral: bool,
+    checkpoint: Option<String>,
+    checkpoint_interval: Option<Float>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut scene = None;
+        let mut output = PathBuf::from("render.png");
+        let mut format = None;
+        let mut samples = None;
+        let mut time = None;
+        let mut dimensions = None;
+        let mut seed = None;
+        let mut noise_threshold = None;
+        let mut region = None;
+        let mut aovs = Vec::new();
+        let mut denoise = false;
+        let mut spectral = false;
+        let mut checkpoint = None;
+        let mut checkpoint_interval = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("missing value for '{}'", name))
+            };
+
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    exit(0);
+                }
+                "-o" | "--output" => output = PathBuf::from(value(&arg)?),
+                "-f" | "--format" => {
+                    format = Some(match value(&arg)?.as_str() {
+                        "png" => OutputFormat::Png,
+                        "png16" => OutputFormat::Png16,
+                        "exr" => OutputFormat::Exr,
+                        other => return Err(format!("unknown format '{}'", other)),
+                    })
+                }
+                "-s" | "--samples" => {
+                    let value = value(&arg)?;
+                    samples = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid sample count '{}'", value))?,
+                    );
+                }
+                "-t" | "--time" => {
+                    let value = value(&arg)?;
+                    time = Some(
+                        parse_time(&value)
+                            .ok_or_else(|| format!("invalid time budget '{}'", value))?,
+                    );
+                }
+                "-d" | "--dimensions" => {
+                    let value = value(&arg)?;
+                    dimensions = Some(
+                        parse_dimensions(&value)
+                            .ok_or_else(|| format!("invalid dimensions '{}'", value))?,
+                    );
+                }
+                "--seed" => {
+                    let value = value(&arg)?;
+                    seed = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid seed '{}'", value))?,
+                    );
+                }
+                "--noise-threshold" => {
+                    let value = value(&arg)?;
+                    noise_threshold = Some(
+                        parse_noise_threshold(&value)
+                            .ok_or_else(|| format!("invalid noise threshold '{}'", value))?,
+                    );
+                }
+                "--region" => {
+                    let value = value(&arg)?;
+                    region = Some(
+                        parse_region(&value)
+                            .ok_or_else(|| format!("invalid region '{}'", value))?,
+                    );
+                }
+                "--aovs" => {
+                    for name in value(&arg)?.split(',') {
+                        aovs.push(
+                            Aov::from_name(name)
+                                .ok_or_else(|| format!("unknown AOV '{}'", name))?,
+                        );
+                    }
+                }
+                "--denoise" => denoise = true,
+                "--spectral" => spectral = true,
+                "--checkpoint" => checkpoint = Some(value(&arg)?),
+                "--checkpoint-interval" => {
+                    let value = value(&arg)?;
+                    checkpoint_interval = Some(
+                        parse_checkpoint_interval(&value)
+                            .ok_or_else(|| format!("invalid checkpoint interval '{}'", value))?,
+                    );
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+                _ if scene.is_none() => scene = Some(arg.clone()),
+                _ => return Err(format!("unexpected argument '{}'", arg)),
+            }
+        }
+
+        Ok(Self {
+            scene: scene.ok_or_else(|| "no scene specified".to_owned())?,
+            output,
+            format,
+            samples,
+            time,
+            dimensions,
+            seed,
+            noise_threshold,
+            region,
+            aovs,
+            denoise,
+            spectral,
+            checkpoint,
+            checkpoint_interval,
+        })
+    }
+}
+
+/// Time budget in seconds, which must be finite and not negative
+fn parse_time(value: &str) -> Option<Duration> {
+    let seconds: f64 = value.parse().ok()?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Adaptive sampling threshold, which must be finite and not negative, 0 disables it
+fn parse_noise_threshold(value: &str) -> Option<Float> {
+    let threshold: Float = value.parse().ok()?;
+    (threshold.is_finite() && threshold >= 0.0).then_some(threshold)
+}
+
+/// Seconds between checkpoints, which must be finite and not negative
+fn parse_checkpoint_interval(value: &str) -> Option<Float> {
+    let interval: Float = value.parse().ok()?;
+    (interval.is_finite() && interval >= 0.0).then_some(interval)
+}
+
+/// Image dimensions, which must not be zero and whose buffers must fit in memory
+fn parse_dimensions(value: &str) -> Option<[u32; 2]> {
+    let (width, height) = value.split_once('x')?;
+    let dimensions = [width.parse().ok()?, height.parse().ok()?];
+    let valid = dimensions.iter().all(|&size| size > 0);
+    (valid && HdrImage::buffer_len(dimensions).is_some()).then_some(dimensions)
+}
+
+fn parse_region(value: &str) -> Option<[u32; 4]> {
+    let mut parts = value.split(',').map(|part| part.parse().ok());
+    let region = [parts.next()??, parts.next()??, parts.next()??, parts.next()??];
+    parts.next().is_none().then_some(region)
+}
+
+fn load_scene(name: &str) -> Result<(Scene, Settings, [u32; 2]), String> {
+    match name {
+        "spheres" => spheres::scene().map_err(|err| err.to_string()),
+        "cornell" => cornell::scene().map_err(|err| err.to_string()),
+        "mushroom" => mushroom::scene().map_err(|err| err.to_string()),
+        "material" => material::scene().map_err(|err| err.to_string()),
+        path if path.ends_with(".ron") => scene_file::load_scene(path)
+            .map_err(|err| format!("failed to load '{}': {}", path, err)),
+        path if path.ends_with(".gltf") || path.ends_with(".glb") => gltf_import::import_gltf(path)
+            .map(|scene| (scene, Settings::default(), [1000, 1000]))
+            .map_err(|err| format!("failed to load '{}': {}", path, err)),
+        other => Err(format!("unknown scene '{}'", other)),
+    }
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let format = match options.format {
+        Some(format) => format,
+        None => OutputFormat::from_path(&options.output).ok_or_else(|| {
+            format!(
+                "cannot guess output format of '{}', use --format",
+                options.output.display()
+            )
+        })?,
+    };
+
+    if !options.aovs.is_empty() && format != OutputFormat::Exr {
+        return Err("AOVs can only be written to EXR output".to_owned());
+    }
+
+    let (scene, mut settings, dimensions) = load_scene(&options.scene)?;
+    let dimensions = options.dimensions.unwrap_or(dimensions);
+    if let Some(samples) = options.samples {
+        settings.render.total_samples = samples.max(1);
+    }
+    if let Some(seed) = options.seed {
+        settings.render.seed = seed;
+    }
+    if let Some(threshold) = options.noise_threshold {
+        settings.render.noise_threshold = threshold;
+    }
+    if let Some(region) = options.region {
+        settings.render.region = Some(region);
+    }
+    if !options.aovs.is_empty() {
+        settings.render.aovs = options.aovs;
+    }
+    if format != OutputFormat::Exr {
+        // Only EXR files have room for the extra layers
+        settings.render.aovs.clear();
+    }
+    if options.denoise {
+        settings.color_management.denoise = true;
+    }
+    if options.spectral {
+        settings.render.render_mode = RenderMode::Spectral;
+    }
+    if let Some(checkpoint) = options.checkpoint {
+        settings.render.checkpoint_path = Some(checkpoint);
+    }
+    if let Some(interval) = options.checkpoint_interval {
+        settings.render.checkpoint_interval = interval;
+    }
+    if settings.color_management.denoise {
+        add_denoise_features(&mut settings.render.aovs);
+    }
+
+    println!(
+        "rendering '{}' at {}x{} with {} samples",
+        options.scene, dimensions[0], dimensions[1], settings.render.total_samples
+    );
+
+    let start_time = Instant::now();
+    let budget = options.time.unwrap_or(Duration::MAX);
+    let mut output = render_to_image_timed(&scene, &settings.render, dimensions, budget);
+
+    println!(
+        "rendered {} samples in {:.2}s",
+        output.samples,
+        start_time.elapsed().as_secs_f64()
+    );
+    for err in &output.errors {
+        eprintln!("warning: {}", err);
+    }
+
+    if settings.color_management.denoise {
+        output.image = denoise_layers(&output.image, &output.aovs);
+    }
+
+    // HDR output is kept linear, LDR output goes through the same color management as the viewport
+    let result = match format {
+        OutputFormat::Exr if !output.aovs.is_empty() => output
+            .image
+            .save_layers(&options.output, &output.aovs)
+            .map_err(|err| err.to_string()),
+        OutputFormat::Exr => output
+            .image
+            .save(&options.output, format)
+            .map_err(|err| err.to_string()),
+        OutputFormat::Png | OutputFormat::Png16 => {
+            color_manage(&output.image, &settings.color_management)
+                .save(&options.output, format)
+                .map_err(|err| err.to_string())
+        }
+    };
+    result.map_err(|err| format!("failed to write '{}': {}", options.output.display(), err))?;
+
+    println!("saved '{}'", options.output.display());
+    Ok(())
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            exit(1);
+        }
+    };
+
+    if let Err(err) = run(options) {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+}
@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+use voidray_renderer::preamble::*;
+
+/// Smallest distribution width, perfectly smooth surfaces are approximated by very narrow lobes
+const MIN_ALPHA: Float = 1.0e-3;
+
+/// Statistical distribution of the normals of the microfacets of a rough surface
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum MicrofacetDistribution {
+    /// Gaussian distribution of slopes, with short highlight tails
+    #[default]
+    Beckmann,
+    /// Trowbridge-Reitz distribution, with long highlight tails
+    GGX,
+}
+
+/// A microfacet distribution with its widths along the tangent and the bitangent of the
+/// surface, for directions in the local shading frame where the normal is +z
+///
+/// Masking and shadowing follow the height-correlated Smith model, and normals are sampled from
+/// the distribution of normals visible from the viewer.
+///
+/// References:
+///
+/// - Heitz, "Understanding the Masking-Shadowing Function in Microfacet-Based BRDFs" (2014)
+/// - Heitz, "Sampling the GGX Distribution of Visible Normals" (2018)
+/// - http://www.pbr-book.org/3ed-2018/Reflection_Models/Microfacet_Models.html
+#[derive(Clone, Copy)]
+pub(crate) struct Microfacets {
+    distribution: MicrofacetDistribution,
+    alpha_x: Float,
+    alpha_y: Float,
+}
+
+impl Microfacets {
+    /// Distribution of width `alpha`, stretched along the tangent by `anisotropy` in [0, 1]
+    pub(crate) fn new(
+        distribution: MicrofacetDistribution,
+        alpha: Float,
+        anisotropy: Float,
+    ) -> Self {
+        let aspect = (1.0 - 0.9 * anisotropy.clamp(0.0, 1.0)).sqrt();
+        Self {
+            distribution,
+            alpha_x: (alpha / aspect).max(MIN_ALPHA),
+            alpha_y: (alpha * aspect).max(MIN_ALPHA),
+        }
+    }
+
+    /// Density of microfacet normals `h` per projected area of the surface
+    pub(crate) fn d(&self, h: Vec3) -> Float {
+        let cos2 = h.z * h.z;
+        if cos2 == 0.0 {
+            return 0.0;
+        }
+        let (x2, y2) = ((h.x / self.alpha_x).powi(2), (h.y / self.alpha_y).powi(2));
+        let area = PI * self.alpha_x * self.alpha_y;
+
+        match self.distribution {
+            // D = exp(-tan^2(θ) / α^2) / (πα^2 cos^4 θ)
+            MicrofacetDistribution::Beckmann => (-(x2 + y2) / cos2).exp() / (area * cos2 * cos2),
+            // D = 1 / (πα^2 cos^4 θ (1 + tan^2(θ) / α^2)^2)
+            MicrofacetDistribution::GGX => 1.0 / (area * (x2 + y2 + cos2).powi(2)),
+        }
+    }
+
+    /// Smith auxiliary function Λ, the ratio of the area of backfacing microfacets to the
+    /// projected area of the surface seen from `w`
+    fn lambda(&self, w: Vec3) -> Float {
+        // α tan θ along the azimuth of w
+        let slope =
+            ((w.x * self.alpha_x).powi(2) + (w.y * self.alpha_y).powi(2)).sqrt() / w.z.abs();
+
+        match self.distribution {
+            MicrofacetDistribution::Beckmann => {
+                let a = 1.0 / slope;
+                0.5 * (erf(a) - 1.0) + (-a * a).exp() / (2.0 * a * PI.sqrt())
+            }
+            MicrofacetDistribution::GGX => 0.5 * ((1.0 + slope * slope).sqrt() - 1.0),
+        }
+    }
+
+    /// Fraction of the microfacets visible from `w`
+    pub(crate) fn g1(&self, w: Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction of the microfacets visible from both `wo` and `wi`, where microfacets which are
+    /// higher up are more likely to be visible from both
+    pub(crate) fn g(&self, wo: Vec3, wi: Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Density of the normals visible from `wo`, the pdf of `sample_visible`
+    pub(crate) fn visible_pdf(&self, wo: Vec3, h: Vec3) -> Float {
+        let h = if h.z * wo.z < 0.0 { -h } else { h };
+        self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z.abs()
+    }
+
+    /// Sample a microfacet normal visible from `wo`, on the same side of the surface as `wo`
+    pub(crate) fn sample_visible(&self, wo: Vec3, [u, v]: [Float; 2]) -> Vec3 {
+        let below = wo.z < 0.0;
+        let wo = if below { vec3!(wo.x, wo.y, -wo.z) } else { wo };
+
+        // Stretch the view direction to the configuration of a unit roughness
+        let view = vec3!(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).normalize();
+        let h = match self.distribution {
+            MicrofacetDistribution::Beckmann => {
+                let [x, y] = sample_beckmann_slopes(view, [u, v]);
+                vec3!(-self.alpha_x * x, -self.alpha_y * y, 1.0).normalize()
+            }
+            MicrofacetDistribution::GGX => {
+                let n = sample_ggx_hemisphere(view, [u, v]);
+                vec3!(self.alpha_x * n.x, self.alpha_y * n.y, n.z.max(0.0)).normalize()
+            }
+        };
+
+        if below {
+            vec3!(h.x, h.y, -h.z)
+        } else {
+            h
+        }
+    }
+}
+
+/// Sample the slopes of the unit roughness Beckmann normals visible from `view` by numerically
+/// inverting their distribution, as in pbrt
+fn sample_beckmann_slopes(view: Vec3, [u, v]: [Float; 2]) -> [Float; 2] {
+    let cos = view.z;
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let [x, y] = if cos > 0.9999 {
+        // At normal incidence the visible slopes follow the distribution itself
+        let r = (-(1.0 - u).ln()).sqrt();
+        let (sin, cos) = (2.0 * PI * v).sin_cos();
+        [r * cos, r * sin]
+    } else {
+        let tan = sin / cos;
+        let cot = 1.0 / tan;
+        let u = u.max(1.0e-6);
+
+        // Solve CDF(x) = u for the slope along the view direction, in the erf domain
+        let (mut low, mut high) = (-1.0, erf(cot));
+        let theta = cos.acos();
+        let fit = 1.0 + theta * (-0.876 + theta * (0.4265 - 0.0594 * theta));
+        let mut b = high - (1.0 + high) * (1.0 - u).powf(fit);
+        let normalization = 1.0 / (1.0 + high + tan * (-cot * cot).exp() / PI.sqrt());
+
+        for _ in 0..10 {
+            // Bisection if Newton's method left the interval, which also catches NaNs
+            if !(low..=high).contains(&b) {
+                b = 0.5 * (low + high);
+            }
+
+            let slope = erf_inv(b);
+            let value = normalization * (1.0 + b + tan * (-slope * slope).exp() / PI.sqrt()) - u;
+            if value.abs() < 1.0e-5 {
+                break;
+            }
+            if value > 0.0 {
+                high = b;
+            } else {
+                low = b;
+            }
+
+            let derivative = normalization * (1.0 - slope * tan);
+            b -= value / derivative;
+        }
+
+        [erf_inv(b), erf_inv(2.0 * v.max(1.0e-6) - 1.0)]
+    };
+
+    // Rotate to the azimuth of the view direction
+    let (cos_phi, sin_phi) = if sin > 0.0 {
+        (view.x / sin, view.y / sin)
+    } else {
+        (1.0, 0.0)
+    };
+    [cos_phi * x - sin_phi * y, sin_phi * x + cos_phi * y]
+}
+
+/// Sample the normals of the unit hemisphere visible from `view`, which are the visible
+/// normals of the unit roughness GGX distribution
+fn sample_ggx_hemisphere(view: Vec3, [u, v]: [Float; 2]) -> Vec3 {
+    let length2 = view.x * view.x + view.y * view.y;
+    let t1 = if length2 > 0.0 {
+        vec3!(-view.y, view.x, 0.0) / length2.sqrt()
+    } else {
+        vec3!(1.0, 0.0, 0.0)
+    };
+    let t2 = view.cross(t1);
+
+    // Sample the projected area of the hemisphere, a disk whose lower half is squashed
+    let r = u.sqrt();
+    let (sin, cos) = (2.0 * PI * v).sin_cos();
+    let p1 = r * cos;
+    let s = 0.5 * (1.0 + view.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * sin;
+
+    t1 * p1 + t2 * p2 + view * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt()
+}
+
+/// Error function, with a maximum error of 1.5e-7 (Abramowitz and Stegun 7.1.26)
+#[allow(clippy::excessive_precision)]
+fn erf(x: Float) -> Float {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - polynomial * (-x * x).exp()).copysign(x)
+}
+
+/// Inverse of the error function (Giles, "Approximating the erfinv function")
+#[allow(clippy::excessive_precision)]
+fn erf_inv(x: Float) -> Float {
+    let x = x.clamp(-0.99999, 0.99999);
+    let w = -((1.0 - x) * (1.0 + x)).ln();
+    let p = if w < 5.0 {
+        let w = w - 2.5;
+        [
+            2.81022636e-08,
+            3.43273939e-07,
+            -3.5233877e-06,
+            -4.39150654e-06,
+            0.00021858087,
+            -0.00125372503,
+            -0.00417768164,
+            0.246640727,
+            1.50140941,
+        ]
+        .iter()
+        .fold(0.0, |p, c| c + p * w)
+    } else {
+        let w = w.sqrt() - 3.0;
+        [
+            -0.000200214257,
+            0.000100950558,
+            0.00134934322,
+            -0.00367342844,
+            0.00573950773,
+            -0.0076224613,
+            0.00943887047,
+            1.00167406,
+            2.83297682,
+        ]
+        .iter()
+        .fold(0.0, |p, c| c + p * w)
+    };
+    p * x
+}
+
+/// Orthonormal frame around a shading normal, whose tangent follows the given tangent where it
+/// isn't parallel to the normal
+pub(crate) struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl Frame {
+    pub(crate) fn new(normal: Vec3, tangent: Vec3) -> Self {
+        let normal = normal.normalize();
+        let tangent = tangent - normal * normal.dot(tangent);
+        let tangent = if tangent.magnitude2() > 1.0e-8 {
+            tangent.normalize()
+        } else if normal.x.abs() > 0.5 {
+            vec3!(normal.y, -normal.x, 0.0).normalize()
+        } else {
+            vec3!(0.0, -normal.z, normal.y).normalize()
+        };
+
+        Self {
+            tangent,
+            bitangent: normal.cross(tangent),
+            normal,
+        }
+    }
+
+    pub(crate) fn to_local(&self, v: Vec3) -> Vec3 {
+        vec3!(
+            v.dot(self.tangent),
+            v.dot(self.bitangent),
+            v.dot(self.normal)
+        )
+    }
+
+    pub(crate) fn to_world(&self, v: Vec3) -> Vec3 {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
+}
+
+#[test]
+fn visible_normals_test() {
+    use voidray_renderer::rand::*;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let samples = 100_000;
+    for distribution in [
+        MicrofacetDistribution::Beckmann,
+        MicrofacetDistribution::GGX,
+    ] {
+        for (alpha, anisotropy) in [(0.1, 0.0), (0.5, 0.0), (0.4, 0.8)] {
+            let microfacets = Microfacets::new(distribution, alpha, anisotropy);
+            let wo = vec3!(0.6, -0.3, 0.4).normalize();
+
+            // Projected areas of the visible microfacets add up to the projected area of the
+            // surface
+            let projected = (0..samples)
+                .map(|_| {
+                    let [u, v] = [rng.gen::<Float>(), rng.gen::<Float>()];
+                    let (sin, cos) = (2.0 * PI * v).sin_cos();
+                    let r = (1.0 - u * u).sqrt();
+                    let h = vec3!(r * cos, r * sin, u);
+                    microfacets.g1(wo) * wo.dot(h).max(0.0) * microfacets.d(h) * 2.0 * PI
+                })
+                .sum::<Float>()
+                / samples as Float;
+            assert!(
+                (projected - wo.z).abs() < 0.03,
+                "{:?} {}",
+                distribution,
+                projected
+            );
+
+            // Sampled normals are visible, and their mean matches the one of the density
+            let mut mean = vec3!(0.0, 0.0, 0.0);
+            for _ in 0..samples {
+                let h = microfacets.sample_visible(wo, [rng.gen(), rng.gen()]);
+                assert!(h.z >= 0.0 && wo.dot(h) >= -1.0e-4, "{:?}", h);
+                mean = mean + h / samples as Float;
+            }
+            let mut expected = vec3!(0.0, 0.0, 0.0);
+            for _ in 0..samples {
+                let [u, v] = [rng.gen::<Float>(), rng.gen::<Float>()];
+                let (sin, cos) = (2.0 * PI * v).sin_cos();
+                let r = (1.0 - u * u).sqrt();
+                let h = vec3!(r * cos, r * sin, u);
+                expected =
+                    expected + h * (microfacets.visible_pdf(wo, h) * 2.0 * PI / samples as Float);
+            }
+            assert!(
+                (mean - expected).magnitude() < 0.03,
+                "{:?} {:?} {:?}",
+                distribution,
+                mean,
+                expected
+            );
+        }
+    }
+}
@@ -1,9 +1,15 @@
+use std::hash::Hasher;
+
 use voidray_renderer::color::*;
+use voidray_renderer::hash::StableHasher;
 use voidray_renderer::math::lerp;
+use voidray_renderer::math::sample_sphere;
 use voidray_renderer::math::to_spherical_coords;
 use voidray_renderer::preamble::*;
+use voidray_renderer::rand::*;
 use voidray_renderer::ray::*;
 use voidray_renderer::traits::Environment;
+use voidray_renderer::Error;
 pub struct Environments {}
 
 impl Environments {
@@ -14,9 +20,13 @@ impl Environments {
     pub fn hdri(path: &str) -> Option<Arc<dyn Environment>> {
         Some(Arc::new(HDRIEnvironment::new(path)))
     }
+
+    pub fn try_hdri(path: &str) -> Result<Option<Arc<dyn Environment>>, Error> {
+        Ok(Some(Arc::new(HDRIEnvironment::try_new(path)?)))
+    }
 }
 
-struct UniformEnvironment {
+pub(crate) struct UniformEnvironment {
     pub color: Color,
 }
 
@@ -30,28 +40,69 @@ impl Environment for UniformEnvironment {
     fn sample(&self, _: &Ray) -> Color {
         self.color
     }
+
+    fn sample_direction(&self, rng: &mut dyn Sampler) -> Option<(Vec3, Float)> {
+        Some((sample_sphere(rng.get_2d()), 1.0 / (4.0 * PI)))
+    }
+
+    fn pdf(&self, _: &Vec3) -> Float {
+        1.0 / (4.0 * PI)
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_color(self.color);
+    }
 }
 
-struct HDRIEnvironment {
+pub(crate) struct HDRIEnvironment {
+    pub(crate) path: String,
     image: Vec<Color>,
     width: usize,
     height: usize,
+    distribution: Distribution2D,
 }
 
 impl HDRIEnvironment {
+    /// Load an HDRI, panics if the file can't be loaded
     fn new(path: &str) -> Self {
-        let image = image::open(path).unwrap().to_rgb32f();
+        Self::try_new(path).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn try_new(path: &str) -> Result<Self, Error> {
+        let image = image::open(path)
+            .map_err(|source| Error::Image {
+                path: path.to_owned(),
+                source,
+            })?
+            .to_rgb32f();
         let dimensions = image.dimensions();
+        let image: Vec<Color> = image
+            .into_vec()
+            .chunks_exact(3)
+            .map(|data| Color::new(data[0], data[1], data[2]))
+            .collect();
+        let width = dimensions.0 as usize;
+        let height = dimensions.1 as usize;
 
-        Self {
-            image: image
-                .into_vec()
-                .chunks_exact(3)
-                .map(|data| Color::new(data[0], data[1], data[2]))
-                .collect(),
-            width: dimensions.0 as usize,
-            height: dimensions.1 as usize,
+        // Rows of the distribution go from the bottom of the sphere (θ = 0) to the top, which is
+        // the reverse order of the image rows. Weighting by sin θ accounts for the stretching
+        // of the lat-long mapping near the poles.
+        let mut weights = Vec::with_capacity(width * height);
+        for row in 0..height {
+            let sin_theta = (PI * (row as Float + 0.5) / height as Float).sin();
+            let image_row = height - 1 - row;
+            for column in 0..width {
+                weights.push(image[image_row * width + column].mean().max(0.0) * sin_theta);
+            }
         }
+
+        Ok(Self {
+            path: path.to_owned(),
+            image,
+            width,
+            height,
+            distribution: Distribution2D::new(weights, width, height),
+        })
     }
 
     fn bilinear_sample(&self, x: Float, y: Float) -> Color {
@@ -88,4 +139,140 @@ impl Environment for HDRIEnvironment {
         // No bilinear sample
         // self.image[(x as usize % self.width) + (y as usize % self.height) * self.height]
     }
+
+    fn sample_direction(&self, rng: &mut dyn Sampler) -> Option<(Vec3, Float)> {
+        let (u, v, pdf) = self.distribution.sample(rng)?;
+
+        // Inverse of `to_spherical_coords`, u is the polar and v the azimuthal angle
+        let theta = u * PI;
+        let phi = v * 2.0 * PI - PI;
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        if sin_theta <= 0.0 {
+            return None;
+        }
+
+        let direction = vec3!(sin_theta * phi.cos(), -cos_theta, -sin_theta * phi.sin());
+
+        // Change of variables from the unit square to solid angle, dω = 2π² sin θ du dv
+        Some((direction, pdf / (2.0 * PI * PI * sin_theta)))
+    }
+
+    fn pdf(&self, direction: &Vec3) -> Float {
+        let spherical_coords = to_spherical_coords(direction.normalize());
+        let sin_theta = spherical_coords.x.sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let u = spherical_coords.x / PI;
+        let v = spherical_coords.y / (2.0 * PI);
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Hashes the pixels rather than the path, so edits to the image file are noticed
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_u64(self.width as u64);
+        hasher.write_u64(self.height as u64);
+        self.image
+            .iter()
+            .for_each(|color| hasher.write_color(*color));
+    }
+}
+
+/// Find the cell of a normalized cumulative distribution containing `target`, returns the cell
+/// along with the position of `target` within it, so that stratified targets stay stratified
+fn sample_cdf(cdf: &[Float], target: Float) -> (usize, Float) {
+    let index = cdf.partition_point(|cdf| *cdf < target).min(cdf.len() - 1);
+    let start = if index == 0 { 0.0 } else { cdf[index - 1] };
+    let width = cdf[index] - start;
+    let offset = if width > 0.0 {
+        ((target - start) / width).clamp(0.0, 1.0)
+    } else {
+        0.5
+    };
+    (index, offset)
+}
+
+/// Piecewise constant distribution over the unit square, built from a grid of weights using a
+/// marginal distribution over rows and a conditional distribution over the columns of each row
+struct Distribution2D {
+    width: usize,
+    height: usize,
+    /// Normalized cumulative weights within each row
+    conditional_cdf: Vec<Float>,
+    /// Normalized cumulative row weights
+    marginal_cdf: Vec<Float>,
+    /// Grid weights divided by their mean, i.e. the density over the unit square
+    density: Vec<Float>,
+}
+
+impl Distribution2D {
+    fn new(weights: Vec<Float>, width: usize, height: usize) -> Self {
+        let total: Float = weights.iter().sum();
+        let mut conditional_cdf = Vec::with_capacity(width * height);
+        let mut marginal_cdf = Vec::with_capacity(height);
+        let mut cumulative_rows = 0.0;
+
+        for row in weights.chunks_exact(width) {
+            let row_total: Float = row.iter().sum();
+            let mut cumulative = 0.0;
+            for weight in row {
+                cumulative += weight;
+                conditional_cdf.push(if row_total > 0.0 {
+                    cumulative / row_total
+                } else {
+                    0.0
+                });
+            }
+
+            cumulative_rows += row_total;
+            marginal_cdf.push(if total > 0.0 {
+                cumulative_rows / total
+            } else {
+                0.0
+            });
+        }
+
+        let scale = if total > 0.0 {
+            (width * height) as Float / total
+        } else {
+            0.0
+        };
+
+        Self {
+            width,
+            height,
+            conditional_cdf,
+            marginal_cdf,
+            density: weights.iter().map(|weight| weight * scale).collect(),
+        }
+    }
+
+    /// Returns (u, v, pdf) where u selects the row and v the column
+    fn sample(&self, rng: &mut dyn Sampler) -> Option<(Float, Float, Float)> {
+        if self.marginal_cdf.last().copied().unwrap_or(0.0) <= 0.0 {
+            return None;
+        }
+
+        let [row_target, column_target] = rng.get_2d();
+        let (row, row_offset) = sample_cdf(&self.marginal_cdf, row_target);
+        let conditional = &self.conditional_cdf[row * self.width..(row + 1) * self.width];
+        let (column, column_offset) = sample_cdf(conditional, column_target);
+
+        let u = (row as Float + row_offset) / self.height as Float;
+        let v = (column as Float + column_offset) / self.width as Float;
+        let pdf = self.density[row * self.width + column];
+
+        if pdf > 0.0 {
+            Some((u, v, pdf))
+        } else {
+            None
+        }
+    }
+
+    fn pdf(&self, u: Float, v: Float) -> Float {
+        let row = ((u * self.height as Float) as usize).min(self.height - 1);
+        let column = ((v * self.width as Float) as usize).min(self.width - 1);
+        self.density[row * self.width + column]
+    }
 }
@@ -2,7 +2,7 @@ use voidray_renderer::math::degrees_to_radians;
 
 use crate::examples::example_import::*;
 
-pub fn scene() -> (Scene, Settings, [u32; 2]) {
+pub fn scene() -> Result<(Scene, Settings, [u32; 2]), Error> {
     let mut scene = Scene::empty();
     let mut settings = Settings::default();
 
@@ -62,11 +62,9 @@ pub fn scene() -> (Scene, Settings, [u32; 2]) {
     scene.add_object(light, light_plane);
 
     let sph = scene.add_analytic_surface(Surfaces::sphere(vec3!(555.0/2.0, 100.0, 555.0/2.0), 100.0));
-    let glass = scene.add_material(Materials::dielectric(1.33));
-    let sph_inner = scene.add_analytic_surface(Surfaces::sphere(vec3!(555.0/2.0, 100.0, 555.0/2.0), 99.9));
-    let glass_inner = scene.add_material(Materials::lambertian(hex_color(0x0F1BF0)));
+    // Blue glass, which is darker through its center than near its edges
+    let glass = scene.add_material(Materials::tinted_dielectric(1.33, hex_color(0x4A6CF0), 100.0));
     scene.add_object(glass, sph);
-    scene.add_object(glass_inner, sph_inner);
 
     scene.camera = Camera::look_at(
         vec3!(278.0, 278.0, -800.0), 
@@ -74,5 +72,5 @@ pub fn scene() -> (Scene, Settings, [u32; 2]) {
         vec3!(0.0, 1.0, 0.0), 
         degrees_to_radians(40.0));
 
-    (scene, settings, dimensions)
+    Ok((scene, settings, dimensions))
 }
@@ -1,12 +1,12 @@
 use crate::examples::example_import::*;
 
-pub fn scene() -> (Scene, Settings, [u32; 2]) {
+pub fn scene() -> Result<(Scene, Settings, [u32; 2]), Error> {
     let mut scene = Scene::empty();
     let settings = Settings::default();
     let dimensions = [1000, 1000];
 
     let ground = scene.add_analytic_surface(Surfaces::ground_plane(0.0));
-    let uv_test = scene.add_image_texture("assets/uv_test.png", SampleType::Nearest);
+    let uv_test = scene.try_add_image_texture("assets/uv_test.png", SampleType::Nearest)?;
     let ground_mat = scene.add_material(Materials::lambertian_texture_no_normal(uv_test));
 
     scene.add_object(ground_mat, ground);
@@ -25,7 +25,7 @@ pub fn scene() -> (Scene, Settings, [u32; 2]) {
 
     scene.camera.eye = vec3!(0.5, 0.6, 5.0);
 
-    scene.environment = Environments::hdri("assets/indoor.exr");
+    scene.environment = Environments::try_hdri("assets/indoor.exr")?;
 
-    (scene, settings, dimensions)
+    Ok((scene, settings, dimensions))
 }
@@ -1,7 +1,7 @@
 pub mod example_import {
-    pub use voidray_common::simple::Materials;
-    pub use voidray_common::Environments;
-    pub use voidray_common::Surfaces;
+    pub use crate::simple::Materials;
+    pub use crate::Environments;
+    pub use crate::Surfaces;
     pub use voidray_renderer::camera::Camera;
     pub use voidray_renderer::mesh::*;
     pub use voidray_renderer::color::*;
@@ -10,6 +10,7 @@ pub mod example_import {
     pub use voidray_renderer::settings::Settings;
     pub use voidray_renderer::settings::Tonemap;
     pub use voidray_renderer::texture::SampleType;
+    pub use voidray_renderer::Error;
 }
 
 pub mod spheres;
@@ -1,23 +1,22 @@
 use crate::examples::example_import::*;
 
-pub fn scene() -> (Scene, Settings, [u32; 2]) {
+pub fn scene() -> Result<(Scene, Settings, [u32; 2]), Error> {
     let mut scene = Scene::empty();
     let mut settings = Settings::default();
 
     let dimensions = [1000, 1000];
 
-    let mushroom_albedo = scene.add_image_texture("assets/mushroom_albedo.jpg", SampleType::Bilinear);
-    let mushroom_normal = scene.add_image_texture("assets/mushroom_normal.jpg", SampleType::Bilinear);
+    let mushroom_albedo = scene.try_add_image_texture("assets/mushroom_albedo.jpg", SampleType::Bilinear)?;
 
-    let mushroom_mtl = scene.add_material(Materials::lambertian_texture(mushroom_albedo, mushroom_normal));
-    let mushroom = scene.add_mesh_from_file("assets/mushroom.obj");
+    let mushroom_mtl = scene.add_material(Materials::subsurface_texture(mushroom_albedo, Color::new(0.05, 0.03, 0.02), 1.4));
+    let mushroom = scene.try_add_mesh_from_file("assets/mushroom.obj")?;
     scene.add_object(mushroom_mtl, mushroom);
 
-    let ground_albedo = scene.add_image_texture("assets/mossy_ground_albedo.jpg", SampleType::Bilinear);
-    let ground_normal = scene.add_image_texture("assets/mossy_ground_normal.jpg", SampleType::Bilinear);
+    let ground_albedo = scene.try_add_image_texture("assets/mossy_ground_albedo.jpg", SampleType::Bilinear)?;
+    let ground_normal = scene.try_add_image_texture("assets/mossy_ground_normal.jpg", SampleType::Bilinear)?;
 
     let ground_mtl = scene.add_material(Materials::lambertian_texture(ground_albedo, ground_normal));
-    let ground = scene.add_mesh_from_file("assets/mossy_ground.obj");
+    let ground = scene.try_add_mesh_from_file("assets/mossy_ground.obj")?;
     scene.add_object(ground_mtl, ground);
 
     // let sph = scene.add_analytic_surface(Surfaces::sphere(vec3!(5.0, 10.0, 0.0), 7.0));
@@ -33,7 +32,7 @@ pub fn scene() -> (Scene, Settings, [u32; 2]) {
     settings.color_management.exposure = 1.0;
     settings.color_management.tonemap = Tonemap::ACES;
 
-    scene.environment = Environments::hdri("assets/studio.exr");
+    scene.environment = Environments::try_hdri("assets/studio.exr")?;
 
-    (scene, settings, dimensions)
+    Ok((scene, settings, dimensions))
 }
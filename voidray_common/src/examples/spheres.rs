@@ -1,8 +1,8 @@
-use voidray_common::MicrofacetBSDF;
+use crate::MicrofacetBSDF;
 
 use crate::examples::example_import::*;
 
-pub fn scene() -> (Scene, Settings, [u32; 2]) {
+pub fn scene() -> Result<(Scene, Settings, [u32; 2]), Error> {
     let mut scene = Scene::empty();
     let mut settings = Settings::default();
     let dimensions = [1000, 1000];
@@ -45,18 +45,18 @@ pub fn scene() -> (Scene, Settings, [u32; 2]) {
     );
     scene.camera.dof = Some((0.12, vec3!(0.1, 0.6, -2.0)));
 
-    let saloon_albedo = scene.add_image_texture("assets/wood_albedo.tif", SampleType::Bilinear);
-    let saloon_normal = scene.add_image_texture("assets/wood_normal.tif", SampleType::Nearest);
+    let saloon_albedo = scene.try_add_image_texture("assets/wood_albedo.tif", SampleType::Bilinear)?;
+    let saloon_normal = scene.try_add_image_texture("assets/wood_normal.tif", SampleType::Nearest)?;
 
     let gnd = scene.add_analytic_surface(Surfaces::ground_plane(0.0));
     let gnd_mat = scene.add_material(Materials::lambertian_texture(saloon_albedo, saloon_normal));
     scene.add_object(gnd_mat, gnd);
 
-    scene.environment = Environments::hdri("assets/indoor.exr");
+    scene.environment = Environments::try_hdri("assets/indoor.exr")?;
 
     settings.color_management.tonemap = Tonemap::ACES;
     settings.color_management.gamma = 1.0;
     settings.color_management.exposure = 2.0;
 
-    (scene, settings, dimensions)
+    Ok((scene, settings, dimensions))
 }
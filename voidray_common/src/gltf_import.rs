@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use gltf::camera::Projection;
+use gltf::image::{Format, Source};
+use gltf::khr_lights_punctual::Kind;
+use voidray_renderer::camera::Camera;
+use voidray_renderer::color::*;
+use voidray_renderer::mesh::{Mesh, Vertex};
+use voidray_renderer::preamble::*;
+use voidray_renderer::scene::{MaterialHandle, Scene, SurfaceHandle, TextureHandle};
+use voidray_renderer::texture::{ImageTexture, SampleType, Texture};
+use voidray_renderer::transform::Transform;
+use voidray_renderer::Error;
+
+use crate::simple::Materials;
+use crate::{MicrofacetBSDF, MicrofacetDistribution, Surfaces, TexturedMicrofacetBSDF};
+
+/// Punctual lights are turned into small emissive spheres of this radius
+const PUNCTUAL_LIGHT_RADIUS: Float = 0.05;
+
+/// Smallest roughness used for imported materials, microfacet distributions are undefined at 0
+const MIN_ROUGHNESS: Float = 0.01;
+
+#[derive(Debug)]
+pub enum GltfImportError {
+    Gltf(gltf::Error),
+    /// A texture referenced by the file failed to load
+    Asset(Error),
+}
+
+impl fmt::Display for GltfImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GltfImportError::Gltf(err) => write!(f, "{}", err),
+            GltfImportError::Asset(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for GltfImportError {}
+
+impl From<gltf::Error> for GltfImportError {
+    fn from(err: gltf::Error) -> Self {
+        GltfImportError::Gltf(err)
+    }
+}
+
+impl From<Error> for GltfImportError {
+    fn from(err: Error) -> Self {
+        GltfImportError::Asset(err)
+    }
+}
+
+fn to_mat4(matrix: [[f32; 4]; 4]) -> Mat4 {
+    Mat4::from(matrix.map(|column| column.map(|x| x as Float)))
+}
+
+fn to_vec3(vector: [f32; 3]) -> Vec3 {
+    vec3!(vector[0] as Float, vector[1] as Float, vector[2] as Float)
+}
+
+/// Decode the pixels of an image into rgb colors, the alpha channel is dropped
+fn decode_pixels(image: &gltf::image::Data) -> Vec<Color> {
+    let (channels, bytes) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+
+    let channel = |data: &[u8]| -> Float {
+        match bytes {
+            1 => data[0] as Float / u8::MAX as Float,
+            2 => u16::from_le_bytes([data[0], data[1]]) as Float / u16::MAX as Float,
+            _ => f32::from_le_bytes([data[0], data[1], data[2], data[3]]) as Float,
+        }
+    };
+
+    image
+        .pixels
+        .chunks_exact(channels * bytes)
+        .map(|pixel| {
+            let values: Vec<Float> = pixel.chunks_exact(bytes).map(channel).collect();
+            match channels {
+                1 | 2 => GRAY(values[0]),
+                _ => Color::new(values[0], values[1], values[2]),
+            }
+        })
+        .collect()
+}
+
+/// State shared while walking the node hierarchy
+struct Importer<'a> {
+    directory: &'a Path,
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    scene: Scene,
+    textures: HashMap<usize, TextureHandle>,
+    materials: HashMap<Option<usize>, MaterialHandle>,
+    surfaces: HashMap<(usize, usize), SurfaceHandle>,
+    has_camera: bool,
+}
+
+impl<'a> Importer<'a> {
+    fn texture(&mut self, texture: gltf::Texture) -> Result<TextureHandle, Error> {
+        let image = texture.source();
+        if let Some(handle) = self.textures.get(&image.index()) {
+            return Ok(*handle);
+        }
+
+        // Textures stored next to the file are referenced by path so that the scene can be
+        // saved, embedded images are decoded by the importer
+        let file = match image.source() {
+            Source::Uri { uri, .. } if !uri.starts_with("data:") => {
+                Some(self.directory.join(uri)).filter(|path| path.is_file())
+            }
+            _ => None,
+        };
+
+        let handle = match file {
+            Some(path) => self
+                .scene
+                .try_add_image_texture(&path.to_string_lossy(), SampleType::Bilinear)?,
+            None => {
+                let data = &self.images[image.index()];
+                self.scene
+                    .add_texture(Arc::new(Texture::Image(ImageTexture::from_pixels(
+                        decode_pixels(data),
+                        data.width as usize,
+                        data.height as usize,
+                        SampleType::Bilinear,
+                    ))))
+            }
+        };
+
+        if let Some(name) = image.name().or_else(|| texture.name()) {
+            self.scene.rename_texture(handle, name);
+        }
+        self.textures.insert(image.index(), handle);
+        Ok(handle)
+    }
+
+    /// Map a metallic-roughness material onto a microfacet material, metallic-roughness
+    /// textures are not supported so only the factors are used
+    fn material(&mut self, material: gltf::Material) -> Result<MaterialHandle, Error> {
+        if let Some(handle) = self.materials.get(&material.index()) {
+            return Ok(*handle);
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor();
+        let roughness = pbr.roughness_factor() as Float;
+
+        let mut bsdf = MicrofacetBSDF {
+            color: Color::new(r as Float, g as Float, b as Float),
+            index: 1.5,
+            // glTF specifies a GGX distribution whose width is the square of the perceptual roughness
+            roughness: (roughness * roughness).max(MIN_ROUGHNESS),
+            distribution: MicrofacetDistribution::GGX,
+            anisotropy: 0.0,
+            metallic: pbr.metallic_factor() as Float,
+            emittance: 0.0,
+            transparent: alpha < 1.0 && material.alpha_mode() == gltf::material::AlphaMode::Blend,
+            dispersion: None,
+            absorption: BLACK,
+        };
+
+        // Emission is stored as a color scaled by a strength
+        let emissive = Color(to_vec3(material.emissive_factor()));
+        let strength = emissive.r().max(emissive.g()).max(emissive.b());
+        if strength > 0.0 {
+            bsdf.color = emissive * (1.0 / strength);
+            bsdf.emittance = strength;
+        }
+
+        let albedo = match pbr.base_color_texture() {
+            Some(info) => Some(self.texture(info.texture())?),
+            None => None,
+        };
+        let normal = match material.normal_texture() {
+            Some(normal) => Some(self.texture(normal.texture())?),
+            None => None,
+        };
+
+        let handle = if albedo.is_some() || normal.is_some() {
+            self.scene.add_material(Arc::new(TexturedMicrofacetBSDF {
+                bsdf,
+                albedo,
+                normal,
+            }))
+        } else {
+            self.scene.add_material(Arc::new(bsdf))
+        };
+
+        if let Some(name) = material.name() {
+            self.scene.rename_material(handle, name);
+        }
+        self.materials.insert(material.index(), handle);
+        Ok(handle)
+    }
+
+    /// Build the mesh of a primitive, meshes are shared between all nodes using them
+    fn surface(&mut self, mesh: &gltf::Mesh, primitive: &gltf::Primitive) -> Option<SurfaceHandle> {
+        let key = (mesh.index(), primitive.index());
+        if let Some(handle) = self.surfaces.get(&key) {
+            return Some(*handle);
+        }
+
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            println!(
+                "skipping primitive {} of mesh {}, only triangles are supported",
+                primitive.index(),
+                mesh.index()
+            );
+            return None;
+        }
+
+        let buffers = self.buffers;
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data.0[..]));
+
+        let positions: Vec<Vec3> = reader.read_positions()?.map(to_vec3).collect();
+        let normals: Vec<Vec3> = match reader.read_normals() {
+            Some(normals) => normals.map(to_vec3).collect(),
+            None => vec![vec3!(0.0); positions.len()],
+        };
+        let tangents: Vec<Vec3> = match reader.read_tangents() {
+            Some(tangents) => tangents.map(|[x, y, z, _]| to_vec3([x, y, z])).collect(),
+            None => vec![vec3!(0.0); positions.len()],
+        };
+        // glTF uv coordinates start at the top left, voidray textures at the bottom left
+        let uvs: Vec<Vec2> = match reader.read_tex_coords(0) {
+            Some(uvs) => uvs
+                .into_f32()
+                .map(|[u, v]| Vec2::new(u as Float, 1.0 - v as Float))
+                .collect(),
+            None => vec![Vec2::new(0.0, 0.0); positions.len()],
+        };
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        // Malformed primitives would index past the vertex buffer when the mesh is built
+        let count = positions.len();
+        let attributes = [normals.len(), uvs.len(), tangents.len()];
+        if indices.len() % 3 != 0
+            || indices.iter().any(|index| *index as usize >= count)
+            || attributes.iter().any(|len| *len != count)
+        {
+            println!(
+                "skipping primitive {} of mesh {}, its indices or attributes are invalid",
+                primitive.index(),
+                mesh.index()
+            );
+            return None;
+        }
+
+        let vertices = positions
+            .into_iter()
+            .zip(normals)
+            .zip(uvs)
+            .zip(tangents)
+            .map(|(((position, normal), uv), tangent)| {
+                Vertex::new(position, uv, normal).with_tangent(tangent)
+            })
+            .collect();
+
+        let handle = self
+            .scene
+            .add_mesh(Arc::new(Mesh::from_buffers(vertices, indices)));
+        if let Some(name) = mesh.name() {
+            self.scene.rename_surface(handle, name);
+        }
+        self.surfaces.insert(key, handle);
+        Some(handle)
+    }
+
+    fn camera(&mut self, camera: gltf::Camera, transform: &Transform) {
+        let perspective = match camera.projection() {
+            Projection::Perspective(perspective) => perspective,
+            Projection::Orthographic(_) => {
+                println!("skipping orthographic camera, only perspective cameras are supported");
+                return;
+            }
+        };
+
+        // glTF stores the vertical field of view, voidray the one in the longer direction
+        let yfov = perspective.yfov() as Float;
+        let aspect = perspective.aspect_ratio().unwrap_or(1.0) as Float;
+        let fov = if aspect > 1.0 {
+            2.0 * (aspect * (yfov / 2.0).tan()).atan()
+        } else {
+            yfov
+        };
+
+        let eye = transform.transform_point(vec3!(0.0));
+        let direction = transform.transform_vector(vec3!(0.0, 0.0, -1.0));
+        let up = transform.transform_vector(vec3!(0.0, 1.0, 0.0));
+        self.scene.camera = Camera::look_at(eye, eye + direction, up, fov);
+        self.has_camera = true;
+    }
+
+    /// Punctual lights become small emissive spheres with the same intensity, spot light
+    /// cones are ignored and directional lights are not supported
+    fn light(&mut self, light: gltf::khr_lights_punctual::Light, transform: &Transform) {
+        match light.kind() {
+            Kind::Directional => {
+                println!("skipping directional light, only point and spot lights are supported");
+                return;
+            }
+            Kind::Spot { .. } => println!("importing spot light as a point light"),
+            Kind::Point => {}
+        }
+
+        // A sphere of radius r and radiance L has an intensity of L π r² in every direction
+        let radius = PUNCTUAL_LIGHT_RADIUS;
+        let strength = light.intensity() as Float / (PI * radius * radius);
+        let material = self.scene.add_material(Materials::colored_emissive(
+            Color(to_vec3(light.color())),
+            strength,
+        ));
+        let surface = self.scene.add_analytic_surface(Surfaces::sphere(
+            transform.transform_point(vec3!(0.0)),
+            radius,
+        ));
+        let object = self.scene.add_object(material, surface);
+
+        if let Some(name) = light.name() {
+            self.scene.rename_material(material, name);
+            self.scene.rename_surface(surface, name);
+            self.scene.rename_object(object, name);
+        }
+    }
+
+    fn node(&mut self, node: gltf::Node, parent: Mat4) -> Result<(), Error> {
+        let matrix = parent * to_mat4(node.transform().matrix());
+        let transform = match Transform::new(matrix) {
+            Some(transform) => transform,
+            None => {
+                println!("skipping node {} with a degenerate transform", node.index());
+                return Ok(());
+            }
+        };
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                if let Some(surface) = self.surface(&mesh, &primitive) {
+                    let material = self.material(primitive.material())?;
+                    let object = self.scene.add_instance(surface, material, matrix);
+                    if let Some(name) = node.name() {
+                        self.scene.rename_object(object, name);
+                    }
+                }
+            }
+        }
+
+        if let Some(camera) = node.camera() {
+            // The first camera in the hierarchy is used
+            if !self.has_camera {
+                self.camera(camera, &transform);
+            }
+        }
+
+        if let Some(light) = node.light() {
+            self.light(light, &transform);
+        }
+
+        for child in node.children() {
+            self.node(child, matrix)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Import a glTF 2.0 (`.gltf` or `.glb`) file as a scene
+///
+/// Meshes are shared between the nodes using them through instancing, metallic-roughness
+/// materials are mapped onto `MicrofacetBSDF`, the first perspective camera becomes the scene
+/// camera and punctual lights become small emissive spheres.
+pub fn import_gltf(path: &str) -> Result<Scene, GltfImportError> {
+    let (document, buffers, images) = gltf::import(path)?;
+
+    let mut importer = Importer {
+        directory: Path::new(path).parent().unwrap_or_else(|| Path::new("")),
+        buffers: &buffers,
+        images: &images,
+        scene: Scene::empty(),
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        surfaces: HashMap::new(),
+        has_camera: false,
+    };
+
+    if let Some(scene) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        for node in scene.nodes() {
+            importer.node(node, Mat4::from_scale(1.0))?;
+        }
+    }
+
+    println!(
+        "loaded '{}' with {} objects, {} materials",
+        path,
+        importer.scene.objects.len(),
+        importer.scene.materials.len()
+    );
+
+    Ok(importer.scene)
+}
@@ -1,11 +1,23 @@
 #![allow(dead_code, unused_variables)]
 
+mod distribution;
 mod environments;
+pub mod examples;
+pub mod gltf_import;
+mod media;
 mod microfacet;
+pub mod obj_import;
+mod principled;
+pub mod scene_file;
 pub mod sdf;
 pub mod simple;
+mod subsurface;
 mod surfaces;
 
+pub use distribution::MicrofacetDistribution;
 pub use environments::Environments;
-pub use microfacet::MicrofacetBSDF;
+pub use media::Media;
+pub use microfacet::{MicrofacetBSDF, TexturedMicrofacetBSDF};
+pub use principled::{PrincipledBSDF, PrincipledTextures};
+pub use subsurface::Subsurface;
 pub use surfaces::Surfaces;
@@ -0,0 +1,313 @@
+use std::hash::Hasher;
+
+use voidray_renderer::aabb::AABB;
+use voidray_renderer::color::*;
+use voidray_renderer::hash::StableHasher;
+use voidray_renderer::medium::HenyeyGreenstein;
+use voidray_renderer::preamble::*;
+use voidray_renderer::rand::*;
+use voidray_renderer::ray::*;
+use voidray_renderer::traits::Medium;
+
+pub struct Media {}
+
+impl Media {
+    /// Medium with the same absorption and scattering coefficients everywhere, in units of
+    /// inverse scene distance
+    pub fn homogeneous(sigma_a: Color, sigma_s: Color, g: Float) -> Arc<dyn Medium> {
+        Arc::new(HomogeneousMedium {
+            sigma_a,
+            sigma_s,
+            g,
+        })
+    }
+
+    /// Medium with a varying density stored in a voxel grid spanning `bounds`, in x-major
+    /// order, the extinction coefficient at a point is `sigma_t` times the density there
+    pub fn grid(
+        bounds: AABB,
+        resolution: [usize; 3],
+        density: Vec<Float>,
+        sigma_t: Float,
+        albedo: Color,
+        g: Float,
+    ) -> Arc<dyn Medium> {
+        Arc::new(GridMedium::new(
+            bounds, resolution, density, sigma_t, albedo, g,
+        ))
+    }
+
+    /// Grid medium with the density function sampled at the centers of its voxels
+    pub fn grid_from_fn(
+        bounds: AABB,
+        resolution: [usize; 3],
+        sigma_t: Float,
+        albedo: Color,
+        g: Float,
+        density: impl Fn(Vec3) -> Float,
+    ) -> Arc<dyn Medium> {
+        let [nx, ny, nz] = resolution;
+        let size = bounds.dimensions();
+        let mut values = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let point = bounds.min
+                        + vec3!(
+                            size.x * (x as Float + 0.5) / nx as Float,
+                            size.y * (y as Float + 0.5) / ny as Float,
+                            size.z * (z as Float + 0.5) / nz as Float
+                        );
+                    values.push(density(point));
+                }
+            }
+        }
+        Self::grid(bounds, resolution, values, sigma_t, albedo, g)
+    }
+}
+
+pub(crate) struct HomogeneousMedium {
+    pub(crate) sigma_a: Color,
+    pub(crate) sigma_s: Color,
+    pub(crate) g: Float,
+}
+
+impl Medium for HomogeneousMedium {
+    fn sample(&self, ray: &Ray, t_max: Float, rng: &mut dyn Sampler) -> (Color, Option<Float>) {
+        // Sample a distance based on the extinction of a random channel, the pdf is the average
+        // over all channels
+        let sigma_t = self.sigma_a + self.sigma_s;
+        let channel = ((rng.get_1d() * 3.0) as usize).min(2);
+        let speed = ray.direction.magnitude();
+        let t = if sigma_t.0[channel] > 0.0 {
+            -(1.0 - rng.get_1d()).ln() / (sigma_t.0[channel] * speed)
+        } else {
+            INF
+        };
+
+        let scattered = t < t_max;
+        let transmittance = sigma_t.beer_lambert(t.min(t_max) * speed);
+        let density = if scattered {
+            sigma_t * transmittance
+        } else {
+            transmittance
+        };
+        let pdf = density.mean();
+        if pdf <= 0.0 {
+            return (BLACK, None);
+        }
+
+        if scattered {
+            (self.sigma_s * transmittance / pdf, Some(t))
+        } else {
+            (transmittance / pdf, None)
+        }
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: Float, _: &mut dyn Sampler) -> Color {
+        let sigma_t = self.sigma_a + self.sigma_s;
+        sigma_t.beer_lambert(t_max * ray.direction.magnitude())
+    }
+
+    fn phase(&self) -> HenyeyGreenstein {
+        HenyeyGreenstein::new(self.g)
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_color(self.sigma_a);
+        hasher.write_color(self.sigma_s);
+        hasher.write_floats(&[self.g]);
+    }
+}
+
+/// Heterogeneous medium with a trilinearly interpolated density grid, which is sampled with
+/// delta tracking and ratio tracking against the maximum density of the grid
+pub(crate) struct GridMedium {
+    pub(crate) bounds: AABB,
+    pub(crate) resolution: [usize; 3],
+    pub(crate) density: Vec<Float>,
+    max_density: Float,
+    pub(crate) sigma_t: Float,
+    pub(crate) albedo: Color,
+    pub(crate) g: Float,
+}
+
+impl GridMedium {
+    fn new(
+        bounds: AABB,
+        resolution: [usize; 3],
+        density: Vec<Float>,
+        sigma_t: Float,
+        albedo: Color,
+        g: Float,
+    ) -> Self {
+        assert_eq!(
+            density.len(),
+            resolution.iter().product::<usize>(),
+            "density grid doesn't match its resolution"
+        );
+        let density: Vec<Float> = density.into_iter().map(|x| x.max(0.0)).collect();
+        let max_density = density.iter().copied().fold(0.0, Float::max);
+
+        Self {
+            bounds,
+            resolution,
+            density,
+            max_density,
+            sigma_t,
+            albedo,
+            g,
+        }
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> Float {
+        let [nx, ny, _] = self.resolution;
+        self.density[(z * ny + y) * nx + x]
+    }
+
+    /// Trilinearly interpolated density at a point, zero outside of the grid
+    fn density_at(&self, point: Vec3) -> Float {
+        let size = self.bounds.dimensions();
+        let local = point - self.bounds.min;
+        let local = [local.x / size.x, local.y / size.y, local.z / size.z];
+        if local.iter().any(|x| !(0.0..=1.0).contains(x)) {
+            return 0.0;
+        }
+
+        // Voxel values are stored at the voxel centers
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let resolution = self.resolution[axis];
+            let position = (local[axis] * resolution as Float - 0.5).max(0.0);
+            lower[axis] = (position as usize).min(resolution - 1);
+            upper[axis] = (lower[axis] + 1).min(resolution - 1);
+            fraction[axis] = (position - lower[axis] as Float).min(1.0);
+        }
+
+        let [fx, fy, fz] = fraction;
+        let lerp_x =
+            |y, z| self.voxel(lower[0], y, z) * (1.0 - fx) + self.voxel(upper[0], y, z) * fx;
+        let lerp_y = |z| lerp_x(lower[1], z) * (1.0 - fy) + lerp_x(upper[1], z) * fy;
+        lerp_y(lower[2]) * (1.0 - fz) + lerp_y(upper[2]) * fz
+    }
+
+    /// Ray parameters where the ray enters and leaves the grid before `t_max`
+    fn clip(&self, ray: &Ray, t_max: Float) -> Option<(Float, Float)> {
+        let mut t0: Float = 0.0;
+        let mut t1 = t_max;
+        for axis in 0..3 {
+            let inv_d = 1.0 / ray.direction[axis];
+            let mut near = (self.bounds.min[axis] - ray.origin[axis]) * inv_d;
+            let mut far = (self.bounds.max[axis] - ray.origin[axis]) * inv_d;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaNs from rays in the plane of a slab leave the interval unchanged
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+        }
+        (t0 < t1).then_some((t0, t1))
+    }
+
+    /// Extinction coefficient of the densest point, per unit of the ray parameter
+    fn majorant(&self, ray: &Ray) -> Float {
+        self.sigma_t * self.max_density * ray.direction.magnitude()
+    }
+}
+
+impl Medium for GridMedium {
+    fn sample(&self, ray: &Ray, t_max: Float, rng: &mut dyn Sampler) -> (Color, Option<Float>) {
+        let majorant = self.majorant(ray);
+        let (mut t, t_end) = match self.clip(ray, t_max) {
+            Some(range) if majorant > 0.0 => range,
+            _ => return (WHITE, None),
+        };
+
+        // Delta tracking, tentative collisions with the majorant are real with the probability
+        // of the density relative to the maximum density
+        loop {
+            t -= (1.0 - rng.get_1d()).ln() / majorant;
+            if t >= t_end {
+                return (WHITE, None);
+            }
+            if self.density_at(ray.at(t)) > rng.get_1d() * self.max_density {
+                return (self.albedo, Some(t));
+            }
+        }
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: Float, rng: &mut dyn Sampler) -> Color {
+        let majorant = self.majorant(ray);
+        let (mut t, t_end) = match self.clip(ray, t_max) {
+            Some(range) if majorant > 0.0 => range,
+            _ => return WHITE,
+        };
+
+        // Ratio tracking, every tentative collision attenuates by the chance it wasn't real
+        let mut transmittance = 1.0;
+        loop {
+            t -= (1.0 - rng.get_1d()).ln() / majorant;
+            if t >= t_end {
+                return GRAY(transmittance);
+            }
+            transmittance *= 1.0 - self.density_at(ray.at(t)) / self.max_density;
+        }
+    }
+
+    fn phase(&self) -> HenyeyGreenstein {
+        HenyeyGreenstein::new(self.g)
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        let AABB { min, max } = &self.bounds;
+        hasher.write_floats(&[min.x, min.y, min.z, max.x, max.y, max.z]);
+        self.resolution
+            .iter()
+            .for_each(|n| hasher.write_u64(*n as u64));
+        hasher.write_floats(&self.density);
+        hasher.write_floats(&[self.sigma_t, self.g]);
+        hasher.write_color(self.albedo);
+    }
+}
+
+#[test]
+fn grid_medium_test() {
+    let bounds = AABB {
+        min: vec3!(-1.0, -1.0, -1.0),
+        max: vec3!(1.0, 1.0, 1.0),
+    };
+    let sigma_t = 0.8;
+    let homogeneous = HomogeneousMedium {
+        sigma_a: GRAY(sigma_t * 0.5),
+        sigma_s: GRAY(sigma_t * 0.5),
+        g: 0.0,
+    };
+    let grid = GridMedium::new(bounds, [4, 4, 4], vec![1.0; 64], sigma_t, GRAY(0.5), 0.0);
+    assert!((grid.density_at(vec3!(0.3, -0.9, 0.99)) - 1.0).abs() < 1.0e-5);
+    assert_eq!(grid.density_at(vec3!(0.0, 1.5, 0.0)), 0.0);
+
+    // A constant grid matches a homogeneous medium over the part of the ray inside the grid
+    let mut rng = StdRng::seed_from_u64(0);
+    let ray = Ray::new(vec3!(-3.0, 0.2, 0.1), vec3!(1.0, 0.1, 0.0));
+    let (t0, t1) = grid.clip(&ray, 10.0).unwrap();
+    let expected = homogeneous.transmittance(&ray, t1 - t0, &mut rng).r();
+    let samples = 20_000;
+    let estimate = (0..samples)
+        .map(|_| grid.transmittance(&ray, 10.0, &mut rng).r())
+        .sum::<Float>()
+        / samples as Float;
+    assert!(
+        (estimate - expected).abs() < 0.01,
+        "{} {}",
+        estimate,
+        expected
+    );
+
+    let passed = (0..samples)
+        .filter(|_| grid.sample(&ray, 10.0, &mut rng).1.is_none())
+        .count() as Float
+        / samples as Float;
+    assert!((passed - expected).abs() < 0.01, "{} {}", passed, expected);
+}
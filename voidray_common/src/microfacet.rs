@@ -1,11 +1,22 @@
+use std::hash::Hasher;
+
 use voidray_renderer::color::*;
+use voidray_renderer::hash::StableHasher;
 use voidray_renderer::math::*;
 use voidray_renderer::preamble::*;
 use voidray_renderer::rand::*;
+use voidray_renderer::ray::*;
+use voidray_renderer::scene::{SceneAcceleration, TextureHandle};
+use voidray_renderer::spectrum::{Ior, SampledSpectrum, SampledWavelengths, LAMBDA_D};
+use voidray_renderer::texture::AbstractTexture;
 
-use voidray_renderer::traits::BSDFMaterial;
+use voidray_renderer::traits::{BSDFMaterial, Material};
 use voidray_renderer::vec3;
 
+use crate::distribution::{Frame, MicrofacetDistribution, Microfacets};
+use crate::simple::hash_ior;
+
+#[derive(Clone)]
 pub struct MicrofacetBSDF {
     /// Albedo color
     pub color: Color,
@@ -13,9 +24,15 @@ pub struct MicrofacetBSDF {
     /// Index of refraction
     pub index: Float,
 
-    /// Roughness parameter for Beckmann microfacet distribution
+    /// Width α of the microfacet distribution
     pub roughness: Float,
 
+    /// Shape of the microfacet distribution
+    pub distribution: MicrofacetDistribution,
+
+    /// Stretch of the highlights along the surface tangent, from 0 (isotropic) to 1
+    pub anisotropy: Float,
+
     /// Metallic versus dielectric
     pub metallic: Float,
 
@@ -24,6 +41,13 @@ pub struct MicrofacetBSDF {
 
     /// Transmittance (e.g., glass)
     pub transparent: bool,
+
+    /// Wavelength dependent index of refraction, replaces `index` in spectral renders
+    pub dispersion: Option<Ior>,
+
+    /// Absorption coefficient of the interior of transparent materials, see
+    /// `Material::absorption`
+    pub absorption: Color,
 }
 
 impl MicrofacetBSDF {
@@ -33,9 +57,13 @@ impl MicrofacetBSDF {
             color,
             index: 1.5,
             roughness: 1.0,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
             metallic: 0.0,
             emittance: 0.0,
             transparent: false,
+            dispersion: None,
+            absorption: BLACK,
         })
     }
 
@@ -45,9 +73,13 @@ impl MicrofacetBSDF {
             color,
             index: 1.5,
             roughness,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
             metallic: 0.0,
             emittance: 0.0,
             transparent: false,
+            dispersion: None,
+            absorption: BLACK,
         })
     }
 
@@ -57,9 +89,13 @@ impl MicrofacetBSDF {
             color: hex_color(0xFFFFFF),
             index,
             roughness,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
             metallic: 0.0,
             emittance: 0.0,
             transparent: true,
+            dispersion: None,
+            absorption: BLACK,
         })
     }
 
@@ -69,9 +105,52 @@ impl MicrofacetBSDF {
             color,
             index,
             roughness,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
+            metallic: 0.0,
+            emittance: 0.0,
+            transparent: true,
+            dispersion: None,
+            absorption: BLACK,
+        })
+    }
+
+    /// Clear material which absorbs light travelling through it, turning white light into
+    /// `color` over `distance`, so thick parts are more saturated than thin ones
+    pub fn tinted(
+        color: Color,
+        index: Float,
+        roughness: Float,
+        distance: Float,
+    ) -> Arc<MicrofacetBSDF> {
+        Arc::new(MicrofacetBSDF {
+            color: hex_color(0xFFFFFF),
+            index,
+            roughness,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
+            metallic: 0.0,
+            emittance: 0.0,
+            transparent: true,
+            dispersion: None,
+            absorption: color.to_absorption(distance),
+        })
+    }
+
+    /// Clear material which disperses light, its index of refraction in rgb renders is the one
+    /// at the sodium D line
+    pub fn dispersive(ior: Ior, roughness: Float) -> Arc<MicrofacetBSDF> {
+        Arc::new(MicrofacetBSDF {
+            color: hex_color(0xFFFFFF),
+            index: ior.at(LAMBDA_D),
+            roughness,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
             metallic: 0.0,
             emittance: 0.0,
             transparent: true,
+            dispersion: Some(ior),
+            absorption: BLACK,
         })
     }
 
@@ -81,9 +160,13 @@ impl MicrofacetBSDF {
             color,
             index: 1.5,
             roughness,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
             metallic: 1.0,
             emittance: 0.0,
             transparent: false,
+            dispersion: None,
+            absorption: BLACK,
         })
     }
 
@@ -93,53 +176,84 @@ impl MicrofacetBSDF {
             color,
             index: 1.0,
             roughness: 1.0,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
             metallic: 0.0,
             emittance,
             transparent: false,
+            dispersion: None,
+            absorption: BLACK,
         })
     }
 }
 
+impl MicrofacetBSDF {
+    /// Opaque materials are two-sided, so their normal is flipped to face the viewer
+    fn facing_normal(&self, n: &Vec3, wo: &Vec3) -> Vec3 {
+        if !self.transparent && n.dot(*wo) < 0.0 {
+            -n
+        } else {
+            *n
+        }
+    }
+
+    /// Probability of sampling the specular lobe, estimated using the Fresnel term
+    fn specular_probability(&self) -> Float {
+        let f0 = ((self.index - 1.0) / (self.index + 1.0)).powi(2);
+        let f = (1.0 - self.metallic) * f0 + self.metallic * self.color.mean();
+        lerp(f, 1.0, 0.2)
+    }
+
+    /// Local shading frame around the facing normal, along with the distribution of microfacet
+    /// normals in that frame
+    fn microfacets(&self, n: &Vec3, tangent: &Vec3, wo: &Vec3) -> (Frame, Microfacets) {
+        let frame = Frame::new(self.facing_normal(n, wo), *tangent);
+        let microfacets = Microfacets::new(self.distribution, self.roughness, self.anisotropy);
+        (frame, microfacets)
+    }
+}
+
 #[allow(clippy::many_single_char_names)]
 impl BSDFMaterial for MicrofacetBSDF {
     /// Bidirectional scattering distribution function
     ///
     /// - `n` - surface normal vector
+    /// - `tangent` - surface tangent, the direction of anisotropic highlights
     /// - `wo` - unit direction vector toward the viewer
     /// - `wi` - unit direction vector toward the incident ray
     ///
-    /// This works for both opaque and transmissive materials, based on a Beckmann
-    /// microfacet distribution model, Cook-Torrance shading for the specular component,
-    /// and Lambertian shading for the diffuse component. Useful references:
+    /// This works for both opaque and transmissive materials, based on a Beckmann or GGX
+    /// microfacet distribution with height-correlated Smith masking-shadowing, Cook-Torrance
+    /// shading for the specular component, and Lambertian shading for the diffuse component.
+    /// Useful references:
     ///
     /// - http://www.codinglabs.net/article_physically_based_rendering_cook_torrance.aspx
     /// - https://computergraphics.stackexchange.com/q/4394
     /// - https://graphics.stanford.edu/courses/cs148-10-summer/docs/2006--degreve--reflection_refraction.pdf
     /// - http://www.pbr-book.org/3ed-2018/materials/BSDFs.html
     /// - https://www.cs.cornell.edu/~srm/publications/EGSR07-btdf.pdf
-    fn bsdf(&self, n: &Vec3, wo: &Vec3, wi: &Vec3) -> Color {
-        let n = *n;
-        let wo = *wo;
-        let wi = *wi;
-
-        let n_dot_wi = n.dot(wi);
-        let n_dot_wo = n.dot(wo);
-        let wi_outside = n_dot_wi.is_sign_positive();
-        let wo_outside = n_dot_wo.is_sign_positive();
+    fn bsdf(&self, n: &Vec3, tangent: &Vec3, wo: &Vec3, wi: &Vec3) -> Color {
+        let (frame, microfacets) = self.microfacets(n, tangent, wo);
+        let wo = frame.to_local(*wo);
+        let wi = frame.to_local(*wi);
+
+        let wi_outside = wi.z.is_sign_positive();
+        let wo_outside = wo.z.is_sign_positive();
         if !self.transparent && (!wi_outside || !wo_outside) {
             // Opaque materials do not transmit light
             return BLACK;
         }
+
+        // g: geometry function, height-correlated Smith masking-shadowing
+        // G = 1 / (1 + Λ(wo) + Λ(wi))
+        let g = microfacets.g(wo, wi);
+
         if wi_outside == wo_outside {
             let h = (wi + wo).normalize(); // halfway vector
             let wo_dot_h = wo.dot(h);
-            let n_dot_h = n.dot(h);
-            let nh2 = n_dot_h.powi(2);
 
             // d: microfacet distribution function
-            // D = exp(((n • h)^2 - 1) / (m^2 (n • h)^2)) / (π m^2 (n • h)^4)
-            let m2 = self.roughness * self.roughness;
-            let d = ((nh2 - 1.0) / (m2 * nh2)).exp() / (m2 * PI * nh2 * nh2);
+            let d = microfacets.d(h);
 
             // f: fresnel, schlick's approximation
             // F = F0 + (1 - F0)(1 - wi • h)^5
@@ -152,16 +266,10 @@ impl BSDFMaterial for MicrofacetBSDF {
                 f0 + (vec3!(1.0, 1.0, 1.0) - f0) * (1.0 - wo_dot_h).powi(5)
             };
 
-            // g: geometry function, microfacet shadowing
-            // G = min(1, 2(n • h)(n • wo)/(wo • h), 2(n • h)(n • wi)/(wo • h))
-            let g = Float::min(n_dot_wi * n_dot_h, n_dot_wo * n_dot_h);
-            let g = (2.0 * g) / wo_dot_h;
-            let g = g.min(1.0);
-
             // BRDF: putting it all together
             // Cook-Torrance = DFG / (4(n • wi)(n • wo))
             // Lambert = (1 - F) * c / π
-            let specular = d * f * g / (4.0 * n_dot_wo * n_dot_wi);
+            let specular = d * f * g / (4.0 * wo.z * wi.z);
             if self.transparent {
                 Color(specular)
             } else {
@@ -178,13 +286,9 @@ impl BSDFMaterial for MicrofacetBSDF {
             let h = (wi * eta_t + wo).normalize(); // halfway vector
             let wi_dot_h = wi.dot(h);
             let wo_dot_h = wo.dot(h);
-            let n_dot_h = n.dot(h);
-            let nh2 = n_dot_h.powi(2);
 
             // d: microfacet distribution function
-            // D = exp(((n • h)^2 - 1) / (m^2 (n • h)^2)) / (π m^2 (n • h)^4)
-            let m2 = self.roughness * self.roughness;
-            let d = ((nh2 - 1.0) / (m2 * nh2)).exp() / (m2 * PI * nh2 * nh2);
+            let d = microfacets.d(h);
 
             // f: fresnel, schlick's approximation
             // F = F0 + (1 - F0)(1 - wi • h)^5
@@ -192,16 +296,10 @@ impl BSDFMaterial for MicrofacetBSDF {
             let f0 = lerp(vec3!(f0), self.color.0, self.metallic);
             let f = f0 + (vec3!(1.0, 1.0, 1.0) - f0) * (1.0 - wi_dot_h.abs()).powi(5);
 
-            // g: geometry function, microfacet shadowing
-            // G = min(1, 2(n • h)(n • wo)/(wo • h), 2(n • h)(n • wi)/(wo • h))
-            let g = Float::min((n_dot_wi * n_dot_h).abs(), (n_dot_wo * n_dot_h).abs());
-            let g = (2.0 * g) / wo_dot_h.abs();
-            let g = g.min(1.0);
-
             // BTDF: putting it all together
             // Cook-Torrance = |h • wi|/|n • wi| * |h • wo|/|n • wo|
             //                  * η_o^2 (1 - F)DG / (η_i (h • wi) + η_o (h • wo))^2
-            let btdf = (wi_dot_h * wo_dot_h / (n_dot_wi * n_dot_wo)).abs()
+            let btdf = (wi_dot_h * wo_dot_h / (wi.z * wo.z)).abs()
                 * (d * (vec3!(1.0, 1.0, 1.0) - f) * g / (eta_t * wi_dot_h + wo_dot_h).powi(2));
             Color(btdf.mul_element_wise(self.color.0))
         }
@@ -209,68 +307,50 @@ impl BSDFMaterial for MicrofacetBSDF {
 
     /// Sample the light hemisphere, returning a tuple of (direction vector, PDF)
     ///
-    /// This implementation samples according to the Beckmann distribution
-    /// function D. Specifically, it uses the fact that ∫ D(h) (n • h) dω = 1,
-    /// which creates a probability distribution that can be sampled from using a
-    /// probability integral transform.
+    /// The specular and transmitted components sample a microfacet normal from the
+    /// distribution of normals visible from the viewer, so that no samples are wasted on
+    /// microfacets which face away from it, and reflect or refract the viewer direction about
+    /// it.
     ///
     /// We also need to sample from the diffuse BRDF as well, independently. We
     /// calculate the ratio of samples from the diffuse vs specular components by
     /// estimating the average magnitude of the Fresnel term.
     ///
-    /// Reference: https://agraphicsguy.wordpress.com/2015/11/01/sampling-microfacet-brdf/
-    fn sample(&self, n: &Vec3, wo: &Vec3, rng: &mut ThreadRng) -> Option<(Vec3, Float)> {
-        let n = *n;
-        let wo = *wo;
-
-        let m2 = self.roughness * self.roughness;
-
-        // Estimate specular contribution using Fresnel term
-        let f0 = ((self.index - 1.0) / (self.index + 1.0)).powi(2);
-        let f = (1.0 - self.metallic) * f0 + self.metallic * self.color.mean();
-        let f = lerp(f, 1.0, 0.2);
+    /// Reference: https://jcgt.org/published/0007/04/01/
+    fn sample(
+        &self,
+        n: &Vec3,
+        tangent: &Vec3,
+        wo: &Vec3,
+        rng: &mut dyn Sampler,
+    ) -> Option<(Vec3, Float)> {
+        let (frame, microfacets) = self.microfacets(n, tangent, wo);
+        let wo_local = frame.to_local(*wo);
+
+        let f = self.specular_probability();
 
         // Ratio of refractive indices
-        let eta_t = if wo.dot(n) > 0.0 {
+        let eta_t = if wo_local.z > 0.0 {
             self.index
         } else {
             1.0 / self.index
         };
 
-        let beckmann = |rng: &mut ThreadRng| {
-            // PIT for Beckmann distribution microfacet normal
-            // θ = arctan √(-m^2 ln U)
-            let theta = (m2 * -rng.gen::<Float>().ln()).sqrt().atan();
-            let (sin_t, cos_t) = theta.sin_cos();
-
-            // Generate halfway vector by sampling azimuth uniformly
-            let [x, y]: [Float; 2] = rng.sample(rand_distr::UnitCircle);
-            let h = vec3!(x * sin_t, y * sin_t, cos_t);
-            local_to_world(n) * h
-        };
-
-        let beckmann_pdf = |h: Vec3| {
-            // p = 1 / (πm^2 cos^3 θ) * e^(-tan^2(θ) / m^2)
-            let cos_t = h.dot(n).abs();
-            let sin_t = (1.0 - cos_t * cos_t).sqrt();
-            (PI * m2 * cos_t.powi(3)).recip() * (-(sin_t / cos_t).powi(2) / m2).exp()
-        };
-
-        let wi = if rng.gen_bool(f as f64) {
+        let wi = if rng.get_1d() < f {
             // Specular component
-            let h = beckmann(rng);
-            -reflect(wo, h)
+            let h = microfacets.sample_visible(wo_local, rng.get_2d());
+            -reflect(wo_local, h)
         } else if !self.transparent {
             // Diffuse component (Lambertian)
             // Simple cosine-sampling using Malley's method
-            let [x, y]: [Float; 2] = rng.sample(rand_distr::UnitDisc);
-            let z = (1.0 - x * x - y * y).sqrt();
-            local_to_world(n) * vec3!(x, y, z)
+            let [x, y] = sample_disk(rng.get_2d());
+            let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+            vec3!(x, y, z)
         } else {
             // Transmitted component
-            let h = beckmann(rng);
-            let cos_to = h.dot(wo);
-            let wo_perp = wo - h * cos_to;
+            let h = microfacets.sample_visible(wo_local, rng.get_2d());
+            let cos_to = h.dot(wo_local);
+            let wo_perp = wo_local - h * cos_to;
             let wi_perp = -wo_perp / eta_t;
             let sin2_ti = wi_perp.magnitude2();
             if sin2_ti > 1.0 {
@@ -279,36 +359,315 @@ impl BSDFMaterial for MicrofacetBSDF {
                 return None;
             }
             let cos_ti = (1.0 - sin2_ti).sqrt();
-            -cos_to.signum() * cos_ti * h + wi_perp
+            let wi = -cos_to.signum() * cos_ti * h + wi_perp;
+            if wi.z * wo_local.z >= 0.0 {
+                // Refracted back to the viewer's side of the surface
+                return None;
+            }
+            wi
+        };
+
+        let wi = frame.to_world(wi);
+        let p = self.pdf(n, tangent, wo, &wi);
+        if p == 0.0 {
+            None
+        } else {
+            Some((wi, p))
+        }
+    }
+
+    /// Probability density of `sample` returning `wi`, the sum of the densities of the
+    /// specular, diffuse and transmitted lobes (multiple importance sampling)
+    fn pdf(&self, n: &Vec3, tangent: &Vec3, wo: &Vec3, wi: &Vec3) -> Float {
+        let (frame, microfacets) = self.microfacets(n, tangent, wo);
+        let wo = frame.to_local(*wo);
+        let wi = frame.to_local(*wi);
+
+        let f = self.specular_probability();
+        let eta_t = if wo.z > 0.0 {
+            self.index
+        } else {
+            1.0 / self.index
         };
 
-        // Multiple importance sampling - add up total probability
         let mut p = 0.0;
         p += {
             // Specular component
             let h = (wi + wo).normalize();
-            let p_h = beckmann_pdf(h);
+            let p_h = microfacets.visible_pdf(wo, h);
             f * p_h / (4.0 * h.dot(wo).abs())
         };
         p += if !self.transparent {
             // Diffuse component
-            (1.0 - f) * wi.dot(n).max(0.0) / PI
-        } else if wo.dot(n).is_sign_positive() != wi.dot(n).is_sign_positive() {
+            (1.0 - f) * wi.z.max(0.0) / PI
+        } else if wo.z.is_sign_positive() != wi.z.is_sign_positive() {
             // Transmitted component
             let h = (wi * eta_t + wo).normalize();
-            let p_h = beckmann_pdf(h);
+            let p_h = microfacets.visible_pdf(wo, h);
             let h_dot_wo = h.dot(wo);
             let h_dot_wi = h.dot(wi);
-            let jacobian = h_dot_wo.abs() / (eta_t * h_dot_wi + h_dot_wo).powi(2);
+            let jacobian = eta_t * eta_t * h_dot_wi.abs() / (eta_t * h_dot_wi + h_dot_wo).powi(2);
             (1.0 - f) * p_h * jacobian
         } else {
             0.0
         };
 
-        if p == 0.0 {
-            None
+        if p.is_finite() {
+            p
         } else {
-            Some((wi, p))
+            0.0
+        }
+    }
+
+    fn emittance(&self) -> Color {
+        self.color * self.emittance
+    }
+
+    fn base_color(&self) -> Color {
+        self.color
+    }
+
+    fn interior_absorption(&self) -> Color {
+        if self.transparent {
+            self.absorption
+        } else {
+            BLACK
+        }
+    }
+
+    /// Light which isn't reflected by the Fresnel term at normal incidence or by the metal is
+    /// diffuse, transparent materials are treated as fully specular
+    fn diffuse_weight(&self) -> Float {
+        if self.transparent {
+            return 0.0;
+        }
+
+        let f0 = ((self.index - 1.0) / (self.index + 1.0)).powi(2);
+        (1.0 - self.metallic) * (1.0 - f0)
+    }
+
+    fn at_wavelength(&self, wavelength: Float) -> Option<Self> {
+        let ior = self.dispersion.filter(Ior::is_dispersive)?;
+        Some(MicrofacetBSDF {
+            index: ior.at(wavelength),
+            dispersion: None,
+            ..self.clone()
+        })
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some_and(|ior| ior.is_dispersive())
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_color(self.color);
+        hasher.write_floats(&[
+            self.index,
+            self.roughness,
+            self.anisotropy,
+            self.metallic,
+            self.emittance,
+        ]);
+        hasher.write_u8(self.distribution as u8);
+        hasher.write_u8(self.transparent as u8);
+        match &self.dispersion {
+            Some(ior) => hash_ior(ior, hasher),
+            None => hasher.write_u8(u8::MAX),
+        }
+        hasher.write_color(self.absorption);
+    }
+}
+
+/// A `MicrofacetBSDF` whose color and normal can be driven by textures, e.g. for imported
+/// OBJ materials
+pub struct TexturedMicrofacetBSDF {
+    pub bsdf: MicrofacetBSDF,
+
+    /// Albedo texture, replaces the color of the BSDF
+    pub albedo: Option<TextureHandle>,
+
+    /// Tangent space normal map, ignored on surfaces without a uv parametrization
+    pub normal: Option<TextureHandle>,
+}
+
+impl TexturedMicrofacetBSDF {
+    /// Returns the BSDF and hit record at a hit, with the textures applied
+    fn resolve(&self, scene: &SceneAcceleration, hit: &HitRecord) -> (MicrofacetBSDF, HitRecord) {
+        let mut bsdf = self.bsdf.clone();
+        if let Some(albedo) = self.albedo {
+            bsdf.color = scene.texture_ref(albedo).sample(hit.uv.x, hit.uv.y);
+        }
+
+        let mut hit = hit.clone();
+        if let Some(normal) = self.normal {
+            apply_normal_map(scene, normal, &mut hit);
+        }
+
+        (bsdf, hit)
+    }
+}
+
+/// Replace the normal of a hit with the one from a tangent space normal map, hits without a
+/// tangent are left unchanged
+pub(crate) fn apply_normal_map(
+    scene: &SceneAcceleration,
+    normal: TextureHandle,
+    hit: &mut HitRecord,
+) {
+    let n = hit.normal;
+    let t = hit.tangent - n * n.dot(hit.tangent);
+
+    if t.magnitude2() > 0.0 {
+        let t = t.normalize();
+        let b = n.cross(t);
+        let local = scene.texture_ref(normal).sample(hit.uv.x, hit.uv.y).0 * 2.0 - vec3!(1.0);
+        let shading = (t * local.x + b * local.y + n * local.z).normalize();
+
+        if shading.x.is_finite() {
+            hit.normal = shading;
+        }
+    }
+}
+
+impl Material for TexturedMicrofacetBSDF {
+    fn scatter(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        rng: &mut dyn Sampler,
+    ) -> (Color, Option<Ray>) {
+        let (bsdf, hit) = self.resolve(scene, hit);
+        bsdf.scatter(scene, ray, &hit, rng)
+    }
+
+    fn eval(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+    ) -> Option<(Color, Float)> {
+        let (bsdf, hit) = self.resolve(scene, hit);
+        bsdf.eval(scene, ray, &hit, to_incident)
+    }
+
+    fn emission(&self) -> Color {
+        self.bsdf.emittance()
+    }
+
+    fn absorption(&self) -> Color {
+        self.bsdf.absorption()
+    }
+
+    fn dispersive(&self) -> bool {
+        self.bsdf.is_dispersive()
+    }
+
+    fn scatter_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelengths: &SampledWavelengths,
+        rng: &mut dyn Sampler,
+    ) -> (SampledSpectrum, Option<Ray>) {
+        let (bsdf, hit) = self.resolve(scene, hit);
+        bsdf.scatter_spectral(scene, ray, &hit, wavelengths, rng)
+    }
+
+    fn eval_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+        wavelengths: &SampledWavelengths,
+    ) -> Option<(SampledSpectrum, Float)> {
+        let (bsdf, hit) = self.resolve(scene, hit);
+        bsdf.eval_spectral(scene, ray, &hit, to_incident, wavelengths)
+    }
+
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
+        self.resolve(scene, hit).0.color
+    }
+
+    fn shading_normal(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Vec3 {
+        self.resolve(scene, hit).1.normal
+    }
+
+    fn diffuse_fraction(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Float {
+        self.bsdf.diffuse_weight()
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        BSDFMaterial::hash_parameters(&self.bsdf, hasher);
+        hasher.write_texture(self.albedo);
+        hasher.write_texture(self.normal);
+    }
+}
+
+#[test]
+fn microfacet_sampling_test() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let anisotropic = |distribution, bsdf: Arc<MicrofacetBSDF>| MicrofacetBSDF {
+        distribution,
+        anisotropy: 0.7,
+        ..(*bsdf).clone()
+    };
+    let materials = [
+        (*MicrofacetBSDF::specular(Color::new(0.8, 0.4, 0.2), 0.5)).clone(),
+        anisotropic(
+            MicrofacetDistribution::GGX,
+            MicrofacetBSDF::metallic(Color::new(0.9, 0.6, 0.2), 0.3),
+        ),
+        anisotropic(
+            MicrofacetDistribution::Beckmann,
+            MicrofacetBSDF::metallic(Color::new(0.9, 0.6, 0.2), 0.3),
+        ),
+        MicrofacetBSDF {
+            distribution: MicrofacetDistribution::GGX,
+            ..(*MicrofacetBSDF::clear(1.5, 0.3)).clone()
+        },
+        (*MicrofacetBSDF::clear(1.5, 0.3)).clone(),
+    ];
+    let n = vec3!(0.0, 0.6, 0.8);
+    let tangent = vec3!(1.0, 0.0, 0.0);
+    let samples = 100_000;
+
+    // Importance sampling converges to the same reflectance as uniform sampling of the sphere
+    // from either side of the surface, with a pdf which integrates to at most one
+    for (i, material) in materials.iter().enumerate() {
+        for wo in [vec3!(0.5, 0.2, 0.6), vec3!(0.3, -0.4, -0.5)] {
+            let wo = wo.normalize();
+
+            let mut sampled = 0.0;
+            for _ in 0..samples {
+                if let Some((wi, pdf)) = material.sample(&n, &tangent, &wo, &mut rng) {
+                    sampled += material.bsdf(&n, &tangent, &wo, &wi).mean() * wi.dot(n).abs() / pdf;
+                }
+            }
+            let sampled = sampled / samples as Float;
+
+            let mut uniform = 0.0;
+            let mut total_pdf = 0.0;
+            for _ in 0..samples {
+                let wi = sample_sphere(rng.get_2d());
+                uniform +=
+                    material.bsdf(&n, &tangent, &wo, &wi).mean() * wi.dot(n).abs() * 4.0 * PI;
+                total_pdf += material.pdf(&n, &tangent, &wo, &wi) * 4.0 * PI;
+            }
+            let uniform = uniform / samples as Float;
+            let total_pdf = total_pdf / samples as Float;
+
+            assert!(total_pdf < 1.05, "{} {}", i, total_pdf);
+            assert!(
+                (sampled - uniform).abs() < 0.02 + 0.05 * uniform,
+                "{} {} {}",
+                i,
+                sampled,
+                uniform
+            );
         }
     }
 }
@@ -0,0 +1,442 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use obj::raw::object::Polygon;
+use obj::raw::parse_obj;
+use voidray_renderer::color::*;
+use voidray_renderer::mesh::{Mesh, Vertex};
+use voidray_renderer::preamble::*;
+use voidray_renderer::scene::{MaterialHandle, ObjectHandle, Scene, TextureHandle};
+use voidray_renderer::texture::SampleType;
+use voidray_renderer::Error;
+
+use crate::microfacet::TexturedMicrofacetBSDF;
+use crate::{MicrofacetBSDF, MicrofacetDistribution};
+
+/// Name of the group holding faces which come before any `usemtl` statement
+const DEFAULT_GROUP: &str = "default";
+
+#[derive(Debug)]
+pub enum ObjImportError {
+    Io(std::io::Error),
+    Parse(obj::ObjError),
+    /// A texture referenced by the MTL file failed to load
+    Asset(Error),
+    /// A material library referenced by the OBJ file doesn't exist
+    MissingLibrary(String),
+    /// A texture referenced by the MTL file doesn't exist
+    MissingTexture(String),
+    /// A face, counted from one, references a position, uv or normal which doesn't exist
+    InvalidFace(usize),
+}
+
+impl fmt::Display for ObjImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjImportError::Io(err) => write!(f, "{}", err),
+            ObjImportError::Parse(err) => write!(f, "parse error: {}", err),
+            ObjImportError::Asset(err) => write!(f, "{}", err),
+            ObjImportError::MissingLibrary(name) => {
+                write!(f, "material library '{}' not found", name)
+            }
+            ObjImportError::MissingTexture(name) => write!(f, "texture '{}' not found", name),
+            ObjImportError::InvalidFace(face) => {
+                write!(f, "face {} references a missing vertex", face)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ObjImportError {}
+
+impl From<std::io::Error> for ObjImportError {
+    fn from(err: std::io::Error) -> Self {
+        ObjImportError::Io(err)
+    }
+}
+
+impl From<obj::ObjError> for ObjImportError {
+    fn from(err: obj::ObjError) -> Self {
+        ObjImportError::Parse(err)
+    }
+}
+
+impl From<Error> for ObjImportError {
+    fn from(err: Error) -> Self {
+        ObjImportError::Asset(err)
+    }
+}
+
+/// Material statements read from a `.mtl` file, only the ones voidray can make use of
+#[derive(Default)]
+struct MtlMaterial {
+    /// Kd
+    diffuse: Option<Color>,
+    /// Ks
+    specular: Option<Color>,
+    /// Ns
+    specular_exponent: Option<Float>,
+    /// Ni
+    optical_density: Option<Float>,
+    /// d, or 1 - Tr
+    dissolve: Option<Float>,
+    /// Ke
+    emissive: Option<Color>,
+    /// map_Kd
+    diffuse_map: Option<String>,
+    /// map_Bump, bump or norm
+    normal_map: Option<String>,
+}
+
+impl MtlMaterial {
+    /// Approximate the MTL parameters with a microfacet material
+    fn to_bsdf(&self) -> MicrofacetBSDF {
+        let diffuse = self.diffuse.unwrap_or(GRAY(0.8));
+        let specular = self.specular.unwrap_or(BLACK);
+
+        // Beckmann roughness from the Blinn-Phong exponent, m = √(2 / (Ns + 2))
+        let roughness = match self.specular_exponent {
+            Some(exponent) => (2.0 / (exponent.max(0.0) + 2.0)).sqrt(),
+            None => 1.0,
+        };
+
+        let index = match self.optical_density {
+            Some(index) if index > 1.0 => index,
+            _ => 1.5,
+        };
+
+        let mut bsdf = MicrofacetBSDF {
+            color: diffuse,
+            index,
+            roughness,
+            distribution: MicrofacetDistribution::Beckmann,
+            anisotropy: 0.0,
+            metallic: 0.0,
+            emittance: 0.0,
+            transparent: matches!(self.dissolve, Some(dissolve) if dissolve < 1.0),
+            dispersion: None,
+            absorption: BLACK,
+        };
+
+        // Materials with only a specular color are treated as metals
+        if diffuse.mean() == 0.0 && specular.mean() > 0.0 {
+            bsdf.color = specular;
+            bsdf.metallic = 1.0;
+        }
+
+        // Emission is stored as a color scaled by a strength
+        if let Some(emissive) = self.emissive {
+            let strength = emissive.r().max(emissive.g()).max(emissive.b());
+            if strength > 0.0 {
+                bsdf.color = emissive * (1.0 / strength);
+                bsdf.emittance = strength;
+            }
+        }
+
+        bsdf
+    }
+}
+
+fn parse_floats(values: &[&str]) -> Vec<Float> {
+    values
+        .iter()
+        .filter_map(|value| value.parse::<Float>().ok())
+        .collect()
+}
+
+fn parse_color(values: &[&str]) -> Option<Color> {
+    match parse_floats(values)[..] {
+        [r, g, b, ..] => Some(Color::new(r, g, b)),
+        [v] => Some(GRAY(v)),
+        _ => None,
+    }
+}
+
+/// Texture statements may contain options before the file name, e.g. `map_Bump -bm 1.0 n.png`
+fn parse_map(values: &[&str]) -> Option<String> {
+    values.last().map(|file| file.to_string())
+}
+
+/// Parse the statements of a `.mtl` file, unknown statements are ignored
+fn parse_mtl(contents: &str) -> HashMap<String, MtlMaterial> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for line in contents.lines() {
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+        let values: Vec<&str> = words.collect();
+
+        if keyword == "newmtl" {
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material);
+            }
+            current = Some((values.join(" "), MtlMaterial::default()));
+            continue;
+        }
+
+        let material = match current.as_mut() {
+            Some((_, material)) => material,
+            None => continue,
+        };
+
+        let first = parse_floats(&values).first().copied();
+        match keyword {
+            "Kd" => material.diffuse = parse_color(&values),
+            "Ks" => material.specular = parse_color(&values),
+            "Ke" => material.emissive = parse_color(&values),
+            "Ns" => material.specular_exponent = first,
+            "Ni" => material.optical_density = first,
+            "d" => material.dissolve = first,
+            "Tr" => material.dissolve = first.map(|transparency| 1.0 - transparency),
+            "map_Kd" => material.diffuse_map = parse_map(&values),
+            "map_Bump" | "map_bump" | "bump" | "norm" => material.normal_map = parse_map(&values),
+            _ => {}
+        }
+    }
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material);
+    }
+
+    materials
+}
+
+/// Resolve a path referenced by an OBJ or MTL file. Paths are relative to the referencing
+/// file, absolute paths which don't exist (e.g. from another machine) are looked up by file
+/// name next to the referencing file.
+fn resolve_path(directory: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let candidates = [directory.join(path), directory.join(path.file_name()?)];
+
+    candidates.into_iter().find(|candidate| candidate.is_file())
+}
+
+/// `name`, followed by a counter if it is already taken
+fn unique_name<'a>(name: String, taken: impl Iterator<Item = &'a str>) -> String {
+    let taken: HashSet<&str> = taken.collect();
+    if !taken.contains(name.as_str()) {
+        return name;
+    }
+
+    (2..)
+        .map(|count| format!("{}_{}", name, count))
+        .find(|candidate| !taken.contains(candidate.as_str()))
+        .expect("ran out of names")
+}
+
+/// Import a Wavefront OBJ file along with its MTL materials
+///
+/// The OBJ is split into one mesh per `usemtl` group, each group gets its own material built
+/// from the MTL file, and textures referenced by `map_Kd` and `map_Bump`/`norm` are loaded as
+/// image textures. Materials, surfaces and objects are named after the file and the group,
+/// and all names are made unique within the scene. Returns the handles of the objects which
+/// were added to the scene.
+pub fn import_obj(scene: &mut Scene, path: &str) -> Result<Vec<ObjectHandle>, ObjImportError> {
+    let raw = parse_obj(BufReader::new(File::open(path)?))?;
+    let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let stem = Path::new(path)
+        .file_stem()
+        .map_or_else(|| "obj".into(), |stem| stem.to_string_lossy());
+
+    let mut mtl_materials = HashMap::new();
+    for library in &raw.material_libraries {
+        let library = resolve_path(directory, library)
+            .ok_or_else(|| ObjImportError::MissingLibrary(library.clone()))?;
+        mtl_materials.extend(parse_mtl(&fs::read_to_string(library)?));
+    }
+
+    // Group polygon indices by material, faces outside any group use the default material
+    let mut covered = vec![false; raw.polygons.len()];
+    let mut groups: Vec<(String, Vec<usize>)> = raw
+        .meshes
+        .iter()
+        .map(|(name, group)| {
+            let polygons: Vec<usize> = group
+                .polygons
+                .iter()
+                .flat_map(|range| range.start..range.end)
+                .collect();
+            polygons.iter().for_each(|polygon| covered[*polygon] = true);
+            (name.clone(), polygons)
+        })
+        .collect();
+
+    let uncovered: Vec<usize> = (0..raw.polygons.len())
+        .filter(|polygon| !covered[*polygon])
+        .collect();
+    if !uncovered.is_empty() {
+        groups.push((DEFAULT_GROUP.to_owned(), uncovered));
+    }
+
+    // Keep the import order stable
+    groups.sort_by(|a, b| a.1.first().cmp(&b.1.first()));
+
+    let mut textures = HashMap::<PathBuf, TextureHandle>::new();
+    let mut load_texture = |scene: &mut Scene, file: &Option<String>| {
+        let file = match file {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let path = resolve_path(directory, file)
+            .ok_or_else(|| ObjImportError::MissingTexture(file.clone()))?;
+
+        if let Some(handle) = textures.get(&path) {
+            return Ok(Some(*handle));
+        }
+
+        let handle = scene.try_add_image_texture(&path.to_string_lossy(), SampleType::Bilinear)?;
+        if let Some(name) = path.file_stem() {
+            let name = unique_name(
+                name.to_string_lossy().into_owned(),
+                scene.textures.iter().map(|texture| texture.name()),
+            );
+            scene.rename_texture(handle, &name);
+        }
+        textures.insert(path, handle);
+        Ok::<_, ObjImportError>(Some(handle))
+    };
+
+    let mut materials = HashMap::<String, MaterialHandle>::new();
+    let mut objects = Vec::new();
+
+    for (group, polygons) in groups {
+        let name = format!("{}/{}", stem, group);
+        let material = match materials.get(&group) {
+            Some(material) => *material,
+            None => {
+                let mtl = mtl_materials.get(&group);
+                let bsdf = mtl
+                    .map(MtlMaterial::to_bsdf)
+                    .unwrap_or_else(|| MicrofacetBSDF::diffuse(GRAY(0.8)).as_ref().clone());
+                let (albedo, normal) = match mtl {
+                    Some(mtl) => (
+                        load_texture(scene, &mtl.diffuse_map)?,
+                        load_texture(scene, &mtl.normal_map)?,
+                    ),
+                    None => (None, None),
+                };
+
+                let handle = if albedo.is_some() || normal.is_some() {
+                    scene.add_material(Arc::new(TexturedMicrofacetBSDF {
+                        bsdf,
+                        albedo,
+                        normal,
+                    }))
+                } else {
+                    scene.add_material(Arc::new(bsdf))
+                };
+                let name = unique_name(
+                    name.clone(),
+                    scene.materials.iter().map(|material| material.name()),
+                );
+                scene.rename_material(handle, &name);
+                materials.insert(group.clone(), handle);
+                handle
+            }
+        };
+
+        // Deduplicate (position, uv, normal) triples into vertices, fan triangulating polygons
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut vertex_indices = HashMap::<(usize, Option<usize>, Option<usize>), u32>::new();
+
+        for polygon in polygons {
+            let corners: Vec<(usize, Option<usize>, Option<usize>)> = match &raw.polygons[polygon] {
+                Polygon::P(p) => p.iter().map(|&p| (p, None, None)).collect(),
+                Polygon::PT(pt) => pt.iter().map(|&(p, t)| (p, Some(t), None)).collect(),
+                Polygon::PN(pn) => pn.iter().map(|&(p, n)| (p, None, Some(n))).collect(),
+                Polygon::PTN(ptn) => ptn.iter().map(|&(p, t, n)| (p, Some(t), Some(n))).collect(),
+            };
+
+            let valid = corners.iter().all(|&(p, t, n)| {
+                p < raw.positions.len()
+                    && t.is_none_or(|t| t < raw.tex_coords.len())
+                    && n.is_none_or(|n| n < raw.normals.len())
+            });
+            if !valid {
+                return Err(ObjImportError::InvalidFace(polygon + 1));
+            }
+
+            let corner_indices: Vec<u32> = corners
+                .into_iter()
+                .map(|corner| {
+                    *vertex_indices.entry(corner).or_insert_with(|| {
+                        let (p, t, n) = corner;
+                        let position = raw.positions[p];
+                        let uv = t.map_or(Vec2::new(0.0, 0.0), |t| {
+                            let uv = raw.tex_coords[t];
+                            Vec2::new(uv.0 as Float, uv.1 as Float)
+                        });
+                        let normal = n.map_or(vec3!(0.0), |n| {
+                            let normal = raw.normals[n];
+                            vec3!(normal.0 as Float, normal.1 as Float, normal.2 as Float)
+                        });
+
+                        vertices.push(Vertex::new(
+                            vec3!(
+                                position.0 as Float,
+                                position.1 as Float,
+                                position.2 as Float
+                            ),
+                            uv,
+                            normal,
+                        ));
+                        (vertices.len() - 1) as u32
+                    })
+                })
+                .collect();
+
+            if let Some((&first, rest)) = corner_indices.split_first() {
+                for edge in rest.windows(2) {
+                    indices.extend([first, edge[0], edge[1]]);
+                }
+            }
+        }
+
+        if indices.is_empty() {
+            continue;
+        }
+
+        println!(
+            "loaded group '{}' of '{}' with {} vertices, {} faces",
+            name,
+            path,
+            vertices.len(),
+            indices.len() / 3
+        );
+
+        let surface = scene.add_mesh(Arc::new(Mesh::from_buffers(vertices, indices)));
+        let surface_name = unique_name(
+            name.clone(),
+            scene.surfaces.iter().map(|surface| surface.name()),
+        );
+        scene.rename_surface(surface, &surface_name);
+        let object = scene.add_object(material, surface);
+        let object_name = unique_name(name, scene.objects.iter().map(|object| object.name()));
+        scene.rename_object(object, &object_name);
+        objects.push(object);
+    }
+
+    Ok(objects)
+}
+
+#[test]
+fn import_obj_invalid_face_test() {
+    let path = std::env::temp_dir().join(format!("voidray_invalid_{}.obj", std::process::id()));
+    fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nf 1 3 4\n").unwrap();
+    let result = import_obj(&mut Scene::empty(), path.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+
+    // Out of range faces are an error rather than a panic
+    assert!(matches!(
+        result,
+        Err(ObjImportError::Parse(_) | ObjImportError::InvalidFace(2))
+    ));
+}
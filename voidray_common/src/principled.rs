@@ -0,0 +1,596 @@
+use voidray_renderer::color::*;
+use voidray_renderer::hash::StableHasher;
+use voidray_renderer::math::{lerp, sample_disk};
+use voidray_renderer::preamble::*;
+use voidray_renderer::rand::*;
+use voidray_renderer::ray::*;
+use voidray_renderer::scene::{SceneAcceleration, TextureHandle};
+use voidray_renderer::texture::AbstractTexture;
+use voidray_renderer::traits::Material;
+
+use crate::distribution::{Frame, MicrofacetDistribution, Microfacets};
+use crate::microfacet::apply_normal_map;
+
+/// Material with the parameter set of the Disney principled BSDF, so that materials authored
+/// in content creation tools map over directly
+///
+/// A diffuse base with retro-reflection and sheen is layered with an anisotropic GGX specular
+/// lobe, a rough transmission lobe and a clearcoat. Parameters other than the colors and the
+/// index of refraction lie in [0, 1], and each of them can be driven by a texture.
+///
+/// References:
+///
+/// - Burley, "Physically Based Shading at Disney" (2012)
+/// - Burley, "Extending the Disney BRDF to a BSDF with Integrated Subsurface Scattering" (2015)
+/// - Heitz, "Sampling the GGX Distribution of Visible Normals" (2018)
+#[derive(Clone, Copy)]
+pub struct PrincipledBSDF {
+    /// Diffuse color of dielectrics and specular color of metals
+    pub base_color: Color,
+
+    /// Metallic versus dielectric
+    pub metallic: Float,
+
+    /// Roughness of the specular reflection, squared to get the width of the GGX lobe
+    pub roughness: Float,
+
+    /// Stretches specular highlights along the tangent of the surface
+    pub anisotropic: Float,
+
+    /// Specular reflectance of dielectrics, 0.5 corresponds to 4% at normal incidence
+    pub specular: Float,
+
+    /// Tints the specular reflection of dielectrics towards the base color
+    pub specular_tint: Float,
+
+    /// Additional reflection at grazing angles, e.g. for cloth
+    pub sheen: Float,
+
+    /// Tints the sheen towards the base color
+    pub sheen_tint: Float,
+
+    /// Strength of a colorless specular layer on top of the material
+    pub clearcoat: Float,
+
+    /// Glossiness of the clearcoat, from satin to gloss
+    pub clearcoat_gloss: Float,
+
+    /// Fraction of the light refracted into dielectrics instead of being diffused
+    pub transmission: Float,
+
+    /// Roughness of the refraction, independent of the roughness of the reflection
+    pub transmission_roughness: Float,
+
+    /// Index of refraction of transmissive materials
+    pub ior: Float,
+
+    /// Self-emittance of light
+    pub emission: Color,
+
+    /// Textures replacing the parameters
+    pub textures: PrincipledTextures,
+}
+
+impl Default for PrincipledBSDF {
+    /// Gray dielectric of medium roughness, with the defaults of the Disney BRDF
+    fn default() -> Self {
+        Self {
+            base_color: GRAY(0.8),
+            metallic: 0.0,
+            roughness: 0.5,
+            anisotropic: 0.0,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            transmission_roughness: 0.0,
+            ior: 1.5,
+            emission: BLACK,
+            textures: PrincipledTextures::default(),
+        }
+    }
+}
+
+/// Textures driving the parameters of a `PrincipledBSDF`, scalar parameters are read from the
+/// mean of the color channels so that grayscale maps can be used directly
+#[derive(Clone, Copy, Default)]
+pub struct PrincipledTextures {
+    pub base_color: Option<TextureHandle>,
+    pub metallic: Option<TextureHandle>,
+    pub roughness: Option<TextureHandle>,
+    pub anisotropic: Option<TextureHandle>,
+    pub specular: Option<TextureHandle>,
+    pub specular_tint: Option<TextureHandle>,
+    pub sheen: Option<TextureHandle>,
+    pub sheen_tint: Option<TextureHandle>,
+    pub clearcoat: Option<TextureHandle>,
+    pub clearcoat_gloss: Option<TextureHandle>,
+    pub transmission: Option<TextureHandle>,
+    pub transmission_roughness: Option<TextureHandle>,
+
+    /// Tangent space normal map, ignored on surfaces without a uv parametrization
+    pub normal: Option<TextureHandle>,
+}
+
+/// Weights and shapes of the lobes of a `PrincipledBSDF` seen from one side of the surface
+struct Lobes {
+    /// Weight of the diffuse and sheen lobes
+    diffuse: Float,
+    /// Weight of the clearcoat
+    clearcoat: Float,
+    /// Weight of the transmission lobe
+    transmission: Float,
+    /// Ratio of the indices of refraction behind and in front of the surface
+    eta: Float,
+    /// Specular reflectance at normal incidence, for the light which isn't transmitted
+    specular: Color,
+    /// GGX distribution of the specular lobe
+    reflection: Microfacets,
+    /// GGX distribution of the transmission lobe
+    refraction: Microfacets,
+    /// GTR1 width of the clearcoat
+    clearcoat_alpha: Float,
+    /// Probabilities of sampling the diffuse, specular, clearcoat and transmission lobes
+    probabilities: [Float; 4],
+}
+
+impl PrincipledBSDF {
+    /// Returns the parameters and hit record at a hit, with the textures applied
+    fn resolve(&self, scene: &SceneAcceleration, hit: &HitRecord) -> (PrincipledBSDF, HitRecord) {
+        let sample = |texture: TextureHandle| scene.texture_ref(texture).sample(hit.uv.x, hit.uv.y);
+        let scalar = |value: &mut Float, texture: Option<TextureHandle>| {
+            if let Some(texture) = texture {
+                *value = sample(texture).mean();
+            }
+        };
+
+        let textures = &self.textures;
+        let mut bsdf = *self;
+        if let Some(texture) = textures.base_color {
+            bsdf.base_color = sample(texture);
+        }
+        scalar(&mut bsdf.metallic, textures.metallic);
+        scalar(&mut bsdf.roughness, textures.roughness);
+        scalar(&mut bsdf.anisotropic, textures.anisotropic);
+        scalar(&mut bsdf.specular, textures.specular);
+        scalar(&mut bsdf.specular_tint, textures.specular_tint);
+        scalar(&mut bsdf.sheen, textures.sheen);
+        scalar(&mut bsdf.sheen_tint, textures.sheen_tint);
+        scalar(&mut bsdf.clearcoat, textures.clearcoat);
+        scalar(&mut bsdf.clearcoat_gloss, textures.clearcoat_gloss);
+        scalar(&mut bsdf.transmission, textures.transmission);
+        scalar(
+            &mut bsdf.transmission_roughness,
+            textures.transmission_roughness,
+        );
+
+        let mut hit = hit.clone();
+        if let Some(normal) = textures.normal {
+            apply_normal_map(scene, normal, &mut hit);
+        }
+
+        (bsdf, hit)
+    }
+
+    /// Base color normalized to unit luminance, the hue the tint parameters blend towards
+    fn tint(&self) -> Color {
+        let luminance = self.base_color.luminance();
+        if luminance > 0.0 {
+            self.base_color / luminance
+        } else {
+            WHITE
+        }
+    }
+
+    /// Lobes of the material seen from the front or, for transmissive materials, from inside
+    /// the object, where only the specular and transmission lobes exist
+    fn lobes(&self, back_face: bool) -> Lobes {
+        let inside = back_face && self.transmission > 0.0;
+        let dielectric = 1.0 - self.metallic;
+        let transmission = dielectric * self.transmission;
+        let (diffuse, clearcoat) = if inside {
+            (0.0, 0.0)
+        } else {
+            (
+                dielectric * (1.0 - self.transmission),
+                0.25 * self.clearcoat,
+            )
+        };
+        let eta = if inside { 1.0 / self.ior } else { self.ior };
+
+        let specular = lerp(WHITE, self.tint(), self.specular_tint) * (0.08 * self.specular);
+        let specular = lerp(specular, self.base_color, self.metallic);
+
+        let reflection = Microfacets::new(
+            MicrofacetDistribution::GGX,
+            self.roughness * self.roughness,
+            self.anisotropic,
+        );
+        let refraction = Microfacets::new(
+            MicrofacetDistribution::GGX,
+            self.transmission_roughness * self.transmission_roughness,
+            0.0,
+        );
+
+        // Lobes are sampled in proportion to a rough estimate of the light they scatter
+        let reflectance = lerp(
+            specular.mean(),
+            ((eta - 1.0) / (eta + 1.0)).powi(2),
+            transmission,
+        );
+        let weights = [
+            diffuse,
+            lerp(reflectance, 1.0, 0.2),
+            clearcoat,
+            transmission,
+        ];
+        let total: Float = weights.iter().sum();
+
+        Lobes {
+            diffuse,
+            clearcoat,
+            transmission,
+            eta,
+            specular,
+            reflection,
+            refraction,
+            clearcoat_alpha: lerp(0.1, 0.001, self.clearcoat_gloss),
+            probabilities: weights.map(|weight| weight / total),
+        }
+    }
+
+    /// BSDF in the local shading frame, where `wo` lies in the upper hemisphere
+    fn f(&self, lobes: &Lobes, wo: Vec3, wi: Vec3) -> Color {
+        let (cos_o, cos_i) = (wo.z, wi.z);
+        if cos_o <= 0.0 || cos_i == 0.0 {
+            return BLACK;
+        }
+
+        if cos_i > 0.0 {
+            let h = (wo + wi).normalize();
+            let cos_d = wi.dot(h);
+            let mut f = BLACK;
+
+            if lobes.diffuse > 0.0 {
+                // Lambertian with retro-reflection at grazing angles of rough surfaces
+                let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+                let fd = (1.0 + (fd90 - 1.0) * schlick_weight(cos_i))
+                    * (1.0 + (fd90 - 1.0) * schlick_weight(cos_o));
+                let sheen = lerp(WHITE, self.tint(), self.sheen_tint)
+                    * (self.sheen * schlick_weight(cos_d));
+                f += (self.base_color * (fd / PI) + sheen) * lobes.diffuse;
+            }
+
+            // Light which could also be transmitted is reflected with the dielectric Fresnel term
+            let fresnel = lerp(
+                schlick(lobes.specular, cos_d),
+                GRAY(fresnel_dielectric(cos_d, lobes.eta)),
+                lobes.transmission,
+            );
+            let d = lobes.reflection.d(h);
+            let g = lobes.reflection.g(wo, wi);
+            f += fresnel * (d * g / (4.0 * cos_o * cos_i));
+
+            if lobes.clearcoat > 0.0 {
+                let d = gtr1_d(h.z, lobes.clearcoat_alpha);
+                let g = Microfacets::new(MicrofacetDistribution::GGX, 0.25, 0.0).g(wo, wi);
+                let fresnel = lerp(0.04, 1.0, schlick_weight(cos_d));
+                f += GRAY(lobes.clearcoat * d * g * fresnel / (4.0 * cos_o * cos_i));
+            }
+
+            f
+        } else if lobes.transmission > 0.0 {
+            let eta = lobes.eta;
+            let h = match refraction_half_vector(wo, wi, eta) {
+                Some(h) => h,
+                None => return BLACK,
+            };
+            let (wo_h, wi_h) = (wo.dot(h), wi.dot(h));
+
+            // Microfacet BTDF, the 1 / η^2 scaling of radiance cancels the η^2 of the Jacobian
+            let d = lobes.refraction.d(h);
+            let g = lobes.refraction.g(wo, wi);
+            let fresnel = fresnel_dielectric(wo_h, eta);
+            let btdf = d * g * (1.0 - fresnel) * (wo_h * wi_h).abs()
+                / ((wo_h + eta * wi_h).powi(2) * (cos_o * cos_i).abs());
+            self.base_color * (lobes.transmission * btdf)
+        } else {
+            BLACK
+        }
+    }
+
+    /// Sample a direction toward the incident light in the local shading frame
+    fn sample(&self, lobes: &Lobes, wo: Vec3, rng: &mut dyn Sampler) -> Option<Vec3> {
+        let [diffuse, specular, clearcoat, _] = lobes.probabilities;
+        let u = rng.get_1d();
+
+        if u < diffuse {
+            // Cosine-sampling using Malley's method
+            let [x, y] = sample_disk(rng.get_2d());
+            Some(vec3!(x, y, (1.0 - x * x - y * y).max(0.0).sqrt()))
+        } else if u < diffuse + specular {
+            let h = lobes.reflection.sample_visible(wo, rng.get_2d());
+            Some(h * (2.0 * wo.dot(h)) - wo)
+        } else if u < diffuse + specular + clearcoat {
+            // PIT for the GTR1 distribution of microfacet normals
+            let [u, v] = rng.get_2d();
+            let a2 = lobes.clearcoat_alpha * lobes.clearcoat_alpha;
+            let cos2 = ((1.0 - a2.powf(1.0 - u)) / (1.0 - a2)).clamp(0.0, 1.0);
+            let sin = (1.0 - cos2).sqrt();
+            let (y, x) = (2.0 * PI * v).sin_cos();
+            let h = vec3!(x * sin, y * sin, cos2.sqrt());
+            Some(h * (2.0 * wo.dot(h)) - wo)
+        } else {
+            let h = lobes.refraction.sample_visible(wo, rng.get_2d());
+
+            // Refract around the microfacet normal, unless it is totally internally reflected
+            let cos_o = wo.dot(h);
+            let sin2_i = (1.0 - cos_o * cos_o) / (lobes.eta * lobes.eta);
+            if sin2_i >= 1.0 {
+                return None;
+            }
+            let cos_i = (1.0 - sin2_i).sqrt();
+            Some(h * (cos_o / lobes.eta - cos_i) - wo / lobes.eta)
+        }
+    }
+
+    /// Probability density of `sample` returning `wi`, the sum of the densities of all lobes
+    fn pdf(&self, lobes: &Lobes, wo: Vec3, wi: Vec3) -> Float {
+        let [diffuse, specular, clearcoat, transmission] = lobes.probabilities;
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+
+        let pdf = if wi.z > 0.0 {
+            let h = (wo + wi).normalize();
+            let reflection = 1.0 / (4.0 * wo.dot(h));
+
+            diffuse * wi.z / PI
+                + specular * lobes.reflection.visible_pdf(wo, h) * reflection
+                + clearcoat * gtr1_d(h.z, lobes.clearcoat_alpha) * h.z * reflection
+        } else if transmission > 0.0 {
+            let eta = lobes.eta;
+            let h = match refraction_half_vector(wo, wi, eta) {
+                Some(h) => h,
+                None => return 0.0,
+            };
+            let (wo_h, wi_h) = (wo.dot(h), wi.dot(h));
+            let jacobian = eta * eta * wi_h.abs() / (wo_h + eta * wi_h).powi(2);
+            transmission * lobes.refraction.visible_pdf(wo, h) * jacobian
+        } else {
+            0.0
+        };
+
+        if pdf.is_finite() {
+            pdf
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Weight of the reflectance at grazing angles in Schlick's approximation
+fn schlick_weight(cosine: Float) -> Float {
+    (1.0 - cosine).clamp(0.0, 1.0).powi(5)
+}
+
+/// Schlick's approximation of the Fresnel term
+fn schlick(f0: Color, cosine: Float) -> Color {
+    lerp(f0, WHITE, schlick_weight(cosine))
+}
+
+/// Fresnel reflectance of a dielectric interface with a relative index of refraction `eta`
+fn fresnel_dielectric(cosine: Float, eta: Float) -> Float {
+    let cos_i = cosine.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+/// Microfacet normal refracting `wo` into `wi`, on the side of the shading normal
+fn refraction_half_vector(wo: Vec3, wi: Vec3, eta: Float) -> Option<Vec3> {
+    let h = (wo + wi * eta).normalize();
+    let h = if h.z < 0.0 { -h } else { h };
+    if !h.z.is_finite() || wo.dot(h) * wi.dot(h) >= 0.0 {
+        None
+    } else {
+        Some(h)
+    }
+}
+
+/// Generalized Trowbridge-Reitz distribution with γ = 1, whose long tails give the clearcoat
+/// its haze
+fn gtr1_d(cos: Float, alpha: Float) -> Float {
+    let a2 = alpha * alpha;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos * cos))
+}
+
+impl Material for PrincipledBSDF {
+    fn scatter(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        rng: &mut dyn Sampler,
+    ) -> (Color, Option<Ray>) {
+        let (bsdf, hit) = self.resolve(scene, hit);
+        let frame = Frame::new(hit.normal, hit.tangent);
+        let lobes = bsdf.lobes(!hit.front_face);
+        let wo = frame.to_local(-ray.direction.normalize());
+
+        let wi = match bsdf.sample(&lobes, wo, rng) {
+            Some(wi) => wi,
+            None => return (BLACK, None),
+        };
+        let pdf = bsdf.pdf(&lobes, wo, wi);
+        if pdf <= 0.0 {
+            return (BLACK, None);
+        }
+
+        let indirect = bsdf.f(&lobes, wo, wi) * (wi.z.abs() / pdf);
+        (indirect, Some(Ray::new(hit.point, frame.to_world(wi))))
+    }
+
+    fn eval(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+    ) -> Option<(Color, Float)> {
+        let (bsdf, hit) = self.resolve(scene, hit);
+        let frame = Frame::new(hit.normal, hit.tangent);
+        let lobes = bsdf.lobes(!hit.front_face);
+        let wo = frame.to_local(-ray.direction.normalize());
+        let wi = frame.to_local(to_incident.normalize());
+
+        let f = bsdf.f(&lobes, wo, wi) * wi.z.abs();
+        Some((f, bsdf.pdf(&lobes, wo, wi)))
+    }
+
+    fn emission(&self) -> Color {
+        self.emission
+    }
+
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
+        self.resolve(scene, hit).0.base_color
+    }
+
+    fn shading_normal(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Vec3 {
+        self.resolve(scene, hit).1.normal
+    }
+
+    /// Light which isn't reflected specularly by dielectrics, transmission is treated as
+    /// specular
+    fn diffuse_fraction(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Float {
+        let bsdf = self.resolve(scene, hit).0;
+        (1.0 - bsdf.metallic) * (1.0 - bsdf.transmission) * (1.0 - 0.08 * bsdf.specular)
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_color(self.base_color);
+        hasher.write_floats(&[
+            self.metallic,
+            self.roughness,
+            self.anisotropic,
+            self.specular,
+            self.specular_tint,
+            self.sheen,
+            self.sheen_tint,
+            self.clearcoat,
+            self.clearcoat_gloss,
+            self.transmission,
+            self.transmission_roughness,
+            self.ior,
+        ]);
+        hasher.write_color(self.emission);
+
+        let textures = &self.textures;
+        for texture in [
+            textures.base_color,
+            textures.metallic,
+            textures.roughness,
+            textures.anisotropic,
+            textures.specular,
+            textures.specular_tint,
+            textures.sheen,
+            textures.sheen_tint,
+            textures.clearcoat,
+            textures.clearcoat_gloss,
+            textures.transmission,
+            textures.transmission_roughness,
+            textures.normal,
+        ] {
+            hasher.write_texture(texture);
+        }
+    }
+}
+
+#[test]
+fn principled_sampling_test() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let materials = [
+        PrincipledBSDF::default(),
+        PrincipledBSDF {
+            base_color: Color::new(0.9, 0.6, 0.2),
+            metallic: 1.0,
+            roughness: 0.3,
+            anisotropic: 0.8,
+            ..Default::default()
+        },
+        PrincipledBSDF {
+            sheen: 1.0,
+            clearcoat: 1.0,
+            clearcoat_gloss: 0.5,
+            ..Default::default()
+        },
+        PrincipledBSDF {
+            base_color: WHITE,
+            roughness: 0.2,
+            transmission: 1.0,
+            transmission_roughness: 0.4,
+            ..Default::default()
+        },
+    ];
+    let wo = vec3!(0.5, 0.2, 0.6).normalize();
+    let samples = 100_000;
+
+    // Importance sampling converges to the same reflectance as uniform sampling of the sphere,
+    // and opaque materials don't reflect more light than they receive. Refraction scales
+    // radiance by 1 / η^2, so transmissive materials can exceed it from inside.
+    for (i, material) in materials.iter().enumerate() {
+        for back_face in [false, true] {
+            let lobes = material.lobes(back_face);
+
+            let mut sampled = 0.0;
+            for _ in 0..samples {
+                if let Some(wi) = material.sample(&lobes, wo, &mut rng) {
+                    let pdf = material.pdf(&lobes, wo, wi);
+                    if pdf > 0.0 {
+                        sampled += material.f(&lobes, wo, wi).mean() * wi.z.abs() / pdf;
+                    }
+                }
+            }
+            let sampled = sampled / samples as Float;
+
+            let mut uniform = 0.0;
+            let mut total_pdf = 0.0;
+            for _ in 0..samples {
+                let [u, v] = rng.get_2d();
+                let z = 1.0 - 2.0 * u;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let (y, x) = (2.0 * PI * v).sin_cos();
+                let wi = vec3!(r * x, r * y, z);
+                uniform += material.f(&lobes, wo, wi).mean() * wi.z.abs() * 4.0 * PI;
+                total_pdf += material.pdf(&lobes, wo, wi) * 4.0 * PI;
+            }
+            let uniform = uniform / samples as Float;
+            let total_pdf = total_pdf / samples as Float;
+
+            assert!(
+                sampled < 1.01 || material.transmission > 0.0,
+                "{} {}",
+                i,
+                sampled
+            );
+            assert!(total_pdf < 1.05, "{} {}", i, total_pdf);
+            assert!(
+                (sampled - uniform).abs() < 0.02 + 0.05 * uniform,
+                "{} {} {}",
+                i,
+                sampled,
+                uniform
+            );
+        }
+    }
+}
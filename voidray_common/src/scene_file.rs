@@ -0,0 +1,1206 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+use voidray_renderer::aabb::AABB;
+use voidray_renderer::camera::Camera;
+use voidray_renderer::color::{Color, BLACK};
+use voidray_renderer::mesh::{Mesh, Vertex};
+use voidray_renderer::preamble::*;
+use voidray_renderer::render::image::HdrImage;
+use voidray_renderer::scene::{MaterialHandle, MediumHandle, Scene, SurfaceHandle, TextureHandle};
+use voidray_renderer::settings::Settings;
+use voidray_renderer::spectrum::{ComplexIor, Ior, LAMBDA_D};
+use voidray_renderer::texture::{SampleType, Texture};
+use voidray_renderer::traits::{Environment, Material, Medium, Surface};
+use voidray_renderer::transform::Transform;
+use voidray_renderer::Error;
+
+use crate::environments::{HDRIEnvironment, UniformEnvironment};
+use crate::media::{GridMedium, HomogeneousMedium};
+use crate::simple::{
+    ColorType, Conductor, Dielectric, Emission, Interface, Lambertian, LambertianBSDF, Metal,
+};
+use crate::surfaces::{GroundPlane, Sphere};
+use crate::{
+    Environments, Media, MicrofacetBSDF, MicrofacetDistribution, PrincipledBSDF,
+    PrincipledTextures, Subsurface, Surfaces, TexturedMicrofacetBSDF,
+};
+
+/// Vectors and colors are stored as plain triples in scene files
+pub type Triple = [Float; 3];
+
+/// Declarative description of a scene, stored on disk in the RON format
+///
+/// Textures, materials and surfaces are referenced by name, these are the same names that
+/// are stored in the `Named` entries of the built `Scene`. Asset paths are relative to the
+/// working directory.
+#[derive(Serialize, Deserialize)]
+pub struct SceneDescription {
+    #[serde(default = "default_dimensions")]
+    pub dimensions: [u32; 2],
+    #[serde(default)]
+    pub settings: Settings,
+    pub camera: CameraDescription,
+    #[serde(default)]
+    pub environment: Option<EnvironmentDescription>,
+    /// Medium filling the space outside of objects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    #[serde(default)]
+    pub textures: Vec<TextureDescription>,
+    #[serde(default)]
+    pub materials: Vec<MaterialDescription>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub media: Vec<MediumDescription>,
+    #[serde(default)]
+    pub surfaces: Vec<SurfaceDescription>,
+    #[serde(default)]
+    pub objects: Vec<ObjectDescription>,
+}
+
+fn default_dimensions() -> [u32; 2] {
+    [1000, 1000]
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CameraDescription {
+    pub eye: Triple,
+    pub direction: Triple,
+    pub up: Triple,
+    pub fov: Float,
+    /// Aperture radius and focal point
+    #[serde(default)]
+    pub dof: Option<(Float, Triple)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum EnvironmentDescription {
+    Uniform(Triple),
+    Hdri(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TextureDescription {
+    pub name: String,
+    pub path: String,
+    #[serde(default = "default_sample_type")]
+    pub sample_type: SampleType,
+}
+
+fn default_sample_type() -> SampleType {
+    SampleType::Bilinear
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MaterialDescription {
+    pub name: String,
+    pub material: MaterialKind,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ColorSource {
+    Color(Triple),
+    Texture(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum MaterialKind {
+    Lambertian {
+        albedo: ColorSource,
+        #[serde(default)]
+        normal: Option<String>,
+    },
+    LambertianBsdf {
+        albedo: Triple,
+    },
+    Metal {
+        albedo: Triple,
+        fuzz: Float,
+    },
+    Dielectric {
+        index: Float,
+        /// Wavelength dependent index of refraction, replaces `index`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dispersion: Option<Ior>,
+        /// Absorption coefficient of the interior, in units of inverse scene distance
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        absorption: Option<Triple>,
+    },
+    Conductor {
+        ior: ComplexIor,
+        #[serde(default)]
+        fuzz: Float,
+    },
+    Emissive {
+        color: Triple,
+        strength: Float,
+    },
+    Microfacet {
+        color: Triple,
+        index: Float,
+        roughness: Float,
+        /// Shape of the microfacet distribution
+        #[serde(default)]
+        distribution: MicrofacetDistribution,
+        /// Stretch of the highlights along the surface tangent, from 0 to 1
+        #[serde(default)]
+        anisotropy: Float,
+        #[serde(default)]
+        metallic: Float,
+        #[serde(default)]
+        emittance: Float,
+        #[serde(default)]
+        transparent: bool,
+        /// Wavelength dependent index of refraction, replaces `index` in spectral renders
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dispersion: Option<Ior>,
+        /// Absorption coefficient of the interior of transparent materials
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        absorption: Option<Triple>,
+        /// Albedo texture, replaces `color`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        texture: Option<String>,
+        /// Tangent space normal map
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        normal: Option<String>,
+    },
+    /// Invisible boundary of an object filled with a medium
+    Interface,
+    /// Light scatters below the surface, the mean free path is in scene distance units
+    Subsurface {
+        albedo: ColorSource,
+        mean_free_path: Triple,
+        index: Float,
+        #[serde(default)]
+        g: Float,
+    },
+    Principled(PrincipledDescription),
+}
+
+/// Parameters of a `PrincipledBSDF`, left out parameters take their default values
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrincipledDescription {
+    pub base_color: Triple,
+    pub metallic: Float,
+    pub roughness: Float,
+    pub anisotropic: Float,
+    pub specular: Float,
+    pub specular_tint: Float,
+    pub sheen: Float,
+    pub sheen_tint: Float,
+    pub clearcoat: Float,
+    pub clearcoat_gloss: Float,
+    pub transmission: Float,
+    pub transmission_roughness: Float,
+    pub ior: Float,
+    pub emission: Triple,
+    /// Names of the textures replacing the parameters
+    pub textures: PrincipledTextureNames,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrincipledTextureNames {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metallic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roughness: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anisotropic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specular: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specular_tint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sheen: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sheen_tint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clearcoat: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clearcoat_gloss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transmission: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transmission_roughness: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normal: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MediumDescription {
+    pub name: String,
+    pub medium: MediumKind,
+}
+
+/// Coefficients are in units of inverse scene distance
+#[derive(Serialize, Deserialize)]
+pub enum MediumKind {
+    Homogeneous {
+        sigma_a: Triple,
+        sigma_s: Triple,
+        #[serde(default)]
+        g: Float,
+    },
+    /// Density grid spanning the box from `min` to `max`, in x-major order
+    Grid {
+        min: Triple,
+        max: Triple,
+        resolution: [usize; 3],
+        density: Vec<Float>,
+        sigma_t: Float,
+        albedo: Triple,
+        #[serde(default)]
+        g: Float,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SurfaceDescription {
+    pub name: String,
+    pub surface: SurfaceKind,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum SurfaceKind {
+    Sphere {
+        center: Triple,
+        radius: Float,
+    },
+    GroundPlane {
+        height: Float,
+    },
+    Quad([Triple; 4]),
+    /// Wavefront OBJ file
+    Mesh(String),
+    /// Inline triangle mesh, three indices per triangle. Texture coordinates, vertex normals
+    /// and tangents are optional, when present there is one per position.
+    TriangleMesh {
+        positions: Vec<Triple>,
+        indices: Vec<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        uvs: Vec<[Float; 2]>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        normals: Vec<Triple>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tangents: Vec<Triple>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ObjectDescription {
+    pub name: String,
+    pub surface: String,
+    pub material: String,
+    /// Column-major object to world matrix, the surface is shared with other objects using it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<[[Float; 4]; 4]>,
+    /// Medium filling the object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interior: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    Serialize(ron::Error),
+    /// A texture, material, surface or medium was referenced by a name which does not exist
+    UnknownName(String),
+    /// Two textures, materials, surfaces or media have the same name
+    DuplicateName(String),
+    /// The scene file describes something inconsistent, e.g. a mesh index out of bounds
+    Invalid(String),
+    /// The scene contains something which can't be described by a scene file
+    Unsupported(String),
+    /// An object has a transform which can't be inverted
+    InvalidTransform(String),
+    /// A texture, mesh or environment referenced by the scene failed to load
+    Asset(Error),
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneFileError::Io(err) => write!(f, "{}", err),
+            SceneFileError::Parse(err) => write!(f, "parse error: {}", err),
+            SceneFileError::Serialize(err) => write!(f, "serialize error: {}", err),
+            SceneFileError::UnknownName(name) => write!(f, "unknown name '{}'", name),
+            SceneFileError::DuplicateName(name) => write!(f, "duplicate name '{}'", name),
+            SceneFileError::Invalid(what) => write!(f, "invalid {}", what),
+            SceneFileError::Unsupported(what) => write!(f, "unsupported {}", what),
+            SceneFileError::InvalidTransform(name) => {
+                write!(f, "transform of object '{}' is not invertible", name)
+            }
+            SceneFileError::Asset(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SceneFileError {}
+
+impl From<std::io::Error> for SceneFileError {
+    fn from(err: std::io::Error) -> Self {
+        SceneFileError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for SceneFileError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        SceneFileError::Parse(err)
+    }
+}
+
+impl From<Error> for SceneFileError {
+    fn from(err: Error) -> Self {
+        SceneFileError::Asset(err)
+    }
+}
+
+impl From<ron::Error> for SceneFileError {
+    fn from(err: ron::Error) -> Self {
+        SceneFileError::Serialize(err)
+    }
+}
+
+/// Load a scene file, returning the scene along with its settings and dimensions
+pub fn load_scene(path: &str) -> Result<(Scene, Settings, [u32; 2]), SceneFileError> {
+    let description: SceneDescription = ron::from_str(&fs::read_to_string(path)?)?;
+    description.build()
+}
+
+/// Write a scene, along with its settings and dimensions, to a scene file
+pub fn save_scene(
+    path: &str,
+    scene: &Scene,
+    settings: &Settings,
+    dimensions: [u32; 2],
+) -> Result<(), SceneFileError> {
+    let description = SceneDescription::from_scene(scene, settings, dimensions)?;
+    let contents = ron::ser::to_string_pretty(&description, ron::ser::PrettyConfig::default())?;
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+fn lookup<H: Copy>(handles: &HashMap<String, H>, name: &str) -> Result<H, SceneFileError> {
+    handles
+        .get(name)
+        .copied()
+        .ok_or_else(|| SceneFileError::UnknownName(name.to_owned()))
+}
+
+/// Register a named handle, names have to be unique so references can't be ambiguous
+fn insert_unique<H>(
+    handles: &mut HashMap<String, H>,
+    name: &str,
+    handle: H,
+) -> Result<(), SceneFileError> {
+    if handles.insert(name.to_owned(), handle).is_some() {
+        return Err(SceneFileError::DuplicateName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Fails if a name occurs more than once, such scenes can't be loaded again after saving
+fn check_unique<'a>(names: impl Iterator<Item = &'a str>) -> Result<(), SceneFileError> {
+    let mut seen = HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(SceneFileError::DuplicateName(name.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// Empty if every value is zero, so optional vertex attributes aren't written
+fn unless_zero<T: Default + PartialEq>(values: Vec<T>) -> Vec<T> {
+    if values.iter().all(|value| *value == T::default()) {
+        Vec::new()
+    } else {
+        values
+    }
+}
+
+fn to_vec3(triple: Triple) -> Vec3 {
+    Vec3::new(triple[0], triple[1], triple[2])
+}
+
+fn to_triple(vector: Vec3) -> Triple {
+    [vector.x, vector.y, vector.z]
+}
+
+/// Materials without absorption leave it out of the scene file
+fn describe_absorption(absorption: Color) -> Option<Triple> {
+    (absorption.max_component() > 0.0).then(|| to_triple(absorption.0))
+}
+
+fn to_color(triple: Triple) -> Color {
+    Color(to_vec3(triple))
+}
+
+impl SceneDescription {
+    /// Build a renderable scene from the description
+    pub fn build(&self) -> Result<(Scene, Settings, [u32; 2]), SceneFileError> {
+        let [width, height] = self.dimensions;
+        if width == 0 || height == 0 || HdrImage::buffer_len(self.dimensions).is_none() {
+            return Err(SceneFileError::Invalid(format!(
+                "dimensions {}x{}",
+                width, height
+            )));
+        }
+
+        let mut scene = Scene::empty();
+
+        scene.camera = Camera {
+            eye: to_vec3(self.camera.eye),
+            direction: to_vec3(self.camera.direction),
+            up: to_vec3(self.camera.up),
+            fov: self.camera.fov,
+            dof: self
+                .camera
+                .dof
+                .map(|(aperture, focal_point)| (aperture, to_vec3(focal_point))),
+        };
+
+        scene.environment = match &self.environment {
+            None => None,
+            Some(EnvironmentDescription::Uniform(color)) => Environments::uniform(to_color(*color)),
+            Some(EnvironmentDescription::Hdri(path)) => Environments::try_hdri(path)?,
+        };
+
+        let mut textures = HashMap::<String, TextureHandle>::new();
+        for texture in &self.textures {
+            let handle = scene.try_add_image_texture(&texture.path, texture.sample_type)?;
+            scene.rename_texture(handle, &texture.name);
+            insert_unique(&mut textures, &texture.name, handle)?;
+        }
+
+        let mut materials = HashMap::<String, MaterialHandle>::new();
+        for material in &self.materials {
+            let handle = scene.add_material(material.material.build(&textures)?);
+            scene.rename_material(handle, &material.name);
+            insert_unique(&mut materials, &material.name, handle)?;
+        }
+
+        let mut media = HashMap::<String, MediumHandle>::new();
+        for medium in &self.media {
+            let handle = scene.add_medium(medium.medium.build()?);
+            scene.rename_medium(handle, &medium.name);
+            insert_unique(&mut media, &medium.name, handle)?;
+        }
+        scene.medium = match &self.medium {
+            Some(name) => Some(lookup(&media, name)?),
+            None => None,
+        };
+
+        let mut surfaces = HashMap::<String, SurfaceHandle>::new();
+        for surface in &self.surfaces {
+            let handle = match &surface.surface {
+                SurfaceKind::Sphere { center, radius } => {
+                    scene.add_analytic_surface(Surfaces::sphere(to_vec3(*center), *radius))
+                }
+                SurfaceKind::GroundPlane { height } => {
+                    scene.add_analytic_surface(Surfaces::ground_plane(*height))
+                }
+                SurfaceKind::Quad([q1, q2, q3, q4]) => scene.add_mesh(Surfaces::quad(
+                    to_vec3(*q1),
+                    to_vec3(*q2),
+                    to_vec3(*q3),
+                    to_vec3(*q4),
+                )),
+                SurfaceKind::Mesh(path) => scene.try_add_mesh_from_file(path)?,
+                SurfaceKind::TriangleMesh {
+                    positions,
+                    indices,
+                    uvs,
+                    normals,
+                    tangents,
+                } => {
+                    let count = positions.len();
+                    let attributes = [uvs.len(), normals.len(), tangents.len()];
+                    if indices.len() % 3 != 0
+                        || indices.iter().any(|index| *index as usize >= count)
+                        || attributes.iter().any(|len| *len != 0 && *len != count)
+                    {
+                        return Err(SceneFileError::Invalid(format!(
+                            "triangle mesh '{}'",
+                            surface.name
+                        )));
+                    }
+
+                    let vertices = positions
+                        .iter()
+                        .enumerate()
+                        .map(|(i, position)| {
+                            let [u, v] = uvs.get(i).copied().unwrap_or_default();
+                            let normal = normals.get(i).copied().unwrap_or_default();
+                            let tangent = tangents.get(i).copied().unwrap_or_default();
+                            Vertex::new(to_vec3(*position), Vec2::new(u, v), to_vec3(normal))
+                                .with_tangent(to_vec3(tangent))
+                        })
+                        .collect();
+                    scene.add_mesh(Arc::new(Mesh::from_buffers(vertices, indices.clone())))
+                }
+            };
+            scene.rename_surface(handle, &surface.name);
+            insert_unique(&mut surfaces, &surface.name, handle)?;
+        }
+
+        for object in &self.objects {
+            let material = lookup(&materials, &object.material)?;
+            let surface = lookup(&surfaces, &object.surface)?;
+
+            let handle = match object.transform {
+                None => scene.add_object(material, surface),
+                Some(transform) => {
+                    let transform = Mat4::from(transform);
+                    if Transform::new(transform).is_none() {
+                        return Err(SceneFileError::InvalidTransform(object.name.clone()));
+                    }
+                    scene.add_instance(surface, material, transform)
+                }
+            };
+            scene.rename_object(handle, &object.name);
+            if let Some(interior) = &object.interior {
+                scene.set_interior(handle, Some(lookup(&media, interior)?));
+            }
+        }
+
+        Ok((scene, self.settings.clone(), self.dimensions))
+    }
+
+    /// Describe an existing scene, fails if the scene contains materials, surfaces, textures
+    /// or environments which are not part of this crate, or names which aren't unique
+    pub fn from_scene(
+        scene: &Scene,
+        settings: &Settings,
+        dimensions: [u32; 2],
+    ) -> Result<Self, SceneFileError> {
+        let camera = CameraDescription {
+            eye: to_triple(scene.camera.eye),
+            direction: to_triple(scene.camera.direction),
+            up: to_triple(scene.camera.up),
+            fov: scene.camera.fov,
+            dof: scene
+                .camera
+                .dof
+                .map(|(aperture, focal_point)| (aperture, to_triple(focal_point))),
+        };
+
+        let environment = match &scene.environment {
+            None => None,
+            Some(environment) => Some(EnvironmentDescription::describe(environment.as_ref())?),
+        };
+
+        let textures = scene
+            .textures
+            .iter()
+            .map(|named| match named.object().as_ref() {
+                Texture::Image(image) => Ok(TextureDescription {
+                    name: named.name().to_owned(),
+                    path: image
+                        .path()
+                        .ok_or_else(|| {
+                            SceneFileError::Unsupported(format!("texture '{}'", named.name()))
+                        })?
+                        .to_owned(),
+                    sample_type: image.sample_type(),
+                }),
+                Texture::Abstract(_) => Err(SceneFileError::Unsupported(format!(
+                    "texture '{}'",
+                    named.name()
+                ))),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let materials = scene
+            .materials
+            .iter()
+            .map(|named| {
+                Ok(MaterialDescription {
+                    name: named.name().to_owned(),
+                    material: MaterialKind::describe(scene, named.object().as_ref()).ok_or_else(
+                        || SceneFileError::Unsupported(format!("material '{}'", named.name())),
+                    )?,
+                })
+            })
+            .collect::<Result<_, SceneFileError>>()?;
+
+        let media = scene
+            .media
+            .iter()
+            .map(|named| {
+                Ok(MediumDescription {
+                    name: named.name().to_owned(),
+                    medium: MediumKind::describe(named.object().as_ref()).ok_or_else(|| {
+                        SceneFileError::Unsupported(format!("medium '{}'", named.name()))
+                    })?,
+                })
+            })
+            .collect::<Result<_, SceneFileError>>()?;
+
+        let surfaces = scene
+            .surfaces
+            .iter()
+            .map(|named| {
+                Ok(SurfaceDescription {
+                    name: named.name().to_owned(),
+                    surface: SurfaceKind::describe(scene, named.object()).ok_or_else(|| {
+                        SceneFileError::Unsupported(format!("surface '{}'", named.name()))
+                    })?,
+                })
+            })
+            .collect::<Result<_, SceneFileError>>()?;
+
+        check_unique(scene.textures.iter().map(|named| named.name()))?;
+        check_unique(scene.materials.iter().map(|named| named.name()))?;
+        check_unique(scene.media.iter().map(|named| named.name()))?;
+        check_unique(scene.surfaces.iter().map(|named| named.name()))?;
+
+        let objects = scene
+            .objects
+            .iter()
+            .map(|named| ObjectDescription {
+                name: named.name().to_owned(),
+                surface: scene.surface_name(named.object().surface).to_owned(),
+                material: scene.material_name(named.object().material).to_owned(),
+                transform: named
+                    .object()
+                    .transform
+                    .as_ref()
+                    .map(|transform| (*transform.matrix()).into()),
+                interior: named
+                    .object()
+                    .interior
+                    .map(|medium| scene.medium_name(medium).to_owned()),
+            })
+            .collect();
+
+        Ok(Self {
+            dimensions,
+            settings: settings.clone(),
+            camera,
+            environment,
+            medium: scene
+                .medium
+                .map(|medium| scene.medium_name(medium).to_owned()),
+            textures,
+            materials,
+            media,
+            surfaces,
+            objects,
+        })
+    }
+}
+
+impl EnvironmentDescription {
+    fn describe(environment: &dyn Environment) -> Result<Self, SceneFileError> {
+        let any = environment.as_any();
+
+        if let Some(uniform) = any.downcast_ref::<UniformEnvironment>() {
+            Ok(EnvironmentDescription::Uniform(to_triple(uniform.color.0)))
+        } else if let Some(hdri) = any.downcast_ref::<HDRIEnvironment>() {
+            Ok(EnvironmentDescription::Hdri(hdri.path.clone()))
+        } else {
+            Err(SceneFileError::Unsupported("environment".to_owned()))
+        }
+    }
+}
+
+impl MediumKind {
+    fn build(&self) -> Result<Arc<dyn Medium>, SceneFileError> {
+        Ok(match self {
+            MediumKind::Homogeneous {
+                sigma_a,
+                sigma_s,
+                g,
+            } => Media::homogeneous(to_color(*sigma_a), to_color(*sigma_s), *g),
+            MediumKind::Grid {
+                min,
+                max,
+                resolution,
+                density,
+                sigma_t,
+                albedo,
+                g,
+            } => {
+                if density.len() != resolution.iter().product::<usize>() {
+                    return Err(SceneFileError::Invalid(
+                        "density grid which doesn't match its resolution".to_owned(),
+                    ));
+                }
+                Media::grid(
+                    AABB {
+                        min: to_vec3(*min),
+                        max: to_vec3(*max),
+                    },
+                    *resolution,
+                    density.clone(),
+                    *sigma_t,
+                    to_color(*albedo),
+                    *g,
+                )
+            }
+        })
+    }
+
+    fn describe(medium: &dyn Medium) -> Option<Self> {
+        let any = medium.as_any();
+
+        if let Some(homogeneous) = any.downcast_ref::<HomogeneousMedium>() {
+            Some(MediumKind::Homogeneous {
+                sigma_a: to_triple(homogeneous.sigma_a.0),
+                sigma_s: to_triple(homogeneous.sigma_s.0),
+                g: homogeneous.g,
+            })
+        } else {
+            any.downcast_ref::<GridMedium>()
+                .map(|grid| MediumKind::Grid {
+                    min: to_triple(grid.bounds.min),
+                    max: to_triple(grid.bounds.max),
+                    resolution: grid.resolution,
+                    density: grid.density.clone(),
+                    sigma_t: grid.sigma_t,
+                    albedo: to_triple(grid.albedo.0),
+                    g: grid.g,
+                })
+        }
+    }
+}
+
+impl MaterialKind {
+    fn build(
+        &self,
+        textures: &HashMap<String, TextureHandle>,
+    ) -> Result<Arc<dyn Material>, SceneFileError> {
+        Ok(match self {
+            MaterialKind::Lambertian { albedo, normal } => Arc::new(Lambertian {
+                albedo: match albedo {
+                    ColorSource::Color(color) => ColorType::Color(to_color(*color)),
+                    ColorSource::Texture(name) => ColorType::Texture(lookup(textures, name)?),
+                },
+                normal: match normal {
+                    Some(name) => Some(lookup(textures, name)?),
+                    None => None,
+                },
+            }),
+            MaterialKind::LambertianBsdf { albedo } => Arc::new(LambertianBSDF {
+                albedo: to_color(*albedo),
+            }),
+            MaterialKind::Metal { albedo, fuzz } => Arc::new(Metal {
+                albedo: to_color(*albedo),
+                fuzz: *fuzz,
+            }),
+            MaterialKind::Dielectric {
+                index,
+                dispersion,
+                absorption,
+            } => Arc::new(Dielectric {
+                ior: dispersion.unwrap_or(Ior::Constant(*index)),
+                absorption: absorption.map_or(BLACK, to_color),
+            }),
+            MaterialKind::Conductor { ior, fuzz } => Arc::new(Conductor {
+                ior: *ior,
+                fuzz: *fuzz,
+            }),
+            MaterialKind::Emissive { color, strength } => {
+                Arc::new(Emission::new(to_color(*color), *strength))
+            }
+            MaterialKind::Interface => Arc::new(Interface {}),
+            MaterialKind::Subsurface {
+                albedo,
+                mean_free_path,
+                index,
+                g,
+            } => Arc::new(Subsurface {
+                albedo: match albedo {
+                    ColorSource::Color(color) => ColorType::Color(to_color(*color)),
+                    ColorSource::Texture(name) => ColorType::Texture(lookup(textures, name)?),
+                },
+                mean_free_path: to_color(*mean_free_path),
+                ior: *index,
+                g: *g,
+            }),
+            MaterialKind::Principled(principled) => Arc::new(principled.build(textures)?),
+            MaterialKind::Microfacet {
+                color,
+                index,
+                roughness,
+                distribution,
+                anisotropy,
+                metallic,
+                emittance,
+                transparent,
+                dispersion,
+                absorption,
+                texture,
+                normal,
+            } => {
+                let bsdf = MicrofacetBSDF {
+                    color: to_color(*color),
+                    index: *index,
+                    roughness: *roughness,
+                    distribution: *distribution,
+                    anisotropy: *anisotropy,
+                    metallic: *metallic,
+                    emittance: *emittance,
+                    transparent: *transparent,
+                    dispersion: *dispersion,
+                    absorption: absorption.map_or(BLACK, to_color),
+                };
+
+                if texture.is_none() && normal.is_none() {
+                    Arc::new(bsdf)
+                } else {
+                    Arc::new(TexturedMicrofacetBSDF {
+                        bsdf,
+                        albedo: match texture {
+                            Some(name) => Some(lookup(textures, name)?),
+                            None => None,
+                        },
+                        normal: match normal {
+                            Some(name) => Some(lookup(textures, name)?),
+                            None => None,
+                        },
+                    })
+                }
+            }
+        })
+    }
+
+    fn describe(scene: &Scene, material: &dyn Material) -> Option<Self> {
+        let any = material.as_any();
+
+        if let Some(lambertian) = any.downcast_ref::<Lambertian>() {
+            Some(MaterialKind::Lambertian {
+                albedo: match lambertian.albedo {
+                    ColorType::Color(color) => ColorSource::Color(to_triple(color.0)),
+                    ColorType::Texture(texture) => {
+                        ColorSource::Texture(scene.texture_name(texture).to_owned())
+                    }
+                },
+                normal: lambertian
+                    .normal
+                    .map(|texture| scene.texture_name(texture).to_owned()),
+            })
+        } else if let Some(lambertian) = any.downcast_ref::<LambertianBSDF>() {
+            Some(MaterialKind::LambertianBsdf {
+                albedo: to_triple(lambertian.albedo.0),
+            })
+        } else if let Some(metal) = any.downcast_ref::<Metal>() {
+            Some(MaterialKind::Metal {
+                albedo: to_triple(metal.albedo.0),
+                fuzz: metal.fuzz,
+            })
+        } else if let Some(dielectric) = any.downcast_ref::<Dielectric>() {
+            Some(MaterialKind::Dielectric {
+                index: dielectric.ior.at(LAMBDA_D),
+                dispersion: Some(dielectric.ior).filter(Ior::is_dispersive),
+                absorption: describe_absorption(dielectric.absorption),
+            })
+        } else if let Some(conductor) = any.downcast_ref::<Conductor>() {
+            Some(MaterialKind::Conductor {
+                ior: conductor.ior,
+                fuzz: conductor.fuzz,
+            })
+        } else if let Some(emission) = any.downcast_ref::<Emission>() {
+            Some(MaterialKind::Emissive {
+                color: to_triple(emission.color.0),
+                strength: 1.0,
+            })
+        } else if any.is::<Interface>() {
+            Some(MaterialKind::Interface)
+        } else if let Some(subsurface) = any.downcast_ref::<Subsurface>() {
+            Some(MaterialKind::Subsurface {
+                albedo: match subsurface.albedo {
+                    ColorType::Color(color) => ColorSource::Color(to_triple(color.0)),
+                    ColorType::Texture(texture) => {
+                        ColorSource::Texture(scene.texture_name(texture).to_owned())
+                    }
+                },
+                mean_free_path: to_triple(subsurface.mean_free_path.0),
+                index: subsurface.ior,
+                g: subsurface.g,
+            })
+        } else if let Some(principled) = any.downcast_ref::<PrincipledBSDF>() {
+            Some(MaterialKind::Principled(PrincipledDescription::describe(
+                scene, principled,
+            )))
+        } else if let Some(textured) = any.downcast_ref::<TexturedMicrofacetBSDF>() {
+            let microfacet = &textured.bsdf;
+            Some(MaterialKind::Microfacet {
+                color: to_triple(microfacet.color.0),
+                index: microfacet.index,
+                roughness: microfacet.roughness,
+                distribution: microfacet.distribution,
+                anisotropy: microfacet.anisotropy,
+                metallic: microfacet.metallic,
+                emittance: microfacet.emittance,
+                transparent: microfacet.transparent,
+                dispersion: microfacet.dispersion,
+                absorption: describe_absorption(microfacet.absorption),
+                texture: textured
+                    .albedo
+                    .map(|texture| scene.texture_name(texture).to_owned()),
+                normal: textured
+                    .normal
+                    .map(|texture| scene.texture_name(texture).to_owned()),
+            })
+        } else {
+            any.downcast_ref::<MicrofacetBSDF>()
+                .map(|microfacet| MaterialKind::Microfacet {
+                    color: to_triple(microfacet.color.0),
+                    index: microfacet.index,
+                    roughness: microfacet.roughness,
+                    distribution: microfacet.distribution,
+                    anisotropy: microfacet.anisotropy,
+                    metallic: microfacet.metallic,
+                    emittance: microfacet.emittance,
+                    transparent: microfacet.transparent,
+                    dispersion: microfacet.dispersion,
+                    absorption: describe_absorption(microfacet.absorption),
+                    texture: None,
+                    normal: None,
+                })
+        }
+    }
+}
+
+impl Default for PrincipledDescription {
+    fn default() -> Self {
+        Self::new(
+            &PrincipledBSDF::default(),
+            PrincipledTextureNames::default(),
+        )
+    }
+}
+
+impl PrincipledDescription {
+    fn new(bsdf: &PrincipledBSDF, textures: PrincipledTextureNames) -> Self {
+        Self {
+            base_color: to_triple(bsdf.base_color.0),
+            metallic: bsdf.metallic,
+            roughness: bsdf.roughness,
+            anisotropic: bsdf.anisotropic,
+            specular: bsdf.specular,
+            specular_tint: bsdf.specular_tint,
+            sheen: bsdf.sheen,
+            sheen_tint: bsdf.sheen_tint,
+            clearcoat: bsdf.clearcoat,
+            clearcoat_gloss: bsdf.clearcoat_gloss,
+            transmission: bsdf.transmission,
+            transmission_roughness: bsdf.transmission_roughness,
+            ior: bsdf.ior,
+            emission: to_triple(bsdf.emission.0),
+            textures,
+        }
+    }
+
+    fn build(
+        &self,
+        textures: &HashMap<String, TextureHandle>,
+    ) -> Result<PrincipledBSDF, SceneFileError> {
+        let texture = |name: &Option<String>| match name {
+            Some(name) => lookup(textures, name).map(Some),
+            None => Ok(None),
+        };
+        let names = &self.textures;
+
+        Ok(PrincipledBSDF {
+            base_color: to_color(self.base_color),
+            metallic: self.metallic,
+            roughness: self.roughness,
+            anisotropic: self.anisotropic,
+            specular: self.specular,
+            specular_tint: self.specular_tint,
+            sheen: self.sheen,
+            sheen_tint: self.sheen_tint,
+            clearcoat: self.clearcoat,
+            clearcoat_gloss: self.clearcoat_gloss,
+            transmission: self.transmission,
+            transmission_roughness: self.transmission_roughness,
+            ior: self.ior,
+            emission: to_color(self.emission),
+            textures: PrincipledTextures {
+                base_color: texture(&names.base_color)?,
+                metallic: texture(&names.metallic)?,
+                roughness: texture(&names.roughness)?,
+                anisotropic: texture(&names.anisotropic)?,
+                specular: texture(&names.specular)?,
+                specular_tint: texture(&names.specular_tint)?,
+                sheen: texture(&names.sheen)?,
+                sheen_tint: texture(&names.sheen_tint)?,
+                clearcoat: texture(&names.clearcoat)?,
+                clearcoat_gloss: texture(&names.clearcoat_gloss)?,
+                transmission: texture(&names.transmission)?,
+                transmission_roughness: texture(&names.transmission_roughness)?,
+                normal: texture(&names.normal)?,
+            },
+        })
+    }
+
+    fn describe(scene: &Scene, bsdf: &PrincipledBSDF) -> Self {
+        let name = |texture: Option<TextureHandle>| {
+            texture.map(|texture| scene.texture_name(texture).to_owned())
+        };
+        let textures = &bsdf.textures;
+
+        Self::new(
+            bsdf,
+            PrincipledTextureNames {
+                base_color: name(textures.base_color),
+                metallic: name(textures.metallic),
+                roughness: name(textures.roughness),
+                anisotropic: name(textures.anisotropic),
+                specular: name(textures.specular),
+                specular_tint: name(textures.specular_tint),
+                sheen: name(textures.sheen),
+                sheen_tint: name(textures.sheen_tint),
+                clearcoat: name(textures.clearcoat),
+                clearcoat_gloss: name(textures.clearcoat_gloss),
+                transmission: name(textures.transmission),
+                transmission_roughness: name(textures.transmission_roughness),
+                normal: name(textures.normal),
+            },
+        )
+    }
+}
+
+impl SurfaceKind {
+    fn describe(scene: &Scene, surface: &Surface) -> Option<Self> {
+        match surface {
+            Surface::Analytic(analytic) => {
+                let any = analytic.as_ref().as_any();
+
+                if let Some(sphere) = any.downcast_ref::<Sphere>() {
+                    Some(SurfaceKind::Sphere {
+                        center: to_triple(sphere.center),
+                        radius: sphere.radius,
+                    })
+                } else {
+                    any.downcast_ref::<GroundPlane>()
+                        .map(|plane| SurfaceKind::GroundPlane {
+                            height: plane.height,
+                        })
+                }
+            }
+            &Surface::Mesh(handle) => {
+                let mesh = scene.mesh_ref(handle);
+
+                Some(match mesh.path() {
+                    Some(path) => SurfaceKind::Mesh(path.to_owned()),
+                    None => SurfaceKind::TriangleMesh {
+                        positions: mesh.positions().map(to_triple).collect(),
+                        indices: mesh.indices(),
+                        uvs: unless_zero(mesh.uvs().map(|uv| [uv.x, uv.y]).collect()),
+                        normals: unless_zero(mesh.normals().map(to_triple).collect()),
+                        tangents: unless_zero(mesh.tangents().map(to_triple).collect()),
+                    },
+                })
+            }
+        }
+    }
+}
+
+#[test]
+fn scene_file_round_trip_test() {
+    let (scene, settings, dimensions) = load_scene("../assets/cornell.ron").unwrap();
+    assert_eq!(dimensions, [500, 500]);
+    assert_eq!(scene.objects.len(), 8);
+    assert_eq!(
+        scene.material_name(scene.objects[5].object().material),
+        "light"
+    );
+
+    let description = SceneDescription::from_scene(&scene, &settings, dimensions).unwrap();
+    let contents = ron::to_string(&description).unwrap();
+    let (reloaded, _, _) = ron::from_str::<SceneDescription>(&contents)
+        .unwrap()
+        .build()
+        .unwrap();
+    assert_eq!(reloaded.surfaces.len(), scene.surfaces.len());
+    assert_eq!(
+        reloaded.surface_name(reloaded.objects[6].object().surface),
+        "sphere"
+    );
+}
+
+#[test]
+fn triangle_mesh_round_trip_test() {
+    use crate::simple::Materials;
+
+    let vertices = vec![
+        Vertex::new(
+            vec3!(0.0, 0.0, 0.0),
+            Vec2::new(0.0, 0.0),
+            vec3!(0.0, 0.0, 1.0),
+        ),
+        Vertex::new(
+            vec3!(1.0, 0.0, 0.0),
+            Vec2::new(1.0, 0.0),
+            vec3!(0.0, 0.0, 1.0),
+        ),
+        Vertex::new(
+            vec3!(0.0, 1.0, 0.0),
+            Vec2::new(0.0, 1.0),
+            vec3!(0.0, 0.6, 0.8),
+        ),
+    ];
+    let mut scene = Scene::empty();
+    let surface = scene.add_mesh(Arc::new(Mesh::from_buffers(vertices, vec![0, 1, 2])));
+    let material = scene.add_material(Materials::lambertian(Color::new(0.5, 0.5, 0.5)));
+    scene.add_object(material, surface);
+
+    let description = SceneDescription::from_scene(&scene, &Settings::default(), [1, 1]).unwrap();
+    let contents = ron::to_string(&description).unwrap();
+    let description = ron::from_str::<SceneDescription>(&contents).unwrap();
+    let (reloaded, _, _) = description.build().unwrap();
+    let mesh = match reloaded.surfaces[0].object() {
+        Surface::Mesh(handle) => reloaded.mesh_ref(*handle),
+        Surface::Analytic(_) => panic!("mesh was reloaded as an analytic surface"),
+    };
+    assert_eq!(mesh.uvs().nth(2), Some(Vec2::new(0.0, 1.0)));
+    assert_eq!(mesh.normals().nth(2), Some(vec3!(0.0, 0.6, 0.8)));
+
+    // Names have to be unique, otherwise objects could bind to the wrong surface
+    let mut description = description;
+    description.surfaces.push(SurfaceDescription {
+        name: description.surfaces[0].name.clone(),
+        surface: SurfaceKind::GroundPlane { height: 0.0 },
+    });
+    assert!(matches!(
+        description.build(),
+        Err(SceneFileError::DuplicateName(_))
+    ));
+}
+
+#[test]
+fn render_hash_parameters_test() {
+    use voidray_renderer::render::checkpoint::render_hash;
+
+    let contents = fs::read_to_string("../assets/cornell.ron").unwrap();
+    let hash = |contents: &str| {
+        let (scene, settings, dimensions) = ron::from_str::<SceneDescription>(contents)
+            .unwrap()
+            .build()
+            .unwrap();
+        render_hash(&scene, &settings.render, dimensions)
+    };
+
+    // A checkpoint can't be resumed once a material of its scene was edited
+    let original = hash(&contents);
+    assert_eq!(hash(&contents), original);
+    assert_ne!(
+        hash(&contents.replace("strength: 15.0", "strength: 10.0")),
+        original
+    );
+    assert_ne!(
+        hash(&contents.replace("index: 1.33", "index: 1.5")),
+        original
+    );
+}
@@ -1,17 +1,24 @@
+use std::hash::Hasher;
 use std::sync::Arc;
 
 use voidray_renderer::aabb::AABB;
 use voidray_renderer::cgmath::Rad;
 use voidray_renderer::color::*;
-use voidray_renderer::math::{near_zero, reflect, refract};
+use voidray_renderer::hash::StableHasher;
+use voidray_renderer::math::{
+    local_to_world, near_zero, reflect, refract, sample_disk, sample_sphere,
+};
 use voidray_renderer::rand::rand_distr::UnitSphere;
-use voidray_renderer::rand::{Rng, ThreadRng};
+use voidray_renderer::rand::{Rng, Sampler};
 use voidray_renderer::ray::*;
 use voidray_renderer::scene::{SceneAcceleration, TextureHandle};
+use voidray_renderer::spectrum::{ComplexIor, Ior, SampledSpectrum, SampledWavelengths, LAMBDA_D};
 use voidray_renderer::texture::AbstractTexture;
 use voidray_renderer::traits::{BSDFMaterial, Material};
 use voidray_renderer::vector::*;
 
+use crate::subsurface::Subsurface;
+
 pub struct Materials {}
 
 impl Materials {
@@ -45,7 +52,33 @@ impl Materials {
     }
 
     pub fn dielectric(ir: Float) -> Arc<dyn Material> {
-        Arc::new(Dielectric { ir })
+        Arc::new(Dielectric {
+            ior: Ior::Constant(ir),
+            absorption: BLACK,
+        })
+    }
+
+    /// Colored glass or liquid which turns white light into `color` after travelling `distance`
+    /// through it
+    pub fn tinted_dielectric(ir: Float, color: Color, distance: Float) -> Arc<dyn Material> {
+        Arc::new(Dielectric {
+            ior: Ior::Constant(ir),
+            absorption: color.to_absorption(distance),
+        })
+    }
+
+    /// Dielectric whose index of refraction depends on the wavelength, which splits light into
+    /// its colors in spectral renders
+    pub fn dispersive_dielectric(ior: Ior) -> Arc<dyn Material> {
+        Arc::new(Dielectric {
+            ior,
+            absorption: BLACK,
+        })
+    }
+
+    /// Metal described by its complex index of refraction, e.g. `ComplexIor::GOLD`
+    pub fn conductor(ior: ComplexIor, fuzz: Float) -> Arc<dyn Material> {
+        Arc::new(Conductor { ior, fuzz })
     }
 
     pub fn emissive(strength: Float) -> Arc<dyn Material> {
@@ -55,28 +88,90 @@ impl Materials {
     pub fn colored_emissive(color: Color, strength: Float) -> Arc<dyn Material> {
         Arc::new(Emission::new(color, strength))
     }
+
+    /// Invisible material for the boundary of an object filled with a medium, see
+    /// `Scene::set_interior`
+    pub fn interface() -> Arc<dyn Material> {
+        Arc::new(Interface {})
+    }
+
+    /// Translucent material scattering light below its surface, light travels `mean_free_path`
+    /// on average between scattering events inside it, see `Subsurface`
+    pub fn subsurface(albedo: Color, mean_free_path: Color, ior: Float) -> Arc<dyn Material> {
+        Arc::new(Subsurface {
+            albedo: ColorType::Color(albedo),
+            mean_free_path,
+            ior,
+            g: 0.0,
+        })
+    }
+
+    pub fn subsurface_texture(
+        albedo: TextureHandle,
+        mean_free_path: Color,
+        ior: Float,
+    ) -> Arc<dyn Material> {
+        Arc::new(Subsurface {
+            albedo: ColorType::Texture(albedo),
+            mean_free_path,
+            ior,
+            g: 0.0,
+        })
+    }
 }
 
 pub struct LambertianBSDF {
-    albedo: Color,
+    pub(crate) albedo: Color,
 }
 
 impl BSDFMaterial for LambertianBSDF {
-    fn bsdf(&self, normal: &Vec3, to_viewer: &Vec3, to_incident: &Vec3) -> Color {
+    fn bsdf(&self, normal: &Vec3, tangent: &Vec3, to_viewer: &Vec3, to_incident: &Vec3) -> Color {
+        if normal.dot(*to_viewer).is_sign_positive() != normal.dot(*to_incident).is_sign_positive()
+        {
+            // No transmission through the surface
+            return BLACK;
+        }
+
         self.albedo / PI
     }
 
     fn sample(
         &self,
         normal: &Vec3,
+        tangent: &Vec3,
         to_viewer: &Vec3,
-        rng: &mut ThreadRng,
+        rng: &mut dyn Sampler,
     ) -> Option<(Vec3, Float)> {
         assert!(normal.magnitude2() != 0.0);
-        loop {
-            let dir = Vec3::from(rng.sample(UnitSphere));
-            return Some((dir.normalize(), 1.0));
+
+        // Cosine-sample the hemisphere on the viewer's side using Malley's method
+        let normal = if normal.dot(*to_viewer) < 0.0 {
+            -normal
+        } else {
+            *normal
+        };
+        let [x, y] = sample_disk(rng.get_2d());
+        let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+        let dir = local_to_world(normal) * Vec3::new(x, y, z);
+
+        Some((dir, z / PI)).filter(|(_, pdf)| *pdf > 0.0)
+    }
+
+    fn pdf(&self, normal: &Vec3, tangent: &Vec3, to_viewer: &Vec3, to_incident: &Vec3) -> Float {
+        if normal.dot(*to_viewer).is_sign_positive() != normal.dot(*to_incident).is_sign_positive()
+        {
+            return 0.0;
         }
+
+        normal.dot(*to_incident).abs() / PI
+    }
+
+    fn base_color(&self) -> Color {
+        self.albedo
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_color(self.albedo);
     }
 }
 
@@ -85,9 +180,40 @@ pub enum ColorType {
     Texture(TextureHandle),
 }
 
+impl ColorType {
+    pub(crate) fn hash_parameters(&self, hasher: &mut StableHasher) {
+        match self {
+            ColorType::Color(color) => {
+                hasher.write_color(*color);
+                hasher.write_texture(None);
+            }
+            ColorType::Texture(texture) => hasher.write_texture(Some(*texture)),
+        }
+    }
+}
+
+/// Feed the coefficients of an index of refraction into a hasher, including its kind
+pub(crate) fn hash_ior(ior: &Ior, hasher: &mut StableHasher) {
+    match ior {
+        Ior::Constant(n) => {
+            hasher.write_u8(0);
+            hasher.write_floats(&[*n]);
+        }
+        Ior::Cauchy { a, b } => {
+            hasher.write_u8(1);
+            hasher.write_floats(&[*a, *b]);
+        }
+        Ior::Sellmeier { b, c } => {
+            hasher.write_u8(2);
+            hasher.write_floats(b);
+            hasher.write_floats(c);
+        }
+    }
+}
+
 pub struct Lambertian {
-    albedo: ColorType,
-    normal: Option<TextureHandle>,
+    pub(crate) albedo: ColorType,
+    pub(crate) normal: Option<TextureHandle>,
 }
 
 impl Lambertian {
@@ -105,15 +231,11 @@ impl Material for Lambertian {
         scene: &SceneAcceleration,
         ray: &Ray,
         hit: &HitRecord,
-        rng: &mut ThreadRng,
+        rng: &mut dyn Sampler,
     ) -> (Color, Option<Ray>) {
-        let normal = if let Some(normal_map) = self.normal {
-            scene.texture_ref(normal_map).sample(hit.uv.x, hit.uv.y).0
-        } else {
-            hit.normal
-        };
+        let normal = self.shading_normal(scene, hit);
 
-        let mut scatter_direction = normal + Vec3::from(rng.sample(UnitSphere));
+        let mut scatter_direction = normal + sample_sphere(rng.get_2d());
 
         if near_zero(scatter_direction) {
             // Catch degenerate scatter direction
@@ -121,20 +243,51 @@ impl Material for Lambertian {
         }
 
         let scattered = Ray::new(hit.point, scatter_direction);
+        (self.albedo(scene, hit), Some(scattered))
+    }
+
+    fn eval(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+    ) -> Option<(Color, Float)> {
+        // Scattering samples the cosine distribution around the shading normal
+        let cosine = self
+            .shading_normal(scene, hit)
+            .normalize()
+            .dot(*to_incident)
+            .max(0.0);
+
+        Some((self.albedo(scene, hit) * (cosine / PI), cosine / PI))
+    }
+
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
         match self.albedo {
-            ColorType::Color(albedo) => (albedo, Some(scattered)),
-            ColorType::Texture(texture) => (
-                scene.texture_ref(texture).sample(hit.uv.x, hit.uv.y),
-                // Color::new(u, v, 0.0),
-                Some(scattered),
-            ),
+            ColorType::Color(albedo) => albedo,
+            ColorType::Texture(texture) => scene.texture_ref(texture).sample(hit.uv.x, hit.uv.y),
+        }
+    }
+
+    /// Taken from the normal map if there is one
+    fn shading_normal(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Vec3 {
+        if let Some(normal_map) = self.normal {
+            scene.texture_ref(normal_map).sample(hit.uv.x, hit.uv.y).0
+        } else {
+            hit.normal
         }
     }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        self.albedo.hash_parameters(hasher);
+        hasher.write_texture(self.normal);
+    }
 }
 
 pub struct Metal {
-    albedo: Color,
-    fuzz: Float,
+    pub(crate) albedo: Color,
+    pub(crate) fuzz: Float,
 }
 
 impl Material for Metal {
@@ -143,25 +296,107 @@ impl Material for Metal {
         scene: &SceneAcceleration,
         ray: &Ray,
         hit: &HitRecord,
-        rng: &mut ThreadRng,
+        rng: &mut dyn Sampler,
     ) -> (Color, Option<Ray>) {
-        let reflected = reflect(ray.direction, hit.normal).normalize();
+        (
+            self.albedo,
+            Some(fuzzy_reflection(ray, hit, self.fuzz, rng)),
+        )
+    }
 
-        loop {
-            let scattered = Ray::new(
-                hit.point,
-                reflected + self.fuzz * Vec3::from(rng.sample(UnitSphere)),
-            );
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
+        self.albedo
+    }
 
-            if scattered.direction.dot(hit.normal) > 0.0 {
-                return (self.albedo, Some(scattered));
-            }
+    fn diffuse_fraction(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Float {
+        0.0
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_color(self.albedo);
+        hasher.write_floats(&[self.fuzz]);
+    }
+}
+/// Mirror reflection of a ray, randomly perturbed by up to `fuzz`
+fn fuzzy_reflection(ray: &Ray, hit: &HitRecord, fuzz: Float, rng: &mut dyn Sampler) -> Ray {
+    let reflected = reflect(ray.direction, hit.normal).normalize();
+
+    loop {
+        let scattered = Ray::new(
+            hit.point,
+            reflected + fuzz * Vec3::from(rng.sample(UnitSphere)),
+        );
+
+        if scattered.direction.dot(hit.normal) > 0.0 {
+            return scattered;
         }
     }
 }
+
+/// Metal whose reflectance follows the Fresnel equations for its complex index of refraction,
+/// in spectral renders the reflectance is evaluated per wavelength
+pub struct Conductor {
+    pub(crate) ior: ComplexIor,
+    pub(crate) fuzz: Float,
+}
+
+impl Conductor {
+    /// Cosine of the angle between the incoming ray and the normal
+    fn cosine(ray: &Ray, hit: &HitRecord) -> Float {
+        -ray.direction.normalize().dot(hit.normal)
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        rng: &mut dyn Sampler,
+    ) -> (Color, Option<Ray>) {
+        let reflectance = self.ior.rgb_reflectance(Conductor::cosine(ray, hit));
+        (
+            reflectance,
+            Some(fuzzy_reflection(ray, hit, self.fuzz, rng)),
+        )
+    }
+
+    fn scatter_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelengths: &SampledWavelengths,
+        rng: &mut dyn Sampler,
+    ) -> (SampledSpectrum, Option<Ray>) {
+        let cosine = Conductor::cosine(ray, hit);
+        let reflectance = wavelengths
+            .lambda()
+            .map(|lambda| self.ior.reflectance(cosine, lambda));
+        (
+            SampledSpectrum(reflectance),
+            Some(fuzzy_reflection(ray, hit, self.fuzz, rng)),
+        )
+    }
+
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
+        self.ior.rgb_reflectance(1.0)
+    }
+
+    fn diffuse_fraction(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Float {
+        0.0
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_floats(&self.ior.eta);
+        hasher.write_floats(&self.ior.k);
+        hasher.write_floats(&[self.fuzz]);
+    }
+}
 //
 pub struct Emission {
-    color: Color,
+    pub(crate) color: Color,
 }
 
 impl Emission {
@@ -178,14 +413,42 @@ impl Material for Emission {
         scene: &SceneAcceleration,
         ray: &Ray,
         hit: &HitRecord,
-        rng: &mut ThreadRng,
+        rng: &mut dyn Sampler,
+    ) -> (Color, Option<Ray>) {
+        (BLACK, None)
+    }
+
+    fn emission(&self) -> Color {
+        self.color
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_color(self.color);
+    }
+}
+//
+pub struct Interface {}
+
+impl Material for Interface {
+    fn scatter(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        rng: &mut dyn Sampler,
     ) -> (Color, Option<Ray>) {
-        (self.color, None)
+        (WHITE, Some(Ray::new(hit.point, ray.direction)))
+    }
+
+    fn is_interface(&self) -> bool {
+        true
     }
 }
 //
 pub struct Dielectric {
-    ir: Float,
+    pub(crate) ior: Ior,
+    /// Absorption coefficient of the interior
+    pub(crate) absorption: Color,
 }
 
 impl Dielectric {
@@ -195,21 +458,10 @@ impl Dielectric {
         r0 = r0 * r0;
         r0 + (1.0 - r0) * Float::powi(1.0 - cosine, 5)
     }
-}
 
-impl Material for Dielectric {
-    fn scatter(
-        &self,
-        scene: &SceneAcceleration,
-        ray: &Ray,
-        hit: &HitRecord,
-        rng: &mut ThreadRng,
-    ) -> (Color, Option<Ray>) {
-        let refraction_ratio = if hit.front_face {
-            1.0 / self.ir
-        } else {
-            self.ir
-        };
+    /// Reflect or refract a ray, for an index of refraction `ir`
+    fn scatter_with_index(ir: Float, ray: &Ray, hit: &HitRecord, rng: &mut dyn Sampler) -> Ray {
+        let refraction_ratio = if hit.front_face { 1.0 / ir } else { ir };
 
         let unit_direction = ray.direction.normalize();
         let cos_theta = Float::min(hit.normal.dot(-unit_direction), 1.0);
@@ -217,18 +469,64 @@ impl Material for Dielectric {
 
         let cannot_refract: bool = (refraction_ratio * sin_theta) > 1.0;
         let direction = if cannot_refract
-            || Dielectric::reflectance(cos_theta, refraction_ratio) > rng.gen_range(0.0..1.0)
+            || Dielectric::reflectance(cos_theta, refraction_ratio) > rng.get_1d()
         {
             reflect(unit_direction, hit.normal)
         } else {
             refract(unit_direction, hit.normal, refraction_ratio)
         };
 
+        Ray::new(hit.point, direction)
+    }
+}
+
+impl Material for Dielectric {
+    /// Dispersive dielectrics use their index of refraction at the sodium D line
+    fn scatter(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        rng: &mut dyn Sampler,
+    ) -> (Color, Option<Ray>) {
+        let ir = self.ior.at(LAMBDA_D);
+        (
+            WHITE,
+            Some(Dielectric::scatter_with_index(ir, ray, hit, rng)),
+        )
+    }
+
+    fn absorption(&self) -> Color {
+        self.absorption
+    }
+
+    fn dispersive(&self) -> bool {
+        self.ior.is_dispersive()
+    }
+
+    fn scatter_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelengths: &SampledWavelengths,
+        rng: &mut dyn Sampler,
+    ) -> (SampledSpectrum, Option<Ray>) {
+        let ir = self.ior.at(wavelengths.hero());
         (
-            Color::new(1.0, 1.0, 1.0),
-            Some(Ray::new(hit.point, direction)),
+            SampledSpectrum::splat(1.0),
+            Some(Dielectric::scatter_with_index(ir, ray, hit, rng)),
         )
     }
+
+    fn diffuse_fraction(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Float {
+        0.0
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hash_ior(&self.ior, hasher);
+        hasher.write_color(self.absorption);
+    }
 }
 //
 // pub struct MixMaterial {
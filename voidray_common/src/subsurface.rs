@@ -0,0 +1,215 @@
+use voidray_renderer::color::*;
+use voidray_renderer::hash::StableHasher;
+use voidray_renderer::math::{near_zero, reflect, sample_sphere};
+use voidray_renderer::preamble::*;
+use voidray_renderer::rand::*;
+use voidray_renderer::ray::*;
+use voidray_renderer::scene::SceneAcceleration;
+use voidray_renderer::texture::AbstractTexture;
+use voidray_renderer::traits::{Material, Medium};
+
+use crate::media::HomogeneousMedium;
+use crate::simple::ColorType;
+
+/// Maximum number of scattering events of a random walk, light which hasn't left the object by
+/// then is considered absorbed
+const MAX_STEPS: u32 = 256;
+
+/// Translucent material which scatters light below its surface, e.g. skin, wax or marble
+///
+/// Light which isn't reflected by the specular coating enters the object and performs a random
+/// walk through a homogeneous medium filling it, until it reaches the surface again. The walk
+/// finds the surface through the hit queries of the scene, so meshes with this material have to
+/// be closed.
+pub struct Subsurface {
+    /// Color of the material where it is thick enough that no light passes through
+    pub(crate) albedo: ColorType,
+    /// Average distance light travels between scattering events, per channel
+    pub(crate) mean_free_path: Color,
+    /// Index of refraction of the specular coating
+    pub(crate) ior: Float,
+    /// Asymmetry of the phase function inside the material
+    pub(crate) g: Float,
+}
+
+impl Subsurface {
+    /// Medium inside the object at a point where light entered it
+    ///
+    /// The single scattering albedo of the medium is chosen so that the multiple scattering
+    /// albedo of a semi-infinite slab matches `albedo`, using the fit from Chiang et al.,
+    /// "Practical and Controllable Subsurface Scattering for Production Path Tracing".
+    fn medium(&self, albedo: Color) -> HomogeneousMedium {
+        let single_scattering = |a: Float| {
+            let a = a.clamp(0.0, 1.0);
+            1.0 - (a * (-5.09406 + a * (2.61188 - a * 4.31805))).exp()
+        };
+        let sigma_t = |mean_free_path: Float| 1.0 / mean_free_path.max(1.0e-6);
+
+        let sigma_t = Color::new(
+            sigma_t(self.mean_free_path.r()),
+            sigma_t(self.mean_free_path.g()),
+            sigma_t(self.mean_free_path.b()),
+        );
+        let sigma_s = sigma_t
+            * Color::new(
+                single_scattering(albedo.r()),
+                single_scattering(albedo.g()),
+                single_scattering(albedo.b()),
+            );
+
+        HomogeneousMedium {
+            sigma_a: Color(sigma_t.0 - sigma_s.0),
+            sigma_s,
+            g: self.g,
+        }
+    }
+
+    /// Follow light through the medium from where it entered the object, returns the weight of
+    /// the walk and its last segment, which ends where the light reaches the surface
+    fn random_walk(
+        scene: &SceneAcceleration,
+        medium: &HomogeneousMedium,
+        mut ray: Ray,
+        rng: &mut dyn Sampler,
+    ) -> (Color, Option<Ray>) {
+        let phase = medium.phase();
+        let mut throughput = WHITE;
+
+        for _ in 0..MAX_STEPS {
+            let boundary = match scene.hit(&ray) {
+                Some((hit, _)) => hit.t,
+                // The walk escaped through a hole in the mesh
+                None => break,
+            };
+
+            let (weight, scattering) = medium.sample(&ray, boundary, rng);
+            throughput = throughput * weight;
+            let t = match scattering {
+                Some(t) => t,
+                None => return (throughput, Some(ray)),
+            };
+
+            let survival = throughput.max_component().min(1.0);
+            if survival <= 0.0 || rng.get_1d() >= survival {
+                break;
+            }
+            throughput *= 1.0 / survival;
+
+            let (direction, _) = phase.sample(&-ray.direction, rng.get_2d());
+            ray = Ray::new(ray.at(t), direction);
+        }
+
+        (BLACK, None)
+    }
+}
+
+/// Cosine distributed direction around a normal
+fn cosine_direction(normal: Vec3, rng: &mut dyn Sampler) -> Vec3 {
+    let direction = normal + sample_sphere(rng.get_2d());
+    if near_zero(direction) {
+        normal
+    } else {
+        direction
+    }
+}
+
+impl Material for Subsurface {
+    /// Front faces start a random walk, whose last segment is returned so that the walk leaves
+    /// the object where the tracer hits the back face it ends on, from which light exits
+    /// diffusely
+    fn scatter(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        rng: &mut dyn Sampler,
+    ) -> (Color, Option<Ray>) {
+        if !hit.front_face {
+            let direction = cosine_direction(-hit.normal, rng);
+            return (WHITE, Some(Ray::new(hit.point, direction)));
+        }
+
+        // Schlick's approximation for the reflectance of the coating
+        let cosine = hit.normal.dot(-ray.direction.normalize()).clamp(0.0, 1.0);
+        let r0 = ((1.0 - self.ior) / (1.0 + self.ior)).powi(2);
+        if r0 + (1.0 - r0) * (1.0 - cosine).powi(5) > rng.get_1d() {
+            let direction = reflect(ray.direction.normalize(), hit.normal);
+            return (WHITE, Some(Ray::new(hit.point, direction)));
+        }
+
+        let medium = self.medium(self.albedo(scene, hit));
+        let entered = Ray::new(hit.point, cosine_direction(-hit.normal, rng));
+        Subsurface::random_walk(scene, &medium, entered, rng)
+    }
+
+    /// Only light exiting the object can be evaluated, light arriving at the front face is
+    /// transported below the surface by the walk
+    fn eval(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+    ) -> Option<(Color, Float)> {
+        if hit.front_face {
+            return None;
+        }
+
+        let cosine = (-hit.normal).dot(to_incident.normalize()).max(0.0);
+        Some((GRAY(cosine / PI), cosine / PI))
+    }
+
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
+        match self.albedo {
+            ColorType::Color(albedo) => albedo,
+            ColorType::Texture(texture) => scene.texture_ref(texture).sample(hit.uv.x, hit.uv.y),
+        }
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        self.albedo.hash_parameters(hasher);
+        hasher.write_color(self.mean_free_path);
+        hasher.write_floats(&[self.ior, self.g]);
+    }
+}
+
+#[test]
+fn random_walk_test() {
+    use voidray_renderer::scene::{Accelerable, Scene};
+
+    use crate::simple::Materials;
+    use crate::Surfaces;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let material = Subsurface {
+        albedo: ColorType::Color(Color::new(0.8, 0.5, 0.1)),
+        mean_free_path: GRAY(0.05),
+        ior: 1.0,
+        g: 0.0,
+    };
+
+    let mut scene = Scene::empty();
+    let sphere = scene.add_analytic_surface(Surfaces::sphere(vec3!(0.0, 0.0, 0.0), 1.0));
+    let lambertian = scene.add_material(Materials::lambertian(WHITE));
+    scene.add_object(lambertian, sphere);
+    let scene = scene.build_acceleration();
+
+    // Walks through a thick object come back out near where they entered, colored like the
+    // albedo
+    let ray = Ray::new(vec3!(0.0, 0.0, -3.0), vec3!(0.0, 0.0, 1.0));
+    let (hit, _) = scene.hit(&ray).unwrap();
+    let samples = 20_000;
+    let mut reflected = BLACK;
+    for _ in 0..samples {
+        if let (weight, Some(exit)) = material.scatter(&scene, &ray, &hit, &mut rng) {
+            let (exit, _) = scene.hit(&exit).unwrap();
+            assert!(!exit.front_face);
+            assert!((exit.point - hit.point).magnitude() < 0.5);
+            reflected += weight;
+        }
+    }
+    let reflected = reflected * (1.0 / samples as Float);
+    assert!((reflected.r() - 0.8).abs() < 0.05, "{:?}", reflected.0);
+    assert!((reflected.g() - 0.5).abs() < 0.05, "{:?}", reflected.0);
+    assert!((reflected.b() - 0.1).abs() < 0.05, "{:?}", reflected.0);
+}
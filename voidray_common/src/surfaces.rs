@@ -1,6 +1,8 @@
 use voidray_renderer::aabb::*;
+use voidray_renderer::math::sample_sphere;
 use voidray_renderer::mesh::{Mesh, Vertex};
 use voidray_renderer::preamble::*;
+use voidray_renderer::rand::*;
 use voidray_renderer::ray::*;
 use voidray_renderer::traits::*;
 
@@ -77,6 +79,15 @@ impl AnalyticSurface for Sphere {
             ray,
         ))
     }
+
+    fn area(&self) -> Option<Float> {
+        Some(4.0 * PI * self.radius * self.radius)
+    }
+
+    fn sample_point(&self, rng: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let normal = sample_sphere(rng.get_2d());
+        Some((self.center + normal * self.radius, normal))
+    }
 }
 
 pub struct GroundPlane {
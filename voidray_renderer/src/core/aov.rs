@@ -0,0 +1,121 @@
+use crate::color::{Color, BLACK};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Arbitrary output variables, written by the tracer in the same pass as the beauty image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Aov {
+    /// Surface color at the first hit
+    Albedo,
+    /// Shading normal at the first hit
+    Normal,
+    /// Distance from the camera to the first hit
+    Depth,
+    /// World space position of the first hit
+    Position,
+    Uv,
+    /// Index of the object at the first hit plus one, so that misses are 0. Ids can't be
+    /// blended, each pixel keeps the id of its first sample instead of being filtered.
+    ObjectId,
+    /// Index of the material at the first hit plus one, so that misses are 0
+    MaterialId,
+    /// Light reaching the camera after a single bounce, split by the diffuse fraction of the
+    /// first hit
+    DiffuseDirect,
+    /// Light reaching the camera after two or more bounces
+    DiffuseIndirect,
+    SpecularDirect,
+    SpecularIndirect,
+    /// Emission of surfaces seen directly by the camera
+    Emission,
+    /// Number of samples taken in each pixel, not filtered
+    SampleCount,
+}
+
+impl Aov {
+    pub const COUNT: usize = 13;
+
+    pub const ALL: [Aov; Aov::COUNT] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Uv,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::DiffuseDirect,
+        Aov::DiffuseIndirect,
+        Aov::SpecularDirect,
+        Aov::SpecularIndirect,
+        Aov::Emission,
+        Aov::SampleCount,
+    ];
+
+    /// Layer name used in multi-layer EXR files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Uv => "uv",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::DiffuseDirect => "diffuse_direct",
+            Aov::DiffuseIndirect => "diffuse_indirect",
+            Aov::SpecularDirect => "specular_direct",
+            Aov::SpecularIndirect => "specular_indirect",
+            Aov::Emission => "emission",
+            Aov::SampleCount => "sample_count",
+        }
+    }
+
+    /// Whether samples are splatted through the reconstruction filter, ids and sample counts
+    /// are written per pixel instead
+    pub fn is_filtered(&self) -> bool {
+        !matches!(self, Aov::ObjectId | Aov::MaterialId | Aov::SampleCount)
+    }
+
+    /// Inverse of `name`
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.into_iter().find(|aov| aov.name() == name)
+    }
+
+    /// Names of the channels of the AOV, stored in the leading rgb components of a pixel
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::Uv => &["U", "V"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::SampleCount => &["count"],
+            _ => &["R", "G", "B"],
+        }
+    }
+}
+
+/// AOV values of a single camera path
+#[derive(Clone, Copy)]
+pub struct AovSample([Color; Aov::COUNT]);
+
+impl AovSample {
+    pub fn get(&self, aov: Aov) -> Color {
+        self.0[aov as usize]
+    }
+
+    pub fn set(&mut self, aov: Aov, value: Color) {
+        self.0[aov as usize] = value;
+    }
+
+    pub fn add(&mut self, aov: Aov, value: Color) {
+        self.0[aov as usize] += value;
+    }
+}
+
+impl Default for AovSample {
+    fn default() -> Self {
+        Self([BLACK; Aov::COUNT])
+    }
+}
@@ -1,11 +1,12 @@
 use crate::aabb::*;
 use crate::ray::*;
+use crate::vec3;
 use crate::vector::*;
 
 /// Acceleration strcture for faster ray-scene intersections
 pub struct BvhTree<'s, S> {
     scene: &'s S,
-    root: BvhNode,
+    root: Bvh,
 }
 
 pub trait BoundsCollection: Sync {
@@ -22,7 +23,7 @@ where
     pub fn build(scene: &'s S) -> Self {
         Self {
             scene,
-            root: BvhNode::from_list(&mut scene.objects(), scene),
+            root: Bvh::build(scene.objects(), scene),
         }
     }
 
@@ -31,104 +32,230 @@ where
     }
 }
 
-pub enum Axis {
-    X,
-    Y,
-    Z,
+/// Number of buckets used to evaluate split candidates along an axis
+const SAH_BINS: usize = 12;
+
+/// Nodes with at most this many objects are never split
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Relative cost of traversing a node versus intersecting an object
+const TRAVERSAL_COST: Float = 0.5;
+
+/// A node in the flattened tree. Interior nodes store their first child directly after
+/// themselves, so only the index of the second child is needed.
+#[derive(Clone)]
+struct BvhNode {
+    bounds: AABB,
+    /// Leaf: index of the first object, interior: index of the second child
+    offset: usize,
+    /// Number of objects in a leaf, zero for interior nodes
+    count: usize,
+    /// Axis the node was split along
+    axis: usize,
 }
 
-#[derive(PartialEq)]
-pub enum BvhNode {
-    None,
-    Object(usize),
-    Split(AABB, Box<BvhNode>, Box<BvhNode>),
+/// Bounding volume hierarchy built with a binned surface area heuristic, stored as a flat
+/// array of nodes in depth-first order
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    /// Object handles, leaves reference contiguous ranges of this list
+    objects: Vec<usize>,
+    /// Objects without finite bounds (e.g. ground planes), which are tested against every ray
+    unbounded: Vec<usize>,
 }
 
-impl BvhNode {
-    pub fn from_list<S>(objects: &mut Vec<usize>, scene: &S) -> Self
+/// Bounds and centroid of an object, computed once before building
+struct BuildObject {
+    handle: usize,
+    bounds: AABB,
+    centroid: Vec3,
+}
+
+impl Bvh {
+    pub fn empty() -> Self {
+        Self {
+            nodes: Vec::new(),
+            objects: Vec::new(),
+            unbounded: Vec::new(),
+        }
+    }
+
+    pub fn build<S>(objects: Vec<usize>, scene: &S) -> Self
     where
         S: BoundsCollection,
     {
-        match objects.len() {
-            0 => BvhNode::None,
-            1 => BvhNode::Object(objects[0]),
-            _ => {
-                // Calculate bounds for the whole node
-                let bounds = objects
-                    .iter()
-                    .map(|object| scene.bounds_ref(*object))
-                    .reduce(AABB::surround)
-                    .unwrap_or_default();
-
-                // See which axis has the greatest variance among centroids
-                let centroids = objects
-                    .iter()
-                    .map(|object| AABB::from_point(scene.bounds_ref(*object).centroid()))
-                    .reduce(AABB::surround)
-                    .unwrap_or_default();
-
-                let spread = centroids.max - centroids.min;
-                let axis = if spread.x > spread.y && spread.x > spread.z {
-                    Axis::X
-                } else if spread.y > spread.x && spread.y > spread.z {
-                    Axis::Y
-                } else {
-                    Axis::Z
-                };
-
-                // Sort objects by laying bounding boxes along an axis
-                match axis {
-                    Axis::X => {
-                        objects.sort_by(|a, b| {
-                            scene
-                                .bounds_ref(*a)
-                                .centroid()
-                                .x
-                                .total_cmp(&scene.bounds_ref(*b).centroid().x)
-                        });
-                    }
-                    Axis::Y => {
-                        objects.sort_by(|a, b| {
-                            scene
-                                .bounds_ref(*a)
-                                .centroid()
-                                .y
-                                .total_cmp(&scene.bounds_ref(*b).centroid().y)
-                        });
-                    }
-                    Axis::Z => {
-                        objects.sort_by(|a, b| {
-                            scene
-                                .bounds_ref(*a)
-                                .centroid()
-                                .z
-                                .total_cmp(&scene.bounds_ref(*b).centroid().z)
-                        });
-                    }
-                }
+        // Unbounded objects have no meaningful centroid or surface area, so they are kept out
+        // of the tree
+        let mut result = Self::empty();
+        let mut build_objects = Vec::new();
+        for handle in objects {
+            let bounds = scene.bounds_ref(handle);
+            if bounds.is_finite() {
+                // Flat objects such as axis aligned quads have boxes without thickness, which
+                // the slab test would never report as hit
+                let bounds = AABB::epsilon_expand(bounds, 0.001);
+                build_objects.push(BuildObject {
+                    handle,
+                    centroid: bounds.centroid(),
+                    bounds,
+                });
+            } else {
+                result.unbounded.push(handle);
+            }
+        }
 
-                // Assign first half to left half and second half to right half
-                let mut left_list = Vec::new();
-                let mut right_list = Vec::new();
+        if !build_objects.is_empty() {
+            result.nodes.reserve(2 * build_objects.len());
+            result.build_recursive(&mut build_objects, 0);
+        }
+        result.objects = build_objects.iter().map(|object| object.handle).collect();
+        result
+    }
 
-                for (index, element) in objects.iter().enumerate() {
-                    if index < objects.len() / 2 {
-                        left_list.push(*element);
-                    } else {
-                        right_list.push(*element);
-                    }
-                }
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.unbounded.is_empty()
+    }
 
-                // Create the node
-                BvhNode::Split(
-                    bounds,
-                    Box::new(BvhNode::from_list(&mut left_list, scene)),
-                    Box::new(BvhNode::from_list(&mut right_list, scene)),
-                )
+    /// Builds the subtree for `objects`, which starts at `offset` in the final object list
+    fn build_recursive(&mut self, objects: &mut [BuildObject], offset: usize) {
+        let bounds = objects
+            .iter()
+            .map(|object| object.bounds.clone())
+            .reduce(AABB::surround)
+            .unwrap_or_default();
+
+        let node_index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: bounds.clone(),
+            offset,
+            count: objects.len(),
+            axis: 0,
+        });
+
+        if objects.len() <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        // Split along the axis with the greatest spread among centroids
+        let centroids = objects
+            .iter()
+            .map(|object| AABB::from_point(object.centroid))
+            .reduce(AABB::surround)
+            .unwrap_or_default();
+
+        let spread = centroids.max - centroids.min;
+        let axis = if spread.x > spread.y && spread.x > spread.z {
+            0
+        } else if spread.y > spread.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = match Self::sah_split(objects, &bounds, &centroids, axis) {
+            Some(mid) => mid,
+            // Splitting is more expensive than intersecting everything
+            None if objects.len() <= 4 * MAX_LEAF_SIZE => return,
+            // Degenerate case (e.g. identical centroids), split in half
+            None => {
+                let mid = objects.len() / 2;
+                objects.select_nth_unstable_by(mid, |a, b| {
+                    a.centroid[axis].total_cmp(&b.centroid[axis])
+                });
+                mid
+            }
+        };
+
+        let (left, right) = objects.split_at_mut(mid);
+        self.build_recursive(left, offset);
+        let second_child = self.nodes.len();
+        self.build_recursive(right, offset + mid);
+
+        let node = &mut self.nodes[node_index];
+        node.offset = second_child;
+        node.count = 0;
+        node.axis = axis;
+    }
+
+    /// Partition objects using the cheapest binned SAH split along the axis, returns the
+    /// number of objects in the left half or `None` if no split is worth it
+    fn sah_split(
+        objects: &mut [BuildObject],
+        bounds: &AABB,
+        centroids: &AABB,
+        axis: usize,
+    ) -> Option<usize> {
+        let min = centroids.min[axis];
+        let extent = centroids.max[axis] - min;
+        if extent <= 0.0 {
+            return None;
+        }
+
+        let bin_of = |object: &BuildObject| {
+            let bin = (SAH_BINS as Float * (object.centroid[axis] - min) / extent) as usize;
+            bin.min(SAH_BINS - 1)
+        };
+
+        let mut bin_bounds: Vec<AABB> = vec![AABB::default(); SAH_BINS];
+        let mut bin_counts = [0usize; SAH_BINS];
+        for object in objects.iter() {
+            let bin = bin_of(object);
+            bin_counts[bin] += 1;
+            bin_bounds[bin] = AABB::surround(bin_bounds[bin].clone(), object.bounds.clone());
+        }
+
+        // Sweep from the right to get the area of every right half
+        let mut right_area = [0.0; SAH_BINS];
+        let mut right_count = [0usize; SAH_BINS];
+        let mut right_bounds = AABB::default();
+        let mut count = 0;
+        for bin in (1..SAH_BINS).rev() {
+            right_bounds = AABB::surround(right_bounds, bin_bounds[bin].clone());
+            count += bin_counts[bin];
+            right_area[bin] = surface_area(&right_bounds);
+            right_count[bin] = count;
+        }
+
+        // Sweep from the left, evaluating a split after each bin. The cost of a leaf is the
+        // number of objects.
+        let area = surface_area(bounds);
+        let mut best = None;
+        let mut best_cost = objects.len() as Float;
+        let mut left_bounds = AABB::default();
+        let mut count = 0;
+        for bin in 0..SAH_BINS - 1 {
+            left_bounds = AABB::surround(left_bounds, bin_bounds[bin].clone());
+            count += bin_counts[bin];
+
+            if count == 0 || right_count[bin + 1] == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                + (count as Float * surface_area(&left_bounds)
+                    + right_count[bin + 1] as Float * right_area[bin + 1])
+                    / area;
+
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some(bin);
+            }
+        }
+
+        let split_bin = best?;
+        let mut mid = 0;
+        for index in 0..objects.len() {
+            if bin_of(&objects[index]) <= split_bin {
+                objects.swap(index, mid);
+                mid += 1;
             }
         }
+
+        Some(mid)
     }
 
+    /// Closest hit along the ray, children are visited front to back and `t_max` shrinks as
+    /// hits are found so that farther subtrees can be skipped
     pub fn hit<S>(
         &self,
         ray: &Ray,
@@ -139,43 +266,232 @@ impl BvhNode {
     where
         S: BoundsCollection,
     {
-        match self {
-            BvhNode::Object(handle) => {
-                return scene
-                    .hit(*handle, ray, t_min, t_max)
-                    .map(|hit| (hit, *handle));
+        let mut result = None;
+        let mut closest = t_max;
+
+        for &handle in &self.unbounded {
+            if let Some(hit) = scene.hit(handle, ray, t_min, closest) {
+                if hit.t < closest {
+                    closest = hit.t;
+                    result = Some((hit, handle));
+                }
             }
-            BvhNode::Split(bounds, left, right) => {
-                if bounds.hit(ray, t_min, t_max) {
-                    let hit_left = left.hit(ray, t_min, t_max, scene);
-                    let hit_right = right.hit(ray, t_min, t_max, scene);
+        }
+
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let inv_direction = vec3!(
+            1.0 / ray.direction.x,
+            1.0 / ray.direction.y,
+            1.0 / ray.direction.z
+        );
+        let direction_negative = [
+            inv_direction.x < 0.0,
+            inv_direction.y < 0.0,
+            inv_direction.z < 0.0,
+        ];
 
-                    return merge_optionals(hit_left, hit_right);
+        let mut stack = Vec::with_capacity(64);
+        let mut current = 0;
+
+        loop {
+            let node = &self.nodes[current];
+
+            if hit_bounds(&node.bounds, ray, inv_direction, t_min, closest) {
+                if node.count > 0 {
+                    for &handle in &self.objects[node.offset..node.offset + node.count] {
+                        if let Some(hit) = scene.hit(handle, ray, t_min, closest) {
+                            if hit.t < closest {
+                                closest = hit.t;
+                                result = Some((hit, handle));
+                            }
+                        }
+                    }
+                } else if direction_negative[node.axis] {
+                    // Visit the second (farther) child first
+                    stack.push(current + 1);
+                    current = node.offset;
+                    continue;
+                } else {
+                    stack.push(node.offset);
+                    current += 1;
+                    continue;
                 }
             }
-            _ => {}
+
+            match stack.pop() {
+                Some(next) => current = next,
+                None => break,
+            }
         }
 
-        None
+        result
     }
 }
 
-/// Commonly used to merge hit record results in a Bvh tree
 #[inline]
-fn merge_optionals<H>(hit_left: Option<H>, hit_right: Option<H>) -> Option<H>
-where
-    H: PartialOrd,
-{
-    match (hit_left, hit_right) {
-        (Some(record_left), Some(record_right)) => {
-            if record_left < record_right {
-                Some(record_left)
-            } else {
-                Some(record_right)
+fn surface_area(bounds: &AABB) -> Float {
+    let d = bounds.dimensions();
+    if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+        return 0.0;
+    }
+    2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+}
+
+/// Slab test using a precomputed inverse direction
+#[inline]
+fn hit_bounds(bounds: &AABB, ray: &Ray, inv_direction: Vec3, t_min: Float, t_max: Float) -> bool {
+    let mut t_min = t_min;
+    let mut t_max = t_max;
+
+    for axis in 0..3 {
+        let mut t0 = (bounds.min[axis] - ray.origin[axis]) * inv_direction[axis];
+        let mut t1 = (bounds.max[axis] - ray.origin[axis]) * inv_direction[axis];
+        if inv_direction[axis] < 0.0 {
+            std::mem::swap(&mut t0, &mut t1);
+        }
+
+        t_min = if t0 > t_min { t0 } else { t_min };
+        t_max = if t1 < t_max { t1 } else { t_max };
+        if t_max <= t_min {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// A grid of small triangles at varying depths
+#[cfg(test)]
+fn triangle_grid() -> crate::mesh::Mesh {
+    use crate::mesh::{Mesh, Vertex};
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for i in 0..20 {
+        for j in 0..20 {
+            let (x, y, z) = (i as Float, j as Float, ((i * 7 + j * 3) % 5) as Float);
+            let base = vertices.len() as u32;
+            vertices.push(Vertex::position(vec3!(x, y, z)));
+            vertices.push(Vertex::position(vec3!(x + 0.9, y, z)));
+            vertices.push(Vertex::position(vec3!(x, y + 0.9, z)));
+            indices.extend([base, base + 1, base + 2]);
+        }
+    }
+    Mesh::from_buffers(vertices, indices)
+}
+
+#[cfg(test)]
+fn test_rays() -> impl Iterator<Item = Ray> {
+    (0..50).map(|i| {
+        let origin = vec3!(i as Float * 0.4, 10.0, 20.0);
+        Ray::new(origin, vec3!(0.1, -0.05 * i as Float + 0.7, -1.0))
+    })
+}
+
+#[test]
+fn bvh_matches_brute_force_test() {
+    let mesh = triangle_grid();
+
+    for ray in test_rays() {
+        let brute_force = mesh
+            .objects()
+            .into_iter()
+            .filter_map(|handle| BoundsCollection::hit(&mesh, handle, &ray, 0.0001, INF))
+            .map(|hit| hit.t)
+            .reduce(Float::min);
+
+        assert_eq!(mesh.hit(&ray, 0.0001, INF).map(|hit| hit.t), brute_force);
+    }
+}
+
+#[test]
+fn bvh_ground_plane_test() {
+    /// The triangle grid with an unbounded plane at z = 2.5 as object 0
+    struct WithPlane(crate::mesh::Mesh);
+
+    impl BoundsCollection for WithPlane {
+        fn hit(&self, handle: usize, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+            if handle > 0 {
+                return BoundsCollection::hit(&self.0, handle - 1, ray, t_min, t_max);
+            }
+            let t = (2.5 - ray.origin.z) / ray.direction.z;
+            (t > t_min && t < t_max).then(|| {
+                let uv = Vec2::new(0.0, 0.0);
+                HitRecord::new(ray.at(t), vec3!(0.0, 0.0, 1.0), t, uv, ray)
+            })
+        }
+
+        fn bounds_ref(&self, handle: usize) -> AABB {
+            match handle {
+                0 => AABB {
+                    min: vec3!(-INF, -INF, 2.5),
+                    max: vec3!(INF, INF, 2.5),
+                },
+                _ => self.0.bounds_ref(handle - 1),
             }
         }
-        (Some(record_left), None) => Some(record_left),
-        (None, Some(record_right)) => Some(record_right),
-        _ => None,
+
+        fn objects(&self) -> Vec<usize> {
+            (0..=self.0.objects().len()).collect()
+        }
     }
+
+    let scene = WithPlane(triangle_grid());
+    let bvh = Bvh::build(scene.objects(), &scene);
+
+    // The plane is kept out of the tree, which is split as if the plane wasn't there
+    assert_eq!(bvh.unbounded, vec![0]);
+    assert_eq!(
+        bvh.nodes.len(),
+        Bvh::build(scene.0.objects(), &scene.0).nodes.len()
+    );
+
+    for ray in test_rays() {
+        let brute_force = scene
+            .objects()
+            .into_iter()
+            .filter_map(|handle| scene.hit(handle, &ray, 0.0001, INF))
+            .map(|hit| hit.t)
+            .reduce(Float::min);
+
+        let hit = bvh.hit(&ray, 0.0001, INF, &scene);
+        assert_eq!(hit.map(|(hit, _)| hit.t), brute_force);
+    }
+}
+
+#[test]
+fn bvh_flat_object_test() {
+    /// A single quad in the plane z = 0, its bounds have no thickness
+    struct FlatQuad;
+
+    impl BoundsCollection for FlatQuad {
+        fn hit(&self, _: usize, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+            let t = -ray.origin.z / ray.direction.z;
+            let point = ray.at(t);
+            let inside = (0.0..=5.0).contains(&point.x) && (-5.0..=5.0).contains(&point.y);
+            (inside && t > t_min && t < t_max).then(|| {
+                let uv = Vec2::new(0.0, 0.0);
+                HitRecord::new(point, vec3!(0.0, 0.0, 1.0), t, uv, ray)
+            })
+        }
+
+        fn bounds_ref(&self, _: usize) -> AABB {
+            AABB {
+                min: vec3!(0.0, -5.0, 0.0),
+                max: vec3!(5.0, 5.0, 0.0),
+            }
+        }
+
+        fn objects(&self) -> Vec<usize> {
+            vec![0]
+        }
+    }
+
+    let bvh = Bvh::build(FlatQuad.objects(), &FlatQuad);
+    let ray = Ray::new(vec3!(1.0, 0.0, 10.0), vec3!(1.0, 0.5, -10.0));
+    let hit = bvh.hit(&ray, 0.0001, INF, &FlatQuad);
+    assert_eq!(hit.map(|(hit, handle)| (hit.t, handle)), Some((1.0, 0)));
 }
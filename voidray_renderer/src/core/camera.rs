@@ -1,5 +1,4 @@
-use rand_distr::UnitDisc;
-
+use crate::math::sample_disk;
 use crate::rand::*;
 use crate::ray::*;
 use crate::vector::*;
@@ -66,14 +65,17 @@ pub struct CameraAcceleration {
 
 impl CameraAcceleration {
     /// Cast a ray, parametrized by x and y, each in the range [-1, 1]
-    pub fn cast_ray(&self, x: Float, y: Float, rng: &mut ThreadRng) -> Ray {
+    pub fn cast_ray(&self, x: Float, y: Float, rng: &mut dyn Sampler) -> Ray {
         let mut origin = self.origin;
         let mut new_dir = self.d * self.direction + x * self.right + y * self.up;
 
+        // Drawn even without depth of field so that later dimensions line up
+        let lens = rng.get_2d();
+
         if let Some((aperture, focal_length)) = self.dof {
             // Depth of field
             let focal_point = origin + new_dir.normalize() * focal_length;
-            let [x, y]: [Float; 2] = rng.sample(UnitDisc);
+            let [x, y] = sample_disk(lens);
             origin += (x * self.right + y * self.up) * aperture;
             new_dir = focal_point - origin;
         }
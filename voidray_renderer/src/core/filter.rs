@@ -0,0 +1,96 @@
+use crate::preamble::*;
+use crate::settings::FilterType;
+
+/// Pixel reconstruction filter, weighs samples by their offset from a pixel center
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Filter {
+    filter_type: FilterType,
+    radius: Float,
+}
+
+impl Filter {
+    /// Radius is given in pixels and is clamped to at least half a pixel so that every
+    /// sample contributes to the pixel it was taken in
+    pub fn new(filter_type: FilterType, radius: Float) -> Self {
+        Self {
+            filter_type,
+            radius: radius.max(0.5),
+        }
+    }
+
+    pub fn radius(&self) -> Float {
+        self.radius
+    }
+
+    /// Number of neighbouring pixels on each side a sample within a pixel can reach
+    pub fn reach(&self) -> i64 {
+        (self.radius + 0.5).ceil() as i64 - 1
+    }
+
+    /// Weight of a sample at offset (x, y) in pixels from the pixel center, the filters are
+    /// separable and not normalized since the weights are divided out when resolving
+    pub fn evaluate(&self, [x, y]: [Float; 2]) -> Float {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn evaluate_1d(&self, x: Float) -> Float {
+        let x = x.abs();
+        let r = self.radius;
+        if x >= r {
+            return 0.0;
+        }
+
+        match self.filter_type {
+            FilterType::Box => 1.0,
+            FilterType::Tent => 1.0 - x / r,
+            FilterType::Gaussian => {
+                let sigma = r / 3.0;
+                let gaussian = |x: Float| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(r)
+            }
+            FilterType::Mitchell => mitchell(2.0 * x / r),
+            FilterType::BlackmanHarris => {
+                let t = 2.0 * PI * (0.5 + 0.5 * x / r);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+            }
+        }
+    }
+}
+
+/// Mitchell-Netravali cubic with B = C = 1/3, `x` is in [0, 2]
+fn mitchell(x: Float) -> Float {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let result = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b)
+    } else {
+        (-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    };
+    result / 6.0
+}
+
+#[test]
+fn filter_support_test() {
+    let filters = [
+        FilterType::Box,
+        FilterType::Tent,
+        FilterType::Gaussian,
+        FilterType::Mitchell,
+        FilterType::BlackmanHarris,
+    ];
+
+    for filter_type in filters {
+        let filter = Filter::new(filter_type, 1.5);
+        assert_eq!(filter.reach(), 1);
+        assert!(filter.evaluate([0.0, 0.0]) > 0.0);
+        assert_eq!(filter.evaluate([1.5, 0.0]), 0.0);
+        assert_eq!(filter.evaluate([0.0, -2.0]), 0.0);
+        assert!(filter.evaluate([0.0, 0.0]) >= filter.evaluate([0.7, 0.3]));
+    }
+
+    assert_eq!(Filter::new(FilterType::Box, 0.5).reach(), 0);
+}
@@ -0,0 +1,144 @@
+use super::scene::{Object, ObjectHandle, SceneAcceleration};
+use crate::color::*;
+use crate::preamble::*;
+use crate::rand::*;
+use crate::ray::*;
+
+/// An emissive object which can be sampled directly
+struct Light {
+    object: ObjectHandle,
+    emission: Color,
+    area: Float,
+}
+
+/// A point sampled on a light, as seen from a shading point
+pub struct LightSample {
+    /// Unit direction from the shading point toward the light
+    pub direction: Vec3,
+    /// Distance from the shading point to the sampled point
+    pub distance: Float,
+    /// Solid angle pdf of the sample, including the probability of picking the light
+    pub pdf: Float,
+    /// Radiance emitted toward the shading point
+    pub emission: Color,
+}
+
+/// List of all emissive objects in a scene, lights are picked proportional to their power
+///
+/// Instanced lights are assumed to be scaled uniformly, otherwise their area is approximate
+pub struct LightList {
+    lights: Vec<Light>,
+    /// Cumulative light power, used to pick a light
+    cdf: Vec<Float>,
+    /// Maps each object to its index in `lights`, if it is a light
+    object_lights: Vec<Option<usize>>,
+}
+
+impl LightList {
+    pub fn empty() -> Self {
+        Self {
+            lights: Vec::new(),
+            cdf: Vec::new(),
+            object_lights: Vec::new(),
+        }
+    }
+
+    pub fn build(objects: &[Object], scene: &SceneAcceleration) -> Self {
+        let mut result = Self::empty();
+        let mut total_power = 0.0;
+
+        for (index, object) in objects.iter().enumerate() {
+            let emission = scene.material_ref(object.material).emission();
+            let area = scene.object_area(ObjectHandle(index));
+
+            match area {
+                Some(area) if area > 0.0 && emission.mean() > 0.0 => {
+                    total_power += emission.mean() * area;
+                    result.cdf.push(total_power);
+                    result.object_lights.push(Some(result.lights.len()));
+                    result.lights.push(Light {
+                        object: ObjectHandle(index),
+                        emission,
+                        area,
+                    });
+                }
+                _ => result.object_lights.push(None),
+            }
+        }
+
+        result
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    fn total_power(&self) -> Float {
+        self.cdf.last().copied().unwrap_or(0.0)
+    }
+
+    /// Probability of picking a given light
+    fn select_pdf(&self, index: usize) -> Float {
+        let previous = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        (self.cdf[index] - previous) / self.total_power()
+    }
+
+    /// Sample a point on one of the lights as seen from `point`
+    pub fn sample(
+        &self,
+        scene: &SceneAcceleration,
+        point: Vec3,
+        rng: &mut dyn Sampler,
+    ) -> Option<LightSample> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let target = rng.get_1d() * self.total_power();
+        let index = self
+            .cdf
+            .partition_point(|power| *power < target)
+            .min(self.lights.len() - 1);
+        let light = &self.lights[index];
+
+        let (light_point, light_normal) = scene.sample_object(light.object, rng)?;
+
+        let offset = light_point - point;
+        let distance = offset.magnitude();
+        if distance <= 0.0 {
+            return None;
+        }
+
+        let direction = offset / distance;
+        let cosine = light_normal.dot(direction).abs();
+        if cosine <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            pdf: self.select_pdf(index) * distance * distance / (cosine * light.area),
+            emission: light.emission,
+        })
+    }
+
+    /// Solid angle pdf with which `sample` would have produced the hit along the ray, zero if
+    /// the object is not a light
+    ///
+    /// Like `sample` this uses the geometric normal, so that smooth shaded lights get matching
+    /// pdfs from both strategies.
+    pub fn pdf(&self, object: ObjectHandle, ray: &Ray, hit: &HitRecord) -> Float {
+        match self.object_lights.get(object.0).copied().flatten() {
+            Some(index) => {
+                let cosine = hit.geometric_normal.dot(ray.direction).abs();
+                if cosine <= 0.0 {
+                    return 0.0;
+                }
+
+                self.select_pdf(index) * hit.t * hit.t / (cosine * self.lights[index].area)
+            }
+            None => 0.0,
+        }
+    }
+}
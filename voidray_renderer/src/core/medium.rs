@@ -0,0 +1,83 @@
+use crate::math::local_to_world;
+use crate::preamble::*;
+
+/// Henyey-Greenstein phase function, the distribution of the directions light is scattered in
+/// by a medium
+///
+/// The asymmetry `g` ranges from -1 (back scattering) through 0 (isotropic) to 1 (forward
+/// scattering).
+#[derive(Debug, Clone, Copy)]
+pub struct HenyeyGreenstein {
+    pub g: Float,
+}
+
+impl HenyeyGreenstein {
+    /// Asymmetries are clamped away from ±1, where the phase function becomes a delta function
+    pub fn new(g: Float) -> Self {
+        Self {
+            g: g.clamp(-0.99, 0.99),
+        }
+    }
+
+    /// Density of light arriving from `to_incident` being scattered toward `to_viewer`, both
+    /// directions point away from the scattering event
+    pub fn eval(&self, to_viewer: &Vec3, to_incident: &Vec3) -> Float {
+        // Cosine of the angle between the directions of travel of the incident and scattered light
+        let cosine = -to_viewer.normalize().dot(to_incident.normalize());
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cosine;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(1.0e-8).sqrt())
+    }
+
+    /// Importance sample the direction toward the incident light, returns (to_incident, pdf)
+    pub fn sample(&self, to_viewer: &Vec3, [u, v]: [Float; 2]) -> (Vec3, Float) {
+        let g = self.g;
+        let cosine = if g.abs() < 1.0e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let term = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            ((1.0 + g * g - term * term) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sine = (1.0 - cosine * cosine).max(0.0).sqrt();
+        let (y, x) = (2.0 * PI * v).sin_cos();
+
+        // The incident light travels at the sampled angle to the scattered light
+        let travel = local_to_world(to_viewer.normalize()) * vec3!(x * sine, y * sine, cosine);
+        let to_incident = -travel;
+        (to_incident, self.eval(to_viewer, &to_incident))
+    }
+}
+
+#[test]
+fn phase_function_test() {
+    use crate::math::sample_sphere;
+    use crate::rand::*;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let to_viewer = vec3!(0.0, 0.6, 0.8);
+    for g in [-0.7, 0.0, 0.5, 0.9] {
+        let phase = HenyeyGreenstein::new(g);
+
+        // Integrates to one over the sphere
+        let samples = 200_000;
+        let integral: Float = (0..samples)
+            .map(|_| phase.eval(&to_viewer, &sample_sphere([rng.gen(), rng.gen()])))
+            .sum::<Float>()
+            * 4.0
+            * PI
+            / samples as Float;
+        assert!((integral - 1.0).abs() < 0.05, "g = {}: {}", g, integral);
+
+        // The mean cosine of the scattering angle is the asymmetry
+        let samples = 20_000;
+        let mean: Float = (0..samples)
+            .map(|_| {
+                let (to_incident, pdf) = phase.sample(&to_viewer, [rng.gen(), rng.gen()]);
+                assert!((pdf - phase.eval(&to_viewer, &to_incident)).abs() < 1.0e-4 * pdf);
+                -to_viewer.dot(to_incident)
+            })
+            .sum::<Float>()
+            / samples as Float;
+        assert!((mean - g).abs() < 0.02, "g = {}: {}", g, mean);
+    }
+}
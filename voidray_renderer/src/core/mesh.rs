@@ -1,9 +1,13 @@
 use std::fs::File;
+use std::hash::Hasher;
 use std::io::BufReader;
 
 use crate::aabb::*;
 use crate::bvh::*;
+use crate::error::Error;
+use crate::hash::StableHasher;
 use crate::math::*;
+use crate::rand::*;
 use crate::ray::*;
 use crate::vec3;
 use crate::vector::*;
@@ -20,32 +24,65 @@ pub struct Vertex {
     position: Vec3,
     uv: Vec2,
     normal: Vec3,
+    /// Zero if the tangent should be derived from the uv coordinates
+    tangent: Vec3,
 }
 
 impl Vertex {
+    pub fn new(position: Vec3, uv: Vec2, normal: Vec3) -> Vertex {
+        Vertex {
+            position,
+            uv,
+            normal,
+            tangent: vec3!(0.0),
+        }
+    }
+
     pub fn position(position: Vec3) -> Vertex {
         Vertex {
             position,
             uv: Vec2::new(0.0, 0.0),
             normal: vec3!(0.0),
+            tangent: vec3!(0.0),
         }
     }
+
+    /// Use an explicit tangent, e.g. one supplied by the file the mesh was loaded from
+    pub fn with_tangent(mut self, tangent: Vec3) -> Vertex {
+        self.tangent = tangent;
+        self
+    }
 }
 
 pub struct Mesh {
+    /// File the mesh was loaded from, if any
+    path: Option<String>,
     /// Vertex buffer
     vertices: Vec<Vertex>,
     bounds: AABB,
     triangles: Vec<Triangle>,
-    bvh_root: BvhNode,
+    /// Cumulative triangle areas, used to sample points uniformly on the surface
+    area_cdf: Vec<Float>,
+    bvh: Bvh,
 }
 
-static SMALL_MESH: usize = 4;
-
 impl Mesh {
+    /// Load a Wavefront OBJ file, panics if the file can't be loaded
     pub fn from_file(path: &str) -> Self {
+        Self::try_from_file(path).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Load a Wavefront OBJ file
+    pub fn try_from_file(path: &str) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|source| Error::Io {
+            path: path.to_owned(),
+            source,
+        })?;
         let obj: Obj<obj::TexturedVertex, u32> =
-            load_obj(BufReader::new(File::open(path).unwrap())).unwrap();
+            load_obj(BufReader::new(file)).map_err(|source| Error::Obj {
+                path: path.to_owned(),
+                source,
+            })?;
         let mut vertices: Vec<Vertex> = Vec::new();
 
         for vertex in obj.vertices {
@@ -61,6 +98,7 @@ impl Mesh {
                     vertex.normal[1] as Float,
                     vertex.normal[2] as Float,
                 ),
+                tangent: vec3!(0.0),
             });
         }
 
@@ -70,7 +108,9 @@ impl Mesh {
             vertices.len(),
             obj.indices.len() / 3
         );
-        Self::from_buffers(vertices, obj.indices)
+        let mut mesh = Self::from_buffers(vertices, obj.indices);
+        mesh.path = Some(path.to_owned());
+        Ok(mesh)
     }
 
     pub fn from_buffers(vertices: Vec<Vertex>, indices: Vec<u32>) -> Self {
@@ -89,6 +129,18 @@ impl Mesh {
             })
         }
 
+        // Cumulative area distribution over the triangles
+        let area_cdf = triangles
+            .iter()
+            .scan(0.0, |total, triangle| {
+                let v0 = vertices[triangle.vertices[0] as usize].position;
+                let v1 = vertices[triangle.vertices[1] as usize].position;
+                let v2 = vertices[triangle.vertices[2] as usize].position;
+                *total += 0.5 * (v1 - v0).cross(v2 - v0).magnitude();
+                Some(*total)
+            })
+            .collect();
+
         // Calculate bounding box
         let mut bounds = AABB::default();
         for vertex in &vertices[..] {
@@ -102,46 +154,110 @@ impl Mesh {
         }
 
         let mut result = Self {
+            path: None,
             vertices,
             triangles,
-            bvh_root: BvhNode::None,
+            area_cdf,
+            bvh: Bvh::empty(),
             bounds,
         };
 
-        // Small mesh optimizations
-        if indices.len() > SMALL_MESH * 3 {
-            result.build_bvh();
-        }
+        result.build_bvh();
         result
     }
 
-    pub fn build_bvh(&mut self) {
-        self.bvh_root = BvhNode::from_list(&mut self.objects(), self);
+    /// Path the mesh was loaded from, `None` if it was built from buffers
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
     }
 
-    pub fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        if self.bvh_root != BvhNode::None {
-            self.bvh_root.hit(ray, t_min, t_max, self).map(|a| a.0)
-        } else {
-            let mut result = None;
-            let mut closest_so_far = t_max;
-
-            for triangle in &self.triangles {
-                if let Some(hit) = triangle.hit(ray, t_min, closest_so_far, self) {
-                    if closest_so_far > hit.t {
-                        closest_so_far = hit.t;
-                        result = Some(hit);
-                    }
-                }
-            }
+    /// Iterator over the positions of the vertex buffer
+    pub fn positions(&self) -> impl Iterator<Item = Vec3> + '_ {
+        self.vertices.iter().map(|vertex| vertex.position)
+    }
+
+    /// Iterator over the texture coordinates of the vertex buffer
+    pub fn uvs(&self) -> impl Iterator<Item = Vec2> + '_ {
+        self.vertices.iter().map(|vertex| vertex.uv)
+    }
+
+    /// Iterator over the vertex normals of the vertex buffer, zero where the face normal is used
+    pub fn normals(&self) -> impl Iterator<Item = Vec3> + '_ {
+        self.vertices.iter().map(|vertex| vertex.normal)
+    }
+
+    /// Iterator over the explicit tangents of the vertex buffer, zero where they are derived
+    /// from the uv coordinates
+    pub fn tangents(&self) -> impl Iterator<Item = Vec3> + '_ {
+        self.vertices.iter().map(|vertex| vertex.tangent)
+    }
+
+    /// Flattened index buffer, three indices per triangle
+    pub fn indices(&self) -> Vec<u32> {
+        self.triangles
+            .iter()
+            .flat_map(|triangle| triangle.vertices)
+            .collect()
+    }
+
+    /// Hashes the vertex and index buffers rather than the path, so edits to the mesh are noticed
+    /// even if they keep its bounds
+    pub fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_u64(self.vertices.len() as u64);
+        for vertex in &self.vertices {
+            let (position, normal, tangent) = (vertex.position, vertex.normal, vertex.tangent);
+            hasher.write_floats(&[position.x, position.y, position.z, vertex.uv.x, vertex.uv.y]);
+            hasher.write_floats(&[normal.x, normal.y, normal.z]);
+            hasher.write_floats(&[tangent.x, tangent.y, tangent.z]);
+        }
+        self.triangles
+            .iter()
+            .flat_map(|triangle| triangle.vertices)
+            .for_each(|index| hasher.write_u32(index));
+    }
 
-            result
+    /// Total surface area of the mesh
+    pub fn area(&self) -> Float {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    /// Uniformly sample a point on the mesh, returns (point, geometric normal)
+    pub fn sample_point(&self, rng: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        if self.area() <= 0.0 {
+            return None;
         }
+
+        // Pick a triangle proportional to its area
+        let target = rng.get_1d() * self.area();
+        let index = self
+            .area_cdf
+            .partition_point(|area| *area < target)
+            .min(self.triangles.len() - 1);
+        let triangle = &self.triangles[index];
+
+        // Uniform barycentric coordinates
+        let [u, v] = rng.get_2d();
+        let sqrt_u = u.sqrt();
+        let (b0, b1) = (1.0 - sqrt_u, sqrt_u * (1.0 - v));
+
+        let v0 = self.vertices[triangle.vertices[0] as usize].position;
+        let v1 = self.vertices[triangle.vertices[1] as usize].position;
+        let v2 = self.vertices[triangle.vertices[2] as usize].position;
+
+        Some((b0 * v0 + b1 * v1 + (1.0 - b0 - b1) * v2, triangle.normal))
+    }
+
+    pub fn build_bvh(&mut self) {
+        self.bvh = Bvh::build(self.objects(), self);
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        self.bvh.hit(ray, t_min, t_max, self).map(|a| a.0)
     }
 }
 
 impl Triangle {
-    fn hit(&self, ray: &Ray, tmin: Float, _: Float, mesh: &Mesh) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, tmin: Float, tmax: Float, mesh: &Mesh) -> Option<HitRecord> {
         let v0 = &mesh.vertices[self.vertices[0] as usize];
         let v1 = &mesh.vertices[self.vertices[1] as usize];
         let v2 = &mesh.vertices[self.vertices[2] as usize];
@@ -173,18 +289,39 @@ impl Triangle {
         }
 
         let t = f * e2.dot(q);
+        if t <= tmin || t >= tmax {
+            return None;
+        }
+
         let mut normal = u * v1.normal + v * v2.normal + (1.0 - u - v) * v0.normal;
         let uv = u * v1.uv + v * v2.uv + (1.0 - u - v) * v0.uv;
 
-        if normal.angle(self.normal).0 > degrees_to_radians(30.0) {
+        // Fall back to the face normal for meshes without vertex normals
+        let angle = normal.angle(self.normal).0;
+        if near_zero(normal) || angle.is_nan() || angle > degrees_to_radians(30.0) {
             normal = self.normal;
         }
 
-        if t > tmin {
-            Some(HitRecord::new(ray.at(t), normal, t, uv, ray))
+        // Interpolated vertex tangent, or the tangent from the uv parametrization, dp/du
+        let tangent = u * v1.tangent + v * v2.tangent + (1.0 - u - v) * v0.tangent;
+        let tangent = if !near_zero(tangent) {
+            tangent
         } else {
-            None
-        }
+            let duv1 = v1.uv - v0.uv;
+            let duv2 = v2.uv - v0.uv;
+            let determinant = duv1.x * duv2.y - duv1.y * duv2.x;
+            if determinant.abs() > 1.0e-12 {
+                (e1 * duv2.y - e2 * duv1.y) / determinant
+            } else {
+                vec3!(0.0)
+            }
+        };
+
+        Some(
+            HitRecord::new(ray.at(t), normal, t, uv, ray)
+                .with_tangent(tangent)
+                .with_geometric_normal(self.normal),
+        )
     }
 }
 
@@ -1,4 +1,9 @@
+pub mod aov;
 pub mod camera;
+pub mod filter;
+pub mod light;
+pub mod medium;
+pub mod sampler;
 pub mod scene;
 pub mod settings;
 pub mod texture;
@@ -0,0 +1,471 @@
+use rand::RngCore;
+
+use crate::settings::SamplerType;
+use crate::vector::Float;
+
+const PCG_MULTIPLIER: u64 = 6364136223846793005;
+
+/// Largest float below one, sample values are clamped to it so that they stay in [0, 1)
+#[cfg(not(feature = "high_precision"))]
+const ONE_MINUS_EPSILON: Float = 1.0 - f32::EPSILON / 2.0;
+#[cfg(feature = "high_precision")]
+const ONE_MINUS_EPSILON: Float = 1.0 - f64::EPSILON / 2.0;
+
+/// Primes used as the bases of the Halton sequence, one per dimension
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Source of sample values for a single pixel sample
+///
+/// Values are drawn one dimension at a time, first the pixel offset, then the lens, then the
+/// light, environment and BSDF samples of each bounce. Low-discrepancy samplers spread every
+/// dimension evenly over the samples of a pixel. Samplers are also random number generators, for
+/// decisions which don't benefit from well distributed values.
+pub trait Sampler: RngCore {
+    /// Begin sample `sample` of the pixel with index `pixel`, restarting at the first dimension
+    fn start_sample(&mut self, pixel: u64, sample: u32);
+
+    /// Sample value in [0, 1) for the next dimension
+    fn get_1d(&mut self) -> Float;
+
+    /// Sample values in [0, 1)² for the next two dimensions
+    fn get_2d(&mut self) -> [Float; 2];
+}
+
+/// Create a sampler, `samples_per_pixel` is the total number of samples in the render
+pub fn create_sampler(
+    sampler_type: SamplerType,
+    seed: u64,
+    samples_per_pixel: u32,
+) -> Box<dyn Sampler> {
+    match sampler_type {
+        SamplerType::Independent => Box::new(IndependentSampler::new(seed)),
+        SamplerType::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+        SamplerType::Halton => Box::new(HaltonSampler::new(seed)),
+        SamplerType::Sobol => Box::new(SobolSampler::new(seed)),
+    }
+}
+
+/// SplitMix64 finalizer, used to decorrelate seeds, pixel, sample and dimension indices
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
+
+fn hash(seed: u64, pixel: u64, index: u64) -> u64 {
+    mix(seed ^ mix(pixel ^ mix(index)))
+}
+
+/// Map 32 random bits to [0, 1)
+fn to_float(bits: u32) -> Float {
+    (bits as Float * (1.0 / 4294967296.0)).min(ONE_MINUS_EPSILON)
+}
+
+/// PCG32 generator, the source of all random bits
+#[derive(Clone)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64,
+}
+
+impl Pcg32 {
+    /// Independent stream for one sample of one pixel
+    pub fn new(seed: u64, pixel: u64, sample: u64) -> Self {
+        let hash = hash(seed, pixel, sample);
+        Self::from_state(hash, mix(hash))
+    }
+
+    /// A single stream, useful outside of the render loop
+    pub fn from_seed(seed: u64) -> Self {
+        Self::from_state(mix(seed), mix(!seed))
+    }
+
+    fn from_state(state: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(state);
+        rng.step();
+        rng
+    }
+
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(PCG_MULTIPLIER)
+            .wrapping_add(self.increment);
+    }
+}
+
+impl RngCore for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let state = self.state;
+        self.step();
+
+        // XSH RR output function
+        let xorshifted = (((state >> 18) ^ state) >> 27) as u32;
+        let rotation = (state >> 59) as u32;
+        xorshifted.rotate_right(rotation)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Samplers use their `rng` field for random bits
+macro_rules! impl_rng_core {
+    ($sampler:ty) => {
+        impl RngCore for $sampler {
+            fn next_u32(&mut self) -> u32 {
+                self.rng.next_u32()
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                self.rng.next_u64()
+            }
+
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                self.rng.fill_bytes(dest)
+            }
+
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+                self.rng.try_fill_bytes(dest)
+            }
+        }
+    };
+}
+
+/// Uniform random values, every dimension is independent
+pub struct IndependentSampler {
+    seed: u64,
+    rng: Pcg32,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: Pcg32::new(seed, 0, 0),
+        }
+    }
+}
+
+impl_rng_core!(IndependentSampler);
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, pixel: u64, sample: u32) {
+        self.rng = Pcg32::new(self.seed, pixel, sample as u64);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        to_float(self.rng.next_u32())
+    }
+
+    fn get_2d(&mut self) -> [Float; 2] {
+        [self.get_1d(), self.get_1d()]
+    }
+}
+
+/// Jittered strata, each dimension is split into one stratum per sample (or a square grid of
+/// strata in 2D) and the samples of a pixel visit the strata in a random order
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    pixel: u64,
+    sample: u32,
+    dimension: u64,
+    rng: Pcg32,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> Self {
+        Self {
+            seed,
+            samples_per_pixel: samples_per_pixel.max(1),
+            pixel: 0,
+            sample: 0,
+            dimension: 0,
+            rng: Pcg32::new(seed, 0, 0),
+        }
+    }
+
+    /// Stratum visited by the current sample in the current dimension, out of `strata`
+    fn stratum(&mut self, strata: u32) -> u32 {
+        let hash = hash(self.seed, self.pixel, self.dimension) as u32;
+        self.dimension += 1;
+        permute(self.sample % strata, strata, hash)
+    }
+}
+
+impl_rng_core!(StratifiedSampler);
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, pixel: u64, sample: u32) {
+        self.pixel = pixel;
+        self.sample = sample;
+        self.dimension = 0;
+        self.rng = Pcg32::new(self.seed, pixel, sample as u64);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        let strata = self.samples_per_pixel;
+        let stratum = self.stratum(strata);
+        let jitter = to_float(self.rng.next_u32());
+        ((stratum as Float + jitter) / strata as Float).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> [Float; 2] {
+        let side = (self.samples_per_pixel as Float).sqrt() as u32;
+        let stratum = self.stratum(side * side);
+        let (x, y) = (stratum % side, stratum / side);
+        let jitter = [to_float(self.rng.next_u32()), to_float(self.rng.next_u32())];
+        [
+            ((x as Float + jitter[0]) / side as Float).min(ONE_MINUS_EPSILON),
+            ((y as Float + jitter[1]) / side as Float).min(ONE_MINUS_EPSILON),
+        ]
+    }
+}
+
+/// Halton sequence with a random rotation per pixel and dimension, dimensions past the
+/// supported number of prime bases fall back to random values
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    sample: u32,
+    dimension: usize,
+    rng: Pcg32,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            sample: 0,
+            dimension: 0,
+            rng: Pcg32::new(seed, 0, 0),
+        }
+    }
+}
+
+impl_rng_core!(HaltonSampler);
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, pixel: u64, sample: u32) {
+        self.pixel = pixel;
+        self.sample = sample;
+        self.dimension = 0;
+        self.rng = Pcg32::new(self.seed, pixel, sample as u64);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        match PRIMES.get(dimension) {
+            Some(&base) => {
+                // Cranley-Patterson rotation decorrelates neighbouring pixels
+                let offset = hash(self.seed, self.pixel, dimension as u64) as u32;
+                let value = radical_inverse(base, self.sample) + to_float(offset);
+                (value - value.floor()).min(ONE_MINUS_EPSILON)
+            }
+            None => to_float(self.rng.next_u32()),
+        }
+    }
+
+    fn get_2d(&mut self) -> [Float; 2] {
+        [self.get_1d(), self.get_1d()]
+    }
+}
+
+/// Owen-scrambled Sobol points, every pair of dimensions uses the first two Sobol dimensions
+/// with its own shuffle of the sample order (Burley, "Practical Hash-based Owen Scrambling")
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    sample: u32,
+    dimension: u64,
+    rng: Pcg32,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            sample: 0,
+            dimension: 0,
+            rng: Pcg32::new(seed, 0, 0),
+        }
+    }
+
+    /// Shuffled sample index and scrambling seed for the next dimension
+    fn next_dimension(&mut self) -> (u32, u32) {
+        let hash = hash(self.seed, self.pixel, self.dimension);
+        self.dimension += 1;
+        (
+            nested_uniform_scramble(self.sample, hash as u32),
+            (hash >> 32) as u32,
+        )
+    }
+}
+
+impl_rng_core!(SobolSampler);
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, pixel: u64, sample: u32) {
+        self.pixel = pixel;
+        self.sample = sample;
+        self.dimension = 0;
+        self.rng = Pcg32::new(self.seed, pixel, sample as u64);
+    }
+
+    fn get_1d(&mut self) -> Float {
+        let (index, seed) = self.next_dimension();
+        to_float(nested_uniform_scramble(index.reverse_bits(), seed))
+    }
+
+    fn get_2d(&mut self) -> [Float; 2] {
+        let (index, seed) = self.next_dimension();
+        [
+            to_float(nested_uniform_scramble(index.reverse_bits(), seed)),
+            to_float(nested_uniform_scramble(
+                sobol_second_dimension(index),
+                mix(seed as u64) as u32,
+            )),
+        ]
+    }
+}
+
+/// Radical inverse of `index` in the given base, i.e. its digits mirrored about the decimal point
+fn radical_inverse(base: u32, mut index: u32) -> Float {
+    let inverse_base = 1.0 / base as f64;
+    let mut reversed: u64 = 0;
+    let mut scale = 1.0;
+
+    while index > 0 {
+        reversed = reversed * base as u64 + (index % base) as u64;
+        scale *= inverse_base;
+        index /= base;
+    }
+
+    ((reversed as f64 * scale) as Float).min(ONE_MINUS_EPSILON)
+}
+
+/// Element `index` of a random permutation of `0..length` chosen by `seed` (Kensler, "Correlated
+/// Multi-Jittered Sampling")
+fn permute(mut index: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170893d);
+        index ^= seed >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929eb3f);
+        index ^= seed >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dcb303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e501cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860a3df);
+        index &= mask;
+        index ^= index >> 5;
+
+        if index < length {
+            return index.wrapping_add(seed) % length;
+        }
+    }
+}
+
+/// Second dimension of the Sobol sequence, the first is `index.reverse_bits()`
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut direction = 1 << 31;
+    let mut result = 0;
+
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+
+    result
+}
+
+/// Hash-based approximation of an Owen scramble of `bits`
+fn nested_uniform_scramble(bits: u32, seed: u32) -> u32 {
+    let mut x = bits.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
+
+#[test]
+fn sampler_deterministic_test() {
+    for sampler_type in [
+        SamplerType::Independent,
+        SamplerType::Stratified,
+        SamplerType::Halton,
+        SamplerType::Sobol,
+    ] {
+        let mut sampler = create_sampler(sampler_type, 7, 16);
+        let mut sequence = |pixel, sample| {
+            sampler.start_sample(pixel, sample);
+            let [x, y] = sampler.get_2d();
+            [x, y, sampler.get_1d()]
+        };
+
+        let first = sequence(3, 2);
+        assert_eq!(first, sequence(3, 2));
+        assert_ne!(first, sequence(3, 3));
+        assert!(first.iter().all(|value| (0.0..1.0).contains(value)));
+    }
+}
+
+#[test]
+fn sampler_stratification_test() {
+    // Every interval of length 1/16 holds exactly one of the first 16 samples of a pixel
+    for sampler_type in [SamplerType::Stratified, SamplerType::Sobol] {
+        let mut sampler = create_sampler(sampler_type, 0, 16);
+        let mut strata = [false; 16];
+        for sample in 0..16 {
+            sampler.start_sample(5, sample);
+            strata[(sampler.get_1d() * 16.0) as usize] = true;
+        }
+        assert!(strata.iter().all(|visited| *visited));
+    }
+}
@@ -1,11 +1,15 @@
 use super::camera::{Camera, CameraAcceleration};
+use super::light::LightList;
 use super::texture::{ImageTexture, SampleType, Texture};
 use crate::aabb::{AABB, Bounded};
-use crate::bvh::{BvhNode, BoundsCollection};
+use crate::bvh::{BoundsCollection, Bvh};
 use crate::core::traits::*;
+use crate::error::Error;
 use crate::mesh::Mesh;
 use crate::preamble::*;
+use crate::rand::*;
 use crate::ray::*;
+use crate::transform::Transform;
 
 /// Represents an (object, name) pair
 pub struct Named<T> {
@@ -13,6 +17,20 @@ pub struct Named<T> {
     name: String,
 }
 
+impl<T> Named<T> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn object(&self) -> &T {
+        &self.object
+    }
+
+    pub fn rename(&mut self, name: &str) {
+        self.name = name.to_owned();
+    }
+}
+
 /// Represents a structure which can be turned into an acceleration structure
 pub trait Accelerable<A> {
     fn build_acceleration(&self) -> A;
@@ -31,6 +49,10 @@ where
 pub struct Object {
     pub surface: SurfaceHandle,
     pub material: MaterialHandle,
+    /// Object to world transformation, `None` if the surface is placed as is
+    pub transform: Option<Transform>,
+    /// Medium filling the object, rays refracted into the object travel through it
+    pub interior: Option<MediumHandle>,
 }
 
 pub struct Scene {
@@ -41,53 +63,85 @@ pub struct Scene {
     pub meshes: Vec<Arc<Mesh>>,
     pub materials: Vec<Named<Arc<dyn Material>>>,
     pub environment: Option<Arc<dyn Environment>>,
+    pub media: Vec<Named<Arc<dyn Medium>>>,
+    /// Medium filling the space outside of objects, e.g. fog
+    pub medium: Option<MediumHandle>,
 }
 
 #[derive(Clone, Copy)]
-pub struct MaterialHandle(usize);
+pub struct MaterialHandle(pub(crate) usize);
+
+#[derive(Clone, Copy)]
+pub struct ObjectHandle(pub(crate) usize);
 
 #[derive(Clone, Copy)]
-pub struct ObjectHandle(usize);
+pub struct SurfaceHandle(pub(crate) usize);
 
 #[derive(Clone, Copy)]
-pub struct SurfaceHandle(usize);
+pub struct TextureHandle(pub(crate) usize);
 
 #[derive(Clone, Copy)]
-pub struct TextureHandle(usize);
+pub struct MeshHandle(pub(crate) usize);
 
 #[derive(Clone, Copy)]
-pub struct MeshHandle(usize);
+pub struct MediumHandle(pub(crate) usize);
 
 pub struct SceneAcceleration {
     pub camera: CameraAcceleration,
     pub environment: Option<Arc<dyn Environment>>,
-    bvh: BvhNode,
+    pub medium: Option<Arc<dyn Medium>>,
+    bvh: Bvh,
+    lights: LightList,
     objects: Vec<Object>,
     surfaces: Vec<Surface>,
     textures: Vec<Arc<Texture>>,
     meshes: Vec<Arc<Mesh>>,
     materials: Vec<Arc<dyn Material>>,
+    media: Vec<Arc<dyn Medium>>,
 }
 
 impl BoundsCollection for SceneAcceleration {
     fn bounds_ref(&self, handle: usize) -> AABB {
-        let surface = self.surface_ref(SurfaceHandle(handle));
+        let object = self.object_ref(ObjectHandle(handle));
 
-        match surface {
+        let bounds = match self.surface_ref(object.surface) {
             Surface::Mesh(handle) => self.mesh_ref(*handle).bounds(),
             Surface::Analytic(surface) => surface.bounds(),
+        };
+
+        match &object.transform {
+            Some(transform) => transform.transform_bounds(&bounds),
+            None => bounds,
         }
     }
 
     fn hit(&self, handle: usize, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        match self.surface_ref(SurfaceHandle(handle)) {
-            &Surface::Mesh(handle) => self.mesh_ref(handle).hit(ray, t_min, t_max),
-            Surface::Analytic(surface) => surface.hit(ray, t_min, t_max),
+        let object = self.object_ref(ObjectHandle(handle));
+        let surface = self.surface_ref(object.surface);
+
+        match &object.transform {
+            None => self.surface_hit(surface, ray, t_min, t_max),
+            Some(transform) => {
+                // Intersect in object space, then bring the hit back into world space
+                let (local_ray, scale) = transform.inverse_ray(ray);
+                let hit = self.surface_hit(surface, &local_ray, t_min * scale, t_max * scale)?;
+                let t = hit.t / scale;
+
+                Some(HitRecord {
+                    point: ray.at(t),
+                    normal: transform.transform_normal(hit.normal),
+                    geometric_normal: transform.transform_normal(hit.geometric_normal),
+                    t,
+                    uv: hit.uv,
+                    tangent: transform.transform_vector(hit.tangent),
+                    front_face: hit.front_face,
+                })
+            }
         }
     }
 
     fn objects(&self) -> Vec<usize> {
-        (0..self.surfaces.len()).collect()
+        (0..self.objects.len()).collect()
     }
 }
 
@@ -106,6 +160,8 @@ impl Scene {
             textures: Vec::new(),
             meshes: Vec::new(),
             environment: None,
+            media: Vec::new(),
+            medium: None,
         }
     }
 
@@ -135,29 +191,127 @@ impl Scene {
     }
 
     pub fn add_mesh_from_file(&mut self, path: &str) -> SurfaceHandle {
-        self.meshes.push(Arc::new(Mesh::from_file(path)));
-        self.surfaces.push(Named {
-            object: Surface::Mesh(MeshHandle(self.meshes.len() - 1)),
-            name: format!("mesh_{}", self.surfaces.len()),
-        });
-        SurfaceHandle(self.surfaces.len() - 1)
+        self.add_mesh(Arc::new(Mesh::from_file(path)))
+    }
+
+    pub fn try_add_mesh_from_file(&mut self, path: &str) -> Result<SurfaceHandle, Error> {
+        Ok(self.add_mesh(Arc::new(Mesh::try_from_file(path)?)))
     }
 
     pub fn add_object(&mut self, material: MaterialHandle, surface: SurfaceHandle) -> ObjectHandle {
         self.objects.push(Named {
-            object: Object { surface, material },
+            object: Object {
+                surface,
+                material,
+                transform: None,
+                interior: None,
+            },
             name: format!("object_{}", self.objects.len()),
         });
         ObjectHandle(self.objects.len() - 1)
     }
 
-    pub fn add_image_texture(&mut self, path: &str, sample_type: SampleType) -> TextureHandle {
+    /// Place a surface in the scene with an object to world transformation, the surface (and
+    /// any mesh data) is shared between all of its instances
+    ///
+    /// Panics if the transformation is not invertible
+    pub fn add_instance(
+        &mut self,
+        surface: SurfaceHandle,
+        material: MaterialHandle,
+        transform: Mat4,
+    ) -> ObjectHandle {
+        let transform = Transform::new(transform).expect("instance transform is not invertible");
+
+        self.objects.push(Named {
+            object: Object {
+                surface,
+                material,
+                transform: Some(transform),
+                interior: None,
+            },
+            name: format!("instance_{}", self.objects.len()),
+        });
+        ObjectHandle(self.objects.len() - 1)
+    }
+
+    pub fn add_medium(&mut self, medium: Arc<dyn Medium>) -> MediumHandle {
+        self.media.push(Named {
+            object: medium,
+            name: format!("medium_{}", self.media.len()),
+        });
+        MediumHandle(self.media.len() - 1)
+    }
+
+    /// Fill an object with a medium, its surface should be closed and have a refractive or
+    /// interface material so that rays can enter it
+    pub fn set_interior(&mut self, object_handle: ObjectHandle, medium: Option<MediumHandle>) {
+        self.objects[object_handle.0].object.interior = medium;
+    }
+
+    pub fn add_texture(&mut self, texture: Arc<Texture>) -> TextureHandle {
         self.textures.push(Named {
-            object: Arc::new(Texture::Image(ImageTexture::new(path, sample_type))),
+            object: texture,
             name: format!("texture_{}", self.textures.len()),
         });
         TextureHandle(self.textures.len() - 1)
     }
+
+    pub fn add_image_texture(&mut self, path: &str, sample_type: SampleType) -> TextureHandle {
+        self.add_texture(Arc::new(Texture::Image(ImageTexture::new(path, sample_type))))
+    }
+
+    pub fn try_add_image_texture(
+        &mut self,
+        path: &str,
+        sample_type: SampleType,
+    ) -> Result<TextureHandle, Error> {
+        let texture = ImageTexture::try_new(path, sample_type)?;
+        Ok(self.add_texture(Arc::new(Texture::Image(texture))))
+    }
+}
+
+/// Name lookups for handles, useful for serializing a scene
+impl Scene {
+    pub fn material_name(&self, material_handle: MaterialHandle) -> &str {
+        self.materials[material_handle.0].name()
+    }
+
+    pub fn surface_name(&self, surface_handle: SurfaceHandle) -> &str {
+        self.surfaces[surface_handle.0].name()
+    }
+
+    pub fn texture_name(&self, texture_handle: TextureHandle) -> &str {
+        self.textures[texture_handle.0].name()
+    }
+
+    pub fn medium_name(&self, medium_handle: MediumHandle) -> &str {
+        self.media[medium_handle.0].name()
+    }
+
+    pub fn mesh_ref(&self, mesh_handle: MeshHandle) -> &Mesh {
+        self.meshes[mesh_handle.0].as_ref()
+    }
+
+    pub fn rename_material(&mut self, material_handle: MaterialHandle, name: &str) {
+        self.materials[material_handle.0].rename(name);
+    }
+
+    pub fn rename_surface(&mut self, surface_handle: SurfaceHandle, name: &str) {
+        self.surfaces[surface_handle.0].rename(name);
+    }
+
+    pub fn rename_texture(&mut self, texture_handle: TextureHandle, name: &str) {
+        self.textures[texture_handle.0].rename(name);
+    }
+
+    pub fn rename_object(&mut self, object_handle: ObjectHandle, name: &str) {
+        self.objects[object_handle.0].rename(name);
+    }
+
+    pub fn rename_medium(&mut self, medium_handle: MediumHandle, name: &str) {
+        self.media[medium_handle.0].rename(name);
+    }
 }
 
 impl Accelerable<SceneAcceleration> for Scene {
@@ -169,20 +323,25 @@ impl Accelerable<SceneAcceleration> for Scene {
             materials: self.materials.build_acceleration(),
             textures: self.textures.build_acceleration(),
             meshes: self.meshes.clone(),
-            bvh: BvhNode::None,
+            bvh: Bvh::empty(),
+            lights: LightList::empty(),
             environment: self.environment.clone(),
+            medium: self
+                .medium
+                .map(|handle| self.media[handle.0].object.clone()),
+            media: self.media.build_acceleration(),
         };
 
-        scene_accel.bvh = BvhNode::from_list(&mut scene_accel.objects(), &scene_accel);
+        scene_accel.bvh = Bvh::build(scene_accel.objects(), &scene_accel);
+        scene_accel.lights = LightList::build(&scene_accel.objects, &scene_accel);
         scene_accel
     }
 }
 
 impl SceneAcceleration {
     pub fn hit(&self, ray: &Ray) -> Option<(HitRecord, &Object)> {
-        self.bvh.hit(ray, 0.00001, INF, self).map(|(hit, handle)| {
-            (hit, self.object_ref(ObjectHandle(handle)))
-        })
+        self.hit_object(ray)
+            .map(|(hit, handle)| (hit, self.object_ref(handle)))
 
         // let mut result = None;
         //
@@ -204,6 +363,85 @@ impl SceneAcceleration {
         // result
     }
 
+    /// Same as `hit`, but returns the handle of the object which was hit
+    pub fn hit_object(&self, ray: &Ray) -> Option<(HitRecord, ObjectHandle)> {
+        self.hit_object_within(ray, INF)
+    }
+
+    /// Same as `hit_object`, but ignores hits further along the ray than `t_max`
+    pub fn hit_object_within(&self, ray: &Ray, t_max: Float) -> Option<(HitRecord, ObjectHandle)> {
+        self.bvh
+            .hit(ray, 0.00001, t_max, self)
+            .map(|(hit, handle)| (hit, ObjectHandle(handle)))
+    }
+
+    /// Returns true if anything blocks the segment from `origin` along `direction` up to
+    /// `distance`, used for shadow rays
+    pub fn occluded(&self, origin: Vec3, direction: Vec3, distance: Float) -> bool {
+        let ray = Ray::new(origin, direction);
+        self.bvh
+            .hit(&ray, 0.00001, distance * (1.0 - 1.0e-4), self)
+            .is_some()
+    }
+
+    pub fn lights(&self) -> &LightList {
+        &self.lights
+    }
+
+    /// Returns true if rays can travel through any media, scenes without media skip tracking
+    /// them entirely
+    pub fn has_media(&self) -> bool {
+        !self.media.is_empty()
+    }
+
+    fn surface_hit(
+        &self,
+        surface: &Surface,
+        ray: &Ray,
+        t_min: Float,
+        t_max: Float,
+    ) -> Option<HitRecord> {
+        match surface {
+            &Surface::Mesh(handle) => self.mesh_ref(handle).hit(ray, t_min, t_max),
+            Surface::Analytic(surface) => surface.hit(ray, t_min, t_max),
+        }
+    }
+
+    /// Surface area of an object in world space, `None` if it can't be sampled
+    pub fn object_area(&self, object_handle: ObjectHandle) -> Option<Float> {
+        let object = self.object_ref(object_handle);
+        let area = match self.surface_ref(object.surface) {
+            &Surface::Mesh(handle) => Some(self.mesh_ref(handle).area()),
+            Surface::Analytic(surface) => surface.area(),
+        }?;
+
+        match &object.transform {
+            Some(transform) => Some(area * transform.area_scale()),
+            None => Some(area),
+        }
+    }
+
+    /// Uniformly sample a point on an object, returns (point, normal) in world space
+    pub fn sample_object(
+        &self,
+        object_handle: ObjectHandle,
+        rng: &mut dyn Sampler,
+    ) -> Option<(Vec3, Vec3)> {
+        let object = self.object_ref(object_handle);
+        let (point, normal) = match self.surface_ref(object.surface) {
+            &Surface::Mesh(handle) => self.mesh_ref(handle).sample_point(rng),
+            Surface::Analytic(surface) => surface.sample_point(rng),
+        }?;
+
+        match &object.transform {
+            Some(transform) => Some((
+                transform.transform_point(point),
+                transform.transform_normal(normal),
+            )),
+            None => Some((point, normal)),
+        }
+    }
+
     pub fn surface_ref(&self, surface_handle: SurfaceHandle) -> &Surface {
         &self.surfaces[surface_handle.0]
     }
@@ -223,4 +461,8 @@ impl SceneAcceleration {
     pub fn object_ref(&self, object_handle: ObjectHandle) -> &Object {
         &self.objects[object_handle.0]
     }
+
+    pub fn medium_ref(&self, medium_handle: MediumHandle) -> &dyn Medium {
+        self.media[medium_handle.0].as_ref()
+    }
 }
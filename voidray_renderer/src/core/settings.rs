@@ -1,24 +1,93 @@
+use crate::aov::Aov;
 use crate::preamble::*;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 #[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
 pub struct Settings {
     pub render: RenderSettings,
     pub color_management: ColorManagementSettings,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum RenderMode {
     Normal,
     Full,
+    /// Full render which traces wavelengths instead of rgb, for dispersion and accurate metals
+    Spectral,
+}
+
+/// Strategy used to generate the sample values of each pixel sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SamplerType {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+/// Reconstruction filter used to weigh the samples splatted into each pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FilterType {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    BlackmanHarris,
+}
+
+/// Order in which the tiles of a render are scheduled
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TileOrder {
+    Scanline,
+    /// Outwards from the center of the image
+    Spiral,
+    /// Along a Hilbert curve, keeps consecutive tiles close together
+    Hilbert,
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
 pub struct RenderSettings {
     pub total_samples: u32,
     pub update_frequency: f32,
     pub render_mode: RenderMode,
     pub firefly_clamp: Float,
     pub max_bounces: u32,
+    /// Number of bounces after which paths are randomly terminated based on their throughput
+    pub russian_roulette_depth: u32,
+    /// Seed of the random number streams, renders with the same seed are identical
+    pub seed: u64,
+    pub sampler: SamplerType,
+    pub filter: FilterType,
+    /// Radius of the reconstruction filter in pixels
+    pub filter_radius: Float,
+    /// Pixels stop being sampled once their relative error is below this threshold, 0 disables
+    /// adaptive sampling
+    pub noise_threshold: Float,
+    /// Number of samples every pixel takes before it can be considered converged
+    pub adaptive_min_samples: u32,
+    /// Side length of the render tiles in pixels
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    /// Region of interest as `[x, y, width, height]` in pixels, only this part of the image is
+    /// rendered
+    pub region: Option<[u32; 4]>,
+    /// Render the whole image a few samples at a time instead of finishing tiles one by one
+    pub progressive: bool,
+    /// Extra passes written alongside the beauty image
+    pub aovs: Vec<Aov>,
+    /// File progressive renders are periodically saved to and resumed from, `None` disables
+    /// checkpointing
+    pub checkpoint_path: Option<String>,
+    /// Seconds between two checkpoints
+    pub checkpoint_interval: Float,
 }
 
 impl Default for RenderSettings {
@@ -29,11 +98,26 @@ impl Default for RenderSettings {
             render_mode: RenderMode::Full,
             firefly_clamp: 3.0,
             max_bounces: 10,
+            russian_roulette_depth: 3,
+            seed: 0,
+            sampler: SamplerType::Sobol,
+            filter: FilterType::Box,
+            filter_radius: 0.5,
+            noise_threshold: 0.0,
+            adaptive_min_samples: 16,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            region: None,
+            progressive: true,
+            aovs: Vec::new(),
+            checkpoint_path: None,
+            checkpoint_interval: 300.0,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Tonemap {
     None,
     ACES,
@@ -55,11 +139,14 @@ impl Tonemap {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
 pub struct ColorManagementSettings {
     pub tonemap: Tonemap,
     pub gamma: f32,
     pub exposure: f32,
     pub transparent: bool,
+    /// Show and save renders through the denoiser
+    pub denoise: bool,
 }
 
 impl Default for ColorManagementSettings {
@@ -69,6 +156,7 @@ impl Default for ColorManagementSettings {
             gamma: 2.2,
             exposure: 0.0,
             transparent: true,
+            denoise: false,
         }
     }
 }
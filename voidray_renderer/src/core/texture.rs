@@ -1,9 +1,13 @@
+use std::hash::Hasher;
 use std::sync::Arc;
 
-use crate::{color::Color, math::lerp, vector::Float};
+use crate::{color::Color, error::Error, hash::StableHasher, math::lerp, vector::Float};
 
 pub trait AbstractTexture: Send + Sync {
     fn sample(&self, u: Float, v: Float) -> Color;
+
+    /// Feed the parameters of the texture into the hasher, see `Material::hash_parameters`
+    fn hash_parameters(&self, hasher: &mut StableHasher) {}
 }
 
 pub enum Texture {
@@ -18,14 +22,25 @@ impl AbstractTexture for Texture {
             Texture::Abstract(texture) => texture.sample(u, v),
         }
     }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        match self {
+            Texture::Image(texture) => texture.hash_parameters(hasher),
+            Texture::Abstract(texture) => texture.hash_parameters(hasher),
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SampleType {
     Nearest,
     Bilinear,
 }
 
 pub struct ImageTexture {
+    /// File the texture was loaded from, `None` for textures created from pixel data
+    path: Option<String>,
     image: Vec<Color>,
     width: usize,
     height: usize,
@@ -33,11 +48,23 @@ pub struct ImageTexture {
 }
 
 impl ImageTexture {
+    /// Load an image file, panics if the file can't be loaded
     pub fn new(path: &str, sample_type: SampleType) -> Self {
-        let image = image::open(path).unwrap().to_rgb32f();
+        Self::try_new(path, sample_type).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Load an image file
+    pub fn try_new(path: &str, sample_type: SampleType) -> Result<Self, Error> {
+        let image = image::open(path)
+            .map_err(|source| Error::Image {
+                path: path.to_owned(),
+                source,
+            })?
+            .to_rgb32f();
         let dimensions = image.dimensions();
 
-        Self {
+        Ok(Self {
+            path: Some(path.to_owned()),
             image: image
                 .into_vec()
                 .chunks_exact(3)
@@ -46,9 +73,36 @@ impl ImageTexture {
             width: dimensions.0 as usize,
             height: dimensions.1 as usize,
             sample_type,
+        })
+    }
+
+    /// Create a texture from rgb pixels in row major order, starting at the top left
+    pub fn from_pixels(
+        image: Vec<Color>,
+        width: usize,
+        height: usize,
+        sample_type: SampleType,
+    ) -> Self {
+        assert_eq!(image.len(), width * height);
+
+        Self {
+            path: None,
+            image,
+            width,
+            height,
+            sample_type,
         }
     }
 
+    /// Path the texture was loaded from
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn sample_type(&self) -> SampleType {
+        self.sample_type
+    }
+
     fn nearest_sample(&self, x: Float, y: Float) -> Color {
         let x = (x as usize).min(self.width - 1);
         let y = (y as usize).min(self.height - 1);
@@ -96,4 +150,20 @@ impl AbstractTexture for ImageTexture {
             SampleType::Bilinear => self.bilinear_sample(x, y),
         }
     }
+
+    /// Hashes the pixels rather than the path, so edits to the image file are noticed
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        hasher.write_u8(self.sample_type as u8);
+        hasher.write_u64(self.width as u64);
+        hasher.write_u64(self.height as u64);
+        self.image
+            .iter()
+            .for_each(|color| hasher.write_color(*color));
+    }
+}
+
+#[test]
+fn missing_image_test() {
+    let result = ImageTexture::try_new("assets/does_not_exist.png", SampleType::Nearest);
+    assert!(matches!(result, Err(Error::Image { .. })));
 }
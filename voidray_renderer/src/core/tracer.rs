@@ -1,56 +1,615 @@
-use super::scene::SceneAcceleration;
+use super::aov::{Aov, AovSample};
+use super::medium::HenyeyGreenstein;
+use super::scene::{Object, ObjectHandle, SceneAcceleration};
 use crate::color::*;
+use crate::core::traits::{Material, Medium};
 use crate::preamble::*;
 use crate::rand::*;
 use crate::ray::*;
 use crate::settings::RenderMode;
 use crate::settings::RenderSettings;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths};
+use std::ops::{AddAssign, Mul, MulAssign};
 
+/// Trace a camera ray through the scene, following it from bounce to bounce while carrying the
+/// throughput of the path
+///
+/// After `russian_roulette_depth` bounces paths are randomly terminated with a probability based
+/// on their throughput, surviving paths are reweighted so the estimate stays unbiased.
+///
+/// Paths keep track of the medium they travel through, which may scatter them before they reach
+/// the next surface. Light scattered by a medium counts as diffuse.
+///
+/// The surface AOVs of the first hit and the split of the lighting into diffuse, specular,
+/// direct and indirect light are written to `aovs`.
 pub fn trace_ray(
     scene: &SceneAcceleration,
     settings: &RenderSettings,
     x: Float,
     y: Float,
-    rng: &mut ThreadRng,
+    rng: &mut dyn Sampler,
+    aovs: &mut AovSample,
 ) -> Color {
-    trace_ray_internal(scene, settings, &scene.camera.cast_ray(x, y, rng), 0, rng)
+    match settings.render_mode {
+        RenderMode::Spectral => trace_path::<SampledSpectrum>(scene, settings, x, y, rng, aovs),
+        _ => trace_path::<Color>(scene, settings, x, y, rng, aovs),
+    }
+}
+
+/// Throughput carried along a path, rgb or a spectrum sampled at the wavelengths of the path
+///
+/// The rgb colors of materials, lights and environments are uplifted to the throughput, and
+/// contributions are converted back to rgb once they reach the camera.
+trait Throughput:
+    Copy + Mul<Output = Self> + Mul<Float, Output = Self> + MulAssign<Float> + AddAssign
+{
+    /// State of a path the throughput depends on, the sampled wavelengths for spectra
+    type Wavelengths;
+
+    fn sample_wavelengths(rng: &mut dyn Sampler) -> Self::Wavelengths;
+
+    fn splat(value: Float) -> Self;
+
+    fn uplift(color: Color, wavelengths: &Self::Wavelengths) -> Self;
+
+    fn to_rgb(self, wavelengths: &Self::Wavelengths) -> Color;
+
+    fn max_component(&self) -> Float;
+
+    /// `Material::scatter` for this kind of throughput
+    fn scatter(
+        material: &dyn Material,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelengths: &Self::Wavelengths,
+        rng: &mut dyn Sampler,
+    ) -> (Self, Option<Ray>);
+
+    /// `Material::eval` for this kind of throughput
+    fn eval(
+        material: &dyn Material,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+        wavelengths: &Self::Wavelengths,
+    ) -> Option<(Self, Float)>;
+
+    /// Called for every material the path hits, spectral paths only keep their hero wavelength
+    /// after hitting a dispersive material
+    fn disperse(_material: &dyn Material, _wavelengths: &mut Self::Wavelengths) {}
+
+    /// Rgb light reaching the camera from `radiance` arriving along a path with this throughput
+    fn contribution(self, radiance: Color, wavelengths: &Self::Wavelengths) -> Color {
+        (self * Self::uplift(radiance, wavelengths)).to_rgb(wavelengths)
+    }
+}
+
+impl Throughput for Color {
+    type Wavelengths = ();
+
+    fn sample_wavelengths(_: &mut dyn Sampler) {}
+
+    fn splat(value: Float) -> Self {
+        GRAY(value)
+    }
+
+    fn uplift(color: Color, _: &()) -> Self {
+        color
+    }
+
+    fn to_rgb(self, _: &()) -> Color {
+        self
+    }
+
+    fn max_component(&self) -> Float {
+        Color::max_component(self)
+    }
+
+    fn scatter(
+        material: &dyn Material,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        _: &(),
+        rng: &mut dyn Sampler,
+    ) -> (Self, Option<Ray>) {
+        material.scatter(scene, ray, hit, rng)
+    }
+
+    fn eval(
+        material: &dyn Material,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+        _: &(),
+    ) -> Option<(Self, Float)> {
+        material.eval(scene, ray, hit, to_incident)
+    }
+}
+
+impl Throughput for SampledSpectrum {
+    type Wavelengths = SampledWavelengths;
+
+    fn sample_wavelengths(rng: &mut dyn Sampler) -> SampledWavelengths {
+        SampledWavelengths::sample_visible(rng.get_1d())
+    }
+
+    fn splat(value: Float) -> Self {
+        SampledSpectrum::splat(value)
+    }
+
+    fn uplift(color: Color, wavelengths: &SampledWavelengths) -> Self {
+        wavelengths.uplift(color)
+    }
+
+    fn to_rgb(self, wavelengths: &SampledWavelengths) -> Color {
+        wavelengths.to_rgb(self)
+    }
+
+    fn max_component(&self) -> Float {
+        SampledSpectrum::max_component(self)
+    }
+
+    fn scatter(
+        material: &dyn Material,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelengths: &SampledWavelengths,
+        rng: &mut dyn Sampler,
+    ) -> (Self, Option<Ray>) {
+        material.scatter_spectral(scene, ray, hit, wavelengths, rng)
+    }
+
+    fn eval(
+        material: &dyn Material,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+        wavelengths: &SampledWavelengths,
+    ) -> Option<(Self, Float)> {
+        material.eval_spectral(scene, ray, hit, to_incident, wavelengths)
+    }
+
+    fn disperse(material: &dyn Material, wavelengths: &mut SampledWavelengths) {
+        if material.dispersive() {
+            wavelengths.terminate_secondary();
+        }
+    }
 }
 
-fn trace_ray_internal(
+/// Path tracing integrator of `trace_ray`, generic over the throughput of the path
+///
+/// Spectral paths carry a set of wavelengths sampled for them. Materials with a wavelength
+/// dependent index of refraction split the path up, after which only the hero wavelength is
+/// followed.
+fn trace_path<S: Throughput>(
     scene: &SceneAcceleration,
     settings: &RenderSettings,
+    x: Float,
+    y: Float,
+    rng: &mut dyn Sampler,
+    aovs: &mut AovSample,
+) -> Color {
+    let mut wavelengths = S::sample_wavelengths(rng);
+    let mut ray = scene.camera.cast_ray(x, y, rng);
+    let mut color = BLACK;
+    let mut throughput = S::splat(1.0);
+
+    // Fraction of the light leaving the first hit which is diffuse
+    let mut diffuse = 1.0;
+
+    // Pdf with which the previous bounce sampled `ray`, or `None` if the ray wasn't sampled by
+    // an evaluable material (camera rays, perfect mirrors, ...), in which case emission is never
+    // weighted against light sampling
+    let mut bsdf_pdf = None;
+
+    // Medium the path currently travels through, and the distance it travelled since the last
+    // bounce by passing through medium interfaces
+    let mut medium = scene.medium.as_deref();
+    let mut passed = 0.0;
+
+    let mut depth = 0;
+    while depth < settings.max_bounces {
+        let hit = scene.hit_object(&ray);
+
+        // Normal renders only show the surfaces of the scene
+        let scattering_medium = medium.filter(|_| settings.render_mode != RenderMode::Normal);
+        if let Some(current) = scattering_medium {
+            let t_max = hit.as_ref().map_or(INF, |(hit, _)| hit.t);
+            let (weight, scattering) = current.sample(&ray, t_max, rng);
+            throughput = throughput * S::uplift(weight, &wavelengths);
+
+            if let Some(t) = scattering {
+                let point = ray.at(t);
+                let to_viewer = -ray.direction;
+                let phase = current.phase();
+                let direct = medium_direct_lighting(scene, current, &phase, point, &to_viewer, rng);
+                let direct = throughput
+                    .contribution(direct, &wavelengths)
+                    .clamp(settings.firefly_clamp);
+                color += direct;
+                add_lighting(aovs, diffuse, depth == 0, direct);
+
+                // The phase function is sampled exactly, so the throughput is unchanged
+                let (to_incident, pdf) = phase.sample(&to_viewer, rng.get_2d());
+                ray = Ray::new(point, to_incident);
+                bsdf_pdf = Some(pdf);
+                passed = 0.0;
+
+                match russian_roulette(settings, depth, throughput.max_component(), rng) {
+                    Some(weight) => throughput *= weight,
+                    None => break,
+                }
+                depth += 1;
+                continue;
+            }
+        }
+
+        let (hit, handle) = match hit {
+            Some(hit) => hit,
+            None => {
+                if let Some(environment) = scene.environment.as_ref() {
+                    // Weighted against the chance of having sampled the direction directly
+                    let weight = match bsdf_pdf {
+                        Some(bsdf_pdf) => {
+                            power_heuristic(bsdf_pdf, environment.pdf(&ray.direction))
+                        }
+                        None => 1.0,
+                    };
+                    let contribution =
+                        throughput.contribution(environment.sample(&ray), &wavelengths) * weight;
+                    if depth > 0 {
+                        let contribution = contribution.clamp(settings.firefly_clamp);
+                        add_lighting(aovs, diffuse, depth == 1, contribution);
+                        color += contribution;
+                    } else {
+                        color += contribution;
+                    }
+                }
+                break;
+            }
+        };
+
+        let object = scene.object_ref(handle);
+        let material = scene.material_ref(object.material);
+
+        if material.is_interface() {
+            medium = next_medium(scene, medium, object, &hit, &ray.direction, &ray.direction);
+            passed += hit.t;
+            ray = Ray::new(hit.point, ray.direction);
+            continue;
+        }
+
+        if depth == 0 {
+            diffuse = surface_aovs(scene, &ray, &hit, handle, aovs);
+        }
+
+        if let RenderMode::Normal = settings.render_mode {
+            let normal = Color(0.5 * hit.normal.normalize()) + WHITE * 0.5;
+            return color + throughput.contribution(normal, &wavelengths);
+        }
+
+        // Light leaving a transmissive object was absorbed along the way through it
+        if !hit.front_face {
+            let absorbed = material.absorption().beer_lambert(hit.t + passed);
+            throughput = throughput * S::uplift(absorbed, &wavelengths);
+        }
+
+        S::disperse(material, &mut wavelengths);
+
+        // Emission, weighted against the chance of having sampled it as a light
+        let mut emission = material.emission();
+        if let Some(bsdf_pdf) = bsdf_pdf {
+            let light_pdf = light_pdf(scene, handle, &ray, &hit, passed);
+            emission *= power_heuristic(bsdf_pdf, light_pdf);
+        }
+
+        let emission = throughput
+            .contribution(emission, &wavelengths)
+            .clamp(settings.firefly_clamp);
+        let direct = direct_lighting::<S>(
+            scene,
+            material,
+            object,
+            medium,
+            &ray,
+            &hit,
+            &wavelengths,
+            rng,
+        );
+        let direct = (throughput * direct)
+            .to_rgb(&wavelengths)
+            .clamp(settings.firefly_clamp);
+        color += emission + direct;
+
+        // Emission reaches the camera after `depth` bounces, direct lighting after one more
+        if depth == 0 {
+            aovs.set(Aov::Emission, emission);
+        } else {
+            add_lighting(aovs, diffuse, depth == 1, emission);
+        }
+        add_lighting(aovs, diffuse, depth == 0, direct);
+
+        // Indirect lighting, continue the path if light was scattered
+        let (attenuation, scattered) = S::scatter(material, scene, &ray, &hit, &wavelengths, rng);
+        let scattered = match scattered {
+            Some(scattered) => scattered,
+            None => break,
+        };
+        bsdf_pdf = S::eval(
+            material,
+            scene,
+            &ray,
+            &hit,
+            &scattered.direction,
+            &wavelengths,
+        )
+        .map(|(_, pdf)| pdf);
+        throughput = throughput * attenuation;
+        medium = next_medium(
+            scene,
+            medium,
+            object,
+            &hit,
+            &ray.direction,
+            &scattered.direction,
+        );
+        passed = 0.0;
+        ray = scattered;
+
+        match russian_roulette(settings, depth, throughput.max_component(), rng) {
+            Some(weight) => throughput *= weight,
+            None => break,
+        }
+        depth += 1;
+    }
+
+    color
+}
+
+/// Write the surface AOVs of the first hit of a path, returns the diffuse fraction of the hit
+fn surface_aovs(
+    scene: &SceneAcceleration,
     ray: &Ray,
+    hit: &HitRecord,
+    handle: ObjectHandle,
+    aovs: &mut AovSample,
+) -> Float {
+    let object = scene.object_ref(handle);
+    let material = scene.material_ref(object.material);
+    let normal = material.shading_normal(scene, hit);
+    let uv = hit.uv;
+    aovs.set(Aov::Albedo, material.albedo(scene, hit));
+    aovs.set(Aov::Normal, Color(normal));
+    aovs.set(Aov::Depth, GRAY((hit.point - ray.origin).magnitude()));
+    aovs.set(Aov::Position, Color(hit.point));
+    aovs.set(Aov::Uv, Color::new(uv.x, uv.y, 0.0));
+    aovs.set(Aov::ObjectId, GRAY((handle.0 + 1) as Float));
+    aovs.set(Aov::MaterialId, GRAY((object.material.0 + 1) as Float));
+    material.diffuse_fraction(scene, hit).clamp(0.0, 1.0)
+}
+
+/// Add light which reached the camera to the lighting AOVs, split by the diffuse fraction of the
+/// first hit
+fn add_lighting(aovs: &mut AovSample, diffuse: Float, direct: bool, contribution: Color) {
+    let (diffuse_aov, specular_aov) = if direct {
+        (Aov::DiffuseDirect, Aov::SpecularDirect)
+    } else {
+        (Aov::DiffuseIndirect, Aov::SpecularIndirect)
+    };
+    aovs.add(diffuse_aov, contribution * diffuse);
+    aovs.add(specular_aov, contribution * (1.0 - diffuse));
+}
+
+/// Randomly terminate paths after `russian_roulette_depth` bounces with a probability based on
+/// their throughput, returns the weight of a surviving path or `None` if it was terminated
+fn russian_roulette(
+    settings: &RenderSettings,
     depth: u32,
-    rng: &mut ThreadRng,
+    throughput: Float,
+    rng: &mut dyn Sampler,
+) -> Option<Float> {
+    if depth + 1 < settings.russian_roulette_depth {
+        return Some(1.0);
+    }
+    let survival = throughput.min(1.0);
+    if survival <= 0.0 || rng.get_1d() >= survival {
+        None
+    } else {
+        Some(1.0 / survival)
+    }
+}
+
+/// Pdf of having sampled a hit on a light through light sampling from the last bounce of the
+/// path, which lies `passed` before the origin of the ray if it went through medium interfaces
+fn light_pdf(
+    scene: &SceneAcceleration,
+    handle: ObjectHandle,
+    ray: &Ray,
+    hit: &HitRecord,
+    passed: Float,
+) -> Float {
+    let hit = HitRecord {
+        t: hit.t + passed,
+        ..hit.clone()
+    };
+    scene.lights().pdf(handle, ray, &hit)
+}
+
+/// Medium a path travels through after it arrived at a hit along `incoming` and left it along
+/// `outgoing`
+///
+/// Paths which stay on the same side of the surface keep their medium, entering an object puts
+/// them into its interior and leaving one into the medium of the scene. Media of nested objects
+/// aren't tracked, leaving an object always returns to the medium of the scene.
+fn next_medium<'a>(
+    scene: &'a SceneAcceleration,
+    current: Option<&'a dyn Medium>,
+    object: &Object,
+    hit: &HitRecord,
+    incoming: &Vec3,
+    outgoing: &Vec3,
+) -> Option<&'a dyn Medium> {
+    let outward = if hit.front_face {
+        hit.normal
+    } else {
+        -hit.normal
+    };
+    let from_outside = incoming.dot(outward) < 0.0;
+    let to_outside = outgoing.dot(outward) > 0.0;
+
+    if from_outside == to_outside {
+        current
+    } else if to_outside {
+        scene.medium.as_deref()
+    } else {
+        object.interior.map(|handle| scene.medium_ref(handle))
+    }
+}
+
+/// Fraction of the light from the point `distance` along a unit `direction` which arrives at
+/// `origin`, passing through medium interfaces and the media behind them
+fn transmittance(
+    scene: &SceneAcceleration,
+    medium: Option<&dyn Medium>,
+    origin: Vec3,
+    direction: Vec3,
+    distance: Float,
+    rng: &mut dyn Sampler,
 ) -> Color {
-    let mut color = BLACK; // emittance component
-
-    if depth < settings.max_bounces {
-        match scene.hit(ray) {
-            None => match scene.environment.as_ref() {
-                None => return BLACK,
-                Some(environment) => return environment.sample(ray),
-            },
-            Some((hit, object)) => {
-                let material = scene.material_ref(object.material);
-
-                // Different render modes
-                let (attenuation, scattered) = match settings.render_mode {
-                    RenderMode::Full => material.scatter(scene, ray, &hit, rng),
-                    RenderMode::Normal => (Color(0.5 * hit.normal.normalize()) + WHITE * 0.5, None),
-                };
-
-                // Whether or not light was scattered and should be recursively traced
-                let delta_color = if let Some(scattered) = scattered {
-                    attenuation * trace_ray_internal(scene, settings, &scattered, depth + 1, rng)
-                } else {
-                    attenuation
-                };
-
-                color += delta_color.clamp(settings.firefly_clamp);
-            }
+    // Without media light is either blocked or not
+    if !scene.has_media() {
+        return if scene.occluded(origin, direction, distance) {
+            BLACK
+        } else {
+            WHITE
+        };
+    }
+
+    let mut medium = medium;
+    let mut transmittance = WHITE;
+    let mut ray = Ray::new(origin, direction);
+    let mut remaining = distance * (1.0 - 1.0e-4);
+    loop {
+        let hit = scene.hit_object_within(&ray, remaining);
+        if let Some(medium) = medium {
+            let t_max = hit.as_ref().map_or(remaining, |(hit, _)| hit.t);
+            transmittance = transmittance * medium.transmittance(&ray, t_max, rng);
+        }
+
+        let (hit, handle) = match hit {
+            Some(hit) => hit,
+            None => return transmittance,
+        };
+        let object = scene.object_ref(handle);
+        if !scene.material_ref(object.material).is_interface()
+            || transmittance.max_component() <= 0.0
+        {
+            return BLACK;
+        }
+
+        medium = next_medium(scene, medium, object, &hit, &direction, &direction);
+        remaining -= hit.t;
+        ray = Ray::new(hit.point, direction);
+    }
+}
+
+/// Estimate direct lighting at a scattering event in a medium, like `direct_lighting` with the
+/// phase function in place of a BSDF
+fn medium_direct_lighting(
+    scene: &SceneAcceleration,
+    medium: &dyn Medium,
+    phase: &HenyeyGreenstein,
+    point: Vec3,
+    to_viewer: &Vec3,
+    rng: &mut dyn Sampler,
+) -> Color {
+    let mut color = BLACK;
+
+    if let Some(light) = scene.lights().sample(scene, point, rng) {
+        let pdf = phase.eval(to_viewer, &light.direction);
+        let tr = transmittance(
+            scene,
+            Some(medium),
+            point,
+            light.direction,
+            light.distance,
+            rng,
+        );
+        let weight = power_heuristic(light.pdf, pdf);
+        color += tr * light.emission * (pdf * weight / light.pdf);
+    }
+
+    if let Some(environment) = scene.environment.as_ref() {
+        if let Some((direction, light_pdf)) = environment.sample_direction(rng) {
+            let pdf = phase.eval(to_viewer, &direction);
+            let tr = transmittance(scene, Some(medium), point, direction, INF, rng);
+            let emission = environment.sample(&Ray::new(point, direction));
+            let weight = power_heuristic(light_pdf, pdf);
+            color += tr * emission * (pdf * weight / light_pdf);
         }
     }
 
     color
 }
+
+/// Power heuristic for multiple importance sampling, weight of a sample from strategy `a`
+#[inline]
+fn power_heuristic(pdf_a: Float, pdf_b: Float) -> Float {
+    let (a2, b2) = (pdf_a * pdf_a, pdf_b * pdf_b);
+    if a2 + b2 > 0.0 {
+        a2 / (a2 + b2)
+    } else {
+        1.0
+    }
+}
+
+/// Estimate direct lighting at a hit by sampling a point on a light and a direction toward the
+/// environment, each weighted against BSDF sampling
+#[allow(clippy::too_many_arguments)]
+fn direct_lighting<S: Throughput>(
+    scene: &SceneAcceleration,
+    material: &dyn Material,
+    object: &Object,
+    medium: Option<&dyn Medium>,
+    ray: &Ray,
+    hit: &HitRecord,
+    wavelengths: &S::Wavelengths,
+    rng: &mut dyn Sampler,
+) -> S {
+    let mut radiance = S::splat(0.0);
+
+    if let Some(light) = scene.lights().sample(scene, hit.point, rng) {
+        let to_light = &light.direction;
+        if let Some((f, pdf)) = S::eval(material, scene, ray, hit, to_light, wavelengths) {
+            if f.max_component() > 0.0 {
+                let medium = next_medium(scene, medium, object, hit, &ray.direction, to_light);
+                let tr = transmittance(scene, medium, hit.point, *to_light, light.distance, rng);
+                let weight = power_heuristic(light.pdf, pdf);
+                radiance += f * S::uplift(tr * light.emission, wavelengths) * (weight / light.pdf);
+            }
+        }
+    }
+
+    if let Some(environment) = scene.environment.as_ref() {
+        if let Some((direction, light_pdf)) = environment.sample_direction(rng) {
+            if let Some((f, pdf)) = S::eval(material, scene, ray, hit, &direction, wavelengths) {
+                if f.max_component() > 0.0 {
+                    let medium =
+                        next_medium(scene, medium, object, hit, &ray.direction, &direction);
+                    let tr = transmittance(scene, medium, hit.point, direction, INF, rng);
+                    let emission = environment.sample(&Ray::new(hit.point, direction));
+                    let weight = power_heuristic(light_pdf, pdf);
+                    radiance += f * S::uplift(tr * emission, wavelengths) * (weight / light_pdf);
+                }
+            }
+        }
+    }
+
+    radiance
+}
@@ -1,59 +1,330 @@
+use std::any::Any;
+
 use crate::aabb::Bounded;
 use crate::color::*;
+use crate::hash::StableHasher;
+use crate::medium::HenyeyGreenstein;
 use crate::preamble::*;
 use crate::rand::*;
 use crate::ray::*;
 use crate::scene::MeshHandle;
 use crate::scene::SceneAcceleration;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths};
 
 /// BSDF material trait
+///
+/// The normal passed to these functions is the outward facing geometric normal, so
+/// materials can tell whether `to_viewer` lies inside or outside of the surface.
 pub trait BSDFMaterial: Send + Sync {
-    /// Bidirectional scattering distribution function
-    fn bsdf(&self, normal: &Vec3, to_viewer: &Vec3, to_incident: &Vec3) -> Color;
+    /// Bidirectional scattering distribution function. `tangent` is the surface direction
+    /// anisotropic materials are stretched along, it may be zero if the surface has none.
+    fn bsdf(&self, normal: &Vec3, tangent: &Vec3, to_viewer: &Vec3, to_incident: &Vec3) -> Color;
 
     /// Importance sample function for the light hemisphere, returns (to_incident, pdf)
-    fn sample(&self, normal: &Vec3, to_viewer: &Vec3, rng: &mut ThreadRng)
-        -> Option<(Vec3, Float)>;
+    fn sample(
+        &self,
+        normal: &Vec3,
+        tangent: &Vec3,
+        to_viewer: &Vec3,
+        rng: &mut dyn Sampler,
+    ) -> Option<(Vec3, Float)>;
+
+    /// Solid angle probability density with which `sample` returns `to_incident`
+    fn pdf(&self, normal: &Vec3, tangent: &Vec3, to_viewer: &Vec3, to_incident: &Vec3) -> Float;
+
+    /// Radiance emitted by the surface
+    fn emittance(&self) -> Color {
+        BLACK
+    }
+
+    /// Surface color, written to the albedo AOV
+    fn base_color(&self) -> Color {
+        WHITE
+    }
+
+    /// Fraction of the scattered light which is diffuse rather than specular
+    fn diffuse_weight(&self) -> Float {
+        1.0
+    }
+
+    /// Absorption coefficient of the interior of a transmissive material, see
+    /// `Material::absorption`
+    fn interior_absorption(&self) -> Color {
+        BLACK
+    }
+
+    /// Copy of the material with its properties at a single wavelength in nanometers, for
+    /// materials whose index of refraction depends on the wavelength. Materials without
+    /// dispersion return `None`.
+    fn at_wavelength(&self, wavelength: Float) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Whether `at_wavelength` returns a material, checked on every hit of a spectral path so it
+    /// has to be cheap
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    /// Feed the parameters of the material into the hasher, see `Material::hash_parameters`
+    fn hash_parameters(&self, hasher: &mut StableHasher) {}
+}
+
+/// Returns the outward facing normal of a hit, regardless of the side it was hit from
+fn outward_normal(hit: &HitRecord) -> Vec3 {
+    if hit.front_face {
+        hit.normal
+    } else {
+        -hit.normal
+    }
 }
 
 impl<M> Material for M
 where
-    M: BSDFMaterial,
+    M: BSDFMaterial + 'static,
 {
     fn scatter(
         &self,
         scene: &SceneAcceleration,
         ray: &Ray,
         hit: &HitRecord,
-        rng: &mut ThreadRng,
+        rng: &mut dyn Sampler,
     ) -> (Color, Option<Ray>) {
-        let wo = ray.direction.normalize();
-        if let Some((wi, pdf)) = self.sample(&hit.normal, &wo, rng) {
-            let f = self.bsdf(&hit.normal, &wo, &wi);
+        let n = outward_normal(hit);
+        let wo = -ray.direction.normalize();
+        if let Some((wi, pdf)) = self.sample(&n, &hit.tangent, &wo, rng) {
+            let f = self.bsdf(&n, &hit.tangent, &wo, &wi);
             let ray = Ray::new(ray.at(hit.t), wi);
-            let indirect = f * wi.dot(hit.normal).abs() * (1.0 / pdf);
+            let indirect = f * wi.dot(n).abs() * (1.0 / pdf);
 
             (indirect, Some(ray))
         } else {
             (hex_color(0x000000), None)
         }
     }
+
+    fn eval(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+    ) -> Option<(Color, Float)> {
+        let n = outward_normal(hit);
+        let wo = -ray.direction.normalize();
+        let f = self.bsdf(&n, &hit.tangent, &wo, to_incident) * to_incident.dot(n).abs();
+
+        Some((f, self.pdf(&n, &hit.tangent, &wo, to_incident)))
+    }
+
+    fn emission(&self) -> Color {
+        self.emittance()
+    }
+
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
+        self.base_color()
+    }
+
+    fn diffuse_fraction(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Float {
+        self.diffuse_weight()
+    }
+
+    fn absorption(&self) -> Color {
+        self.interior_absorption()
+    }
+
+    fn dispersive(&self) -> bool {
+        self.is_dispersive()
+    }
+
+    fn hash_parameters(&self, hasher: &mut StableHasher) {
+        BSDFMaterial::hash_parameters(self, hasher)
+    }
+
+    /// Dispersive materials scatter the hero wavelength, the others are terminated by the tracer
+    fn scatter_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelengths: &SampledWavelengths,
+        rng: &mut dyn Sampler,
+    ) -> (SampledSpectrum, Option<Ray>) {
+        let (attenuation, scattered) = match self.at_wavelength(wavelengths.hero()) {
+            Some(material) => material.scatter(scene, ray, hit, rng),
+            None => self.scatter(scene, ray, hit, rng),
+        };
+        (wavelengths.uplift(attenuation), scattered)
+    }
+
+    fn eval_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+        wavelengths: &SampledWavelengths,
+    ) -> Option<(SampledSpectrum, Float)> {
+        let (f, pdf) = match self.at_wavelength(wavelengths.hero()) {
+            Some(material) => material.eval(scene, ray, hit, to_incident),
+            None => self.eval(scene, ray, hit, to_incident),
+        }?;
+        Some((wavelengths.uplift(f), pdf))
+    }
+}
+
+/// Allows trait objects to be downcast to their concrete type, e.g. when serializing a scene
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T> AsAny for T
+where
+    T: Any,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
-pub trait Material: Send + Sync {
+pub trait Material: AsAny + Send + Sync {
+    /// Sample an outgoing ray, returns the attenuation (BSDF * cosine / pdf) along with the
+    /// scattered ray, or `None` if the ray is absorbed
     fn scatter(
         &self,
         scene: &SceneAcceleration,
         ray: &Ray,
         hit: &HitRecord,
-        rng: &mut ThreadRng,
+        rng: &mut dyn Sampler,
     ) -> (Color, Option<Ray>);
+
+    /// Evaluate the material for light arriving from `to_incident`, returns (BSDF * cosine, pdf)
+    /// where the pdf is the solid angle density with which `scatter` would have chosen that
+    /// direction. Materials which can't be evaluated (e.g. perfect mirrors) return `None` and
+    /// are not used for direct light sampling.
+    fn eval(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+    ) -> Option<(Color, Float)> {
+        None
+    }
+
+    /// Radiance emitted by the surface, objects with a non-black emission are used as lights
+    fn emission(&self) -> Color {
+        BLACK
+    }
+
+    /// Surface color at a hit, written to the albedo AOV
+    fn albedo(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Color {
+        WHITE
+    }
+
+    /// Normal used for shading at a hit, e.g. taken from a normal map
+    fn shading_normal(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Vec3 {
+        hit.normal
+    }
+
+    /// Fraction of the light scattered at a hit which is diffuse rather than specular, used to
+    /// split the lighting AOVs
+    fn diffuse_fraction(&self, scene: &SceneAcceleration, hit: &HitRecord) -> Float {
+        1.0
+    }
+
+    /// Invisible boundary of a participating medium, rays pass straight through it without
+    /// counting as a bounce
+    fn is_interface(&self) -> bool {
+        false
+    }
+
+    /// Absorption coefficient of the interior of a transmissive material, in units of inverse
+    /// scene distance. Light leaving the object through its back face is attenuated by the
+    /// Beer-Lambert law over the distance it travelled inside, so thicker parts get darker.
+    fn absorption(&self) -> Color {
+        BLACK
+    }
+
+    /// Whether the direction light is scattered in depends on its wavelength, e.g. for glass
+    /// with dispersion. Spectral paths only keep their hero wavelength after hitting a
+    /// dispersive material.
+    fn dispersive(&self) -> bool {
+        false
+    }
+
+    /// Feed every parameter which changes how the material looks into the hasher, so that a
+    /// render doesn't resume from a checkpoint after the material was edited, see `render_hash`
+    fn hash_parameters(&self, hasher: &mut StableHasher) {}
+
+    /// Spectral version of `scatter` for the spectral render mode, by default the rgb
+    /// attenuation is uplifted to a spectrum
+    fn scatter_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelengths: &SampledWavelengths,
+        rng: &mut dyn Sampler,
+    ) -> (SampledSpectrum, Option<Ray>) {
+        let (attenuation, scattered) = self.scatter(scene, ray, hit, rng);
+        (wavelengths.uplift(attenuation), scattered)
+    }
+
+    /// Spectral version of `eval` for the spectral render mode, by default the rgb result is
+    /// uplifted to a spectrum
+    fn eval_spectral(
+        &self,
+        scene: &SceneAcceleration,
+        ray: &Ray,
+        hit: &HitRecord,
+        to_incident: &Vec3,
+        wavelengths: &SampledWavelengths,
+    ) -> Option<(SampledSpectrum, Float)> {
+        let (f, pdf) = self.eval(scene, ray, hit, to_incident)?;
+        Some((wavelengths.uplift(f), pdf))
+    }
+}
+
+/// A participating medium which absorbs and scatters the light traveling through it, e.g. fog
+/// or smoke
+///
+/// Media fill the interior of objects or the space around them, see `Object::interior` and
+/// `Scene::medium`. Ray parameters are in units of the ray direction, which may not be normalized.
+pub trait Medium: AsAny + Send + Sync {
+    /// Sample the distance to the next scattering event along the ray before `t_max`, returns
+    /// the weight the throughput of the path is multiplied by along with the ray parameter of
+    /// the event, or `None` if the ray passed through the medium
+    fn sample(&self, ray: &Ray, t_max: Float, rng: &mut dyn Sampler) -> (Color, Option<Float>);
+
+    /// Fraction of the light passing through the medium along the ray up to `t_max`, which may
+    /// be a stochastic estimate
+    fn transmittance(&self, ray: &Ray, t_max: Float, rng: &mut dyn Sampler) -> Color;
+
+    /// Distribution of the directions light is scattered in
+    fn phase(&self) -> HenyeyGreenstein;
+
+    /// Feed the parameters of the medium into the hasher, see `Material::hash_parameters`
+    fn hash_parameters(&self, hasher: &mut StableHasher) {}
 }
 
 /// A surface defined mathematically, not through a mesh
-pub trait AnalyticSurface: Bounded + Send + Sync {
+pub trait AnalyticSurface: Bounded + AsAny + Send + Sync {
     /// Function describing the intersection function for the surface
     fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord>;
+
+    /// Surface area, surfaces which return `None` can't be sampled as lights
+    fn area(&self) -> Option<Float> {
+        None
+    }
+
+    /// Uniformly sample a point on the surface, returns (point, outward normal)
+    fn sample_point(&self, rng: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -63,7 +334,22 @@ pub enum Surface {
 }
 
 /// A background environment for the scene, describing the behaviour of escaped rays
-pub trait Environment: Send + Sync {
+pub trait Environment: AsAny + Send + Sync {
     /// Sample the enviroment along the ray direction
     fn sample(&self, ray: &Ray) -> Color;
+
+    /// Importance sample a direction toward the environment, returns (direction, pdf) where
+    /// the pdf is with respect to solid angle. Environments which return `None` are not used
+    /// for direct light sampling.
+    fn sample_direction(&self, rng: &mut dyn Sampler) -> Option<(Vec3, Float)> {
+        None
+    }
+
+    /// Solid angle probability density with which `sample_direction` returns `direction`
+    fn pdf(&self, direction: &Vec3) -> Float {
+        0.0
+    }
+
+    /// Feed the parameters of the environment into the hasher, see `Material::hash_parameters`
+    fn hash_parameters(&self, hasher: &mut StableHasher) {}
 }
@@ -0,0 +1,59 @@
+use std::fmt;
+
+/// Errors which can occur while loading assets from disk or writing checkpoints to it
+#[derive(Debug)]
+pub enum Error {
+    /// The file could not be opened or read
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// The file could not be created or written
+    Write {
+        path: String,
+        source: std::io::Error,
+    },
+    /// The file could not be decoded as an image
+    Image {
+        path: String,
+        source: image::ImageError,
+    },
+    /// The file could not be parsed as a Wavefront OBJ mesh
+    Obj { path: String, source: obj::ObjError },
+}
+
+impl Error {
+    /// Path of the file which failed to load
+    pub fn path(&self) -> &str {
+        match self {
+            Error::Io { path, .. }
+            | Error::Write { path, .. }
+            | Error::Image { path, .. }
+            | Error::Obj { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "failed to read '{}': {}", path, source),
+            Error::Write { path, source } => write!(f, "failed to write '{}': {}", path, source),
+            Error::Image { path, source } => {
+                write!(f, "failed to load image '{}': {}", path, source)
+            }
+            Error::Obj { path, source } => write!(f, "failed to load mesh '{}': {}", path, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Write { source, .. } => Some(source),
+            Error::Image { source, .. } => Some(source),
+            Error::Obj { source, .. } => Some(source),
+        }
+    }
+}
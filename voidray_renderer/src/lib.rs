@@ -1,16 +1,19 @@
 #![allow(dead_code, unused_variables)]
 
 mod core;
+mod error;
 pub mod graphics;
 pub mod render;
 mod util;
 
 pub use crate::core::*;
+pub use error::Error;
 pub use cgmath;
 pub use util::*;
 
 /// Std rand imports
 pub mod rand {
+    pub use crate::core::sampler::Sampler;
     pub use rand::prelude::*;
     pub use rand_distr;
 }
@@ -0,0 +1,341 @@
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use super::image::HdrImage;
+use super::layers::AovBuffer;
+use super::variance::{PixelMoments, VarianceBuffer};
+use crate::aabb::Bounded;
+use crate::aov::Aov;
+use crate::hash::StableHasher;
+use crate::preamble::*;
+use crate::scene::Scene;
+use crate::settings::RenderSettings;
+use crate::texture::AbstractTexture;
+use crate::traits::Surface;
+use crate::Error;
+
+const MAGIC: &[u8; 4] = b"VRCP";
+const VERSION: u32 = 1;
+
+/// Accumulated state of an in-progress render, which can be written to disk and resumed from
+///
+/// Samples are seeded by their pixel and sample index, so a resumed render continues with the
+/// random sequences of the samples it hasn't taken yet instead of repeating earlier ones.
+pub struct Checkpoint {
+    /// Hash of the scene, settings and dimensions of the render, see `render_hash`
+    pub hash: u64,
+    /// Number of samples per pixel taken so far
+    pub samples: u32,
+    /// Unresolved beauty buffer, with the filter weights in alpha
+    pub image: HdrImage,
+    pub variance: VarianceBuffer,
+    pub aovs: AovBuffer,
+}
+
+impl Checkpoint {
+    /// Write the checkpoint to a temporary file which then replaces `path`, so a crash while
+    /// saving never destroys the previous checkpoint
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        self.write(path).map_err(|source| Error::Write {
+            path: path.display().to_string(),
+            source,
+        })
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(std::mem::size_of::<Float>() as u32).to_le_bytes())?;
+        writer.write_all(&self.hash.to_le_bytes())?;
+        writer.write_all(&self.samples.to_le_bytes())?;
+        for x in self.image.dimensions() {
+            writer.write_all(&x.to_le_bytes())?;
+        }
+
+        write_floats(&mut writer, self.image.as_slice())?;
+        for pixel in self.variance.as_slice() {
+            let (samples, mean, m2, converged) = pixel.to_parts();
+            writer.write_all(&samples.to_le_bytes())?;
+            writer.write_all(&mean.to_le_bytes())?;
+            writer.write_all(&m2.to_le_bytes())?;
+            writer.write_all(&[converged as u8])?;
+        }
+
+        writer.write_all(&(self.aovs.aovs().len() as u32).to_le_bytes())?;
+        for (aov, layer) in self.aovs.layers() {
+            writer.write_all(&(aov as u32).to_le_bytes())?;
+            write_floats(&mut writer, layer.as_slice())?;
+        }
+
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        std::fs::rename(&temporary, path)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+            return Err(invalid_data("not a voidray checkpoint"));
+        }
+        if read_u32(&mut reader)? != std::mem::size_of::<Float>() as u32 {
+            return Err(invalid_data(
+                "checkpoint was saved with a different float precision",
+            ));
+        }
+
+        let mut hash = [0; 8];
+        reader.read_exact(&mut hash)?;
+        let hash = u64::from_le_bytes(hash);
+        let samples = read_u32(&mut reader)?;
+        let dimensions = [read_u32(&mut reader)?, read_u32(&mut reader)?];
+        let pixels = dimensions[0] as usize * dimensions[1] as usize;
+
+        let image = HdrImage::from_raw(dimensions, read_floats(&mut reader, pixels * 4)?);
+
+        let mut variance = VarianceBuffer::new(dimensions);
+        for pixel in variance.as_slice_mut() {
+            let samples = read_u32(&mut reader)?;
+            let mean = read_float(&mut reader)?;
+            let m2 = read_float(&mut reader)?;
+            let mut converged = [0];
+            reader.read_exact(&mut converged)?;
+            *pixel = PixelMoments::from_parts((samples, mean, m2, converged[0] != 0));
+        }
+
+        let mut layers = Vec::new();
+        for _ in 0..read_u32(&mut reader)? {
+            let aov = *Aov::ALL
+                .get(read_u32(&mut reader)? as usize)
+                .ok_or_else(|| invalid_data("unknown AOV in checkpoint"))?;
+            let layer = HdrImage::from_raw(dimensions, read_floats(&mut reader, pixels * 4)?);
+            layers.push((aov, layer));
+        }
+
+        Ok(Self {
+            hash,
+            samples,
+            image,
+            variance,
+            aovs: AovBuffer::from_layers(dimensions, layers),
+        })
+    }
+
+    /// Load the checkpoint at `path` if there is one and it was saved by a render with the given
+    /// hash, a render without a usable checkpoint starts from scratch
+    ///
+    /// A missing checkpoint or one of a different render isn't an error, a checkpoint which
+    /// can't be read is.
+    pub fn resume<P: AsRef<Path>>(path: P, hash: u64) -> Result<Option<Self>, Error> {
+        let path = path.as_ref();
+        match Checkpoint::load(path) {
+            Ok(checkpoint) if checkpoint.hash == hash => {
+                println!(
+                    "resuming from checkpoint '{}' at {} samples",
+                    path.display(),
+                    checkpoint.samples
+                );
+                Ok(Some(checkpoint))
+            }
+            Ok(_) => {
+                println!(
+                    "checkpoint '{}' belongs to a different render, starting over",
+                    path.display()
+                );
+                Ok(None)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(Error::Io {
+                path: path.display().to_string(),
+                source,
+            }),
+        }
+    }
+}
+
+/// Hash of everything that changes the result of a render, a checkpoint can only be resumed by
+/// a render with the same hash
+///
+/// Materials, textures, environments and media are trait objects, they feed their parameters
+/// into the hash through their `hash_parameters` methods. Meshes are hashed by their buffers,
+/// analytic surfaces by their bounds.
+pub fn render_hash(scene: &Scene, settings: &RenderSettings, dimensions: [u32; 2]) -> u64 {
+    let mut hasher = StableHasher::default();
+
+    hasher.write_u32(dimensions[0]);
+    hasher.write_u32(dimensions[1]);
+
+    let camera = &scene.camera;
+    hasher.write_floats(&[camera.eye.x, camera.eye.y, camera.eye.z]);
+    hasher.write_floats(&[camera.direction.x, camera.direction.y, camera.direction.z]);
+    hasher.write_floats(&[camera.up.x, camera.up.y, camera.up.z, camera.fov]);
+    if let Some((aperture, focus)) = camera.dof {
+        hasher.write_floats(&[aperture, focus.x, focus.y, focus.z]);
+    }
+
+    for object in &scene.objects {
+        hasher.write(object.name().as_bytes());
+        let object = object.object();
+        hasher.write_u64(object.surface.0 as u64);
+        hasher.write_u64(object.material.0 as u64);
+        if let Some(transform) = &object.transform {
+            let matrix: &[Float; 16] = transform.matrix().as_ref();
+            hasher.write_floats(matrix);
+        }
+        if let Some(interior) = object.interior {
+            hasher.write_u64(interior.0 as u64);
+        }
+    }
+    for surface in &scene.surfaces {
+        hasher.write(surface.name().as_bytes());
+        match surface.object() {
+            Surface::Analytic(surface) => {
+                let bounds = surface.bounds();
+                hasher.write_floats(&[bounds.min.x, bounds.min.y, bounds.min.z]);
+                hasher.write_floats(&[bounds.max.x, bounds.max.y, bounds.max.z]);
+            }
+            Surface::Mesh(handle) => scene.mesh_ref(*handle).hash_parameters(&mut hasher),
+        }
+    }
+    for material in &scene.materials {
+        hasher.write(material.name().as_bytes());
+        material.object().hash_parameters(&mut hasher);
+    }
+    for texture in &scene.textures {
+        hasher.write(texture.name().as_bytes());
+        texture.object().hash_parameters(&mut hasher);
+    }
+    for medium in &scene.media {
+        hasher.write(medium.name().as_bytes());
+        medium.object().hash_parameters(&mut hasher);
+    }
+    hasher.write_u8(scene.environment.is_some() as u8);
+    if let Some(environment) = &scene.environment {
+        environment.hash_parameters(&mut hasher);
+    }
+    if let Some(medium) = scene.medium {
+        hasher.write_u64(medium.0 as u64);
+    }
+
+    hasher.write(format!("{:?}", settings.render_mode).as_bytes());
+    hasher.write(format!("{:?}", settings.sampler).as_bytes());
+    hasher.write(format!("{:?}", settings.filter).as_bytes());
+    hasher.write_u32(settings.total_samples);
+    hasher.write_u32(settings.max_bounces);
+    hasher.write_u32(settings.russian_roulette_depth);
+    hasher.write_u64(settings.seed);
+    hasher.write_floats(&[settings.firefly_clamp, settings.filter_radius]);
+    if let Some(region) = settings.region {
+        region.iter().for_each(|x| hasher.write_u32(*x));
+    }
+    settings
+        .aovs
+        .iter()
+        .for_each(|aov| hasher.write_u32(*aov as u32));
+
+    hasher.finish()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn write_floats(writer: &mut impl Write, values: &[Float]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|x| writer.write_all(&x.to_le_bytes()))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_float(reader: &mut impl Read) -> io::Result<Float> {
+    let mut bytes = [0; std::mem::size_of::<Float>()];
+    reader.read_exact(&mut bytes)?;
+    Ok(Float::from_le_bytes(bytes))
+}
+
+fn read_floats(reader: &mut impl Read, count: usize) -> io::Result<Vec<Float>> {
+    (0..count).map(|_| read_float(reader)).collect()
+}
+
+#[test]
+fn checkpoint_roundtrip_test() {
+    use crate::color::GRAY;
+
+    let dimensions = [3, 2];
+    let mut image = HdrImage::new(dimensions);
+    image
+        .as_slice_mut()
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, x)| *x = i as Float * 0.5);
+    let mut variance = VarianceBuffer::new(dimensions);
+    variance.as_slice_mut()[4].add(GRAY(2.0));
+    let mut albedo = HdrImage::new(dimensions);
+    albedo.as_slice_mut()[7] = 3.0;
+
+    let checkpoint = Checkpoint {
+        hash: 0xdeadbeef,
+        samples: 7,
+        image,
+        variance,
+        aovs: AovBuffer::from_layers(dimensions, vec![(Aov::Albedo, albedo)]),
+    };
+
+    let path = std::env::temp_dir().join(format!("voidray_checkpoint_{}.bin", std::process::id()));
+    checkpoint.save(&path).unwrap();
+    let loaded = Checkpoint::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.hash, checkpoint.hash);
+    assert_eq!(loaded.samples, 7);
+    assert_eq!(loaded.image.as_slice(), checkpoint.image.as_slice());
+    assert_eq!(loaded.variance.as_slice()[4].samples(), 1);
+    assert_eq!(loaded.variance.as_slice()[0].samples(), 0);
+    assert_eq!(loaded.aovs.aovs(), &[Aov::Albedo]);
+    assert_eq!(loaded.aovs.layer(Aov::Albedo).unwrap().as_slice()[7], 3.0);
+
+    assert!(Checkpoint::resume(&path, checkpoint.hash)
+        .unwrap()
+        .is_none());
+
+    // Files which aren't checkpoints can't be resumed from
+    std::fs::write(&path, b"not a checkpoint").unwrap();
+    let result = Checkpoint::resume(&path, checkpoint.hash);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(Error::Io { .. })));
+}
+
+#[test]
+fn render_hash_mesh_test() {
+    use crate::mesh::{Mesh, Vertex};
+
+    let hash = |corner: Float| {
+        let mut scene = Scene::empty();
+        let vertices = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [corner, corner]]
+            .into_iter()
+            .map(|[x, y]| Vertex::position(vec3!(x, y, 0.0)))
+            .collect();
+        let indices = vec![0, 1, 2, 1, 3, 2];
+        scene.add_mesh(Arc::new(Mesh::from_buffers(vertices, indices)));
+        render_hash(&scene, &RenderSettings::default(), [4, 4])
+    };
+
+    // Moving a vertex without changing the bounds of the mesh changes the hash
+    assert_eq!(hash(1.0), hash(1.0));
+    assert_ne!(hash(1.0), hash(0.8));
+}
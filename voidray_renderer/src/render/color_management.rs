@@ -0,0 +1,86 @@
+use super::image::HdrImage;
+use crate::preamble::*;
+use crate::settings::{ColorManagementSettings, Tonemap};
+use rayon::prelude::*;
+
+/// CPU implementation of the post processing shader, applies exposure, tonemapping and gamma
+/// correction to a linear image
+pub fn color_manage(image: &HdrImage, settings: &ColorManagementSettings) -> HdrImage {
+    let mut result = image.clone();
+    let exposure = (2.0 as Float).powf(settings.exposure as Float);
+    let gamma = settings.gamma as Float;
+
+    result
+        .as_slice_mut()
+        .par_chunks_exact_mut(4)
+        .for_each(|pixel| {
+            let color = vec3!(pixel[0], pixel[1], pixel[2]) * exposure;
+            let color = tonemap(color, &settings.tonemap);
+            pixel[0] = color.x.max(0.0).powf(gamma.recip());
+            pixel[1] = color.y.max(0.0).powf(gamma.recip());
+            pixel[2] = color.z.max(0.0).powf(gamma.recip());
+            pixel[3] = 1.0;
+        });
+
+    result
+}
+
+pub fn tonemap(color: Vec3, tonemap: &Tonemap) -> Vec3 {
+    match tonemap {
+        Tonemap::None => color,
+        Tonemap::ACES => tonemap_aces(color),
+        Tonemap::Reinhard => tonemap_reinhard(color),
+        Tonemap::Filmic => tonemap_filmic(color),
+        Tonemap::Uncharted2 => tonemap_uncharted2(color),
+    }
+}
+
+// https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+fn tonemap_aces(color: Vec3) -> Vec3 {
+    let input = Mat3::new(
+        0.59719, 0.076, 0.0284, 0.35458, 0.90834, 0.13383, 0.04823, 0.01566, 0.83777,
+    );
+    let output = Mat3::new(
+        1.60475, -0.10208, -0.00327, -0.53108, 1.10813, -0.07276, -0.07367, -0.00605, 1.07602,
+    );
+
+    let color = input * color;
+    let a = color.mul_element_wise(color + vec3!(0.0245786)) - vec3!(0.000090537);
+    let b = color.mul_element_wise(0.983729 * color + vec3!(0.432951)) + vec3!(0.238081);
+    output * a.div_element_wise(b)
+}
+
+fn tonemap_reinhard(color: Vec3) -> Vec3 {
+    let white = 2.0;
+    let luma = color.dot(vec3!(0.2126, 0.7152, 0.0722));
+    if luma <= 0.0 {
+        return vec3!(0.0);
+    }
+
+    let tonemapped_luma = luma * (1.0 + luma / (white * white)) / (1.0 + luma);
+    color * (tonemapped_luma / luma)
+}
+
+fn tonemap_filmic(color: Vec3) -> Vec3 {
+    let color = vec3!(
+        (color.x - 0.004).max(0.0),
+        (color.y - 0.004).max(0.0),
+        (color.z - 0.004).max(0.0)
+    );
+    let a = color.mul_element_wise(6.2 * color + vec3!(0.5));
+    let b = color.mul_element_wise(6.2 * color + vec3!(1.7)) + vec3!(0.06);
+    a.div_element_wise(b)
+}
+
+fn tonemap_uncharted2(color: Vec3) -> Vec3 {
+    let w = 11.2;
+    let exposure = 2.0;
+
+    let curve = |x: Float| {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    };
+
+    let color = color * exposure;
+    vec3!(curve(color.x), curve(color.y), curve(color.z)) / curve(w)
+}
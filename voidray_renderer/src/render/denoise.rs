@@ -0,0 +1,213 @@
+use super::image::HdrImage;
+use crate::aov::Aov;
+use crate::preamble::*;
+use rayon::prelude::*;
+
+/// AOVs used to guide the denoiser, renders which will be denoised should enable them
+pub const DENOISE_FEATURES: [Aov; 2] = [Aov::Albedo, Aov::Normal];
+
+/// Number of filter passes, the footprint of the filter doubles with every pass
+const PASSES: u32 = 5;
+
+/// B3 spline weights of the taps of a pass
+const KERNEL: [Float; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Edge stopping strengths of the color, normal and albedo differences between two pixels
+const SIGMA_COLOR: Float = 0.5;
+const SIGMA_NORMAL: Float = 0.3;
+const SIGMA_ALBEDO: Float = 0.1;
+
+/// Offset which keeps the albedo demodulation invertible for black surfaces
+const ALBEDO_EPSILON: Float = 0.01;
+
+/// Add the denoiser features to a list of AOVs, keeping it free of duplicates
+pub fn add_denoise_features(aovs: &mut Vec<Aov>) {
+    for feature in DENOISE_FEATURES {
+        if !aovs.contains(&feature) {
+            aovs.push(feature);
+        }
+    }
+}
+
+/// Denoise a resolved image with an edge avoiding à-trous wavelet filter
+///
+/// The albedo is divided out before filtering and multiplied back in afterwards so textures stay
+/// sharp, while the normals keep geometric edges from being blurred. Both feature images are
+/// optional, without them only differences in color stop the filter.
+pub fn denoise(image: &HdrImage, albedo: Option<&HdrImage>, normal: Option<&HdrImage>) -> HdrImage {
+    let dimensions = image.dimensions();
+    let albedo = albedo.map(|albedo| rgb_pixels(albedo.as_slice()));
+    let normal = normal.map(|normal| rgb_pixels(normal.as_slice()));
+
+    let mut color = rgb_pixels(image.as_slice());
+    if let Some(albedo) = &albedo {
+        for (color, albedo) in color.iter_mut().zip(albedo) {
+            for c in 0..3 {
+                color[c] /= albedo[c] + ALBEDO_EPSILON;
+            }
+        }
+    }
+
+    let features = Features {
+        dimensions,
+        albedo: albedo.as_deref(),
+        normal: normal.as_deref(),
+    };
+    for pass in 0..PASSES {
+        color = features.filter_pass(&color, pass);
+    }
+
+    if let Some(albedo) = &albedo {
+        for (color, albedo) in color.iter_mut().zip(albedo) {
+            for c in 0..3 {
+                color[c] *= albedo[c] + ALBEDO_EPSILON;
+            }
+        }
+    }
+
+    let mut result = image.clone();
+    for (pixel, color) in result.as_slice_mut().chunks_exact_mut(4).zip(color) {
+        pixel[..3].copy_from_slice(&color);
+    }
+    result
+}
+
+/// Denoise the image of an offline render, guided by the feature AOVs among its layers
+pub fn denoise_layers(image: &HdrImage, layers: &[(Aov, HdrImage)]) -> HdrImage {
+    let layer = |aov: Aov| {
+        layers
+            .iter()
+            .find(|(layer_aov, _)| *layer_aov == aov)
+            .map(|(_, layer)| layer)
+    };
+    denoise(image, layer(Aov::Albedo), layer(Aov::Normal))
+}
+
+fn rgb_pixels(buffer: &[Float]) -> Vec<[Float; 3]> {
+    buffer
+        .chunks_exact(4)
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect()
+}
+
+fn distance_squared(a: &[Float; 3], b: &[Float; 3]) -> Float {
+    (0..3).map(|c| (a[c] - b[c]) * (a[c] - b[c])).sum()
+}
+
+/// Compress a color into [0, 1) so that bright pixels don't dominate the color distance
+fn compress(color: &[Float; 3]) -> [Float; 3] {
+    color.map(|x| x.max(0.0) / (1.0 + x.max(0.0)))
+}
+
+struct Features<'a> {
+    dimensions: [u32; 2],
+    albedo: Option<&'a [[Float; 3]]>,
+    normal: Option<&'a [[Float; 3]]>,
+}
+
+impl Features<'_> {
+    /// Single à-trous pass with taps `2^pass` pixels apart, the color edge stopping gets
+    /// stricter with every pass as the noise is smoothed out
+    fn filter_pass(&self, color: &[[Float; 3]], pass: u32) -> Vec<[Float; 3]> {
+        let [width, height] = self.dimensions.map(|x| x as i64);
+        let step = 1 << pass;
+        let sigma_color = SIGMA_COLOR / (1 << pass) as Float;
+        let compressed: Vec<[Float; 3]> = color.iter().map(compress).collect();
+
+        let mut result = vec![[0.0; 3]; color.len()];
+        result
+            .par_chunks_exact_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let y = y as i64;
+                for (x, output) in row.iter_mut().enumerate() {
+                    let x = x as i64;
+                    let center = (y * width + x) as usize;
+                    let mut sum = [0.0; 3];
+                    let mut total = 0.0;
+
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        let sy = y + (j as i64 - 2) * step;
+                        if !(0..height).contains(&sy) {
+                            continue;
+                        }
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let sx = x + (i as i64 - 2) * step;
+                            if !(0..width).contains(&sx) {
+                                continue;
+                            }
+
+                            let tap = (sy * width + sx) as usize;
+                            let mut weight = kx * ky;
+                            let mut exponent =
+                                distance_squared(&compressed[center], &compressed[tap])
+                                    / (sigma_color * sigma_color);
+                            if let Some(normal) = self.normal {
+                                exponent += distance_squared(&normal[center], &normal[tap])
+                                    / (SIGMA_NORMAL * SIGMA_NORMAL);
+                            }
+                            if let Some(albedo) = self.albedo {
+                                exponent += distance_squared(&albedo[center], &albedo[tap])
+                                    / (SIGMA_ALBEDO * SIGMA_ALBEDO);
+                            }
+                            weight *= (-exponent).exp();
+
+                            for c in 0..3 {
+                                sum[c] += color[tap][c] * weight;
+                            }
+                            total += weight;
+                        }
+                    }
+
+                    // The center tap always has a positive weight
+                    *output = sum.map(|x| x / total);
+                }
+            });
+
+        result
+    }
+}
+
+#[test]
+fn denoise_edge_test() {
+    use crate::rand::*;
+
+    // Two flat halves with different albedos, covered in noise
+    let dimensions = [32, 16];
+    let truth = |x: u32| if x < 16 { 0.2 } else { 0.8 };
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut image = HdrImage::new(dimensions);
+    let mut albedo = HdrImage::new(dimensions);
+    let mut normal = HdrImage::new(dimensions);
+    for (index, pixel) in image.as_slice_mut().chunks_exact_mut(4).enumerate() {
+        let value = truth(index as u32 % 32) * rng.gen_range(0.5..1.5);
+        pixel.copy_from_slice(&[value, value, value, 1.0]);
+    }
+    for (index, pixel) in albedo.as_slice_mut().chunks_exact_mut(4).enumerate() {
+        let value = truth(index as u32 % 32);
+        pixel.copy_from_slice(&[value, value, value, 1.0]);
+    }
+    for pixel in normal.as_slice_mut().chunks_exact_mut(4) {
+        pixel.copy_from_slice(&[0.0, 0.0, 1.0, 1.0]);
+    }
+
+    let error = |image: &HdrImage| -> Float {
+        (0..16)
+            .flat_map(|y| (0..32).map(move |x| (x, y)))
+            .map(|(x, y)| (image.pixel(x, y).r() - truth(x)).powi(2))
+            .sum()
+    };
+
+    let denoised = denoise(&image, Some(&albedo), Some(&normal));
+    assert!(error(&denoised) < error(&image) * 0.1);
+
+    // The edge between the halves stays sharp
+    for y in 0..16 {
+        assert!((denoised.pixel(15, y).r() - 0.2).abs() < 0.05);
+        assert!((denoised.pixel(16, y).r() - 0.8).abs() < 0.05);
+    }
+
+    // Without features the noise is still reduced
+    let denoised = denoise(&image, None, None);
+    assert!(error(&denoised) < error(&image) * 0.5);
+}
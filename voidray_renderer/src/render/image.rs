@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use crate::aov::Aov;
+use crate::color::Color;
+use crate::preamble::*;
+use ::image::{ImageBuffer, ImageResult, Rgba};
+use exr::prelude::{
+    AnyChannel, AnyChannels, Encoding, FlatSamples, Image, LayerAttributes, WritableImage,
+};
+
+/// File formats an `HdrImage` can be written to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// 8-bit per channel PNG
+    Png,
+    /// 16-bit per channel PNG
+    Png16,
+    /// 32-bit floating point OpenEXR
+    Exr,
+}
+
+impl OutputFormat {
+    /// Guess the format from the extension of a path
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "exr" => Some(OutputFormat::Exr),
+            _ => None,
+        }
+    }
+}
+
+/// Pure CPU rgba floating point image, used as a render target when no Vulkan device is available
+#[derive(Clone)]
+pub struct HdrImage {
+    buffer: Vec<Float>,
+    dimensions: [u32; 2],
+}
+
+impl HdrImage {
+    pub fn new(dimensions: [u32; 2]) -> Self {
+        let len = Self::buffer_len(dimensions).expect("image dimensions are too large");
+        Self {
+            buffer: vec![0.0; len],
+            dimensions,
+        }
+    }
+
+    /// Wrap an rgba buffer of the given dimensions
+    pub fn from_raw(dimensions: [u32; 2], buffer: Vec<Float>) -> Self {
+        assert_eq!(Some(buffer.len()), Self::buffer_len(dimensions));
+        Self { buffer, dimensions }
+    }
+
+    /// Number of floats in an rgba buffer of the given dimensions, `None` if it overflows
+    pub fn buffer_len(dimensions: [u32; 2]) -> Option<usize> {
+        (dimensions[0] as usize)
+            .checked_mul(dimensions[1] as usize)?
+            .checked_mul(4)
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.dimensions
+    }
+
+    pub fn as_slice(&self) -> &[Float] {
+        &self.buffer
+    }
+
+    pub fn as_slice_mut(&mut self) -> &mut [Float] {
+        &mut self.buffer
+    }
+
+    /// Returns the color of the pixel at (x, y), with (0, 0) being the top left corner
+    pub fn pixel(&self, x: u32, y: u32) -> Color {
+        let index = (y as usize * self.dimensions[0] as usize + x as usize) * 4;
+        Color::new(
+            self.buffer[index],
+            self.buffer[index + 1],
+            self.buffer[index + 2],
+        )
+    }
+
+    /// Multiply every channel of the image by a constant factor
+    pub fn scale(&mut self, factor: Float) {
+        self.buffer.iter_mut().for_each(|x| *x *= factor);
+    }
+
+    /// Divide the accumulated color of every pixel by the sum of its filter weights, stored in
+    /// the alpha channel, leaving a plain image with alpha set to 1
+    pub fn resolve(&mut self) {
+        self.buffer.chunks_exact_mut(4).for_each(|pixel| {
+            let scale = if pixel[3] != 0.0 {
+                pixel[3].recip()
+            } else {
+                0.0
+            };
+            pixel[0] *= scale;
+            pixel[1] *= scale;
+            pixel[2] *= scale;
+            pixel[3] = 1.0;
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.iter_mut().for_each(|x| *x = 0.0);
+    }
+
+    /// Write the image to disk, LDR formats are clamped to [0, 1] so the image should already
+    /// be color managed
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: OutputFormat) -> ImageResult<()> {
+        let [width, height] = self.dimensions;
+
+        match format {
+            OutputFormat::Png => {
+                let data = self
+                    .buffer
+                    .iter()
+                    .map(|x| (x.clamp(0.0, 1.0) * u8::MAX as Float).round() as u8)
+                    .collect();
+                ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(width, height, data)
+                    .unwrap()
+                    .save_with_format(path, ::image::ImageFormat::Png)
+            }
+            OutputFormat::Png16 => {
+                let data = self
+                    .buffer
+                    .iter()
+                    .map(|x| (x.clamp(0.0, 1.0) * u16::MAX as Float).round() as u16)
+                    .collect();
+                ImageBuffer::<Rgba<u16>, Vec<u16>>::from_raw(width, height, data)
+                    .unwrap()
+                    .save_with_format(path, ::image::ImageFormat::Png)
+            }
+            OutputFormat::Exr => {
+                let data = self.buffer.iter().map(|x| *x as f32).collect();
+                ImageBuffer::<Rgba<f32>, Vec<f32>>::from_raw(width, height, data)
+                    .unwrap()
+                    .save_with_format(path, ::image::ImageFormat::OpenExr)
+            }
+        }
+    }
+    /// Write the image along with AOV layers to a single multi-layer OpenEXR file, the image
+    /// goes into the unprefixed RGBA channels and every AOV into channels prefixed by its name
+    /// such as `albedo.R` or `depth.Z`
+    pub fn save_layers<P: AsRef<Path>>(
+        &self,
+        path: P,
+        layers: &[(Aov, HdrImage)],
+    ) -> exr::error::Result<()> {
+        let [width, height] = self.dimensions;
+
+        let mut channels: Vec<AnyChannel<FlatSamples>> = ["R", "G", "B", "A"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| self.exr_channel(name.to_owned(), index))
+            .collect();
+        for (aov, layer) in layers {
+            for (index, channel) in aov.channels().iter().enumerate() {
+                channels.push(layer.exr_channel(format!("{}.{}", aov.name(), channel), index));
+            }
+        }
+
+        let layer = exr::prelude::Layer::new(
+            (width as usize, height as usize),
+            LayerAttributes::default(),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(channels.into()),
+        );
+        Image::from_layer(layer).write().to_file(path)
+    }
+
+    /// Single component of every pixel as an OpenEXR channel
+    fn exr_channel(&self, name: String, component: usize) -> AnyChannel<FlatSamples> {
+        let samples = self
+            .buffer
+            .chunks_exact(4)
+            .map(|pixel| pixel[component] as f32)
+            .collect();
+        AnyChannel::new(name.as_str(), FlatSamples::F32(samples))
+    }
+}
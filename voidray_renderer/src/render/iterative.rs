@@ -1,55 +1,304 @@
+use super::image::HdrImage;
+use super::layers::AovBuffer;
 use super::target::CpuRenderTarget;
+use super::tiles::Tile;
+use super::variance::{update_convergence, PixelMoments, VarianceBuffer};
+use crate::aov::{Aov, AovSample};
+use crate::filter::Filter;
+use crate::rand::*;
+use crate::sampler::create_sampler;
 use crate::{
-    color::BLACK,
     core::{scene::SceneAcceleration, tracer::trace_ray},
     preamble::*,
     settings::RenderSettings,
 };
-use rand::{thread_rng, Rng};
 use rayon::prelude::*;
 
+/// Accumulate `samples` samples per pixel of the given tiles into the target, starting at sample
+/// index `first_sample` of the render, along with the AOVs enabled in the target. Pixels which
+/// converged are skipped.
 pub fn iterative_render(
     target: Arc<CpuRenderTarget>,
     scene: &SceneAcceleration,
     settings: &RenderSettings,
+    tiles: &[Tile],
+    first_sample: u32,
     samples: u32,
 ) {
-    let dimensions = target.dimensions();
+    render_buffer(
+        &mut target.buffer().as_slice_mut(),
+        &mut target.variance(),
+        &mut target.aovs(),
+        scene,
+        settings,
+        tiles,
+        first_sample,
+        samples,
+    );
 
-    target
-        .buffer()
-        .as_slice_mut()
-        .par_chunks_exact_mut(4)
-        .enumerate()
-        .for_each(|(index, pixel)| {
-            let x = index as u32 % dimensions[0];
-            let y = index as u32 / dimensions[1];
+    target.try_push();
+}
 
-            // Rng
-            let mut rng = thread_rng();
+/// Same as `iterative_render`, but accumulates into a CPU image without touching Vulkan
+#[allow(clippy::too_many_arguments)]
+pub fn iterative_render_image(
+    image: &mut HdrImage,
+    variance: &mut VarianceBuffer,
+    aovs: &mut AovBuffer,
+    scene: &SceneAcceleration,
+    settings: &RenderSettings,
+    tiles: &[Tile],
+    first_sample: u32,
+    samples: u32,
+) {
+    render_buffer(
+        image.as_slice_mut(),
+        variance,
+        aovs,
+        scene,
+        settings,
+        tiles,
+        first_sample,
+        samples,
+    );
+}
 
-            let d = std::cmp::max(dimensions[0], dimensions[1]) as Float;
-            let x = ((2 * x + 1) as Float - dimensions[0] as Float) / d;
-            let y = ((2 * (dimensions[1] - y) - 1) as Float - dimensions[1] as Float) / d;
+/// Accumulate `samples` samples per pixel of the tiles into an rgba buffer, the rgb channels hold
+/// the filter weighted sum of the radiance and alpha holds the sum of the filter weights
+///
+/// Tiles render in parallel into their own buffers, padded by the reach of the filter so samples
+/// can be splatted across the tile border. The tile buffers are then added to the target in the
+/// order of the tiles, so renders with the same seed match no matter how the tiles are scheduled
+/// between threads. AOV layers are splatted the same way, except for the sample count which is
+/// written straight from the pixel moments and the ids which keep the first sample of each pixel.
+#[allow(clippy::too_many_arguments)]
+fn render_buffer(
+    buffer: &mut [Float],
+    variance: &mut VarianceBuffer,
+    aovs: &mut AovBuffer,
+    scene: &SceneAcceleration,
+    settings: &RenderSettings,
+    tiles: &[Tile],
+    first_sample: u32,
+    samples: u32,
+) {
+    let dimensions = variance.dimensions();
+    let reach = Filter::new(settings.filter, settings.filter_radius).reach();
 
-            let mut color = BLACK;
+    let enabled = aovs.aovs().to_vec();
 
-            for _ in 0..samples {
-                // Camera UV, normalized to [-1, 1]
-                let dx = rng.gen_range((-1.0 / d)..(1.0 / d));
-                let dy = rng.gen_range((-1.0 / d)..(1.0 / d));
+    let rendered: Vec<(TileSplats, Vec<PixelMoments>)> = tiles
+        .par_iter()
+        .map(|tile| {
+            let mut moments = variance.read_tile(tile);
+            let splats = render_tile(
+                tile,
+                &mut moments,
+                &enabled,
+                dimensions,
+                scene,
+                settings,
+                first_sample,
+                samples,
+            );
+            (splats, moments)
+        })
+        .collect();
 
-                color += trace_ray(scene, settings, x + dx, y + dy, &mut rng);
+    for (tile, (splats, moments)) in tiles.iter().zip(rendered) {
+        merge_splats(buffer, &splats.beauty, tile, reach, dimensions);
+        for ((aov, layer), layer_splats) in aovs.layers_mut().zip(&splats.layers) {
+            match aov {
+                Aov::SampleCount => {
+                    write_sample_counts(layer.as_slice_mut(), &moments, tile, dimensions)
+                }
+                _ if !aov.is_filtered() => {
+                    merge_first_samples(layer.as_slice_mut(), layer_splats, tile, reach, dimensions)
+                }
+                _ => merge_splats(layer.as_slice_mut(), layer_splats, tile, reach, dimensions),
             }
+        }
 
-            color *= 1.0 / settings.total_samples as Float;
+        variance.write_tile(tile, &moments);
+    }
+}
 
-            // Copy into the target
-            pixel[0] += color.r() as f32;
-            pixel[1] += color.g() as f32;
-            pixel[2] += color.b() as f32;
-            pixel[3] += color.a() as f32;
-        });
+/// Add the padded rgba splat buffer of a tile to an image, dropping splats outside of the image
+fn merge_splats(
+    buffer: &mut [Float],
+    splats: &[Float],
+    tile: &Tile,
+    reach: i64,
+    dimensions: [u32; 2],
+) {
+    let [width, height] = dimensions;
+    let stride = (tile.width as i64 + 2 * reach) as usize;
+    for (row, y) in splats.chunks_exact(stride * 4).zip(tile.y as i64 - reach..) {
+        if y < 0 || y >= height as i64 {
+            continue;
+        }
 
-    target.try_push();
+        let x0 = tile.x as i64 - reach;
+        let skip = (-x0).max(0);
+        let end = (x0 + stride as i64).min(width as i64);
+        let start = ((y * width as i64 + x0 + skip) * 4) as usize;
+        let row = &row[(skip * 4) as usize..((end - x0) * 4) as usize];
+        buffer[start..start + row.len()]
+            .iter_mut()
+            .zip(row)
+            .for_each(|(target, splat)| *target += *splat);
+    }
+}
+
+/// Copy the unfiltered samples of a tile into the pixels of an image which haven't taken a sample
+/// yet, so every pixel keeps the value of its first sample across passes
+fn merge_first_samples(
+    buffer: &mut [Float],
+    splats: &[Float],
+    tile: &Tile,
+    reach: i64,
+    dimensions: [u32; 2],
+) {
+    let stride = tile.width as i64 + 2 * reach;
+    for ty in 0..tile.height {
+        for tx in 0..tile.width {
+            let source = (((ty as i64 + reach) * stride + tx as i64 + reach) * 4) as usize;
+            let target = (((tile.y + ty) * dimensions[0] + tile.x + tx) * 4) as usize;
+            if buffer[target + 3] == 0.0 && splats[source + 3] != 0.0 {
+                buffer[target..target + 4].copy_from_slice(&splats[source..source + 4]);
+            }
+        }
+    }
+}
+
+/// Write the number of samples taken in each pixel of a tile, with a weight of 1 so resolving
+/// leaves the counts untouched
+fn write_sample_counts(
+    buffer: &mut [Float],
+    moments: &[PixelMoments],
+    tile: &Tile,
+    dimensions: [u32; 2],
+) {
+    for (local, pixel) in moments.iter().enumerate() {
+        let x = tile.x + local as u32 % tile.width;
+        let y = tile.y + local as u32 / tile.width;
+        let index = ((y * dimensions[0] + x) * 4) as usize;
+        let count = pixel.samples() as Float;
+        buffer[index..index + 4].copy_from_slice(&[count, count, count, 1.0]);
+    }
+}
+
+/// Padded rgba splat buffers of a tile, one for the beauty image and one per enabled AOV
+struct TileSplats {
+    beauty: Vec<Float>,
+    layers: Vec<Vec<Float>>,
+}
+
+/// Render `samples` samples for every unconverged pixel of a tile and splat them into a buffer
+/// padded by the reach of the filter on each side
+///
+/// Each pixel sample draws from its own stream seeded by the pixel and sample index. The
+/// luminance of every sample is tracked in the tile moments, once each pixel has taken
+/// `adaptive_min_samples` samples the pixels below the noise threshold stop being sampled.
+#[allow(clippy::too_many_arguments)]
+fn render_tile(
+    tile: &Tile,
+    moments: &mut [PixelMoments],
+    aovs: &[Aov],
+    dimensions: [u32; 2],
+    scene: &SceneAcceleration,
+    settings: &RenderSettings,
+    first_sample: u32,
+    samples: u32,
+) -> TileSplats {
+    let [width, height] = dimensions;
+    let d = std::cmp::max(width, height) as Float;
+    let filter = Filter::new(settings.filter, settings.filter_radius);
+    let reach = filter.reach();
+    let stride = tile.width as i64 + 2 * reach;
+    let size = (stride * (tile.height as i64 + 2 * reach) * 4) as usize;
+    let mut splats = vec![0.0; size];
+    let mut layers = vec![vec![0.0; size]; aovs.len()];
+    let mut sampler = create_sampler(settings.sampler, settings.seed, settings.total_samples);
+
+    for sample in first_sample..first_sample + samples {
+        for (local, pixel) in moments.iter_mut().enumerate() {
+            if pixel.converged() {
+                continue;
+            }
+
+            let tx = local as u32 % tile.width;
+            let ty = local as u32 / tile.width;
+            let x = tile.x + tx;
+            let y = tile.y + ty;
+
+            sampler.start_sample((y * width + x) as u64, sample);
+            let [dx, dy] = sampler.get_2d();
+            let offset = [dx - 0.5, dy - 0.5];
+
+            // Camera UV, normalized to [-1, 1]
+            let u = ((2 * x + 1) as Float + 2.0 * offset[0] - width as Float) / d;
+            let v = ((2 * (height - y) - 1) as Float - 2.0 * offset[1] - height as Float) / d;
+
+            let mut aov_sample = AovSample::default();
+            let radiance = trace_ray(scene, settings, u, v, sampler.as_mut(), &mut aov_sample);
+            pixel.add(radiance);
+
+            // Unfiltered AOVs only keep the first sample of the pixel, with a weight of 1
+            let center = (((ty as i64 + reach) * stride + tx as i64 + reach) * 4) as usize;
+            for (&aov, layer) in aovs.iter().zip(&mut layers) {
+                if !aov.is_filtered() && layer[center + 3] == 0.0 {
+                    let value = aov_sample.get(aov);
+                    layer[center..center + 4].copy_from_slice(&[
+                        value.r(),
+                        value.g(),
+                        value.b(),
+                        1.0,
+                    ]);
+                }
+            }
+
+            // Splat into every pixel within the filter radius
+            for sy in -reach..=reach {
+                for sx in -reach..=reach {
+                    let weight =
+                        filter.evaluate([offset[0] - sx as Float, offset[1] - sy as Float]);
+                    if weight == 0.0 {
+                        continue;
+                    }
+
+                    let index =
+                        (((ty as i64 + reach + sy) * stride + tx as i64 + reach + sx) * 4) as usize;
+                    splats[index] += radiance.r() * weight;
+                    splats[index + 1] += radiance.g() * weight;
+                    splats[index + 2] += radiance.b() * weight;
+                    splats[index + 3] += weight;
+
+                    for (&aov, layer) in aovs.iter().zip(&mut layers) {
+                        if !aov.is_filtered() {
+                            continue;
+                        }
+
+                        let value = aov_sample.get(aov);
+                        layer[index] += value.r() * weight;
+                        layer[index + 1] += value.g() * weight;
+                        layer[index + 2] += value.b() * weight;
+                        layer[index + 3] += weight;
+                    }
+                }
+            }
+        }
+
+        if settings.noise_threshold > 0.0
+            && sample + 1 >= settings.adaptive_min_samples
+            && update_convergence(moments, tile.width as usize, settings.noise_threshold)
+        {
+            break;
+        }
+    }
+
+    TileSplats {
+        beauty: splats,
+        layers,
+    }
 }
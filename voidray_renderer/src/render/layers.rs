@@ -0,0 +1,79 @@
+use super::image::HdrImage;
+use crate::aov::Aov;
+
+/// Accumulation buffers of the AOVs enabled for a render, laid out like the beauty buffer with
+/// the filter weighted sums in rgb and the sum of the weights in alpha
+#[derive(Clone)]
+pub struct AovBuffer {
+    aovs: Vec<Aov>,
+    layers: Vec<HdrImage>,
+    dimensions: [u32; 2],
+}
+
+impl AovBuffer {
+    pub fn new(dimensions: [u32; 2], aovs: &[Aov]) -> Self {
+        let mut enabled: Vec<Aov> = Vec::with_capacity(aovs.len());
+        for aov in aovs {
+            if !enabled.contains(aov) {
+                enabled.push(*aov);
+            }
+        }
+
+        Self {
+            layers: enabled.iter().map(|_| HdrImage::new(dimensions)).collect(),
+            aovs: enabled,
+            dimensions,
+        }
+    }
+
+    /// Buffer holding already accumulated layers, e.g. from a checkpoint
+    pub fn from_layers(dimensions: [u32; 2], layers: Vec<(Aov, HdrImage)>) -> Self {
+        assert!(layers
+            .iter()
+            .all(|(_, layer)| layer.dimensions() == dimensions));
+        let (aovs, layers) = layers.into_iter().unzip();
+        Self {
+            aovs,
+            layers,
+            dimensions,
+        }
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.dimensions
+    }
+
+    /// Enabled AOVs, in the order their layers are stored
+    pub fn aovs(&self) -> &[Aov] {
+        &self.aovs
+    }
+
+    pub fn layer(&self, aov: Aov) -> Option<&HdrImage> {
+        let index = self.aovs.iter().position(|&enabled| enabled == aov)?;
+        Some(&self.layers[index])
+    }
+
+    pub fn layers(&self) -> impl Iterator<Item = (Aov, &HdrImage)> {
+        self.aovs.iter().copied().zip(self.layers.iter())
+    }
+
+    pub fn layers_mut(&mut self) -> impl Iterator<Item = (Aov, &mut HdrImage)> {
+        self.aovs.iter().copied().zip(self.layers.iter_mut())
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.iter_mut().for_each(HdrImage::clear);
+    }
+
+    /// Divide out the filter weights of every layer, see `HdrImage::resolve`
+    pub fn resolve(self) -> Vec<(Aov, HdrImage)> {
+        self.aovs
+            .into_iter()
+            .zip(self.layers)
+            .map(|(aov, mut layer)| {
+                layer.resolve();
+                (aov, layer)
+            })
+            .collect()
+    }
+}
@@ -1,5 +1,13 @@
+pub mod checkpoint;
+pub mod color_management;
+pub mod denoise;
+pub mod image;
 pub mod iterative;
+pub mod layers;
+pub mod offline;
 pub mod post_process;
 pub mod renderer;
 pub mod target;
+pub mod tiles;
+pub mod variance;
 pub mod viewport;
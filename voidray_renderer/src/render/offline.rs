@@ -0,0 +1,409 @@
+use std::time::{Duration, Instant};
+
+use super::checkpoint::{render_hash, Checkpoint};
+use super::image::HdrImage;
+use super::iterative::iterative_render_image;
+use super::layers::AovBuffer;
+use super::tiles::generate_tiles;
+use super::variance::VarianceBuffer;
+use crate::aov::Aov;
+use crate::scene::{Accelerable, Scene};
+use crate::settings::RenderSettings;
+use crate::Error;
+
+/// Result of a timed offline render
+pub struct RenderOutput {
+    /// Linear beauty image, not color managed
+    pub image: HdrImage,
+    /// Layers of the AOVs enabled in the render settings
+    pub aovs: Vec<(Aov, HdrImage)>,
+    /// Number of samples taken per pixel
+    pub samples: u32,
+    /// Checkpoints which failed to load or save, the render carries on without them
+    pub errors: Vec<Error>,
+}
+
+/// Blocking render of a scene into a CPU image, never creates a Vulkan device
+///
+/// The returned image is linear and has not been color managed, AOVs aren't rendered.
+pub fn render_to_image(scene: &Scene, settings: &RenderSettings, dimensions: [u32; 2]) -> HdrImage {
+    let scene_accel = scene.build_acceleration();
+    let mut image = HdrImage::new(dimensions);
+    let mut variance = VarianceBuffer::new(dimensions);
+    let mut aovs = AovBuffer::new(dimensions, &[]);
+    let tiles = generate_tiles(dimensions, settings);
+
+    iterative_render_image(
+        &mut image,
+        &mut variance,
+        &mut aovs,
+        &scene_accel,
+        settings,
+        &tiles,
+        0,
+        settings.total_samples,
+    );
+    image.resolve();
+    image
+}
+
+/// Blocking render which stops after `total_samples` samples, once every pixel converged or once
+/// the time budget is used up, whichever comes first, along with the enabled AOVs
+///
+/// If the settings have a checkpoint path, the render resumes from a matching checkpoint and
+/// saves its progress there every `checkpoint_interval` seconds and once it's done.
+pub fn render_to_image_timed(
+    scene: &Scene,
+    settings: &RenderSettings,
+    dimensions: [u32; 2],
+    budget: Duration,
+) -> RenderOutput {
+    let scene_accel = scene.build_acceleration();
+    let mut image = HdrImage::new(dimensions);
+    let mut variance = VarianceBuffer::new(dimensions);
+    let mut aovs = AovBuffer::new(dimensions, &settings.aovs);
+    let tiles = generate_tiles(dimensions, settings);
+
+    let mut samples = 0;
+    let mut errors = Vec::new();
+    let hash = render_hash(scene, settings, dimensions);
+    let resumed = match &settings.checkpoint_path {
+        Some(path) => Checkpoint::resume(path, hash).unwrap_or_else(|err| {
+            errors.push(err);
+            None
+        }),
+        None => None,
+    };
+    if let Some(checkpoint) = resumed {
+        samples = checkpoint.samples.min(settings.total_samples);
+        image = checkpoint.image;
+        variance = checkpoint.variance;
+        aovs = checkpoint.aovs;
+    }
+    let save_checkpoint =
+        |image: &HdrImage, variance: &VarianceBuffer, aovs: &AovBuffer, samples| {
+            let Some(path) = &settings.checkpoint_path else {
+                return Ok(());
+            };
+            let checkpoint = Checkpoint {
+                hash,
+                samples,
+                image: image.clone(),
+                variance: variance.clone(),
+                aovs: aovs.clone(),
+            };
+            checkpoint.save(path)
+        };
+
+    let start_time = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    while samples < settings.total_samples
+        && start_time.elapsed() < budget
+        && variance.convergence() < 1.0
+    {
+        iterative_render_image(
+            &mut image,
+            &mut variance,
+            &mut aovs,
+            &scene_accel,
+            settings,
+            &tiles,
+            samples,
+            1,
+        );
+        samples += 1;
+
+        if last_checkpoint.elapsed().as_secs_f64() >= settings.checkpoint_interval as f64 {
+            errors.extend(save_checkpoint(&image, &variance, &aovs, samples).err());
+            last_checkpoint = Instant::now();
+        }
+    }
+
+    errors.extend(save_checkpoint(&image, &variance, &aovs, samples).err());
+    image.resolve();
+    RenderOutput {
+        image,
+        aovs: aovs.resolve(),
+        samples,
+        errors,
+    }
+}
+
+/// Environment of a single color, rendered as a flat image
+#[cfg(test)]
+struct Constant(crate::color::Color);
+
+#[cfg(test)]
+impl crate::traits::Environment for Constant {
+    fn sample(&self, _: &crate::ray::Ray) -> crate::color::Color {
+        self.0
+    }
+}
+
+/// Varies with the direction so that the pixel jitter shows up in the image
+#[cfg(test)]
+struct Gradient;
+
+#[cfg(test)]
+impl crate::traits::Environment for Gradient {
+    fn sample(&self, ray: &crate::ray::Ray) -> crate::color::Color {
+        crate::color::Color(ray.direction.map(|x| x.abs()))
+    }
+}
+
+/// Scene with nothing in it but the given environment
+#[cfg(test)]
+fn environment_scene(environment: impl crate::traits::Environment + 'static) -> Scene {
+    let mut scene = Scene::empty();
+    scene.environment = Some(std::sync::Arc::new(environment));
+    scene
+}
+
+#[test]
+fn render_empty_scene_test() {
+    let settings = RenderSettings {
+        total_samples: 2,
+        ..Default::default()
+    };
+
+    let image = render_to_image(&Scene::empty(), &settings, [4, 3]);
+    assert_eq!(image.dimensions(), [4, 3]);
+    assert_eq!(image.as_slice().len(), 4 * 3 * 4);
+    assert!(image
+        .as_slice()
+        .chunks_exact(4)
+        .all(|pixel| pixel[0] == 0.0 && pixel[3] == 1.0));
+}
+
+#[test]
+fn render_deterministic_test() {
+    let scene = environment_scene(Gradient);
+    let mut settings = RenderSettings {
+        total_samples: 4,
+        ..Default::default()
+    };
+
+    let first = render_to_image(&scene, &settings, [8, 8]);
+    let second = render_to_image(&scene, &settings, [8, 8]);
+    assert_eq!(first.as_slice(), second.as_slice());
+
+    settings.seed = 1;
+    let reseeded = render_to_image(&scene, &settings, [8, 8]);
+    assert_ne!(first.as_slice(), reseeded.as_slice());
+}
+
+#[test]
+fn render_filter_test() {
+    use crate::color::Color;
+    use crate::settings::FilterType;
+
+    let scene = environment_scene(Constant(Color::new(0.25, 0.5, 1.0)));
+    let mut settings = RenderSettings {
+        total_samples: 2,
+        filter_radius: 1.5,
+        tile_size: 2,
+        ..Default::default()
+    };
+
+    // Splats are normalized by their weights, so a constant image stays constant up to the borders
+    // of the image and of the tiles
+    for filter in [
+        FilterType::Tent,
+        FilterType::Gaussian,
+        FilterType::BlackmanHarris,
+    ] {
+        settings.filter = filter;
+        let image = render_to_image(&scene, &settings, [5, 4]);
+        assert!(image.as_slice().chunks_exact(4).all(|pixel| {
+            (pixel[0] - 0.25).abs() < 1e-4 && (pixel[2] - 1.0).abs() < 1e-4 && pixel[3] == 1.0
+        }));
+    }
+}
+
+#[test]
+fn render_adaptive_test() {
+    let scene = environment_scene(Constant(crate::color::GRAY(0.5)));
+    let mut settings = RenderSettings {
+        total_samples: 64,
+        ..Default::default()
+    };
+
+    // Adaptive sampling is off by default
+    let output = render_to_image_timed(&scene, &settings, [10, 10], Duration::from_secs(60));
+    assert_eq!(output.samples, settings.total_samples);
+
+    // A noiseless image converges as soon as adaptive sampling kicks in
+    settings.noise_threshold = 0.01;
+    let output = render_to_image_timed(&scene, &settings, [10, 10], Duration::from_secs(60));
+    assert_eq!(output.samples, settings.adaptive_min_samples);
+}
+
+#[test]
+fn render_region_test() {
+    let scene = environment_scene(Constant(crate::color::GRAY(1.0)));
+    let settings = RenderSettings {
+        total_samples: 2,
+        tile_size: 3,
+        region: Some([2, 1, 4, 2]),
+        ..Default::default()
+    };
+
+    let image = render_to_image(&scene, &settings, [8, 4]);
+    for y in 0..4 {
+        for x in 0..8 {
+            let inside = (2..6).contains(&x) && (1..3).contains(&y);
+            assert_eq!(image.pixel(x, y).r(), if inside { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+#[test]
+fn render_aov_test() {
+    let scene = environment_scene(Constant(crate::color::GRAY(0.5)));
+    let settings = RenderSettings {
+        total_samples: 64,
+        filter_radius: 1.5,
+        region: Some([0, 0, 6, 4]),
+        aovs: vec![Aov::SampleCount, Aov::Albedo, Aov::SampleCount],
+        ..Default::default()
+    };
+
+    let output = render_to_image_timed(&scene, &settings, [6, 5], Duration::from_secs(60));
+    assert_eq!(output.aovs.len(), 2);
+
+    // Sample counts aren't filtered, pixels outside of the region took no samples
+    let (aov, counts) = &output.aovs[0];
+    assert_eq!(*aov, Aov::SampleCount);
+    for y in 0..5 {
+        let expected = if y < 4 { output.samples } else { 0 };
+        assert!((0..6).all(|x| counts.pixel(x, y).r() as u32 == expected));
+    }
+
+    // Nothing is hit, so the surface AOVs stay black
+    let (aov, albedo) = &output.aovs[1];
+    assert_eq!(*aov, Aov::Albedo);
+    assert!(albedo.as_slice().iter().all(|&x| x == 0.0 || x == 1.0));
+}
+
+#[test]
+fn render_id_aov_test() {
+    use crate::color::{Color, BLACK};
+    use crate::mesh::{Mesh, Vertex};
+    use crate::rand::Sampler;
+    use crate::ray::{HitRecord, Ray};
+    use crate::scene::SceneAcceleration;
+    use crate::traits::Material;
+    use crate::vec3;
+    use crate::vector::Vec2;
+
+    struct Absorbing;
+    impl Material for Absorbing {
+        fn scatter(
+            &self,
+            _: &SceneAcceleration,
+            _: &Ray,
+            _: &HitRecord,
+            _: &mut dyn Sampler,
+        ) -> (Color, Option<Ray>) {
+            (BLACK, None)
+        }
+    }
+
+    // A quad covering about half of the image, with the second material
+    let mut scene = Scene::empty();
+    scene.add_material(std::sync::Arc::new(Absorbing));
+    let material = scene.add_material(std::sync::Arc::new(Absorbing));
+    let vertices = [[0.0, -5.0], [5.0, -5.0], [5.0, 5.0], [0.0, 5.0]]
+        .into_iter()
+        .map(|[x, y]| Vertex::new(vec3!(x, y, 0.0), Vec2::new(0.0, 0.0), vec3!(0.0, 0.0, 1.0)))
+        .collect();
+    let surface = scene.add_mesh(std::sync::Arc::new(Mesh::from_buffers(
+        vertices,
+        vec![0, 1, 2, 0, 2, 3],
+    )));
+    scene.add_object(material, surface);
+
+    let settings = RenderSettings {
+        total_samples: 8,
+        filter_radius: 1.5,
+        aovs: vec![Aov::ObjectId, Aov::MaterialId],
+        ..Default::default()
+    };
+    let output = render_to_image_timed(&scene, &settings, [16, 16], Duration::from_secs(60));
+
+    // Ids are offset by one so misses stay distinguishable, and aren't blended at the edge
+    for ((aov, layer), id) in output.aovs.iter().zip([1.0, 2.0]) {
+        let values: Vec<_> = layer
+            .as_slice()
+            .chunks_exact(4)
+            .map(|pixel| pixel[0])
+            .collect();
+        assert!(
+            values.iter().all(|&value| value == 0.0 || value == id),
+            "{:?}",
+            aov
+        );
+        assert!(values.contains(&0.0) && values.contains(&id), "{:?}", aov);
+    }
+}
+
+#[test]
+fn render_checkpoint_test() {
+    let scene = environment_scene(Gradient);
+    let dimensions = [6, 4];
+    let mut settings = RenderSettings {
+        total_samples: 8,
+        ..Default::default()
+    };
+    let budget = Duration::from_secs(60);
+    let uninterrupted = render_to_image_timed(&scene, &settings, dimensions, budget);
+
+    // Interrupt a render after 3 samples
+    let mut image = HdrImage::new(dimensions);
+    let mut variance = VarianceBuffer::new(dimensions);
+    let mut aovs = AovBuffer::new(dimensions, &[]);
+    let tiles = generate_tiles(dimensions, &settings);
+    iterative_render_image(
+        &mut image,
+        &mut variance,
+        &mut aovs,
+        &scene.build_acceleration(),
+        &settings,
+        &tiles,
+        0,
+        3,
+    );
+    let path = std::env::temp_dir().join(format!("voidray_resume_{}.bin", std::process::id()));
+    let checkpoint = Checkpoint {
+        hash: render_hash(&scene, &settings, dimensions),
+        samples: 3,
+        image,
+        variance,
+        aovs,
+    };
+    checkpoint.save(&path).unwrap();
+
+    // The resumed render takes the remaining samples and ends up identical
+    settings.checkpoint_path = Some(path.to_str().unwrap().to_owned());
+    let resumed = render_to_image_timed(&scene, &settings, dimensions, budget);
+    assert_eq!(resumed.samples, 8);
+    assert_eq!(resumed.image.as_slice(), uninterrupted.image.as_slice());
+    assert!(resumed.errors.is_empty());
+    assert_eq!(Checkpoint::load(&path).unwrap().samples, 8);
+
+    // Checkpoints of other renders are ignored
+    settings.seed = 1;
+    assert!(
+        Checkpoint::resume(&path, render_hash(&scene, &settings, dimensions))
+            .unwrap()
+            .is_none()
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    // Checkpoints which can't be written are reported, the render still finishes
+    let missing = path.join("checkpoint.bin");
+    settings.checkpoint_path = Some(missing.to_str().unwrap().to_owned());
+    let output = render_to_image_timed(&scene, &settings, dimensions, budget);
+    assert_eq!(output.samples, 8);
+    assert!(matches!(output.errors.as_slice(), [Error::Write { .. }]));
+}
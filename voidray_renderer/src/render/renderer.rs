@@ -11,15 +11,26 @@ use crate::{
     scene::{Accelerable, Scene},
     settings::Settings,
     vector::Float,
+    Error,
 };
 
-use super::{iterative::iterative_render, target::CpuRenderTarget};
+use super::{
+    checkpoint::{render_hash, Checkpoint},
+    denoise::add_denoise_features,
+    iterative::iterative_render,
+    target::CpuRenderTarget,
+    tiles::generate_tiles,
+};
 
 pub struct RendererStats {
     currently_rendering: bool,
     samples: (u32, u32),
+    /// Fraction of pixels which stopped sampling because they converged
+    convergence: Float,
     time: Option<(Instant, Option<Instant>)>,
     remaining: Option<Duration>,
+    /// Checkpoints which failed to load or save since the errors were last taken
+    errors: Vec<Error>,
 }
 
 pub struct RenderThread {
@@ -57,56 +68,157 @@ impl RenderThread {
             // Build the scene
             let scene_accel = thread_scene.read().unwrap().build_acceleration();
             let settings = thread_settings.read().unwrap().clone();
+            let mut aovs = settings.render.aovs.clone();
+            if settings.color_management.denoise {
+                add_denoise_features(&mut aovs);
+            }
+            thread_target.configure_aovs(&aovs);
 
             // Begin rendering
             let mut samples = 0;
             let total_samples = settings.render.total_samples;
+            let tiles = generate_tiles(thread_target.dimensions(), &settings.render);
+
+            // Only progressive renders have a single sample count to resume from
+            let checkpoint_path = settings
+                .render
+                .checkpoint_path
+                .clone()
+                .filter(|_| settings.render.progressive);
+            let hash = render_hash(
+                &thread_scene.read().unwrap(),
+                &settings.render,
+                thread_target.dimensions(),
+            );
+            let resumed = match &checkpoint_path {
+                Some(path) => Checkpoint::resume(path, hash).unwrap_or_else(|err| {
+                    thread_stats.write().unwrap().errors.push(err);
+                    None
+                }),
+                None => None,
+            };
+            if let Some(checkpoint) = resumed {
+                samples = checkpoint.samples.min(total_samples);
+                thread_target.restore(checkpoint);
+                thread_stats.write().unwrap().samples = (samples, total_samples);
+            }
+            let first_sample = samples;
+            let mut last_checkpoint = Instant::now();
+            let save_checkpoint = |samples: u32| {
+                if let Some(path) = &checkpoint_path {
+                    if let Err(err) = thread_target.checkpoint(hash, samples).save(path) {
+                        thread_stats.write().unwrap().errors.push(err);
+                    }
+                }
+            };
 
-            // Time how long it takes to render a single sample
-            let single_sample_time = Instant::now();
-            iterative_render(thread_target.clone(), &scene_accel, &settings.render, 1);
-            samples += 1;
-            thread_stats.write().unwrap().samples = (samples, total_samples);
-
-            // Calculate the appropriate update frequency
-            let single_sample_time = Instant::now()
-                .duration_since(single_sample_time)
-                .as_secs_f64();
-            thread_stats.write().unwrap().remaining = Some(Duration::from_secs_f64(
-                single_sample_time * (total_samples - 1) as f64,
-            ));
-            let mut samples_per_frame =
-                (settings.render.update_frequency / single_sample_time as Float) as u32;
-            samples_per_frame =
-                std::cmp::min(std::cmp::max(samples_per_frame, 1), total_samples - samples);
-
-            // Main render loop
-            while samples < total_samples {
-                // Render
-                let delta_samples = std::cmp::min(samples_per_frame, total_samples - samples);
+            if settings.render.progressive && samples < total_samples {
+                // Time how long it takes to render a single sample
+                let single_sample_time = Instant::now();
                 iterative_render(
                     thread_target.clone(),
                     &scene_accel,
                     &settings.render,
-                    samples_per_frame,
+                    &tiles,
+                    samples,
+                    1,
                 );
-                samples += delta_samples;
+                samples += 1;
                 thread_stats.write().unwrap().samples = (samples, total_samples);
-                let elapsed = Instant::now().duration_since(start_time).as_secs_f64();
+                thread_stats.write().unwrap().convergence = 0.0;
+
+                // Calculate the appropriate update frequency
+                let single_sample_time = Instant::now()
+                    .duration_since(single_sample_time)
+                    .as_secs_f64();
                 thread_stats.write().unwrap().remaining = Some(Duration::from_secs_f64(
-                    elapsed / samples as f64 * (total_samples - samples) as f64,
+                    single_sample_time * (total_samples - samples) as f64,
                 ));
+                let mut samples_per_frame =
+                    (settings.render.update_frequency / single_sample_time as Float) as u32;
+                samples_per_frame =
+                    std::cmp::min(std::cmp::max(samples_per_frame, 1), total_samples - samples);
+
+                // Main render loop
+                while samples < total_samples {
+                    // Render
+                    let delta_samples = std::cmp::min(samples_per_frame, total_samples - samples);
+                    iterative_render(
+                        thread_target.clone(),
+                        &scene_accel,
+                        &settings.render,
+                        &tiles,
+                        samples,
+                        delta_samples,
+                    );
+                    samples += delta_samples;
+                    thread_stats.write().unwrap().samples = (samples, total_samples);
+                    let convergence = thread_target.variance().convergence();
+                    thread_stats.write().unwrap().convergence = convergence;
+                    let elapsed = Instant::now().duration_since(start_time).as_secs_f64();
+                    thread_stats.write().unwrap().remaining = Some(Duration::from_secs_f64(
+                        elapsed / (samples - first_sample) as f64
+                            * (total_samples - samples) as f64,
+                    ));
+
+                    if last_checkpoint.elapsed().as_secs_f64()
+                        >= settings.render.checkpoint_interval as f64
+                    {
+                        save_checkpoint(samples);
+                        last_checkpoint = Instant::now();
+                    }
 
-                // Process other events
-                if let Ok(action) = receiver.try_recv() {
-                    match action {
-                        RenderAction::Cancel => break,
-                        _ => panic!("invalid action {:?}", action),
+                    // Process other events
+                    if let Ok(action) = receiver.try_recv() {
+                        match action {
+                            RenderAction::Cancel => break,
+                            _ => panic!("invalid action {:?}", action),
+                        }
+                    }
+
+                    // Every pixel is below the noise threshold
+                    if convergence >= 1.0 {
+                        break;
+                    }
+                }
+
+                save_checkpoint(samples);
+            } else if !settings.render.progressive {
+                // Finish a batch of tiles per thread at a time, showing each batch once it's done
+                let batch_size = rayon::current_num_threads();
+                let batches = tiles.len().div_ceil(batch_size);
+                for (batch, batch_tiles) in tiles.chunks(batch_size).enumerate() {
+                    iterative_render(
+                        thread_target.clone(),
+                        &scene_accel,
+                        &settings.render,
+                        batch_tiles,
+                        0,
+                        total_samples,
+                    );
+
+                    // Report the progress as the equivalent number of samples over the image
+                    let done = (batch + 1) as f64 / batches as f64;
+                    samples = (total_samples as f64 * done) as u32;
+                    thread_stats.write().unwrap().samples = (samples, total_samples);
+                    let convergence = thread_target.variance().convergence();
+                    thread_stats.write().unwrap().convergence = convergence;
+                    let elapsed = Instant::now().duration_since(start_time).as_secs_f64();
+                    thread_stats.write().unwrap().remaining =
+                        Some(Duration::from_secs_f64(elapsed / done * (1.0 - done)));
+
+                    // Process other events
+                    if let Ok(action) = receiver.try_recv() {
+                        match action {
+                            RenderAction::Cancel => break,
+                            _ => panic!("invalid action {:?}", action),
+                        }
                     }
                 }
             }
 
-            // Finish rendering
+            // Finish rendering, the last frame may have been skipped while the view was busy
+            thread_target.push();
             thread_stats.write().unwrap().currently_rendering = false;
             let time = thread_stats.read().unwrap().time;
             thread_stats.write().unwrap().time =
@@ -183,8 +295,10 @@ impl Renderer {
         let stats = RendererStats {
             currently_rendering: false,
             samples: (0, 0),
+            convergence: 0.0,
             time: None,
             remaining: None,
+            errors: Vec::new(),
         };
 
         Self {
@@ -241,6 +355,11 @@ impl Renderer {
         self.stats.read().unwrap().samples
     }
 
+    /// Fraction of pixels which converged under adaptive sampling, in [0, 1]
+    pub fn convergence(&self) -> Float {
+        self.stats.read().unwrap().convergence
+    }
+
     pub fn elapsed_time(&self) -> Duration {
         let stats = self.stats.read().unwrap();
 
@@ -255,4 +374,9 @@ impl Renderer {
     pub fn remaining_time(&self) -> Option<Duration> {
         self.stats.read().unwrap().remaining
     }
+
+    /// Errors of the render thread since the last call, e.g. checkpoints which failed to save
+    pub fn take_errors(&self) -> Vec<Error> {
+        std::mem::take(&mut self.stats.write().unwrap().errors)
+    }
 }
@@ -1,5 +1,11 @@
 use std::sync::{RwLock, RwLockWriteGuard};
 
+use super::checkpoint::Checkpoint;
+use super::denoise::denoise;
+use super::image::HdrImage;
+use super::layers::AovBuffer;
+use super::variance::VarianceBuffer;
+use crate::aov::Aov;
 use crate::preamble::*;
 use vulkano::{
     buffer::{cpu_access::WriteLock, BufferUsage, CpuAccessibleBuffer},
@@ -80,6 +86,19 @@ impl ViewImage {
 pub struct CpuRenderTarget {
     queue: Arc<Queue>,
     buffer: Arc<RwLock<CpuBufferImage>>,
+    /// Per pixel sample variance, drives adaptive sampling
+    variance: Arc<RwLock<VarianceBuffer>>,
+    /// Accumulated AOV layers, configured at the start of each render
+    aovs: Arc<RwLock<AovBuffer>>,
+    /// AOV shown in the viewport instead of the beauty image
+    display: Arc<RwLock<Option<Aov>>>,
+    /// Whether the beauty image is shown through the denoiser
+    denoise: Arc<RwLock<bool>>,
+    /// Whether the staging buffer holds the denoised beauty image of the accumulated samples,
+    /// cleared whenever samples are accumulated so the denoiser only reruns when they changed
+    denoised: Arc<RwLock<bool>>,
+    /// Staging buffer the displayed AOV is copied through on its way to the intermediate view
+    staging: Arc<RwLock<CpuBufferImage>>,
     intermediate: Arc<RwLock<ViewImage>>,
     views: Arc<RwLock<Vec<ViewImage>>>,
     synced: Arc<RwLock<bool>>,
@@ -91,6 +110,7 @@ impl CpuRenderTarget {
         assert!(passes > 0);
 
         let buffer = CpuBufferImage::new(queue.device().clone(), dimensions);
+        let staging = CpuBufferImage::new(queue.device().clone(), dimensions);
 
         let intermediate = ViewImage::new(
             queue.device().clone(),
@@ -123,6 +143,12 @@ impl CpuRenderTarget {
         Arc::new(Self {
             queue,
             buffer: Arc::new(RwLock::new(buffer)),
+            variance: Arc::new(RwLock::new(VarianceBuffer::new(dimensions))),
+            aovs: Arc::new(RwLock::new(AovBuffer::new(dimensions, &[]))),
+            display: Arc::new(RwLock::new(None)),
+            denoise: Arc::new(RwLock::new(false)),
+            denoised: Arc::new(RwLock::new(false)),
+            staging: Arc::new(RwLock::new(staging)),
             intermediate: Arc::new(RwLock::new(intermediate)),
             views: Arc::new(RwLock::new(views)),
             synced: Arc::new(RwLock::new(false)),
@@ -141,6 +167,11 @@ impl CpuRenderTarget {
 
         *self.buffer.write().unwrap() =
             CpuBufferImage::new(self.queue.device().clone(), new_dimensions);
+        *self.variance.write().unwrap() = VarianceBuffer::new(new_dimensions);
+        let enabled = self.aovs.read().unwrap().aovs().to_vec();
+        *self.aovs.write().unwrap() = AovBuffer::new(new_dimensions, &enabled);
+        *self.staging.write().unwrap() =
+            CpuBufferImage::new(self.queue.device().clone(), new_dimensions);
         *self.intermediate.write().unwrap() = ViewImage::new(
             self.queue.device().clone(),
             ImageUsage {
@@ -168,12 +199,14 @@ impl CpuRenderTarget {
             ));
         }
         *self.views.write().unwrap() = views;
+        *self.denoised.write().unwrap() = false;
     }
 
     /// Assuming we can block on the buffer, try to copy it to the intermediate view
     pub fn try_push(&self) {
-        let buffer_read = self.buffer.read().unwrap();
         if let Ok(intermediate_write) = self.intermediate.try_write() {
+            let display_buffer = self.display_buffer();
+            let _buffer_read = self.buffer.read().unwrap();
             let mut builder = AutoCommandBufferBuilder::primary(
                 self.queue.device().clone(),
                 self.queue.family(),
@@ -183,7 +216,7 @@ impl CpuRenderTarget {
 
             builder
                 .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
-                    buffer_read.buffer.clone(),
+                    display_buffer,
                     intermediate_write.image.clone(),
                 ))
                 .unwrap();
@@ -203,9 +236,10 @@ impl CpuRenderTarget {
 
     /// Assuming we can block on the buffer, blockingly copy it to the intermediate view
     pub fn push(&self) {
-        let buffer_read = self.buffer.read().unwrap();
-
         let intermediate_write = self.intermediate.write().unwrap();
+        let display_buffer = self.display_buffer();
+        let _buffer_read = self.buffer.read().unwrap();
+
         let mut builder = AutoCommandBufferBuilder::primary(
             self.queue.device().clone(),
             self.queue.family(),
@@ -215,7 +249,7 @@ impl CpuRenderTarget {
 
         builder
             .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
-                buffer_read.buffer.clone(),
+                display_buffer,
                 intermediate_write.image.clone(),
             ))
             .unwrap();
@@ -232,6 +266,59 @@ impl CpuRenderTarget {
         *self.synced.write().unwrap() = false;
     }
 
+    /// Buffer holding the image to display, the beauty buffer unless an enabled AOV is selected
+    /// or denoising is enabled
+    ///
+    /// The denoiser works on copies of the accumulated buffers, so the render isn't blocked while
+    /// it runs, and only when samples were accumulated since the last time it ran.
+    fn display_buffer(&self) -> Arc<CpuAccessibleBuffer<[Float]>> {
+        let staging = self.staging.read().unwrap().buffer.clone();
+
+        if let Some(aov) = *self.display.read().unwrap() {
+            if let Some(layer) = self.aovs.read().unwrap().layer(aov) {
+                staging.write().unwrap().copy_from_slice(layer.as_slice());
+                *self.denoised.write().unwrap() = false;
+                return staging;
+            }
+        }
+
+        if *self.denoise.read().unwrap() {
+            let inputs = {
+                let mut denoised = self.denoised.write().unwrap();
+                (!*denoised).then(|| {
+                    *denoised = true;
+                    let buffer = self.buffer.read().unwrap();
+                    let aovs = self.aovs.read().unwrap();
+                    let image = buffer.buffer.read().unwrap().to_vec();
+                    (
+                        HdrImage::from_raw(buffer.dimensions, image),
+                        aovs.layer(Aov::Albedo).cloned(),
+                        aovs.layer(Aov::Normal).cloned(),
+                    )
+                })
+            };
+
+            if let Some((image, albedo, normal)) = inputs {
+                let resolved = |mut image: HdrImage| {
+                    image.resolve();
+                    image
+                };
+                let denoised = denoise(
+                    &resolved(image),
+                    albedo.map(resolved).as_ref(),
+                    normal.map(resolved).as_ref(),
+                );
+                staging
+                    .write()
+                    .unwrap()
+                    .copy_from_slice(denoised.as_slice());
+            }
+            return staging;
+        }
+
+        self.buffer.read().unwrap().buffer.clone()
+    }
+
     /// Assuming we can block on the view, try to copy into it from the intermediate view
     pub fn try_pull(&self) {
         let view = self.views.read().unwrap()[0].view.clone();
@@ -264,9 +351,69 @@ impl CpuRenderTarget {
     }
 
     pub fn buffer(&self) -> RwLockWriteGuard<'_, CpuBufferImage> {
+        *self.denoised.write().unwrap() = false;
         self.buffer.write().unwrap()
     }
 
+    pub fn variance(&self) -> RwLockWriteGuard<'_, VarianceBuffer> {
+        self.variance.write().unwrap()
+    }
+
+    pub fn aovs(&self) -> RwLockWriteGuard<'_, AovBuffer> {
+        *self.denoised.write().unwrap() = false;
+        self.aovs.write().unwrap()
+    }
+
+    /// Snapshot of the accumulated buffers, to be saved as a checkpoint
+    pub fn checkpoint(&self, hash: u64, samples: u32) -> Checkpoint {
+        let buffer = self.buffer.read().unwrap();
+        let image = HdrImage::from_raw(buffer.dimensions, buffer.buffer.read().unwrap().to_vec());
+
+        Checkpoint {
+            hash,
+            samples,
+            image,
+            variance: self.variance.read().unwrap().clone(),
+            aovs: self.aovs.read().unwrap().clone(),
+        }
+    }
+
+    /// Replace the accumulated buffers with those of a checkpoint of the same dimensions
+    pub fn restore(&self, checkpoint: Checkpoint) {
+        assert_eq!(checkpoint.image.dimensions(), self.dimensions());
+
+        self.buffer()
+            .as_slice_mut()
+            .copy_from_slice(checkpoint.image.as_slice());
+        *self.variance() = checkpoint.variance;
+        *self.aovs() = checkpoint.aovs;
+        self.try_push();
+    }
+
+    /// Enable a new set of AOVs, clearing the accumulated layers
+    pub fn configure_aovs(&self, aovs: &[Aov]) {
+        *self.aovs() = AovBuffer::new(self.dimensions(), aovs);
+    }
+
+    /// Show an AOV in the viewport instead of the beauty image, takes effect on the next push
+    pub fn set_display(&self, aov: Option<Aov>) {
+        *self.display.write().unwrap() = aov;
+    }
+
+    pub fn display(&self) -> Option<Aov> {
+        *self.display.read().unwrap()
+    }
+
+    /// Show the beauty image through the denoiser, guided by the albedo and normal AOVs if they
+    /// are enabled, takes effect on the next push
+    pub fn set_denoise(&self, denoise: bool) {
+        *self.denoise.write().unwrap() = denoise;
+    }
+
+    pub fn denoise(&self) -> bool {
+        *self.denoise.read().unwrap()
+    }
+
     pub fn get_view(&self, index: usize) -> Arc<ImageView<AttachmentImage>> {
         self.views.read().unwrap()[index].view.clone()
     }
@@ -286,6 +433,8 @@ impl CpuRenderTarget {
             .as_slice_mut()
             .iter_mut()
             .for_each(|x| *x = 0.0);
+        self.variance().clear();
+        self.aovs().clear();
         self.try_push();
     }
 
@@ -294,6 +443,8 @@ impl CpuRenderTarget {
             .as_slice_mut()
             .iter_mut()
             .for_each(|x| *x = 0.0);
+        self.variance().clear();
+        self.aovs().clear();
         self.push();
     }
 }
@@ -0,0 +1,160 @@
+use crate::settings::{RenderSettings, TileOrder};
+
+/// Rectangular block of pixels rendered by a single thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Split the region of interest, or the whole image if there is none, into tiles of
+/// `tile_size` pixels ordered by `tile_order`
+pub fn generate_tiles(dimensions: [u32; 2], settings: &RenderSettings) -> Vec<Tile> {
+    let [x0, y0, x1, y1] = match settings.region {
+        Some([x, y, width, height]) => [
+            x.min(dimensions[0]),
+            y.min(dimensions[1]),
+            x.saturating_add(width).min(dimensions[0]),
+            y.saturating_add(height).min(dimensions[1]),
+        ],
+        None => [0, 0, dimensions[0], dimensions[1]],
+    };
+
+    let size = settings.tile_size.max(1);
+    let columns = (x1 - x0).div_ceil(size);
+    let rows = (y1 - y0).div_ceil(size);
+
+    let tile = |[column, row]: [u32; 2]| {
+        let x = x0 + column * size;
+        let y = y0 + row * size;
+        Tile {
+            x,
+            y,
+            width: size.min(x1 - x),
+            height: size.min(y1 - y),
+        }
+    };
+
+    match settings.tile_order {
+        TileOrder::Scanline => (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| [column, row]))
+            .map(tile)
+            .collect(),
+        TileOrder::Spiral => spiral_order(columns, rows).into_iter().map(tile).collect(),
+        TileOrder::Hilbert => hilbert_order(columns, rows).into_iter().map(tile).collect(),
+    }
+}
+
+/// Grid cells in a square spiral starting from the center of the grid
+fn spiral_order(columns: u32, rows: u32) -> Vec<[u32; 2]> {
+    let total = (columns * rows) as usize;
+    let mut order = Vec::with_capacity(total);
+    let mut position = [(columns as i64 - 1) / 2, (rows as i64 - 1) / 2];
+    let directions = [[1, 0], [0, 1], [-1, 0], [0, -1]];
+
+    // Leg lengths go 1, 1, 2, 2, 3, 3, ... turning after every leg
+    let mut leg = 0;
+    while order.len() < total {
+        let direction = directions[leg % 4];
+        for _ in 0..leg / 2 + 1 {
+            if (0..columns as i64).contains(&position[0]) && (0..rows as i64).contains(&position[1])
+            {
+                order.push([position[0] as u32, position[1] as u32]);
+            }
+            position[0] += direction[0];
+            position[1] += direction[1];
+        }
+        leg += 1;
+    }
+
+    order
+}
+
+/// Grid cells along a Hilbert curve covering the grid, neighbouring tiles render close in time
+fn hilbert_order(columns: u32, rows: u32) -> Vec<[u32; 2]> {
+    let n = columns.max(rows).next_power_of_two();
+    (0..n as u64 * n as u64)
+        .map(|d| hilbert_point(n, d))
+        .filter(|&[x, y]| x < columns && y < rows)
+        .collect()
+}
+
+/// Position of the `d`th cell along a Hilbert curve filling an `n` by `n` grid
+fn hilbert_point(n: u32, mut d: u64) -> [u32; 2] {
+    let (mut x, mut y) = (0, 0);
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (d / 2) as u32;
+        let ry = 1 & (d as u32 ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        d /= 4;
+        s *= 2;
+    }
+    [x, y]
+}
+
+#[test]
+fn tile_order_test() {
+    let mut settings = RenderSettings {
+        tile_size: 16,
+        ..Default::default()
+    };
+
+    for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+        settings.tile_order = order;
+        let tiles = generate_tiles([100, 40], &settings);
+
+        // Every pixel is covered exactly once
+        assert_eq!(tiles.len(), 7 * 3);
+        let area: u32 = tiles.iter().map(|tile| tile.width * tile.height).sum();
+        assert_eq!(area, 100 * 40);
+        for (i, a) in tiles.iter().enumerate() {
+            assert!(tiles[i + 1..].iter().all(|b| a.x != b.x || a.y != b.y));
+        }
+    }
+
+    // Spirals start in the center, Hilbert curves in a corner
+    settings.tile_order = TileOrder::Hilbert;
+    assert_eq!(
+        generate_tiles([48, 48], &settings)[0],
+        Tile {
+            x: 0,
+            y: 0,
+            width: 16,
+            height: 16
+        }
+    );
+    settings.tile_order = TileOrder::Spiral;
+    assert_eq!(
+        generate_tiles([48, 48], &settings)[0],
+        Tile {
+            x: 16,
+            y: 16,
+            width: 16,
+            height: 16
+        }
+    );
+
+    settings.region = Some([10, 10, 20, 100]);
+    let tiles = generate_tiles([100, 40], &settings);
+    assert!(tiles
+        .iter()
+        .all(|tile| tile.x >= 10 && tile.x + tile.width <= 30 && tile.y + tile.height <= 40));
+    assert_eq!(
+        tiles
+            .iter()
+            .map(|tile| tile.width * tile.height)
+            .sum::<u32>(),
+        20 * 30
+    );
+}
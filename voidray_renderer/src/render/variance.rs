@@ -0,0 +1,190 @@
+use super::tiles::Tile;
+use crate::color::Color;
+use crate::preamble::*;
+
+/// Side length in pixels of the blocks converged pixels are grouped into, a lone pixel with an
+/// unlucky low variance estimate can't stop sampling before its neighbours
+pub const ADAPTIVE_BLOCK_SIZE: u32 = 8;
+
+/// Running luminance moments of the samples taken in a pixel
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelMoments {
+    samples: u32,
+    mean: Float,
+    /// Sum of squared differences from the mean
+    m2: Float,
+    converged: bool,
+}
+
+impl PixelMoments {
+    pub fn add(&mut self, color: Color) {
+        let luminance = color.luminance();
+        self.samples += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.samples as Float;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn converged(&self) -> bool {
+        self.converged
+    }
+
+    /// Raw running state as (samples, mean, m2, converged), used by checkpoints
+    pub fn to_parts(&self) -> (u32, Float, Float, bool) {
+        (self.samples, self.mean, self.m2, self.converged)
+    }
+
+    pub fn from_parts((samples, mean, m2, converged): (u32, Float, Float, bool)) -> Self {
+        Self {
+            samples,
+            mean,
+            m2,
+            converged,
+        }
+    }
+
+    /// Standard error of the mean luminance relative to the mean itself
+    pub fn relative_error(&self) -> Float {
+        if self.samples < 2 {
+            return INF;
+        }
+
+        let variance = self.m2 / (self.samples - 1) as Float;
+        (variance / self.samples as Float).sqrt() / self.mean.max(1e-3)
+    }
+}
+
+/// Second moment buffer of a render, tracks the variance of every pixel so that converged pixels
+/// can stop being sampled
+#[derive(Clone)]
+pub struct VarianceBuffer {
+    pixels: Vec<PixelMoments>,
+    dimensions: [u32; 2],
+}
+
+impl VarianceBuffer {
+    pub fn new(dimensions: [u32; 2]) -> Self {
+        Self {
+            pixels: vec![PixelMoments::default(); (dimensions[0] * dimensions[1]) as usize],
+            dimensions,
+        }
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.dimensions
+    }
+
+    pub fn as_slice(&self) -> &[PixelMoments] {
+        &self.pixels
+    }
+
+    pub fn as_slice_mut(&mut self) -> &mut [PixelMoments] {
+        &mut self.pixels
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels
+            .iter_mut()
+            .for_each(|pixel| *pixel = PixelMoments::default());
+    }
+
+    /// Copy of the moments of the pixels covered by a tile, in row major order
+    pub fn read_tile(&self, tile: &Tile) -> Vec<PixelMoments> {
+        let width = self.dimensions[0];
+        (tile.y..tile.y + tile.height)
+            .flat_map(|y| {
+                let start = (y * width + tile.x) as usize;
+                self.pixels[start..start + tile.width as usize]
+                    .iter()
+                    .copied()
+            })
+            .collect()
+    }
+
+    /// Write back the moments of a tile read with `read_tile`
+    pub fn write_tile(&mut self, tile: &Tile, moments: &[PixelMoments]) {
+        let width = self.dimensions[0];
+        for (row, y) in moments
+            .chunks_exact(tile.width as usize)
+            .zip(tile.y..tile.y + tile.height)
+        {
+            let start = (y * width + tile.x) as usize;
+            self.pixels[start..start + tile.width as usize].copy_from_slice(row);
+        }
+    }
+
+    /// Fraction of the sampled pixels which have converged, in [0, 1]
+    pub fn convergence(&self) -> Float {
+        let sampled = self.pixels.iter().filter(|pixel| pixel.samples > 0).count();
+        if sampled == 0 {
+            return 0.0;
+        }
+
+        let converged = self.pixels.iter().filter(|pixel| pixel.converged).count();
+        converged as Float / sampled as Float
+    }
+}
+
+/// Mark every block of `ADAPTIVE_BLOCK_SIZE` pixels whose noisiest pixel has a relative error
+/// below `threshold` as converged, converged blocks are never unmarked. `pixels` is a row major
+/// tile of the given width, returns whether all of its pixels converged.
+pub fn update_convergence(pixels: &mut [PixelMoments], width: usize, threshold: Float) -> bool {
+    let block = ADAPTIVE_BLOCK_SIZE as usize;
+    if width == 0 {
+        return true;
+    }
+
+    for rows in pixels.chunks_mut(width * block) {
+        for x in (0..width).step_by(block) {
+            let columns = x..(x + block).min(width);
+            let converged = rows
+                .chunks(width)
+                .flat_map(|row| row[columns.clone()].iter())
+                .all(|pixel| pixel.converged || pixel.relative_error() < threshold);
+
+            if converged {
+                for row in rows.chunks_mut(width) {
+                    row[columns.clone()]
+                        .iter_mut()
+                        .for_each(|pixel| pixel.converged = true);
+                }
+            }
+        }
+    }
+
+    pixels.iter().all(|pixel| pixel.converged)
+}
+
+#[test]
+fn variance_convergence_test() {
+    use crate::color::GRAY;
+
+    let mut buffer = VarianceBuffer::new([12, 10]);
+    for (index, pixel) in buffer.as_slice_mut().iter_mut().enumerate() {
+        // Only the top left block is noisy
+        let noisy = index % 12 < 8 && index / 12 < 8;
+        for sample in 0..16 {
+            let value = if noisy && sample % 2 == 0 { 1.0 } else { 0.5 };
+            pixel.add(GRAY(value));
+        }
+    }
+
+    let tile = Tile {
+        x: 0,
+        y: 0,
+        width: 12,
+        height: 10,
+    };
+    let mut moments = buffer.read_tile(&tile);
+    assert!(!update_convergence(&mut moments, 12, 0.01));
+    buffer.write_tile(&tile, &moments);
+
+    assert!(!buffer.as_slice()[0].converged());
+    assert!(buffer.as_slice()[11].converged());
+    assert!(buffer.as_slice()[9 * 12].converged());
+    assert!((buffer.convergence() - 56.0 / 120.0).abs() < 1e-6);
+}
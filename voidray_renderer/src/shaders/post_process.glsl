@@ -8,7 +8,6 @@ layout(set = 0, binding = 0, rgba32f) uniform readonly image2D src;
 layout(set = 0, binding = 1, rgba32f) uniform writeonly image2D dst;
 
 layout(push_constant) uniform PostProcessingData {
-  float scale;
   float gamma;
   float exposure;
   int tonemap;
@@ -22,9 +21,11 @@ layout(push_constant) uniform PostProcessingData {
 
 void main() {
     ivec2 pos = ivec2(gl_GlobalInvocationID.xy);
-    vec4 sampled = imageLoad(src, pos) * ppd.scale;
+    vec4 sampled = imageLoad(src, pos);
 
-    vec3 color = sampled.xyz * pow(2, ppd.exposure);
+    // Alpha holds the sum of the filter weights of the accumulated samples
+    vec3 color = sampled.w != 0.0 ? sampled.xyz / sampled.w : vec3(0.0);
+    color *= pow(2, ppd.exposure);
 
     switch (ppd.tonemap) {
       case TONEMAP_ACES:
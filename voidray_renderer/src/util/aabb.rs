@@ -23,6 +23,11 @@ impl AABB {
         self.min.x <= self.max.x && self.min.y <= self.max.y && self.min.z <= self.max.z
     }
 
+    /// Returns true if every corner is finite, false for unbounded boxes and the default box
+    pub fn is_finite(&self) -> bool {
+        (0..3).all(|axis| self.min[axis].is_finite() && self.max[axis].is_finite())
+    }
+
     pub fn from_point(point: Vec3) -> Self {
         Self {
             min: point,
@@ -39,6 +39,38 @@ impl Color {
         (self.0.x + self.0.y + self.0.z) / 3.0
     }
 
+    pub fn max_component(&self) -> Float {
+        self.0.x.max(self.0.y).max(self.0.z)
+    }
+
+    /// Rec. 709 relative luminance
+    pub fn luminance(&self) -> Float {
+        self.0.dot(vec3!(0.2126, 0.7152, 0.0722))
+    }
+
+    /// Transmittance over `distance` through a material with this extinction coefficient,
+    /// following the Beer-Lambert law, the distance may be infinite
+    pub fn beer_lambert(&self, distance: Float) -> Color {
+        // Keeps a zero coefficient times an infinite distance from becoming NaN
+        let distance = distance.min(Float::MAX);
+        Color(vec3!(
+            (-self.0.x * distance).exp(),
+            (-self.0.y * distance).exp(),
+            (-self.0.z * distance).exp()
+        ))
+    }
+
+    /// Extinction coefficient which attenuates white light to this color over `distance`, the
+    /// inverse of `beer_lambert`
+    pub fn to_absorption(&self, distance: Float) -> Color {
+        let coefficient = |x: Float| -x.clamp(1.0e-4, 1.0).ln() / distance;
+        Color(vec3!(
+            coefficient(self.0.x),
+            coefficient(self.0.y),
+            coefficient(self.0.z)
+        ))
+    }
+
     pub fn r(&self) -> Float {
         self.0.x
     }
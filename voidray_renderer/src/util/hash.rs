@@ -0,0 +1,48 @@
+use std::hash::Hasher;
+
+use crate::color::Color;
+use crate::scene::TextureHandle;
+use crate::vector::Float;
+
+/// 64-bit FNV-1a, unlike the std hashers its output is stable between builds
+///
+/// Used for the render hash of checkpoints, scene objects feed their parameters into it through
+/// their `hash_parameters` methods.
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl StableHasher {
+    pub fn write_floats(&mut self, values: &[Float]) {
+        values.iter().for_each(|x| self.write(&x.to_le_bytes()));
+    }
+
+    pub fn write_color(&mut self, color: Color) {
+        self.write_floats(&[color.r(), color.g(), color.b()]);
+    }
+
+    /// Texture references are hashed by index, the textures themselves are part of the scene
+    pub fn write_texture(&mut self, texture: Option<TextureHandle>) {
+        match texture {
+            Some(texture) => self.write_u64(texture.0 as u64 + 1),
+            None => self.write_u64(0),
+        }
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
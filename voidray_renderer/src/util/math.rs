@@ -45,6 +45,30 @@ where
     a * (1.0 - t) + b * t
 }
 
+/// Map a point of the unit square to the unit disk, keeping the stratification of the square
+/// (Shirley-Chiu concentric mapping)
+pub fn sample_disk([u, v]: [Float; 2]) -> [Float; 2] {
+    let (x, y) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if x == 0.0 && y == 0.0 {
+        return [0.0, 0.0];
+    }
+
+    let (radius, theta) = if x.abs() > y.abs() {
+        (x, PI / 4.0 * (y / x))
+    } else {
+        (y, PI / 2.0 - PI / 4.0 * (x / y))
+    };
+    [radius * theta.cos(), radius * theta.sin()]
+}
+
+/// Map a point of the unit square to a uniformly distributed direction
+pub fn sample_sphere([u, v]: [Float; 2]) -> Vec3 {
+    let z = 1.0 - 2.0 * u;
+    let radius = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    vec3!(radius * phi.cos(), radius * phi.sin(), z)
+}
+
 /// Returns a coordinate transformation which maps vectors in the normal coordinate system to the
 /// world coordinate system.
 pub fn local_to_world(normal: Vec3) -> Mat3 {
@@ -54,7 +78,17 @@ pub fn local_to_world(normal: Vec3) -> Mat3 {
         vec3!(0.0, -normal.z, normal.y).normalize()
     };
     let nss = normal.cross(ns);
-    Mat3::new(
-        ns.x, nss.x, normal.x, ns.y, nss.y, normal.y, ns.z, nss.z, normal.z,
-    )
+    Mat3::from_cols(ns, nss, normal)
+}
+
+#[test]
+fn local_to_world_test() {
+    for normal in [
+        vec3!(0.0, 0.0, 1.0),
+        vec3!(0.0, 0.6, 0.8),
+        vec3!(0.48, -0.6, 0.64),
+    ] {
+        let world = local_to_world(normal) * vec3!(0.0, 0.0, 1.0);
+        assert!((world - normal).magnitude() < 1.0e-6);
+    }
 }
@@ -3,5 +3,8 @@ pub mod vector;
 
 pub mod aabb;
 pub mod color;
+pub mod hash;
 pub mod math;
 pub mod ray;
+pub mod spectrum;
+pub mod transform;
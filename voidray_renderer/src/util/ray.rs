@@ -25,8 +25,12 @@ impl Ray {
 pub struct HitRecord {
     pub point: Vec3,
     pub normal: Vec3,
+    /// Normal of the underlying geometry on the same side as `normal`, which may be interpolated
+    pub geometric_normal: Vec3,
     pub t: Float,
     pub uv: Vec2,
+    /// Direction of increasing u on the surface, zero if the surface has no uv parametrization
+    pub tangent: Vec3,
     pub front_face: bool,
 }
 
@@ -42,11 +46,27 @@ impl HitRecord {
         Self {
             point,
             normal,
+            geometric_normal: normal,
             t,
             uv,
+            tangent: Vec3::new(0.0, 0.0, 0.0),
             front_face,
         }
     }
+
+    pub fn with_tangent(mut self, tangent: Vec3) -> Self {
+        self.tangent = tangent;
+        self
+    }
+
+    pub fn with_geometric_normal(mut self, outward_normal: Vec3) -> Self {
+        self.geometric_normal = if self.front_face {
+            outward_normal
+        } else {
+            -outward_normal
+        };
+        self
+    }
 }
 
 impl PartialEq for HitRecord {
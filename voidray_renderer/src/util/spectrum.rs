@@ -0,0 +1,428 @@
+use std::ops::{Add, AddAssign, Mul, MulAssign};
+
+use crate::color::*;
+use crate::vector::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Number of wavelengths carried along a path, the hero wavelength and evenly spaced rotations of
+/// it through the visible range
+pub const WAVELENGTH_SAMPLES: usize = 4;
+
+/// Range of wavelengths sampled by spectral renders, in nanometers
+pub const LAMBDA_MIN: Float = 360.0;
+pub const LAMBDA_MAX: Float = 830.0;
+
+/// Wavelength of the sodium D line in nanometers, at which the index of refraction of a dispersive
+/// material is usually quoted
+pub const LAMBDA_D: Float = 589.3;
+
+/// Integral of the y matching function over the sampled range, the luminance of a constant
+/// spectrum of 1
+const CIE_Y_INTEGRAL: Float = 106.922;
+
+/// Linear sRGB of the equal energy spectrum, divided out so that white stays white
+const EQUAL_ENERGY_RGB: [Float; 3] = [1.2003, 0.9497, 0.9083];
+
+/// Values of a spectrum at the wavelengths of a `SampledWavelengths`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledSpectrum(pub [Float; WAVELENGTH_SAMPLES]);
+
+impl SampledSpectrum {
+    pub fn splat(value: Float) -> Self {
+        Self([value; WAVELENGTH_SAMPLES])
+    }
+
+    pub fn max_component(&self) -> Float {
+        self.0.iter().copied().fold(0.0, Float::max)
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self += rhs;
+        self
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, rhs: Self) {
+        self.0.iter_mut().zip(rhs.0).for_each(|(x, y)| *x += y);
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(mut self, rhs: Self) -> Self::Output {
+        self.0.iter_mut().zip(rhs.0).for_each(|(x, y)| *x *= y);
+        self
+    }
+}
+
+impl Mul<Float> for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(mut self, rhs: Float) -> Self::Output {
+        self *= rhs;
+        self
+    }
+}
+
+impl MulAssign<Float> for SampledSpectrum {
+    fn mul_assign(&mut self, rhs: Float) {
+        self.0.iter_mut().for_each(|x| *x *= rhs);
+    }
+}
+
+/// Wavelengths carried by a spectral path along with the densities they were sampled with
+///
+/// The first wavelength is the hero wavelength, when a path hits a dispersive material only the
+/// hero wavelength can follow the refracted ray and the others are terminated.
+#[derive(Debug, Clone, Copy)]
+pub struct SampledWavelengths {
+    lambda: [Float; WAVELENGTH_SAMPLES],
+    pdf: [Float; WAVELENGTH_SAMPLES],
+}
+
+impl SampledWavelengths {
+    /// Sample the wavelengths from a distribution concentrated on the wavelengths the eye is most
+    /// sensitive to, the secondary wavelengths use rotations of `u`
+    pub fn sample_visible(u: Float) -> Self {
+        let mut lambda = [0.0; WAVELENGTH_SAMPLES];
+        let mut pdf = [0.0; WAVELENGTH_SAMPLES];
+        for i in 0..WAVELENGTH_SAMPLES {
+            let u = (u + i as Float / WAVELENGTH_SAMPLES as Float).fract();
+            lambda[i] = sample_visible_wavelength(u);
+            pdf[i] = visible_wavelength_pdf(lambda[i]);
+        }
+
+        Self { lambda, pdf }
+    }
+
+    pub fn hero(&self) -> Float {
+        self.lambda[0]
+    }
+
+    pub fn lambda(&self) -> &[Float; WAVELENGTH_SAMPLES] {
+        &self.lambda
+    }
+
+    /// Keep only the hero wavelength, which then carries the weight of the whole sample
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+
+        self.pdf[1..].iter_mut().for_each(|pdf| *pdf = 0.0);
+        self.pdf[0] /= WAVELENGTH_SAMPLES as Float;
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|pdf| *pdf == 0.0)
+    }
+
+    /// Spectrum of an rgb reflectance or radiance at the sampled wavelengths
+    pub fn uplift(&self, color: Color) -> SampledSpectrum {
+        SampledSpectrum(self.lambda.map(|lambda| rgb_to_spectrum(color, lambda)))
+    }
+
+    /// Linear sRGB of the radiance sampled at these wavelengths, this is an unbiased estimate of
+    /// the color of the full spectrum
+    pub fn to_rgb(&self, spectrum: SampledSpectrum) -> Color {
+        let mut xyz = [0.0; 3];
+        for i in 0..WAVELENGTH_SAMPLES {
+            if self.pdf[i] == 0.0 {
+                continue;
+            }
+
+            let matching = cie_xyz(self.lambda[i]);
+            for c in 0..3 {
+                xyz[c] += matching[c] * spectrum.0[i] / self.pdf[i];
+            }
+        }
+
+        xyz_to_rgb(xyz.map(|x| x / (WAVELENGTH_SAMPLES as Float * CIE_Y_INTEGRAL)))
+    }
+}
+
+/// Sample a wavelength with a density of roughly the shape of the y matching function
+fn sample_visible_wavelength(u: Float) -> Float {
+    538.0 - 138.88889 * (0.8569106 - 1.827502 * u).atanh()
+}
+
+fn visible_wavelength_pdf(lambda: Float) -> Float {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    0.003939804 / (0.0072 * (lambda - 538.0)).cosh().powi(2)
+}
+
+/// CIE 1931 color matching functions, using the multi-lobe fit of Wyman, Sloan and Shirley,
+/// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions" (2013)
+pub fn cie_xyz(lambda: Float) -> [Float; 3] {
+    let lobe = |mean: Float, below: Float, above: Float| {
+        let t = (lambda - mean) / if lambda < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+
+    [
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    ]
+}
+
+/// Convert CIE XYZ to linear sRGB, white balanced for the equal energy spectrum
+pub fn xyz_to_rgb([x, y, z]: [Float; 3]) -> Color {
+    Color::new(
+        (3.2404542 * x - 1.5371385 * y - 0.4985314 * z) / EQUAL_ENERGY_RGB[0],
+        (-0.969266 * x + 1.8760108 * y + 0.041556 * z) / EQUAL_ENERGY_RGB[1],
+        (0.0556434 * x - 0.2040259 * y + 1.0572252 * z) / EQUAL_ENERGY_RGB[2],
+    )
+}
+
+/// Smits' basis spectra, sampled at 10 bins spanning 380 to 720nm
+const SMITS_WHITE: [Float; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [Float; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [Float; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [Float; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [Float; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [Float; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [Float; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Uplift an rgb color to a smooth spectrum and evaluate it at `lambda`
+///
+/// Uses Smits, "An RGB-to-Spectrum Conversion for Reflectances" (1999), which builds the spectrum
+/// from white and the spectra of the primaries and their complements. The conversion is linear
+/// in the color, so it also works for radiances above 1.
+pub fn rgb_to_spectrum(color: Color, lambda: Float) -> Float {
+    let [r, g, b] = [color.r(), color.g(), color.b()].map(|x| x.max(0.0));
+    let basis = |spectrum: &[Float; 10]| {
+        // Linear interpolation between the bin centers
+        let x = ((lambda - 380.0) / 34.0 - 0.5).clamp(0.0, 9.0);
+        let i = (x as usize).min(8);
+        let t = x - i as Float;
+        spectrum[i] * (1.0 - t) + spectrum[i + 1] * t
+    };
+
+    if r <= g && r <= b {
+        r * basis(&SMITS_WHITE)
+            + if g <= b {
+                (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+            } else {
+                (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+            }
+    } else if g <= r && g <= b {
+        g * basis(&SMITS_WHITE)
+            + if r <= b {
+                (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+            } else {
+                (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+            }
+    } else {
+        b * basis(&SMITS_WHITE)
+            + if r <= g {
+                (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+            } else {
+                (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+            }
+    }
+}
+
+/// Wavelength dependent index of refraction of a dielectric, wavelengths are in nanometers
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Ior {
+    Constant(Float),
+    /// Cauchy's equation n = a + b / λ², with λ in micrometers
+    Cauchy {
+        a: Float,
+        b: Float,
+    },
+    /// Sellmeier equation n² = 1 + Σ bᵢλ² / (λ² - cᵢ), with λ in micrometers
+    Sellmeier {
+        b: [Float; 3],
+        c: [Float; 3],
+    },
+}
+
+// Sellmeier coefficients are kept as published, high precision builds use all of their digits
+#[allow(clippy::excessive_precision)]
+impl Ior {
+    /// Borosilicate crown glass
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+
+    pub const FUSED_SILICA: Ior = Ior::Sellmeier {
+        b: [0.6961663, 0.4079426, 0.8974794],
+        c: [0.00467914826, 0.0135120631, 97.9340025],
+    };
+
+    /// Dense flint glass, which disperses light much more than crown glasses
+    pub const SF11: Ior = Ior::Sellmeier {
+        b: [1.73759695, 0.313747346, 1.89878101],
+        c: [0.013188707, 0.0623068142, 155.23629],
+    };
+
+    pub fn at(&self, lambda: Float) -> Float {
+        let micrometers = lambda / 1000.0;
+        let l2 = micrometers * micrometers;
+        match self {
+            Ior::Constant(index) => *index,
+            Ior::Cauchy { a, b } => a + b / l2,
+            Ior::Sellmeier { b, c } => {
+                let sum: Float = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
+}
+
+/// Complex index of refraction η + ik of a conductor, tabulated at 400, 500, 600 and 700nm
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ComplexIor {
+    pub eta: [Float; 4],
+    pub k: [Float; 4],
+}
+
+impl ComplexIor {
+    pub const GOLD: ComplexIor = ComplexIor {
+        eta: [1.658, 0.970, 0.250, 0.160],
+        k: [1.956, 1.870, 2.980, 3.950],
+    };
+
+    pub const SILVER: ComplexIor = ComplexIor {
+        eta: [0.050, 0.050, 0.060, 0.040],
+        k: [2.100, 3.100, 4.000, 4.800],
+    };
+
+    pub const COPPER: ComplexIor = ComplexIor {
+        eta: [1.180, 1.120, 0.270, 0.210],
+        k: [2.210, 2.600, 3.410, 4.200],
+    };
+
+    pub const ALUMINIUM: ComplexIor = ComplexIor {
+        eta: [0.490, 0.770, 1.200, 1.830],
+        k: [4.860, 6.080, 7.260, 8.310],
+    };
+
+    /// Wavelengths standing in for the r, g and b channels of rgb renders
+    const RGB_WAVELENGTHS: [Float; 3] = [610.0, 550.0, 465.0];
+
+    /// Returns (η, k) at a wavelength, interpolated linearly between the tabulated values
+    pub fn at(&self, lambda: Float) -> (Float, Float) {
+        let x = ((lambda - 400.0) / 100.0).clamp(0.0, 3.0);
+        let i = (x as usize).min(2);
+        let t = x - i as Float;
+        (
+            self.eta[i] * (1.0 - t) + self.eta[i + 1] * t,
+            self.k[i] * (1.0 - t) + self.k[i + 1] * t,
+        )
+    }
+
+    /// Fresnel reflectance at a wavelength for light arriving at an angle with cosine `cosine`
+    pub fn reflectance(&self, cosine: Float, lambda: Float) -> Float {
+        let (eta, k) = self.at(lambda);
+        fresnel_conductor(cosine, eta, k)
+    }
+
+    /// Fresnel reflectance of the r, g and b channels, for rgb renders
+    pub fn rgb_reflectance(&self, cosine: Float) -> Color {
+        let [r, g, b] = Self::RGB_WAVELENGTHS.map(|lambda| self.reflectance(cosine, lambda));
+        Color::new(r, g, b)
+    }
+}
+
+/// Unpolarized Fresnel reflectance of a conductor with complex index of refraction η + ik
+pub fn fresnel_conductor(cosine: Float, eta: Float, k: Float) -> Float {
+    let cos2 = cosine.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let (eta2, k2) = (eta * eta, k * k);
+
+    let t0 = eta2 - k2 - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2b2 + cos2;
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos2.sqrt() * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+#[test]
+fn spectrum_roundtrip_test() {
+    // Averaging many wavelength samples of an uplifted color gives back the color
+    let roundtrip = |color: Color| {
+        let samples = 4096;
+        let mut sum = BLACK;
+        for i in 0..samples {
+            let wavelengths =
+                SampledWavelengths::sample_visible((i as Float + 0.5) / samples as Float);
+            sum += wavelengths.to_rgb(wavelengths.uplift(color));
+        }
+        sum / samples as Float
+    };
+
+    let white = roundtrip(WHITE);
+    assert!((white.r() - 1.0).abs() < 0.01);
+    assert!((white.g() - 1.0).abs() < 0.01);
+    assert!((white.b() - 1.0).abs() < 0.01);
+
+    let red = roundtrip(Color::new(0.8, 0.1, 0.1));
+    assert!(red.r() > 0.6 && red.g() < 0.2 && red.b() < 0.2);
+
+    // Terminating the secondary wavelengths keeps the estimate unbiased
+    let mut wavelengths = SampledWavelengths::sample_visible(0.3);
+    let before = wavelengths.to_rgb(SampledSpectrum::splat(1.0));
+    wavelengths.terminate_secondary();
+    assert!(wavelengths.secondary_terminated());
+    let after = wavelengths.to_rgb(SampledSpectrum::splat(1.0));
+    assert!(after.0.magnitude() > 0.0 && before.0.magnitude() > 0.0);
+}
+
+#[test]
+fn ior_test() {
+    // BK7 is quoted with n = 1.5168 at the sodium D line, and disperses blue light more
+    assert!((Ior::BK7.at(LAMBDA_D) - 1.5168).abs() < 1e-3);
+    assert!(Ior::BK7.at(450.0) > Ior::BK7.at(650.0));
+    assert!(!Ior::Constant(1.5).is_dispersive());
+
+    // Gold reflects red light much better than blue, at every angle
+    for cosine in [1.0, 0.5, 0.1] {
+        assert!(
+            ComplexIor::GOLD.reflectance(cosine, 650.0)
+                > ComplexIor::GOLD.reflectance(cosine, 450.0)
+        );
+    }
+    assert!((fresnel_conductor(0.0, 0.5, 3.0) - 1.0).abs() < 1e-4);
+}
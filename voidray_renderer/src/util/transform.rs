@@ -0,0 +1,92 @@
+use cgmath::{Matrix, SquareMatrix};
+
+use crate::aabb::AABB;
+use crate::ray::*;
+use crate::vec3;
+use crate::vector::*;
+
+/// An affine transformation along with its inverse, used to place instances in the scene
+#[derive(Clone, PartialEq)]
+pub struct Transform {
+    matrix: Mat4,
+    inverse: Mat4,
+    /// Inverse transpose of the linear part, transforms normals
+    normal_matrix: Mat3,
+}
+
+impl Transform {
+    /// Returns `None` if the matrix is not invertible
+    pub fn new(matrix: Mat4) -> Option<Self> {
+        let inverse = matrix.invert()?;
+        let linear = Mat3::from_cols(
+            matrix.x.truncate(),
+            matrix.y.truncate(),
+            matrix.z.truncate(),
+        );
+
+        Some(Self {
+            matrix,
+            inverse,
+            normal_matrix: linear.invert()?.transpose(),
+        })
+    }
+
+    pub fn matrix(&self) -> &Mat4 {
+        &self.matrix
+    }
+
+    pub fn transform_point(&self, point: Vec3) -> Vec3 {
+        (self.matrix * point.extend(1.0)).truncate()
+    }
+
+    pub fn transform_vector(&self, vector: Vec3) -> Vec3 {
+        (self.matrix * vector.extend(0.0)).truncate()
+    }
+
+    /// Transforms a normal and renormalizes it
+    pub fn transform_normal(&self, normal: Vec3) -> Vec3 {
+        (self.normal_matrix * normal).normalize()
+    }
+
+    /// Transforms a world space ray into object space, returns the ray along with the factor
+    /// to multiply world space distances by to get object space distances
+    pub fn inverse_ray(&self, ray: &Ray) -> (Ray, Float) {
+        let origin = (self.inverse * ray.origin.extend(1.0)).truncate();
+        let direction = (self.inverse * ray.direction.extend(0.0)).truncate();
+        let scale = direction.magnitude() / ray.direction.magnitude();
+
+        (Ray::new(origin, direction), scale)
+    }
+
+    /// Bounding box of the transformed corners of `bounds`. Unbounded boxes become the fully
+    /// unbounded default box, since e.g. a rotated slab is no longer axis-aligned.
+    pub fn transform_bounds(&self, bounds: &AABB) -> AABB {
+        if !bounds.is_finite() {
+            return AABB::default();
+        }
+
+        let corners = [bounds.min, bounds.max];
+        (0..8)
+            .map(|corner| {
+                let point = vec3!(
+                    corners[corner & 1].x,
+                    corners[(corner >> 1) & 1].y,
+                    corners[(corner >> 2) & 1].z
+                );
+                AABB::from_point(self.transform_point(point))
+            })
+            .reduce(AABB::surround)
+            .unwrap_or_default()
+    }
+
+    /// Factor by which the transformation scales surface areas, exact for rotations and
+    /// uniform scales
+    pub fn area_scale(&self) -> Float {
+        let linear = Mat3::from_cols(
+            self.matrix.x.truncate(),
+            self.matrix.y.truncate(),
+            self.matrix.z.truncate(),
+        );
+        linear.determinant().abs().powf(2.0 / 3.0)
+    }
+}